@@ -11,4 +11,5 @@ readelf -Ws target/release/cheese | rg 'GLIBC_2.3[^\. ]'
 mkdir -p linux_build
 rm -rf linux_build/*
 cp controls.md linux_build
+cp -r scenarios linux_build
 cp target/release/cheese linux_build
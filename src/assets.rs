@@ -11,6 +11,9 @@ pub struct Assets {
     pub command_indicator_model: Model,
     pub command_path_model: Model,
     pub armoury_model: Model,
+    pub larder_model: Model,
+    pub turret_model: Model,
+    pub turret_head_model: Model,
     pub cheese_moon_model: Model,
     pub billboard_model: Model,
     pub cheese_droplet_model: Model,
@@ -127,6 +130,21 @@ impl Assets {
                 "Cheese armoury model",
                 device,
             )?,
+            larder_model: Model::load_gltf(
+                include_bytes!("../models/larder.gltf"),
+                "Cheese larder model",
+                device,
+            )?,
+            turret_model: Model::load_gltf(
+                include_bytes!("../models/turret.gltf"),
+                "Cheese turret model",
+                device,
+            )?,
+            turret_head_model: Model::load_gltf(
+                include_bytes!("../models/turret_head.gltf"),
+                "Cheese turret head model",
+                device,
+            )?,
             cheese_moon_model: Model::load_gltf(
                 include_bytes!("../models/cheese_moon.gltf"),
                 "Cheese cheese moon model",
@@ -0,0 +1,405 @@
+use crate::ecs::{
+    AbilityType, Building, BuildingStats, Projectile, Spell, SpellStats, TargetType, Unit,
+    UnitStats, Upgrade, UpgradeStats,
+};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+/// Where the definitions are read from. If the file doesn't exist, `BUILTIN` is used instead.
+pub const PATH: &str = "definitions.ron";
+const BUILTIN: &str = include_str!("../definitions.ron");
+
+/// The stats of every kind of unit, building, upgrade and ability, as read from `definitions.ron`.
+///
+/// These are stored globally so that `Unit::stats` and `Building::stats` can be called from
+/// anywhere. Until `load` is called (e.g. in tests) the built-in definitions are used. Each set of
+/// stats is shared so that looking it up doesn't copy it.
+#[derive(Deserialize)]
+pub struct Definitions {
+    // A hash of the text that the definitions were parsed from, so that replays and saves can tell
+    // if they were made with different definitions.
+    #[serde(skip)]
+    pub hash: u64,
+    pub units: BTreeMap<Unit, Arc<UnitStats>>,
+    pub buildings: BTreeMap<Building, Arc<BuildingStats>>,
+    pub upgrades: BTreeMap<Upgrade, Arc<UpgradeStats>>,
+    pub spells: BTreeMap<Spell, Arc<SpellStats>>,
+}
+
+static DEFINITIONS: RwLock<Option<Definitions>> = RwLock::new(None);
+// When the definitions file was last modified, for hot-reloading.
+static LAST_MODIFIED: Mutex<Option<SystemTime>> = Mutex::new(None);
+
+impl Definitions {
+    pub fn parse(string: &str) -> anyhow::Result<Self> {
+        let mut definitions: Self = ron::de::from_str(string)?;
+        definitions.validate()?;
+        definitions.hash = fnv1a(string.as_bytes());
+        Ok(definitions)
+    }
+
+    fn builtin() -> Self {
+        Self::parse(BUILTIN).expect("The built-in definitions are invalid")
+    }
+
+    // Check for anything that would cause problems in game, and list all the problems at once.
+    fn validate(&self) -> anyhow::Result<()> {
+        let mut errors = Vec::new();
+
+        for unit in Unit::ALL.iter() {
+            let stats = match self.units.get(unit) {
+                Some(stats) => stats,
+                None => {
+                    errors.push(format!("{:?} is missing from `units`", unit));
+                    continue;
+                }
+            };
+
+            let mut check_positive = |name: &str, value: f32| {
+                if value.is_nan() || value <= 0.0 {
+                    errors.push(format!(
+                        "{:?}: `{}` must be greater than 0 but it's {}",
+                        unit, name, value
+                    ));
+                }
+            };
+
+            check_positive("max_health", stats.max_health);
+            check_positive("move_speed", stats.move_speed);
+            check_positive("radius", stats.radius);
+            check_positive("recruitment_time", stats.recruitment_time);
+            check_positive("sight_range", stats.sight_range);
+            if let Some(firing_range) = stats.firing_range {
+                check_positive("firing_range", firing_range);
+                check_positive("damage", stats.damage);
+                check_positive("fire_rate", stats.fire_rate);
+
+                if let Projectile::Shell {
+                    speed,
+                    splash_radius,
+                } = stats.projectile
+                {
+                    check_positive("projectile.speed", speed);
+                    check_positive("projectile.splash_radius", splash_radius);
+                }
+
+                if stats.min_firing_range.is_nan() || stats.min_firing_range < 0.0 {
+                    errors.push(format!(
+                        "{:?}: `min_firing_range` can't be negative but it's {}",
+                        unit, stats.min_firing_range
+                    ));
+                } else if stats.min_firing_range >= firing_range {
+                    errors.push(format!(
+                        "{:?}: `min_firing_range` must be less than `firing_range`",
+                        unit
+                    ));
+                }
+            }
+
+            if stats.armour.is_nan() || stats.armour < 0.0 {
+                errors.push(format!(
+                    "{:?}: `armour` can't be negative but it's {}",
+                    unit, stats.armour
+                ));
+            }
+
+            if stats.max_energy.is_nan() || stats.max_energy < 0.0 {
+                errors.push(format!(
+                    "{:?}: `max_energy` can't be negative but it's {}",
+                    unit, stats.max_energy
+                ));
+            }
+
+            for ability in &stats.abilities {
+                match ability {
+                    AbilityType::Build(_) => {}
+                    AbilityType::Cast(spell) => match self.spells.get(spell) {
+                        Some(spell_stats) if stats.max_energy < spell_stats.energy_cost => errors
+                            .push(format!(
+                                "{:?}: `max_energy` is too low to ever cast {:?}",
+                                unit, spell
+                            )),
+                        _ => {}
+                    },
+                    _ => errors.push(format!(
+                        "{:?}: units can only have `Build` and `Cast` abilities, not `{:?}`",
+                        unit, ability
+                    )),
+                }
+            }
+        }
+
+        for building in Building::ALL.iter() {
+            let stats = match self.buildings.get(building) {
+                Some(stats) => stats,
+                None => {
+                    errors.push(format!("{:?} is missing from `buildings`", building));
+                    continue;
+                }
+            };
+
+            let mut check_positive = |name: &str, value: f32| {
+                if value.is_nan() || value <= 0.0 {
+                    errors.push(format!(
+                        "{:?}: `{}` must be greater than 0 but it's {}",
+                        building, name, value
+                    ));
+                }
+            };
+
+            check_positive("radius", stats.radius);
+            check_positive("max_health", stats.max_health);
+            check_positive("sight_range", stats.sight_range);
+            check_positive("dimensions.x", stats.dimensions.x);
+            check_positive("dimensions.y", stats.dimensions.y);
+            if let Some(firing_range) = stats.firing_range {
+                check_positive("firing_range", firing_range);
+                check_positive("damage", stats.damage);
+                check_positive("fire_rate", stats.fire_rate);
+            }
+
+            if stats.armour.is_nan() || stats.armour < 0.0 {
+                errors.push(format!(
+                    "{:?}: `armour` can't be negative but it's {}",
+                    building, stats.armour
+                ));
+            }
+
+            for (i, unit) in stats.recruits.iter().enumerate() {
+                if stats.recruits[..i].contains(unit) {
+                    errors.push(format!("{:?}: {:?} is recruited twice", building, unit));
+                }
+            }
+
+            for (i, upgrade) in stats.researches.iter().enumerate() {
+                if stats.researches[..i].contains(upgrade) {
+                    errors.push(format!("{:?}: {:?} is researched twice", building, upgrade));
+                }
+            }
+        }
+
+        for upgrade in Upgrade::ALL.iter() {
+            let stats = match self.upgrades.get(upgrade) {
+                Some(stats) => stats,
+                None => {
+                    errors.push(format!("{:?} is missing from `upgrades`", upgrade));
+                    continue;
+                }
+            };
+
+            if stats.research_time.is_nan() || stats.research_time <= 0.0 {
+                errors.push(format!(
+                    "{:?}: `research_time` must be greater than 0 but it's {}",
+                    upgrade, stats.research_time
+                ));
+            }
+
+            if self.requires(*upgrade, *upgrade) {
+                errors.push(format!(
+                    "{:?} requires itself, so it can never be researched",
+                    upgrade
+                ));
+            }
+        }
+
+        for spell in Spell::ALL.iter() {
+            let stats = match self.spells.get(spell) {
+                Some(stats) => stats,
+                None => {
+                    errors.push(format!("{:?} is missing from `spells`", spell));
+                    continue;
+                }
+            };
+
+            if stats.target != TargetType::NoTarget && (stats.range.is_nan() || stats.range <= 0.0)
+            {
+                errors.push(format!(
+                    "{:?}: `range` must be greater than 0 but it's {}",
+                    spell, stats.range
+                ));
+            }
+
+            let mut check_not_negative = |name: &str, value: f32| {
+                if value.is_nan() || value < 0.0 {
+                    errors.push(format!(
+                        "{:?}: `{}` can't be negative but it's {}",
+                        spell, name, value
+                    ));
+                }
+            };
+
+            check_not_negative("energy_cost", stats.energy_cost);
+            check_not_negative("cooldown", stats.cooldown);
+            check_not_negative("channel_time", stats.channel_time);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "Invalid definitions:\n    {}",
+                errors.join("\n    ")
+            ))
+        }
+    }
+
+    // Whether `upgrade` needs `required` to be researched first, directly or not.
+    fn requires(&self, upgrade: Upgrade, required: Upgrade) -> bool {
+        let mut to_check = vec![upgrade];
+        let mut checked = Vec::new();
+
+        while let Some(upgrade) = to_check.pop() {
+            let requires = match self.upgrades.get(&upgrade) {
+                Some(stats) => &stats.requires,
+                None => continue,
+            };
+
+            if requires.contains(&required) {
+                return true;
+            }
+
+            checked.push(upgrade);
+            to_check.extend(requires.iter().filter(|upgrade| !checked.contains(upgrade)));
+        }
+
+        false
+    }
+}
+
+fn with<R>(func: impl FnOnce(&Definitions) -> R) -> R {
+    {
+        let definitions = DEFINITIONS.read().unwrap();
+        if let Some(definitions) = definitions.as_ref() {
+            return func(definitions);
+        }
+    }
+
+    let mut definitions = DEFINITIONS.write().unwrap();
+    func(definitions.get_or_insert_with(Definitions::builtin))
+}
+
+pub fn unit(unit: Unit) -> Arc<UnitStats> {
+    with(|definitions| definitions.units[&unit].clone())
+}
+
+pub fn building(building: Building) -> Arc<BuildingStats> {
+    with(|definitions| definitions.buildings[&building].clone())
+}
+
+pub fn upgrade(upgrade: Upgrade) -> Arc<UpgradeStats> {
+    with(|definitions| definitions.upgrades[&upgrade].clone())
+}
+
+pub fn spell(spell: Spell) -> Arc<SpellStats> {
+    with(|definitions| definitions.spells[&spell].clone())
+}
+
+/// A hash of the definitions in use. Replays and saves store this, to warn if they're loaded with
+/// different definitions to the ones they were made with.
+pub fn hash() -> u64 {
+    with(|definitions| definitions.hash)
+}
+
+/// Load the definitions from `PATH`, or use the built-in ones if it doesn't exist.
+pub fn load() -> anyhow::Result<()> {
+    let path = Path::new(PATH);
+
+    let definitions = if path.exists() {
+        let string = std::fs::read_to_string(path)?;
+        *LAST_MODIFIED.lock().unwrap() = modified();
+        Definitions::parse(&string).map_err(|error| error.context(path.display().to_string()))?
+    } else {
+        log::info!("{} doesn't exist, using the built-in definitions", PATH);
+        Definitions::builtin()
+    };
+
+    *DEFINITIONS.write().unwrap() = Some(definitions);
+    Ok(())
+}
+
+/// Load the definitions again if the file has changed. If the new definitions are invalid, the old
+/// ones are kept.
+///
+/// This must only be called while no match is running, as changing the stats partway through would
+/// make the match play out differently to its replay.
+pub fn reload_if_changed() {
+    let modified = modified();
+    let mut last_modified = LAST_MODIFIED.lock().unwrap();
+
+    if modified.is_none() || modified == *last_modified {
+        return;
+    }
+
+    *last_modified = modified;
+
+    match std::fs::read_to_string(PATH)
+        .map_err(Into::into)
+        .and_then(|string| Definitions::parse(&string))
+    {
+        Ok(definitions) => {
+            log::info!("Reloaded {}", PATH);
+            *DEFINITIONS.write().unwrap() = Some(definitions);
+        }
+        Err(error) => log::error!("Failed to reload {}: {:?}", PATH, error),
+    }
+}
+
+// `DefaultHasher` isn't guaranteed to give the same result across Rust versions, so FNV-1a is used
+// to keep hashes stored in files comparable.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn modified() -> Option<SystemTime> {
+    std::fs::metadata(PATH)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[test]
+fn invalid_definitions_are_reported() {
+    let error = Definitions::parse(
+        &BUILTIN
+            .replace("move_speed: 6.0", "move_speed: -1.0")
+            .replace("fire_rate: 6.0", "fire_rate: 0.0")
+            .replace("fire_rate: 3.0", "fire_rate: -3.0")
+            .replace("min_firing_range: 6.0", "min_firing_range: 30.0")
+            .replace(
+                "recruits: [MouseMarine, Engineer, Psychic, Artillery]",
+                "recruits: [Engineer, Engineer, Psychic, Artillery]",
+            )
+            .replace("max_energy: 200.0", "max_energy: 50.0")
+            .replace("cooldown: 0.0", "cooldown: -1.0")
+            .replace(
+                "requires: [EngineerBuildSpeed]",
+                "requires: [BuildingArmour]",
+            ),
+    )
+    .err()
+    .unwrap()
+    .to_string();
+
+    assert!(error.contains("MouseMarine: `move_speed` must be greater than 0 but it's -1"));
+    assert!(error.contains("MouseMarine: `fire_rate` must be greater than 0 but it's 0"));
+    assert!(error.contains("Turret: `fire_rate` must be greater than 0 but it's -3"));
+    assert!(error.contains("Artillery: `min_firing_range` must be less than `firing_range`"));
+    assert!(error.contains("Armoury: Engineer is recruited twice"));
+    assert!(error.contains("Psychic: `max_energy` is too low to ever cast MindControl"));
+    assert!(error.contains("MindControl: `cooldown` can't be negative but it's -1"));
+    assert!(error.contains("BuildingArmour requires itself, so it can never be researched"));
+}
+
+#[test]
+fn changed_definitions_have_a_different_hash() {
+    let builtin = Definitions::builtin();
+    let changed =
+        Definitions::parse(&BUILTIN.replace("move_speed: 6.0", "move_speed: 7.0")).unwrap();
+
+    assert_eq!(builtin.hash, Definitions::builtin().hash);
+    assert_ne!(builtin.hash, changed.hash);
+}
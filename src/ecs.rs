@@ -2,13 +2,14 @@ use crate::assets::ModelAnimations;
 use crate::pathfinding::{Map, MapHandle};
 use crate::renderer::Image;
 use crate::resources::{
-    Camera, CameraControls, DeltaTime, MouseState, PlayerSide, RtsControls, ScreenDimensions,
+    Camera, CameraControls, DeltaTime, MouseState, NextEntityId, PlayerSide, RtsControls,
+    ScreenDimensions,
 };
 use legion::systems::CommandBuffer;
 use legion::world::SubWorld;
 use legion::*;
-use rand::rngs::SmallRng;
 use rand::Rng;
+use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
 use ultraviolet::{Mat4, Vec2, Vec3};
 use winit::event::VirtualKeyCode;
@@ -19,27 +20,39 @@ mod buildings;
 mod combat;
 mod controls;
 mod debugging;
+mod editor;
 mod effects;
+mod minimap;
 mod movement;
+mod player_commands;
 mod playing_menu;
 mod rendering;
+mod replay_controls;
+mod research;
+mod spells;
+mod triggers;
+mod visibility;
 
 use crate::resources::DebugControls;
-use ai::follow_ai_build_orders_system;
+use ai::{follow_ai_build_orders_system, retreat_damaged_units_system, run_ai_system};
+#[cfg(test)]
+pub use ai::enemies_seen_by_ai;
 use animation::{progress_animations_system, progress_building_animations_system};
 use buildings::{
-    build_buildings_system, free_up_cheese_guysers_system, generate_cheese_coins_system,
-    progress_recruitment_queue_system,
+    build_buildings_system, count_supply_system, free_up_cheese_guysers_system,
+    generate_cheese_coins_system, progress_recruitment_queue_system,
 };
 use combat::{
-    agro_units_system, apply_bullets_system, firing_system, handle_damaged_system,
+    agro_units_system, apply_projectiles_system, firing_system, handle_damaged_system,
     propagate_agro_system, reduce_cooldowns_system, stop_actions_on_dead_entities_system,
-    update_argoed_this_tick_system,
+    turret_firing_system, update_argoed_this_tick_system,
 };
+#[cfg(test)]
+pub use combat::best_target;
 use controls::{
-    cast_ray_system, control_camera_system, handle_control_groups_system,
-    handle_drag_selection_system, handle_keypresses_system, handle_left_click_system,
-    handle_right_click_system, handle_stop_command_system,
+    cast_ray_system, control_camera_system, deselect_hidden_entities_system,
+    handle_control_groups_system, handle_drag_selection_system, handle_keypresses_system,
+    handle_left_click_system, handle_right_click_system, handle_stop_command_system,
     remove_dead_entities_from_control_groups_system, update_playing_state_system,
     update_selected_units_abilities_system,
 };
@@ -49,25 +62,41 @@ use debugging::{
     render_pathfinding_map_system, render_unit_paths_system, set_debug_pathfinding_start_system,
     spawn_debug_building_system,
 };
+use editor::{
+    handle_editor_clicks_system, handle_editor_keypresses_system, render_editor_ui_system,
+};
 use effects::{
     apply_gravity_system, expand_explosions_system, move_cheese_droplets_system,
     render_cheese_droplets_system, render_explosions_system, spawn_cheese_droplets_system,
 };
+use minimap::{control_camera_with_minimap_system, position_on_minimap, render_minimap_system};
 use movement::{
-    apply_steering_system, avoidance_system, move_bullets_system, move_units_system,
-    reset_map_updated_system, set_movement_paths_system, Avoidable, Avoids,
+    apply_steering_system, avoidance_system, move_bullets_system, move_shells_system,
+    move_units_system, reset_map_updated_system, set_movement_paths_system,
+    store_previous_positions_system,
 };
+pub use movement::{Avoidable, Avoidance, Avoids};
+use player_commands::apply_player_commands_system;
+pub use player_commands::{PlayerCastTarget, PlayerCommand};
 use playing_menu::{
     handle_playing_menu_controls_system, render_playing_menu_click_regions_system,
     render_playing_menu_system,
 };
 use rendering::{
-    render_abilities_system, render_building_plan_system, render_buildings_system,
-    render_bullets_system, render_command_paths_system, render_drag_box_system,
-    render_health_bars_system, render_recruitment_waypoints_system, render_selections_system,
-    render_ui_system, render_under_select_box_system, render_unit_under_cursor_system,
-    render_units_system,
+    render_abilities_system, render_building_ghosts_system, render_building_plan_system,
+    render_buildings_system, render_bullets_system, render_command_paths_system,
+    render_drag_box_system, render_energy_bars_system, render_health_bars_system,
+    render_recruitment_waypoints_system, render_selections_system, render_shells_system,
+    render_spell_ranges_system, render_ui_system, render_under_select_box_system,
+    render_unit_under_cursor_system, render_units_system,
+};
+use replay_controls::{handle_replay_keypresses_system, render_replay_ui_system};
+use research::{can_research, upgrade_firing_ranges_system};
+use spells::{
+    can_cast, cast_spells_system, reduce_spell_cooldowns_system, regenerate_energy_system,
 };
+use triggers::check_triggers_system;
+use visibility::update_visibility_system;
 
 #[legion::system]
 fn cleanup_controls(
@@ -88,38 +117,53 @@ fn cleanup_controls(
     debug_controls.set_pathfinding_start_pressed = false;
 }
 
+pub fn add_control_systems(builder: &mut legion::systems::Builder) {
+    builder
+        .add_system(handle_keypresses_system())
+        .add_system(cast_ray_system())
+        .add_system(remove_dead_entities_from_control_groups_system())
+        .add_system(deselect_hidden_entities_system())
+        .add_system(control_camera_system())
+        .add_system(control_camera_with_minimap_system())
+        .add_system(handle_left_click_system())
+        .add_system(handle_right_click_system())
+        .add_system(handle_stop_command_system())
+        .add_system(handle_drag_selection_system())
+        .add_system(handle_control_groups_system())
+        .add_system(update_selected_units_abilities_system())
+        .add_system(set_debug_pathfinding_start_system());
+}
+
 pub fn add_gameplay_systems(builder: &mut legion::systems::Builder) {
     builder
+        .add_system(store_previous_positions_system())
+        .add_system(update_visibility_system())
+        .add_system(apply_player_commands_system())
         // Agro propagation and updating
         .add_system(update_argoed_this_tick_system())
         .flush()
         .add_system(propagate_agro_system())
         .flush()
         //
-        .add_system(handle_keypresses_system())
         .add_system(generate_cheese_coins_system())
+        .add_system(count_supply_system())
         .add_system(progress_recruitment_queue_system())
-        .add_system(reset_map_updated_system())
-        .add_system(cast_ray_system())
+        .add_system(upgrade_firing_ranges_system())
         .add_system(free_up_cheese_guysers_system())
-        .add_system(remove_dead_entities_from_control_groups_system())
         .add_system(stop_actions_on_dead_entities_system())
-        .add_system(control_camera_system())
-        .add_system(handle_left_click_system())
-        .add_system(handle_right_click_system())
-        .add_system(handle_stop_command_system())
-        .add_system(handle_drag_selection_system())
-        .add_system(handle_control_groups_system())
         .add_system(avoidance_system())
         .add_system(agro_units_system())
-        .add_system(update_selected_units_abilities_system())
+        .add_system(check_triggers_system())
+        // Triggers can add AI orders that are followed straight away.
+        .flush()
         .add_system(follow_ai_build_orders_system())
+        .add_system(run_ai_system())
+        .add_system(retreat_damaged_units_system())
         // Needed because a command could place a building using a command buffer, but the entity
         // reference wouldn't be valid until the commands in the buffer have been executed.
         .flush()
         .add_system(set_movement_paths_system())
         .add_system(reduce_cooldowns_system())
-        .add_system(set_debug_pathfinding_start_system())
         // Cheese droplets.
         .add_system(spawn_cheese_droplets_system())
         .flush()
@@ -128,39 +172,57 @@ pub fn add_gameplay_systems(builder: &mut legion::systems::Builder) {
         .add_system(move_cheese_droplets_system())
         .add_system(move_units_system())
         .add_system(move_bullets_system())
+        .add_system(move_shells_system())
         .add_system(apply_steering_system())
         .add_system(build_buildings_system())
+        .add_system(regenerate_energy_system())
+        .add_system(reduce_spell_cooldowns_system())
+        .add_system(cast_spells_system())
         .add_system(firing_system())
-        .add_system(apply_bullets_system())
+        .add_system(turret_firing_system())
+        .add_system(apply_projectiles_system())
         .flush()
         .add_system(handle_damaged_system())
         .add_system(update_playing_state_system())
         // Animations.
         .add_system(progress_animations_system())
-        .add_system(progress_building_animations_system());
+        .add_system(progress_building_animations_system())
+        // This goes last so that buildings placed by the control systems in between ticks still
+        // cause paths to be recalculated.
+        .add_system(reset_map_updated_system())
+        .flush()
+        .add_thread_local_fn(|world, resources| {
+            let mut next_entity_id = resources.get_mut::<NextEntityId>().unwrap();
+            assign_entity_ids(world, &mut next_entity_id);
+        });
 }
 
 pub fn add_rendering_systems(builder: &mut legion::systems::Builder) {
     builder
         .add_system(render_bullets_system())
+        .add_system(render_shells_system())
         .add_system(render_units_system())
         .add_system(render_selections_system())
+        .add_system(render_spell_ranges_system())
         //.add_system(render_firing_ranges_system())
         .add_system(render_under_select_box_system())
         .add_system(render_drag_box_system())
         .add_system(render_command_paths_system())
         .add_system(render_ui_system())
         .add_system(render_health_bars_system())
+        .add_system(render_energy_bars_system())
         .add_system(render_unit_under_cursor_system())
         //.add_system(render_pathfinding_map_system())
         //.add_system(render_unit_paths_system())
         .add_system(render_debug_unit_pathfinding_system())
         .add_system(render_buildings_system())
+        .add_system(render_building_ghosts_system())
         .add_system(render_building_plan_system())
         .add_system(render_cheese_droplets_system())
         .add_system(render_explosions_system())
         .add_system(render_abilities_system())
-        .add_system(render_recruitment_waypoints_system());
+        .add_system(render_recruitment_waypoints_system())
+        .add_system(render_minimap_system());
     //.add_system(debug_select_box_system())
     //.add_system(debug_specific_path_system())
 }
@@ -176,6 +238,23 @@ pub fn add_playing_menu_systems(builder: &mut legion::systems::Builder) {
     //.add_system(render_playing_menu_click_regions_system());
 }
 
+pub fn add_editor_systems(builder: &mut legion::systems::Builder) {
+    builder
+        .add_system(handle_editor_keypresses_system())
+        .add_system(cast_ray_system())
+        .add_system(control_camera_system())
+        .add_system(handle_editor_clicks_system())
+        .add_system(render_editor_ui_system());
+}
+
+pub fn add_replay_systems(builder: &mut legion::systems::Builder) {
+    builder
+        .add_system(handle_replay_keypresses_system())
+        .add_system(control_camera_system())
+        .add_system(control_camera_with_minimap_system())
+        .add_system(render_replay_ui_system());
+}
+
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub struct Ability {
     pub ability_type: AbilityType,
@@ -193,6 +272,16 @@ impl Ability {
         hotkey: VirtualKeyCode::R,
     };
 
+    const BUILD_LARDER: Self = Self {
+        ability_type: AbilityType::Build(Building::Larder),
+        hotkey: VirtualKeyCode::D,
+    };
+
+    const BUILD_TURRET: Self = Self {
+        ability_type: AbilityType::Build(Building::Turret),
+        hotkey: VirtualKeyCode::Y,
+    };
+
     const RECRUIT_ENGINEER: Self = Self {
         ability_type: AbilityType::Recruit(Unit::Engineer),
         hotkey: VirtualKeyCode::E,
@@ -208,52 +297,199 @@ impl Ability {
         hotkey: VirtualKeyCode::W,
     };
 
+    const RECRUIT_PSYCHIC: Self = Self {
+        ability_type: AbilityType::Recruit(Unit::Psychic),
+        hotkey: VirtualKeyCode::G,
+    };
+
+    const RECRUIT_ARTILLERY: Self = Self {
+        ability_type: AbilityType::Recruit(Unit::Artillery),
+        hotkey: VirtualKeyCode::H,
+    };
+
+    const MIND_CONTROL: Self = Self {
+        ability_type: AbilityType::Cast(Spell::MindControl),
+        hotkey: VirtualKeyCode::M,
+    };
+
+    const RESEARCH_MARINE_DAMAGE: Self = Self {
+        ability_type: AbilityType::Research(Upgrade::MarineDamage),
+        hotkey: VirtualKeyCode::Z,
+    };
+
+    const RESEARCH_MARINE_RANGE: Self = Self {
+        ability_type: AbilityType::Research(Upgrade::MarineRange),
+        hotkey: VirtualKeyCode::X,
+    };
+
+    const RESEARCH_ENGINEER_BUILD_SPEED: Self = Self {
+        ability_type: AbilityType::Research(Upgrade::EngineerBuildSpeed),
+        hotkey: VirtualKeyCode::C,
+    };
+
+    const RESEARCH_PUMP_INCOME: Self = Self {
+        ability_type: AbilityType::Research(Upgrade::PumpIncome),
+        hotkey: VirtualKeyCode::V,
+    };
+
+    const RESEARCH_BUILDING_ARMOUR: Self = Self {
+        ability_type: AbilityType::Research(Upgrade::BuildingArmour),
+        hotkey: VirtualKeyCode::B,
+    };
+
+    const ALL: [&'static Self; 15] = [
+        &Self::BUILD_PUMP,
+        &Self::BUILD_ARMOURY,
+        &Self::BUILD_LARDER,
+        &Self::BUILD_TURRET,
+        &Self::RECRUIT_ENGINEER,
+        &Self::RECRUIT_MOUSE_MARINE,
+        &Self::RECRUIT_PSYCHIC,
+        &Self::RECRUIT_ARTILLERY,
+        &Self::MIND_CONTROL,
+        &Self::SET_RECRUITMENT_WAYPOINT,
+        &Self::RESEARCH_MARINE_DAMAGE,
+        &Self::RESEARCH_MARINE_RANGE,
+        &Self::RESEARCH_ENGINEER_BUILD_SPEED,
+        &Self::RESEARCH_PUMP_INCOME,
+        &Self::RESEARCH_BUILDING_ARMOUR,
+    ];
+
+    // Every ability type has exactly one `Ability`.
+    fn for_type(ability_type: AbilityType) -> &'static Self {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|ability| ability.ability_type == ability_type)
+            .unwrap()
+    }
+
     fn image(&self) -> Image {
         match self.ability_type {
-            AbilityType::Build(building) => building.stats().image,
+            AbilityType::Build(building) => building.image(),
             AbilityType::Recruit(Unit::Engineer) => Image::RecruitEngineer,
             AbilityType::Recruit(Unit::MouseMarine) => Image::RecruitMouseMarine,
+            AbilityType::Recruit(Unit::Psychic) => Image::RecruitPsychic,
+            AbilityType::Recruit(Unit::Artillery) => Image::RecruitArtillery,
+            AbilityType::Cast(spell) => spell.image(),
             AbilityType::SetRecruitmentWaypoint => Image::SetRecruitmentWaypoint,
+            AbilityType::Research(upgrade) => upgrade.image(),
         }
     }
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
 pub enum AbilityType {
     Build(Building),
     Recruit(Unit),
     SetRecruitmentWaypoint,
+    Research(Upgrade),
+    Cast(Spell),
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(from = "Vec<AbilityType>", into = "Vec<AbilityType>")]
 pub struct Abilities(pub Vec<&'static Ability>);
 
+impl From<Vec<AbilityType>> for Abilities {
+    fn from(ability_types: Vec<AbilityType>) -> Self {
+        Self(ability_types.into_iter().map(Ability::for_type).collect())
+    }
+}
+
+impl From<Abilities> for Vec<AbilityType> {
+    fn from(abilities: Abilities) -> Self {
+        abilities
+            .0
+            .iter()
+            .map(|ability| ability.ability_type)
+            .collect()
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct CheeseGuyser;
+#[derive(Clone, Serialize, Deserialize)]
 pub struct CheeseGuyserBuiltOn {
+    #[serde(with = "crate::save::entity")]
     pub pump: Entity,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Position(pub Vec2);
+/// A stable id for units and buildings. Legion's `Entity`s aren't the same between runs, so
+/// anything that's written to disk (such as replays) refers to these instead.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+pub struct EntityId(pub u32);
+
+/// Give an `EntityId` to every unit and building that doesn't have one yet. Entities are always
+/// created in the same order for the same match, so they always get the same ids.
+pub fn assign_entity_ids(world: &mut World, next_entity_id: &mut NextEntityId) {
+    let entities: Vec<Entity> = <Entity>::query()
+        .filter(component::<Health>() & !component::<EntityId>())
+        .iter(world)
+        .copied()
+        .collect();
+
+    for entity in entities {
+        if let Some(mut entry) = world.entry(entity) {
+            entry.add_component(EntityId(next_entity_id.0));
+            next_entity_id.0 += 1;
+        }
+    }
+}
+
+/// Where an entity was at the start of the current tick, so that rendering can interpolate
+/// between ticks.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PreviousPosition(pub Vec2);
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Facing(pub f32);
-#[derive(PartialEq, Clone, Copy)]
+/// A name given to an entity in a scenario file, so that triggers and objectives can refer to it.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Tag(pub String);
+/// Which player an entity belongs to. Whether sides are allies or enemies is up to the
+/// `Alliances` resource.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum Side {
     Green,
     Purple,
+    Blue,
+    Orange,
+    Teal,
+    Brown,
+    Pink,
+    Grey,
 }
 
 impl Side {
-    fn flip(&self) -> Self {
-        match self {
-            Self::Green => Self::Purple,
-            Self::Purple => Self::Green,
-        }
+    pub const ALL: [Self; 8] = [
+        Self::Green,
+        Self::Purple,
+        Self::Blue,
+        Self::Orange,
+        Self::Teal,
+        Self::Brown,
+        Self::Pink,
+        Self::Grey,
+    ];
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    /// The side after this one in `ALL`, wrapping around.
+    pub fn next(self) -> Self {
+        Self::ALL[(self.index() + 1) % Self::ALL.len()]
     }
 }
 
+#[derive(Clone)]
 pub struct Selected;
+#[derive(Clone)]
 pub struct Selectable;
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct MovementDebugging {
     triangles: Vec<(Vec2, Vec2)>,
     funnel_points: Vec<(Vec2, Vec2)>,
@@ -261,7 +497,7 @@ pub struct MovementDebugging {
     path_end: Vec2,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Command {
     MoveTo {
         target: Vec2,
@@ -270,6 +506,7 @@ pub enum Command {
         path: Vec<Vec2>,
     },
     Attack {
+        #[serde(with = "crate::save::entity")]
         target: Entity,
         // Was the unit explicitly commanded to attack, or was this caused by attack moving or agro?
         explicit: bool,
@@ -281,9 +518,18 @@ pub enum Command {
         state: ActionState,
     },
     Build {
+        #[serde(with = "crate::save::entity")]
         target: Entity,
         state: ActionState,
     },
+    Cast {
+        spell: Spell,
+        target: CastTarget,
+        // How long the unit has been channelling for. This starts again if the target gets out
+        // of range.
+        progress: f32,
+        state: ActionState,
+    },
 }
 
 impl Command {
@@ -303,6 +549,28 @@ impl Command {
         }
     }
 
+    fn new_cast(spell: Spell, target: CastTarget) -> Self {
+        Self::Cast {
+            spell,
+            target,
+            progress: 0.0,
+            state: ActionState::OutOfRange { path: Vec::new() },
+        }
+    }
+
+    // The entity that the command is aimed at, if there is one.
+    fn target_entity(&self) -> Option<Entity> {
+        match *self {
+            Self::Attack { target, .. }
+            | Self::Build { target, .. }
+            | Self::Cast {
+                target: CastTarget::Entity(target),
+                ..
+            } => Some(target),
+            _ => None,
+        }
+    }
+
     fn path(&self) -> Option<&Vec<Vec2>> {
         if let &Command::MoveTo { ref path, .. }
         | &Command::Attack {
@@ -312,6 +580,10 @@ impl Command {
         | &Command::Build {
             state: ActionState::OutOfRange { ref path },
             ..
+        }
+        | &Command::Cast {
+            state: ActionState::OutOfRange { ref path },
+            ..
         } = self
         {
             Some(path)
@@ -329,6 +601,10 @@ impl Command {
         | &mut Command::Build {
             state: ActionState::OutOfRange { ref mut path },
             ..
+        }
+        | &mut Command::Cast {
+            state: ActionState::OutOfRange { ref mut path },
+            ..
         } = self
         {
             Some(path)
@@ -338,7 +614,7 @@ impl Command {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ActionState {
     OutOfRange { path: Vec<Vec2> },
     InRange,
@@ -350,48 +626,206 @@ impl ActionState {
     }
 }
 
-#[derive(Default)]
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct CommandQueue(VecDeque<Command>);
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Health(pub f32);
+#[derive(Clone, Serialize, Deserialize)]
 pub struct BuildingCompleteness(pub f32);
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct FiringRange(pub f32);
+/// Only for units that can't fire at targets that are too close.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MinFiringRange(pub f32);
+#[derive(Clone, Serialize, Deserialize)]
 pub struct MoveSpeed(pub f32);
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Radius(pub f32);
 
-pub struct DamagedThisTick(pub Entity);
+/// All of the damage that an entity took this tick, after its armour.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DamagedThisTick {
+    pub damage: f32,
+    // Whatever hit it last, so that units can attack back.
+    #[serde(with = "crate::save::entity")]
+    pub source: Entity,
+    // Shells can hit their own side, which shouldn't be treated as an attack.
+    pub by_enemy: bool,
+}
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct AnimationState {
     pub animation: usize,
     pub time: f32,
     pub total_time: f32,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Bullet {
+    #[serde(with = "crate::save::entity")]
     source: Entity,
+    #[serde(with = "crate::save::entity")]
     target: Entity,
     target_position: Vec2,
+    // Before the target's armour.
+    damage: f32,
+    damage_type: DamageType,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Shell {
+    #[serde(with = "crate::save::entity")]
+    source: Entity,
+    // The y axis is up, like for rendering.
+    velocity: Vec3,
+    height: f32,
+    damage: f32,
+    damage_type: DamageType,
+    splash_radius: f32,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Cooldown(pub f32);
+/// Spent on casting spells, and regenerated over time up to the unit's `max_energy`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Energy(pub f32);
+/// How long until each of a unit's spells can be cast again. Spells that aren't in here are ready.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct SpellCooldowns(pub Vec<(Spell, f32)>);
+
+impl SpellCooldowns {
+    pub fn get(&self, spell: Spell) -> f32 {
+        self.0
+            .iter()
+            .find(|(cooldown_spell, _)| *cooldown_spell == spell)
+            .map(|(_, cooldown)| *cooldown)
+            .unwrap_or(0.0)
+    }
+}
+
+/// Abilities that units cast, such as mind control. Everything about a spell apart from what it
+/// actually does when it's cast comes from `definitions.ron`.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+pub enum Spell {
+    // Take control of an enemy unit.
+    MindControl,
+}
+
+impl Spell {
+    pub const ALL: [Self; 1] = [Self::MindControl];
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+    pub fn stats(self) -> std::sync::Arc<SpellStats> {
+        crate::definitions::spell(self)
+    }
+
+    pub fn image(self) -> Image {
+        match self {
+            Self::MindControl => Image::MindControl,
+        }
+    }
+}
+
+/// What a spell is cast on.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Deserialize)]
+pub enum TargetType {
+    // Cast straight away, by every selected unit that can.
+    NoTarget,
+    Point,
+    EnemyUnit,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct SpellStats {
+    pub target: TargetType,
+    // How close the caster has to be to the target. Not needed for `NoTarget` spells.
+    #[serde(default)]
+    pub range: f32,
+    #[serde(default)]
+    pub energy_cost: f32,
+    // How long until the caster can cast the spell again.
+    #[serde(default)]
+    pub cooldown: f32,
+    // How long the caster has to stay in range of the target for before the spell happens.
+    #[serde(default)]
+    pub channel_time: f32,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum CastTarget {
+    Nothing,
+    Point(Vec2),
+    Entity(#[serde(with = "crate::save::entity")] Entity),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
 pub enum Building {
     Armoury,
     Pump,
+    Larder,
+    Turret,
 }
 
+#[derive(Clone, Deserialize)]
 pub struct BuildingStats {
     pub radius: f32,
     pub dimensions: Vec2,
     pub max_health: f32,
     pub cost: u32,
-    pub image: Image,
+    // How far the building's side can see around it.
+    pub sight_range: f32,
+    // The units that can be recruited from this building.
+    #[serde(default)]
+    pub recruits: Vec<Unit>,
+    // The upgrades that can be researched at this building.
+    #[serde(default)]
+    pub researches: Vec<Upgrade>,
+    // How much the building raises its side's supply cap by once it's built.
+    #[serde(default)]
+    pub supply_provided: u32,
+    // Taken off the damage of every hit.
+    #[serde(default)]
+    pub armour: f32,
+    // Buildings with a firing range pick their own targets once they're built.
+    #[serde(default)]
+    pub firing_range: Option<f32>,
+    #[serde(default)]
+    pub damage: f32,
+    #[serde(default)]
+    pub damage_type: DamageType,
+    // Shots per second.
+    #[serde(default)]
+    pub fire_rate: f32,
+}
+
+impl BuildingStats {
+    // The abilities that a fully built building has.
+    fn abilities(&self) -> Option<Abilities> {
+        if self.recruits.is_empty() && self.researches.is_empty() {
+            return None;
+        }
+
+        let recruit = self
+            .recruits
+            .iter()
+            .map(|unit| Ability::for_type(AbilityType::Recruit(*unit)));
+
+        let research = self
+            .researches
+            .iter()
+            .map(|upgrade| Ability::for_type(AbilityType::Research(*upgrade)));
+
+        // Buildings that only research don't have anywhere to send units.
+        let waypoint = Some(&Ability::SET_RECRUITMENT_WAYPOINT).filter(|_| !self.recruits.is_empty());
+
+        Some(Abilities(recruit.chain(research).chain(waypoint).collect()))
+    }
 }
 
 impl Building {
+    pub const ALL: [Self; 4] = [Self::Armoury, Self::Pump, Self::Larder, Self::Turret];
+
     pub fn maybe_plural(self, number: u8) -> &'static str {
         let plural = number != 1;
 
@@ -400,25 +834,23 @@ impl Building {
             Self::Pump => "Pump",
             Self::Armoury if plural => "Armouries",
             Self::Armoury => "Armoury",
+            Self::Larder if plural => "Larders",
+            Self::Larder => "Larder",
+            Self::Turret if plural => "Turrets",
+            Self::Turret => "Turret",
         }
     }
 
-    pub fn stats(self) -> BuildingStats {
+    pub fn stats(self) -> std::sync::Arc<BuildingStats> {
+        crate::definitions::building(self)
+    }
+
+    pub fn image(self) -> Image {
         match self {
-            Self::Armoury => BuildingStats {
-                radius: 6.0,
-                dimensions: Vec2::new(6.0, 10.0),
-                max_health: 500.0,
-                cost: 200,
-                image: Image::BuildArmoury,
-            },
-            Self::Pump => BuildingStats {
-                radius: 3.0,
-                dimensions: Vec2::new(4.0, 4.0),
-                max_health: 200.0,
-                cost: 50,
-                image: Image::BuildPump,
-            },
+            Self::Armoury => Image::BuildArmoury,
+            Self::Pump => Image::BuildPump,
+            Self::Larder => Image::BuildLarder,
+            Self::Turret => Image::BuildTurret,
         }
     }
 
@@ -437,21 +869,14 @@ impl Building {
         Health,
         BuildingCompleteness,
     )> {
-        let BuildingStats {
-            radius,
-            dimensions,
-            max_health: _,
-            cost: _,
-            image: _,
-        } = self.stats();
-
-        let handle = map.insert(position, dimensions)?;
+        let stats = self.stats();
+        let handle = map.insert(position, stats.dimensions)?;
 
         Some((
             Position(position),
             handle,
             self,
-            Radius(radius),
+            Radius(stats.radius),
             Selectable,
             side,
             Health(1.0),
@@ -467,33 +892,39 @@ impl Building {
         animations: &ModelAnimations,
         map: &mut Map,
     ) -> Option<Entity> {
+        let stats = self.stats();
         let mut parts = self.parts(position, side, map)?;
-        parts.6 = Health(self.stats().max_health);
-        parts.7 = BuildingCompleteness(self.stats().max_health);
+        parts.6 = Health(stats.max_health);
+        parts.7 = BuildingCompleteness(stats.max_health);
         let entity = world.push(parts);
 
         let mut entry = world.entry(entity).unwrap();
 
         entry.add_component(FullyBuilt);
 
-        match self {
-            Building::Pump => {
+        if let Building::Pump = self {
+            // Animations aren't loaded when running headless.
+            if let Some(animation) = animations.pump.animations.first() {
                 entry.add_component(animations.pump.skin.clone());
                 entry.add_component(AnimationState {
                     animation: 0,
                     time: 0.0,
-                    total_time: animations.pump.animations[0].total_time,
+                    total_time: animation.total_time,
                 });
-                entry.add_component(Cooldown(0.0));
-            }
-            Building::Armoury => {
-                entry.add_component(Abilities(vec![
-                    &Ability::RECRUIT_MOUSE_MARINE,
-                    &Ability::RECRUIT_ENGINEER,
-                    &Ability::SET_RECRUITMENT_WAYPOINT,
-                ]));
-                entry.add_component(RecruitmentQueue::new(position, self.stats().dimensions));
             }
+            entry.add_component(Cooldown(0.0));
+        }
+
+        if let Some(firing_range) = stats.firing_range {
+            // Which way the turret is pointing.
+            entry.add_component(Facing(0.0));
+            entry.add_component(Cooldown(0.0));
+            entry.add_component(FiringRange(firing_range));
+        }
+
+        if let Some(abilities) = stats.abilities() {
+            entry.add_component(abilities);
+            entry.add_component(RecruitmentQueue::new(position, stats.dimensions));
         }
 
         Some(entity)
@@ -507,45 +938,60 @@ impl Building {
         animations: &ModelAnimations,
         map: &mut Map,
     ) -> Option<Entity> {
+        let stats = self.stats();
         let parts = self.parts(position, side, map)?;
         let entity = buffer.push(parts);
 
-        match self {
-            Building::Pump => {
+        if let Building::Pump = self {
+            if let Some(animation) = animations.pump.animations.first() {
                 buffer.add_component(entity, animations.pump.skin.clone());
                 buffer.add_component(
                     entity,
                     AnimationState {
                         animation: 0,
                         time: 0.0,
-                        total_time: animations.pump.animations[0].total_time,
+                        total_time: animation.total_time,
                     },
                 );
-                buffer.add_component(entity, Cooldown(0.0));
-            }
-            Building::Armoury => {
-                buffer.add_component(
-                    entity,
-                    Abilities(vec![
-                        &Ability::RECRUIT_MOUSE_MARINE,
-                        &Ability::RECRUIT_ENGINEER,
-                        &Ability::SET_RECRUITMENT_WAYPOINT,
-                    ]),
-                );
-                buffer.add_component(
-                    entity,
-                    RecruitmentQueue::new(position, self.stats().dimensions),
-                );
             }
+            buffer.add_component(entity, Cooldown(0.0));
+        }
+
+        if let Some(firing_range) = stats.firing_range {
+            buffer.add_component(entity, Facing(0.0));
+            buffer.add_component(entity, Cooldown(0.0));
+            buffer.add_component(entity, FiringRange(firing_range));
+        }
+
+        if let Some(abilities) = stats.abilities() {
+            buffer.add_component(entity, abilities);
+            buffer.add_component(entity, RecruitmentQueue::new(position, stats.dimensions));
         }
 
         Some(entity)
     }
 }
 
+/// Buildings recruit units and research upgrades one after the other, from the same queue.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum QueueItem {
+    Unit(Unit),
+    Research(Upgrade),
+}
+
+impl QueueItem {
+    fn time(self) -> f32 {
+        match self {
+            Self::Unit(unit) => unit.stats().recruitment_time,
+            Self::Research(upgrade) => upgrade.stats().research_time,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct RecruitmentQueue {
     percentage_progress: f32,
-    pub queue: VecDeque<Unit>,
+    pub queue: VecDeque<QueueItem>,
     waypoint: Vec2,
 }
 
@@ -567,21 +1013,127 @@ impl RecruitmentQueue {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+/// Researched once per side, and then affects all of that side's units or buildings.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+pub enum Upgrade {
+    MarineDamage,
+    MarineRange,
+    EngineerBuildSpeed,
+    PumpIncome,
+    BuildingArmour,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct UpgradeStats {
+    pub cost: u32,
+    pub research_time: f32,
+    // Upgrades that have to be researched first.
+    #[serde(default)]
+    pub requires: Vec<Upgrade>,
+    // How big the upgrade's effect is. What this means depends on the upgrade.
+    pub amount: f32,
+}
+
+impl Upgrade {
+    pub const ALL: [Self; 5] = [
+        Self::MarineDamage,
+        Self::MarineRange,
+        Self::EngineerBuildSpeed,
+        Self::PumpIncome,
+        Self::BuildingArmour,
+    ];
+
+    pub fn stats(self) -> std::sync::Arc<UpgradeStats> {
+        crate::definitions::upgrade(self)
+    }
+
+    pub fn image(self) -> Image {
+        match self {
+            Self::MarineDamage => Image::ResearchMarineDamage,
+            Self::MarineRange => Image::ResearchMarineRange,
+            Self::EngineerBuildSpeed => Image::ResearchEngineerBuildSpeed,
+            Self::PumpIncome => Image::ResearchPumpIncome,
+            Self::BuildingArmour => Image::ResearchBuildingArmour,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
 pub enum Unit {
     MouseMarine,
     Engineer,
+    Psychic,
+    Artillery,
 }
 
+#[derive(Clone, Deserialize)]
 pub struct UnitStats {
     pub max_health: f32,
     pub move_speed: f32,
     pub radius: f32,
     // None if the unit can't attack
+    #[serde(default)]
     pub firing_range: Option<f32>,
+    // Targets closer than this can't be fired at.
+    #[serde(default)]
+    pub min_firing_range: f32,
+    #[serde(default)]
+    pub projectile: Projectile,
+    // The weapon stats are only needed for units that can attack.
+    #[serde(default)]
+    pub damage: f32,
+    #[serde(default)]
+    pub damage_type: DamageType,
+    // Shots per second.
+    #[serde(default)]
+    pub fire_rate: f32,
+    // Taken off the damage of every hit.
+    #[serde(default)]
+    pub armour: f32,
+    pub sight_range: f32,
     pub health_bar_height: f32,
     pub cost: u32,
+    // How much of its side's supply cap the unit takes up.
+    pub supply: u32,
     pub recruitment_time: f32,
+    #[serde(default)]
+    pub abilities: Vec<AbilityType>,
+    // 0 for units that don't use energy.
+    #[serde(default)]
+    pub max_energy: f32,
+    // How much energy the unit gets back every second.
+    #[serde(default)]
+    pub energy_regeneration: f32,
+}
+
+/// Some weapons are better against buildings and some are better against units.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum DamageType {
+    #[default]
+    Normal,
+    // Double damage to buildings, but half damage to units.
+    Siege,
+}
+
+impl DamageType {
+    fn multiplier(self, against_building: bool) -> f32 {
+        match (self, against_building) {
+            (Self::Normal, _) => 1.0,
+            (Self::Siege, true) => 2.0,
+            (Self::Siege, false) => 0.5,
+        }
+    }
+}
+
+/// What a unit fires at its target.
+#[derive(Copy, Clone, PartialEq, Debug, Default, Deserialize)]
+pub enum Projectile {
+    // Follows the target and always hits it.
+    #[default]
+    Bullet,
+    // Flies in an arc to where the target is going to be, and damages everything around where it
+    // lands, on any side. `speed` is how fast it moves across the ground.
+    Shell { speed: f32, splash_radius: f32 },
 }
 
 enum MouseAnimation {
@@ -592,33 +1144,17 @@ enum MouseAnimation {
 }
 
 impl Unit {
-    pub fn stats(self) -> UnitStats {
-        match self {
-            Self::MouseMarine => UnitStats {
-                max_health: 50.0,
-                firing_range: Some(10.0),
-                move_speed: 6.0,
-                radius: 1.0,
-                health_bar_height: 3.0,
-                cost: 100,
-                recruitment_time: 10.0,
-            },
-            Self::Engineer => UnitStats {
-                max_health: 40.0,
-                firing_range: None,
-                move_speed: 6.0,
-                radius: 1.0,
-                health_bar_height: 3.0,
-                cost: 50,
-                recruitment_time: 5.0,
-            },
-        }
+    pub const ALL: [Self; 4] = [Self::MouseMarine, Self::Engineer, Self::Psychic, Self::Artillery];
+
+    pub fn stats(self) -> std::sync::Arc<UnitStats> {
+        crate::definitions::unit(self)
     }
 
     pub fn add_to_world(
         self,
         buffer: &mut CommandBuffer,
-        // This is only `None` when being run in a test
+        // This is only `None` when being run in a test. When running headless, the animations
+        // are present but empty.
         animations: Option<&ModelAnimations>,
         position: Vec2,
         facing: Facing,
@@ -630,10 +1166,11 @@ impl Unit {
             move_speed,
             radius,
             firing_range,
-            health_bar_height: _,
-            cost: _,
-            recruitment_time: _,
-        } = self.stats();
+            min_firing_range,
+            abilities,
+            max_energy,
+            ..
+        } = (*self.stats()).clone();
 
         let mut command_queue = CommandQueue::default();
         if let Some(starting_command) = starting_command {
@@ -642,12 +1179,14 @@ impl Unit {
 
         let entity = buffer.push((
             Position(position),
+            PreviousPosition(position),
             facing,
             side,
             self,
             command_queue,
             Avoids,
             Avoidable,
+            Avoidance(Vec2::zero()),
             Selectable,
             Health(max_health),
             Cooldown(0.0),
@@ -657,15 +1196,27 @@ impl Unit {
             // MovementDebugging::default(),
         ));
 
-        match self {
-            Unit::Engineer => {
-                buffer.add_component(entity, CanBuild);
-                buffer.add_component(
-                    entity,
-                    Abilities(vec![&Ability::BUILD_PUMP, &Ability::BUILD_ARMOURY]),
-                );
-            }
-            Unit::MouseMarine => {}
+        if abilities
+            .iter()
+            .any(|ability| matches!(ability, AbilityType::Build(_)))
+        {
+            buffer.add_component(entity, CanBuild);
+        }
+
+        if abilities
+            .iter()
+            .any(|ability| matches!(ability, AbilityType::Cast(_)))
+        {
+            buffer.add_component(entity, SpellCooldowns::default());
+        }
+
+        if !abilities.is_empty() {
+            buffer.add_component(entity, Abilities::from(abilities));
+        }
+
+        if max_energy > 0.0 {
+            // Units start with half of their energy.
+            buffer.add_component(entity, Energy(max_energy / 2.0));
         }
 
         if let Some(firing_range) = firing_range {
@@ -673,17 +1224,26 @@ impl Unit {
             buffer.add_component(entity, CanAttack);
         }
 
+        if min_firing_range > 0.0 {
+            buffer.add_component(entity, MinFiringRange(min_firing_range));
+        }
+
         if let Some(animations) = animations {
-            buffer.add_component(entity, animations.mouse.skin.clone());
-            buffer.add_component(
-                entity,
-                AnimationState {
-                    animation: MouseAnimation::Idle as usize,
-                    time: 0.0,
-                    total_time: animations.mouse.animations[MouseAnimation::Idle as usize]
-                        .total_time,
-                },
-            );
+            if let Some(idle) = animations
+                .mouse
+                .animations
+                .get(MouseAnimation::Idle as usize)
+            {
+                buffer.add_component(entity, animations.mouse.skin.clone());
+                buffer.add_component(
+                    entity,
+                    AnimationState {
+                        animation: MouseAnimation::Idle as usize,
+                        time: 0.0,
+                        total_time: idle.total_time,
+                    },
+                );
+            }
         }
 
         entity
@@ -747,15 +1307,20 @@ fn vec2_to_ncollide_point(point: Vec2) -> ncollide2d::math::Point<f32> {
     ncollide2d::math::Point::new(point.x, point.y)
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct CheeseDropletPosition(Vec3);
+#[derive(Clone, Serialize, Deserialize)]
 pub struct CheeseDropletVelocity(Vec3);
+#[derive(Clone)]
 pub struct CanBuild;
+#[derive(Clone)]
 pub struct CanAttack;
+#[derive(Clone)]
 pub struct FullyBuilt;
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Serialize, Deserialize)]
 pub enum Agroed {
-    ThisTick(Entity),
-    LastTick(Entity),
+    ThisTick(#[serde(with = "crate::save::entity")] Entity),
+    LastTick(#[serde(with = "crate::save::entity")] Entity),
 }
 
 fn nearest_point_within_building(
@@ -786,6 +1351,7 @@ fn nearest_point_within_building(
     building_pos + Vec2::new(x, y)
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Explosion {
     translation_rotation: Mat4,
     progress: f32,
@@ -793,7 +1359,7 @@ pub struct Explosion {
 }
 
 impl Explosion {
-    pub fn new(position: Vec2, rng: &mut SmallRng, max_size: f32) -> Self {
+    pub fn new(position: Vec2, rng: &mut impl Rng, max_size: f32) -> Self {
         let facing = crate::titlescreen::uniform_sphere_distribution_from_coords(
             rng.gen_range(0.0, 1.0),
             rng.gen_range(0.0, 1.0),
@@ -833,6 +1399,15 @@ fn ease_out_quad(x: f32) -> f32 {
     1.0 - (1.0 - x) * (1.0 - x)
 }
 
+// The cheese guyser that a pump placed at this position would be built on.
+fn free_guyser_near(position: Vec2, world: &SubWorld) -> Option<(Entity, Vec2)> {
+    <(Entity, &Position)>::query()
+        .filter(component::<CheeseGuyser>() & !component::<CheeseGuyserBuiltOn>())
+        .iter(world)
+        .find(|(_, pos)| (position - pos.0).mag_sq() <= 4.0_f32.powi(2))
+        .map(|(entity, pos)| (*entity, pos.0))
+}
+
 fn unit_under_building(building_position: Vec2, building_dims: Vec2, world: &SubWorld) -> bool {
     let top_left = building_position - building_dims / 2.0;
     let (top, left) = (top_left.y, top_left.x);
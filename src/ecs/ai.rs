@@ -1,15 +1,44 @@
 use super::*;
 use crate::assets::ModelAnimations;
 use crate::pathfinding::Map;
-use crate::resources::{AiBuildOrderItem, AiBuildOrders, PlayerSide, TotalTime};
+use crate::resources::{
+    AiBuildOrderItem, AiBuildOrders, AiDifficulty, AiPlayer, AiPlayers, Alliances, CheeseCoins,
+    DeltaTime, MatchSettings, Supply, TotalTime, Upgrades, Visibility,
+};
 
-// I sorta ran out of time/mental energy to implement proper AI, so I just have it follow
-// pre-recorded instructions.
+// Scenarios can either script one enemy with pre-recorded build orders, or have the reactive
+// `AiPlayer` play every side apart from the player's.
 
+// Seconds between the AI's decisions.
+const THINK_INTERVAL: f32 = 1.0;
+// Roughly how far units can see. The AI itself only knows what its side can see through the fog
+// of war, but this is used to judge when things are close enough together.
+const SIGHT_RANGE: f32 = 30.0;
+// How long the AI keeps defending for after its buildings were last attacked.
+const DEFEND_TIME: f32 = 10.0;
+const MAX_PUMPS: usize = 8;
+// Marines are only queued while the armoury has fewer than this many units in its queue.
+const MAX_QUEUE_LENGTH: usize = 2;
+// Larders are built once the army gets within this much supply of the cap.
+const SUPPLY_MARGIN: u32 = 4;
+// Armouries and larders are placed in rings of these radiuses around the AI's base.
+const BUILDING_RINGS: [f32; 4] = [14.0, 20.0, 26.0, 32.0];
+const BUILDING_RING_POSITIONS: usize = 16;
+// The space to leave around new buildings so that units can get between them.
+const BUILDING_GAP: f32 = 4.0;
+// Buildings shouldn't be put anywhere that would block a guyser.
+const BUILDING_GUYSER_DISTANCE: f32 = 8.0;
+const MAP_EDGE: f32 = 90.0;
+// Units can't path into a building, so the army is sent this far short of the enemy that it's
+// attacking and left to agro onto it from there.
+const ATTACK_STANDOFF: f32 = 8.0;
+
+#[allow(clippy::too_many_arguments)]
 #[legion::system]
 #[read_component(Position)]
 #[read_component(Side)]
 #[read_component(Unit)]
+#[read_component(Building)]
 #[write_component(CommandQueue)]
 #[write_component(RecruitmentQueue)]
 pub fn follow_ai_build_orders(
@@ -17,103 +46,142 @@ pub fn follow_ai_build_orders(
     #[resource] total_time: &TotalTime,
     #[resource] map: &mut Map,
     #[resource] animations: &ModelAnimations,
-    #[resource] player_side: &PlayerSide,
+    #[resource] ai_players: &AiPlayers,
+    #[resource] cheese_coins: &mut CheeseCoins,
     world: &mut SubWorld,
     commands: &mut CommandBuffer,
 ) {
+    let ai_side = match ai_players.scripted_side() {
+        Some(side) => side,
+        None => return,
+    };
+
     let mut remove_first = false;
-    if let Some((time, item)) = build_orders.0.first() {
+    if let Some((time, item)) = build_orders.0.first_mut() {
         if *time <= total_time.0 {
             log::debug!(target: "ai", "Following build order: {:?}", item);
 
             let engineer_exists = <&Side>::query()
                 .filter(component::<CanBuild>())
                 .iter(world)
-                .filter(|side| **side != player_side.0)
+                .filter(|side| **side == ai_side)
                 .count()
                 > 0;
 
-            match item {
+            // The AI pays for build orders like everyone else, so they're held up until it can
+            // afford them. Orders that can't be followed at all are skipped.
+            remove_first = match item {
                 AiBuildOrderItem::BuildPump(guyser_entity) => {
                     let position = <&Position>::query().get(world, *guyser_entity).unwrap();
+                    let stats = Building::Pump.stats();
 
                     let unit_under_building =
-                        unit_under_building(position.0, Building::Pump.stats().dimensions, world);
+                        unit_under_building(position.0, stats.dimensions, world);
 
                     if !unit_under_building && engineer_exists {
-                        let pump_entity = Building::Pump
-                            .add_to_world_to_construct(
-                                commands,
-                                position.0,
-                                player_side.0.flip(),
-                                animations,
-                                map,
-                            )
-                            .unwrap();
-
-                        commands.add_component(
-                            *guyser_entity,
-                            CheeseGuyserBuiltOn { pump: pump_entity },
+                        if stats.cost > cheese_coins.get(ai_side) {
+                            return;
+                        }
+
+                        // Another side might have built on the guyser already.
+                        let pump_entity = Building::Pump.add_to_world_to_construct(
+                            commands, position.0, ai_side, animations, map,
                         );
 
-                        <(&mut CommandQueue, &Side)>::query()
-                            .filter(component::<CanBuild>())
-                            .iter_mut(world)
-                            .filter(|(_, side)| **side != player_side.0)
-                            .for_each(|(commands, _)| {
-                                commands.0.clear();
-                                commands.0.push_back(Command::new_build(pump_entity));
-                            });
+                        if let Some(pump_entity) = pump_entity {
+                            cheese_coins.spend(ai_side, stats.cost);
+
+                            commands.add_component(
+                                *guyser_entity,
+                                CheeseGuyserBuiltOn { pump: pump_entity },
+                            );
+
+                            order_engineers_to_build(pump_entity, ai_side, world);
+                        } else {
+                            log::warn!(target: "ai", "Skipping {:?}, the guyser is taken", item);
+                        }
                     }
+
+                    true
                 }
-                AiBuildOrderItem::BuildArmoury(position) => {
+                AiBuildOrderItem::BuildArmoury(position)
+                | AiBuildOrderItem::BuildLarder(position)
+                | AiBuildOrderItem::BuildTurret(position) => {
+                    let position = *position;
+                    let building = match item {
+                        AiBuildOrderItem::BuildArmoury(_) => Building::Armoury,
+                        AiBuildOrderItem::BuildLarder(_) => Building::Larder,
+                        AiBuildOrderItem::BuildTurret(_) => Building::Turret,
+                        _ => unreachable!(),
+                    };
+
                     if engineer_exists {
-                        let armoury_entity = Building::Armoury
-                            .add_to_world_to_construct(
-                                commands,
-                                *position,
-                                player_side.0.flip(),
-                                animations,
-                                map,
-                            )
-                            .unwrap();
-
-                        <(&mut CommandQueue, &Side)>::query()
-                            .filter(component::<CanBuild>())
-                            .iter_mut(world)
-                            .filter(|(_, side)| **side != player_side.0)
-                            .for_each(|(commands, _)| {
-                                commands.0.clear();
-                                commands.0.push_back(Command::new_build(armoury_entity));
-                            });
+                        let cost = building.stats().cost;
+
+                        if cost > cheese_coins.get(ai_side) {
+                            return;
+                        }
+
+                        // The spot might overlap another building.
+                        let building_entity = building.add_to_world_to_construct(
+                            commands, position, ai_side, animations, map,
+                        );
+
+                        if let Some(building_entity) = building_entity {
+                            cheese_coins.spend(ai_side, cost);
+                            order_engineers_to_build(building_entity, ai_side, world);
+                        } else {
+                            log::warn!(target: "ai", "Skipping {:?}, something is in the way", item);
+                        }
                     }
+
+                    true
                 }
+                // Marines are recruited as they can be afforded, until there are none left to
+                // recruit.
                 AiBuildOrderItem::RecruitMarine(times) => {
-                    for _ in 0..*times {
-                        let shortest_queue = <(&mut RecruitmentQueue, &Side)>::query()
+                    let cost = Unit::MouseMarine.stats().cost;
+                    let mut has_armoury = true;
+
+                    while *times > 0 && cheese_coins.get(ai_side) >= cost {
+                        // Larders have queues for research, but can't recruit.
+                        let shortest_queue = <(&mut RecruitmentQueue, &Side, &Building)>::query()
                             .iter_mut(world)
-                            .filter(|(_, side)| **side != player_side.0)
-                            .min_by_key(|(queue, _)| queue.length());
+                            .filter(|(_, side, building)| {
+                                **side == ai_side
+                                    && building.stats().recruits.contains(&Unit::MouseMarine)
+                            })
+                            .min_by_key(|(queue, ..)| queue.length());
 
-                        if let Some((queue, _)) = shortest_queue {
-                            queue.queue.push_back(Unit::MouseMarine);
+                        match shortest_queue {
+                            Some((queue, ..)) => {
+                                cheese_coins.spend(ai_side, cost);
+                                queue.queue.push_back(QueueItem::Unit(Unit::MouseMarine));
+                                *times -= 1;
+                            }
+                            None => {
+                                has_armoury = false;
+                                break;
+                            }
                         }
                     }
+
+                    *times == 0 || !has_armoury
                 }
                 AiBuildOrderItem::SetWaypoint(position) => {
                     <(&mut RecruitmentQueue, &Side)>::query()
                         .iter_mut(world)
-                        .filter(|(_, side)| **side != player_side.0)
+                        .filter(|(_, side)| **side == ai_side)
                         .for_each(|(queue, _)| {
                             queue.waypoint = *position;
-                        })
+                        });
+
+                    true
                 }
                 AiBuildOrderItem::AttackMove(position) => {
                     <(&mut CommandQueue, &Side, &Unit)>::query()
                         .iter_mut(world)
-                        .filter(|(_, side, unit)| {
-                            **side != player_side.0 && **unit == Unit::MouseMarine
-                        })
+                        .filter(|(_, side, unit)| **side == ai_side && **unit == Unit::MouseMarine)
                         .for_each(|(commands, ..)| {
                             commands.0.clear();
                             commands.0.push_back(Command::MoveTo {
@@ -121,11 +189,11 @@ pub fn follow_ai_build_orders(
                                 attack_move: true,
                                 path: Vec::new(),
                             });
-                        })
-                }
-            }
+                        });
 
-            remove_first = true;
+                    true
+                }
+            };
         }
     }
 
@@ -133,3 +201,737 @@ pub fn follow_ai_build_orders(
         build_orders.0.remove(0);
     }
 }
+
+// Build orders take every engineer off of what it's doing.
+fn order_engineers_to_build(building: Entity, side: Side, world: &mut SubWorld) {
+    <(&mut CommandQueue, &Side)>::query()
+        .filter(component::<CanBuild>())
+        .iter_mut(world)
+        .filter(|(_, engineer_side)| **engineer_side == side)
+        .for_each(|(commands, _)| {
+            commands.0.clear();
+            commands.0.push_back(Command::new_build(building));
+        });
+}
+
+// What the AI knows about the world when it makes decisions.
+struct AiView {
+    side: Side,
+    // Engineers and whether they're idle.
+    engineers: Vec<(Entity, bool)>,
+    // Marines and whether they're idle.
+    marines: Vec<(Entity, Vec2, bool)>,
+    buildings: Vec<AiBuilding>,
+    free_guysers: Vec<(Entity, Vec2)>,
+    all_guysers: Vec<Vec2>,
+    // Guysers that are in the fog of war, which are where the enemy is likely to be when the AI
+    // hasn't found it yet.
+    hidden_guysers: Vec<Vec2>,
+    // Enemy units that can attack and that the AI can see.
+    visible_enemy_army: usize,
+    // Enemy buildings that the AI has seen and enemy units that it can see.
+    enemy_positions: Vec<Vec2>,
+    // Where the enemy's buildings (or units, if it has none) are.
+    enemy_center: Option<Vec2>,
+    supply_used: u32,
+    supply_cap: u32,
+}
+
+struct AiBuilding {
+    entity: Entity,
+    building: Building,
+    position: Vec2,
+    fully_built: bool,
+}
+
+// The enemy army that the AI playing `side` thinks it's up against, and where it thinks the enemy
+// is.
+#[cfg(test)]
+pub fn enemies_seen_by_ai(
+    side: Side,
+    world: &mut World,
+    resources: &Resources,
+) -> (usize, Vec<Vec2>) {
+    let view = AiView::new(
+        side,
+        resources.get::<MatchSettings>().unwrap().difficulty,
+        &resources.get::<Alliances>().unwrap(),
+        &resources.get::<Visibility>().unwrap(),
+        &resources.get::<Supply>().unwrap(),
+        &SubWorld::from(world),
+    );
+
+    (view.visible_enemy_army, view.enemy_positions)
+}
+
+// Whether the AI's unit is too damaged to fight.
+fn should_retreat(unit: Unit, health: &Health, difficulty: AiDifficulty) -> bool {
+    difficulty
+        .retreat_health()
+        .map(|fraction| health.0 < unit.stats().max_health * fraction)
+        .unwrap_or(false)
+}
+
+impl AiView {
+    fn new(
+        side: Side,
+        difficulty: AiDifficulty,
+        alliances: &Alliances,
+        visibility: &Visibility,
+        supply: &Supply,
+        world: &SubWorld,
+    ) -> Self {
+        let engineers = <(Entity, &Side, &CommandQueue)>::query()
+            .filter(component::<CanBuild>())
+            .iter(world)
+            .filter(|(_, unit_side, _)| **unit_side == side)
+            .map(|(entity, _, queue)| (*entity, queue.0.is_empty()))
+            .collect();
+
+        // Units that are retreating are left alone.
+        let marines = <(Entity, &Side, &Position, &CommandQueue, &Unit, &Health)>::query()
+            .filter(component::<CanAttack>())
+            .iter(world)
+            .filter(|(_, unit_side, _, _, unit, health)| {
+                **unit_side == side && !should_retreat(**unit, health, difficulty)
+            })
+            .map(|(entity, _, position, queue, ..)| (*entity, position.0, queue.0.is_empty()))
+            .collect();
+
+        let buildings: Vec<_> =
+            <(Entity, &Building, &Side, &Position, Option<&FullyBuilt>)>::query()
+                .iter(world)
+                .filter(|(_, _, building_side, ..)| **building_side == side)
+                .map(|(entity, building, _, position, fully_built)| AiBuilding {
+                    entity: *entity,
+                    building: *building,
+                    position: position.0,
+                    fully_built: fully_built.is_some(),
+                })
+                .collect();
+
+        let free_guysers = <(Entity, &Position)>::query()
+            .filter(component::<CheeseGuyser>() & !component::<CheeseGuyserBuiltOn>())
+            .iter(world)
+            .map(|(entity, position)| (*entity, position.0))
+            .collect();
+
+        let all_guysers: Vec<Vec2> = <&Position>::query()
+            .filter(component::<CheeseGuyser>())
+            .iter(world)
+            .map(|position| position.0)
+            .collect();
+
+        let visible = |position: Vec2| visibility.is_visible(side, position);
+
+        let visible_enemy_army = <(&Position, &Side)>::query()
+            .filter(component::<CanAttack>() & component::<Unit>())
+            .iter(world)
+            .filter(|(position, unit_side)| {
+                alliances.are_enemies(**unit_side, side) && visible(position.0)
+            })
+            .count();
+
+        let enemy_buildings: Vec<Vec2> = if visibility.fog_of_war {
+            visibility
+                .ghosts_seen_by(side)
+                .map(|ghost| ghost.position)
+                .collect()
+        } else {
+            <(&Position, &Side)>::query()
+                .filter(component::<Building>())
+                .iter(world)
+                .filter(|(_, building_side)| alliances.are_enemies(**building_side, side))
+                .map(|(position, _)| position.0)
+                .collect()
+        };
+
+        let visible_enemy_units: Vec<Vec2> = <(&Position, &Side)>::query()
+            .filter(component::<Unit>())
+            .iter(world)
+            .filter(|(position, unit_side)| {
+                alliances.are_enemies(**unit_side, side) && visible(position.0)
+            })
+            .map(|(position, _)| position.0)
+            .collect();
+
+        let hidden_guysers = all_guysers
+            .iter()
+            .copied()
+            .filter(|position| !visible(*position))
+            .collect();
+
+        // If the AI hasn't found the enemy at all, it's probably somewhere towards the middle of
+        // the map.
+        let enemy_center = if enemy_buildings.is_empty() {
+            average(visible_enemy_units.iter().copied()).or(Some(Vec2::zero()))
+        } else {
+            average(enemy_buildings.iter().copied())
+        };
+
+        let mut enemy_positions = enemy_buildings;
+        enemy_positions.extend(visible_enemy_units);
+
+        Self {
+            side,
+            engineers,
+            marines,
+            buildings,
+            free_guysers,
+            all_guysers,
+            hidden_guysers,
+            visible_enemy_army,
+            enemy_positions,
+            enemy_center,
+            supply_used: supply.used(side),
+            supply_cap: supply.cap(side),
+        }
+    }
+
+    fn count(&self, building: Building) -> usize {
+        self.buildings
+            .iter()
+            .filter(|ai_building| ai_building.building == building)
+            .count()
+    }
+
+    // The middle of the AI's buildings, or its engineers if it doesn't have any.
+    fn base_center(&self, world: &SubWorld) -> Option<Vec2> {
+        if self.buildings.is_empty() {
+            average(self.engineers.iter().filter_map(|(entity, _)| {
+                <&Position>::query()
+                    .get(world, *entity)
+                    .ok()
+                    .map(|position| position.0)
+            }))
+        } else {
+            average(self.buildings.iter().map(|building| building.position))
+        }
+    }
+
+    // The next building that the AI wants to build, and where.
+    fn next_building(
+        &self,
+        base_center: Vec2,
+        map: &Map,
+        world: &SubWorld,
+    ) -> Option<(Building, Vec2, Option<Entity>)> {
+        let pumps = self.count(Building::Pump);
+        let armouries = self.count(Building::Armoury);
+
+        let pump = || {
+            if pumps >= MAX_PUMPS {
+                return None;
+            }
+
+            self.free_guysers
+                .iter()
+                .filter(|(_, position)| {
+                    !unit_under_building(*position, Building::Pump.stats().dimensions, world)
+                })
+                .filter(|(_, position)| {
+                    // Don't wander into the enemy's base.
+                    self.enemy_positions
+                        .iter()
+                        .all(|enemy| (*enemy - *position).mag() > SIGHT_RANGE / 2.0)
+                })
+                .min_by_key(|(_, position)| {
+                    ordered_float::OrderedFloat((*position - base_center).mag_sq())
+                })
+                .map(|(guyser, position)| (Building::Pump, *position, Some(*guyser)))
+        };
+
+        let armoury = || {
+            building_site(
+                Building::Armoury,
+                base_center,
+                self.towards_enemy(base_center),
+                self,
+                map,
+                world,
+            )
+            .map(|position| (Building::Armoury, position, None))
+        };
+
+        // Larders go behind the base, out of the way of the army.
+        let larder = || {
+            building_site(
+                Building::Larder,
+                base_center,
+                -self.towards_enemy(base_center),
+                self,
+                map,
+                world,
+            )
+            .map(|position| (Building::Larder, position, None))
+        };
+
+        let needs_supply = armouries > 0
+            && self.supply_cap < Supply::MAX
+            && self.supply_used + SUPPLY_MARGIN >= self.supply_cap;
+
+        if needs_supply {
+            if let Some(larder) = larder() {
+                return Some(larder);
+            }
+        }
+
+        if pumps < 2 || armouries > pumps / 3 {
+            pump().or_else(|| if armouries == 0 { armoury() } else { None })
+        } else {
+            armoury().or_else(pump)
+        }
+    }
+
+    fn towards_enemy(&self, from: Vec2) -> Vec2 {
+        self.enemy_center
+            .map(|enemy| enemy - from)
+            .filter(|direction| direction.mag_sq() > 0.0)
+            .map(|direction| direction.normalized())
+            .unwrap_or(Vec2::new(1.0, 0.0))
+    }
+}
+
+fn average(positions: impl Iterator<Item = Vec2>) -> Option<Vec2> {
+    let (sum, count) = positions.fold((Vec2::zero(), 0), |(sum, count), position| {
+        (sum + position, count + 1)
+    });
+
+    if count == 0 {
+        None
+    } else {
+        Some(sum / count as f32)
+    }
+}
+
+// Find somewhere to put a building near the base, preferring places in the given direction.
+fn building_site(
+    building: Building,
+    base_center: Vec2,
+    direction: Vec2,
+    view: &AiView,
+    map: &Map,
+    world: &SubWorld,
+) -> Option<Vec2> {
+    let dimensions = building.stats().dimensions;
+    let start_angle = direction.y.atan2(direction.x);
+
+    BUILDING_RINGS.iter().find_map(|radius| {
+        (0..BUILDING_RING_POSITIONS)
+            .map(|i| {
+                // Alternate either side of the direction.
+                let offset = i.div_ceil(2) as f32 * if i % 2 == 0 { 1.0 } else { -1.0 };
+                let angle = start_angle
+                    + offset * std::f32::consts::PI * 2.0 / BUILDING_RING_POSITIONS as f32;
+                base_center + Vec2::new(angle.cos(), angle.sin()) * *radius
+            })
+            .find(|position| {
+                position.x.abs() < MAP_EDGE
+                    && position.y.abs() < MAP_EDGE
+                    && map.can_insert(*position, dimensions + Vec2::broadcast(BUILDING_GAP))
+                    && !unit_under_building(*position, dimensions, world)
+                    && view.all_guysers.iter().all(|guyser| {
+                        (*guyser - *position).mag() > BUILDING_GUYSER_DISTANCE + dimensions.y / 2.0
+                    })
+            })
+    })
+}
+
+fn order(entity: Entity, command: Command, world: &mut SubWorld) {
+    if let Ok(queue) = <&mut CommandQueue>::query().get_mut(world, entity) {
+        queue.0.clear();
+        queue.0.push_back(command);
+    }
+}
+
+fn attack_move(target: Vec2) -> Command {
+    Command::MoveTo {
+        target,
+        attack_move: true,
+        path: Vec::new(),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[legion::system]
+#[read_component(Position)]
+#[read_component(Side)]
+#[read_component(Unit)]
+#[read_component(Building)]
+#[read_component(FullyBuilt)]
+#[read_component(CanBuild)]
+#[read_component(CanAttack)]
+#[read_component(CheeseGuyser)]
+#[read_component(CheeseGuyserBuiltOn)]
+#[read_component(DamagedThisTick)]
+#[read_component(Health)]
+#[write_component(CommandQueue)]
+#[write_component(RecruitmentQueue)]
+pub fn run_ai(
+    #[resource] ai_players: &mut AiPlayers,
+    #[resource] cheese_coins: &mut CheeseCoins,
+    #[resource] alliances: &Alliances,
+    #[resource] visibility: &Visibility,
+    #[resource] supply: &Supply,
+    #[resource] upgrades: &Upgrades,
+    #[resource] match_settings: &MatchSettings,
+    #[resource] total_time: &TotalTime,
+    #[resource] delta_time: &DeltaTime,
+    #[resource] map: &mut Map,
+    #[resource] animations: &ModelAnimations,
+    world: &mut SubWorld,
+    commands: &mut CommandBuffer,
+) {
+    for ai in ai_players.0.iter_mut().filter(|ai| ai.enabled) {
+        let side = ai.side;
+
+        // This is checked every tick, as `DamagedThisTick` only lasts for one.
+        let attacker = <(&Side, &DamagedThisTick)>::query()
+            .filter(component::<Building>())
+            .iter(world)
+            .filter(|(building_side, _)| **building_side == side)
+            .filter(|(_, damaged)| damaged.by_enemy)
+            .find_map(|(_, damaged)| <&Position>::query().get(world, damaged.source).ok());
+
+        if let Some(attacker) = attacker {
+            ai.attacked_from = Some((attacker.0, total_time.0));
+        }
+
+        ai.think_cooldown -= delta_time.0;
+        if ai.think_cooldown > 0.0 {
+            continue;
+        }
+        ai.think_cooldown = THINK_INTERVAL;
+
+        let difficulty = match_settings.difficulty;
+        let view = AiView::new(side, difficulty, alliances, visibility, supply, world);
+
+        let base_center = match view.base_center(world) {
+            Some(base_center) => base_center,
+            // Everything's been destroyed.
+            None => continue,
+        };
+
+        let reserve = manage_economy(
+            cheese_coins,
+            &view,
+            base_center,
+            map,
+            animations,
+            world,
+            commands,
+        );
+        research_upgrades(cheese_coins, upgrades, &view, reserve, world);
+        manage_army(ai, &view, base_center, difficulty, total_time, world);
+    }
+}
+
+// Returns the coins that are being saved up for the next building.
+fn manage_economy(
+    cheese_coins: &mut CheeseCoins,
+    view: &AiView,
+    base_center: Vec2,
+    map: &mut Map,
+    animations: &ModelAnimations,
+    world: &mut SubWorld,
+    commands: &mut CommandBuffer,
+) -> u32 {
+    let unfinished = view
+        .buildings
+        .iter()
+        .find(|building| !building.fully_built)
+        .map(|building| building.entity);
+
+    let next_building = if view.engineers.is_empty() {
+        None
+    } else {
+        view.next_building(base_center, map, world)
+    };
+
+    for (engineer, idle) in &view.engineers {
+        if !idle {
+            continue;
+        }
+
+        if let Some(unfinished) = unfinished {
+            order(*engineer, Command::new_build(unfinished), world);
+            continue;
+        }
+
+        let (building, position, guyser) = match next_building {
+            Some(next_building) => next_building,
+            None => break,
+        };
+
+        let cost = building.stats().cost;
+
+        if cost > cheese_coins.get(view.side) {
+            break;
+        }
+
+        let entity =
+            building.add_to_world_to_construct(commands, position, view.side, animations, map);
+
+        if let Some(entity) = entity {
+            log::debug!(target: "ai", "Building {:?} at {:?}", building, position);
+
+            cheese_coins.spend(view.side, cost);
+
+            if let Some(guyser) = guyser {
+                commands.add_component(guyser, CheeseGuyserBuiltOn { pump: entity });
+            }
+
+            order(*engineer, Command::new_build(entity), world);
+        }
+
+        // Only place one building per decision so that the view stays up to date.
+        break;
+    }
+
+    // Keep enough coins for the next building, so that marines don't use up all the income.
+    let reserve = next_building
+        .map(|(building, ..)| building.stats().cost)
+        .unwrap_or(0);
+
+    let rally_point = base_center + view.towards_enemy(base_center) * BUILDING_RINGS[0];
+
+    let recruit = if view.engineers.is_empty() {
+        Unit::Engineer
+    } else {
+        Unit::MouseMarine
+    };
+
+    // Don't queue up more than there's supply for, as the coins would be stuck in the queue.
+    let queued_supply: u32 = view
+        .buildings
+        .iter()
+        .filter_map(|building| {
+            <&RecruitmentQueue>::query()
+                .get(world, building.entity)
+                .ok()
+        })
+        .flat_map(|queue| queue.queue.iter())
+        .map(|item| match item {
+            QueueItem::Unit(unit) => unit.stats().supply,
+            QueueItem::Research(_) => 0,
+        })
+        .sum();
+    let mut supply_left = view
+        .supply_cap
+        .saturating_sub(view.supply_used + queued_supply);
+
+    for building in &view.buildings {
+        if building.building != Building::Armoury || !building.fully_built {
+            continue;
+        }
+
+        if let Ok(queue) = <&mut RecruitmentQueue>::query().get_mut(world, building.entity) {
+            queue.waypoint = rally_point;
+
+            let cost = recruit.stats().cost;
+            let reserve = if recruit == Unit::Engineer {
+                0
+            } else {
+                reserve
+            };
+
+            let supply = recruit.stats().supply;
+
+            if queue.queue.len() < MAX_QUEUE_LENGTH
+                && cheese_coins.get(view.side) >= cost + reserve
+                && supply_left >= supply
+            {
+                cheese_coins.spend(view.side, cost);
+                queue.queue.push_back(QueueItem::Unit(recruit));
+                supply_left -= supply;
+            }
+        }
+    }
+
+    reserve
+}
+
+// Spare coins go on upgrades, at buildings that aren't busy with anything else.
+fn research_upgrades(
+    cheese_coins: &mut CheeseCoins,
+    upgrades: &Upgrades,
+    view: &AiView,
+    reserve: u32,
+    world: &mut SubWorld,
+) {
+    let spare = cheese_coins
+        .get(view.side)
+        .saturating_sub(reserve + Unit::MouseMarine.stats().cost);
+
+    let research = view
+        .buildings
+        .iter()
+        .filter(|building| building.fully_built)
+        .find_map(|building| {
+            let queue = <&RecruitmentQueue>::query()
+                .get(world, building.entity)
+                .ok()?;
+
+            if !queue.queue.is_empty() {
+                return None;
+            }
+
+            building
+                .building
+                .stats()
+                .researches
+                .iter()
+                .copied()
+                .find(|upgrade| {
+                    upgrade.stats().cost <= spare
+                        && can_research(*upgrade, view.side, upgrades, world)
+                })
+                .map(|upgrade| (building.entity, upgrade))
+        });
+
+    if let Some((entity, upgrade)) = research {
+        if let Ok(queue) = <&mut RecruitmentQueue>::query().get_mut(world, entity) {
+            log::debug!(target: "ai", "Researching {:?}", upgrade);
+
+            cheese_coins.spend(view.side, upgrade.stats().cost);
+            queue.queue.push_back(QueueItem::Research(upgrade));
+        }
+    }
+}
+
+fn manage_army(
+    ai: &mut AiPlayer,
+    view: &AiView,
+    base_center: Vec2,
+    difficulty: AiDifficulty,
+    total_time: &TotalTime,
+    world: &mut SubWorld,
+) {
+    let defending = ai
+        .attacked_from
+        .filter(|(_, time)| total_time.0 - time < DEFEND_TIME)
+        .map(|(position, _)| position);
+
+    let (min_attack_size, earliest_attack) = difficulty.attack_timing();
+    let army = view.marines.len();
+    let outnumbers_enemy = total_time.0 >= earliest_attack
+        && army >= min_attack_size
+        && army > view.visible_enemy_army * 3 / 2;
+
+    let target = defending.or_else(|| {
+        if !outnumbers_enemy {
+            return None;
+        }
+
+        // Go for whatever is closest to the army.
+        let army_center = average(view.marines.iter().map(|(_, position, _)| *position))?;
+
+        // If the AI doesn't know where any enemies are, it scouts out the furthest guyser that
+        // it can't see.
+        let enemy = view
+            .enemy_positions
+            .iter()
+            .min_by_key(|position| ordered_float::OrderedFloat((**position - army_center).mag_sq()))
+            .or_else(|| {
+                view.hidden_guysers.iter().max_by_key(|position| {
+                    ordered_float::OrderedFloat((**position - base_center).mag_sq())
+                })
+            })
+            .copied()?;
+
+        let towards_army = army_center - enemy;
+
+        Some(if towards_army.mag() > ATTACK_STANDOFF {
+            enemy + towards_army.normalized() * ATTACK_STANDOFF
+        } else {
+            enemy
+        })
+    });
+
+    let new_target = match (target, ai.army_target) {
+        (Some(target), Some(previous)) => (target - previous).mag() > SIGHT_RANGE / 2.0,
+        (target, previous) => target.is_some() != previous.is_some(),
+    };
+
+    ai.army_target = target;
+
+    let rally_point = base_center + view.towards_enemy(base_center) * BUILDING_RINGS[0];
+
+    for (marine, position, idle) in &view.marines {
+        match target {
+            // Only redirect marines that are busy when the target changes, so that they don't
+            // keep dropping whatever they're fighting.
+            Some(target) if new_target || *idle => order(*marine, attack_move(target), world),
+            None if *idle && (*position - rally_point).mag() > SIGHT_RANGE / 2.0 => {
+                order(*marine, attack_move(rally_point), world)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Send the AI's damaged units back to its base, depending on the difficulty. This works for both
+/// the reactive AI and build orders.
+#[legion::system]
+#[read_component(Position)]
+#[read_component(Side)]
+#[read_component(Unit)]
+#[read_component(Building)]
+#[read_component(Health)]
+#[read_component(CanAttack)]
+#[write_component(CommandQueue)]
+pub fn retreat_damaged_units(
+    #[resource] match_settings: &MatchSettings,
+    #[resource] ai_players: &AiPlayers,
+    world: &mut SubWorld,
+) {
+    let difficulty = match_settings.difficulty;
+
+    if difficulty.retreat_health().is_none() {
+        return;
+    }
+
+    for ai in &ai_players.0 {
+        retreat_to_base(ai.side, difficulty, world);
+    }
+}
+
+fn retreat_to_base(side: Side, difficulty: AiDifficulty, world: &mut SubWorld) {
+    let base = average(
+        <(&Position, &Side)>::query()
+            .filter(component::<Building>())
+            .iter(world)
+            .filter(|(_, building_side)| **building_side == side)
+            .map(|(position, _)| position.0),
+    );
+
+    let base = match base {
+        Some(base) => base,
+        None => return,
+    };
+
+    <(&mut CommandQueue, &Position, &Side, &Unit, &Health)>::query()
+        .filter(component::<CanAttack>())
+        .for_each_mut(world, |(queue, position, unit_side, unit, health)| {
+            let already_retreating = matches!(
+                queue.0.front(),
+                Some(Command::MoveTo {
+                    attack_move: false,
+                    ..
+                })
+            );
+
+            if *unit_side == side
+                && should_retreat(*unit, health, difficulty)
+                && !already_retreating
+                && (position.0 - base).mag() > SIGHT_RANGE / 2.0
+            {
+                queue.0.clear();
+                queue.0.push_back(Command::MoveTo {
+                    target: base,
+                    attack_move: false,
+                    path: Vec::new(),
+                });
+            }
+        });
+}
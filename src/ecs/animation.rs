@@ -28,6 +28,13 @@ pub fn progress_animations(
                 MouseAnimation::Build
             }
         }
+        Some(Command::Cast { state, .. }) => {
+            if state.is_out_of_range() {
+                MouseAnimation::Walking
+            } else {
+                MouseAnimation::Idle
+            }
+        }
         None => MouseAnimation::Idle,
     } as usize;
 
@@ -60,6 +67,6 @@ pub fn progress_building_animations(
             animations.pump.animations[animation_state.animation]
                 .animate(skin, animation_state.time);
         }
-        Building::Armoury => {}
+        Building::Armoury | Building::Larder | Building::Turret => {}
     }
 }
@@ -1,10 +1,12 @@
 use super::{
     nearest_point_within_building, ActionState, Building, BuildingCompleteness,
     CheeseGuyserBuiltOn, Command, CommandQueue, Cooldown, Facing, FullyBuilt, Health, Position,
-    RecruitmentQueue, Side,
+    QueueItem, RecruitmentQueue, Side, Unit, Upgrade,
 };
 use crate::assets::ModelAnimations;
-use crate::resources::{CheeseCoins, DeltaTime, GameStats, PlayerSide};
+use crate::resources::{
+    AiPlayers, CheeseCoins, DeltaTime, GameStats, MatchSettings, PlayerSide, Supply, Upgrades,
+};
 use legion::{component, systems::CommandBuffer, world::SubWorld, Entity, EntityStore, IntoQuery};
 
 #[legion::system(for_each)]
@@ -20,18 +22,21 @@ pub fn build_buildings(
     facing: &mut Facing,
     #[resource] delta_time: &DeltaTime,
     #[resource] player_side: &PlayerSide,
+    #[resource] upgrades: &Upgrades,
     #[resource] stats: &mut GameStats,
     world: &mut SubWorld,
     buffer: &mut CommandBuffer,
 ) {
     let mut pop_front = false;
-    let health_increase_per_sec = 60.0;
-    let health_increase_this_tick = health_increase_per_sec * delta_time.0;
 
-    let position = <&Position>::query()
+    let (position, builder_side) = <(&Position, &Side)>::query()
         .get(world, *entity)
-        .expect("We've applied a filter to this system for Position")
-        .0;
+        .map(|(position, side)| (position.0, *side))
+        .expect("We've applied a filter to this system for Position");
+
+    let health_increase_per_sec =
+        60.0 * (1.0 + upgrades.amount(builder_side, Upgrade::EngineerBuildSpeed));
+    let health_increase_this_tick = health_increase_per_sec * delta_time.0;
 
     if let Some(Command::Build {
         target,
@@ -73,22 +78,53 @@ pub fn build_buildings(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 #[legion::system(for_each)]
 #[filter(component::<FullyBuilt>())]
 pub fn generate_cheese_coins(
     building: &Building,
     side: &Side,
     cooldown: &mut Cooldown,
-    #[resource] player_side: &PlayerSide,
     #[resource] cheese_coins: &mut CheeseCoins,
+    #[resource] ai_players: &AiPlayers,
+    #[resource] match_settings: &MatchSettings,
+    #[resource] upgrades: &Upgrades,
+    #[resource] stats: &mut GameStats,
 ) {
-    if cooldown.0 == 0.0 && building == &Building::Pump && side == &player_side.0 {
+    if cooldown.0 == 0.0 && building == &Building::Pump {
         // Reminder: no delta time stuff needed here because that's done in the cooldown code.
-        cheese_coins.0 += 2;
+        let base_income = 2.0 + upgrades.amount(*side, Upgrade::PumpIncome);
+
+        let income = match ai_players.get(*side) {
+            Some(_) => base_income * match_settings.difficulty.income_multiplier(),
+            None => base_income,
+        };
+
+        let income = cheese_coins.add_income(*side, income);
+        stats.coins_earned[side.index()] += income;
+
         cooldown.0 = 0.5;
     }
 }
 
+#[legion::system]
+#[read_component(Side)]
+#[read_component(Unit)]
+#[read_component(Building)]
+#[read_component(FullyBuilt)]
+pub fn count_supply(#[resource] supply: &mut Supply, world: &SubWorld) {
+    *supply = Supply::default();
+
+    <(&Unit, &Side)>::query().for_each(world, |(unit, side)| supply.add_unit(*side, *unit));
+
+    <(&Building, &Side)>::query()
+        .filter(component::<FullyBuilt>())
+        .for_each(world, |(building, side)| {
+            supply.add_building(*side, *building)
+        });
+}
+
+#[allow(clippy::too_many_arguments)]
 #[legion::system(for_each)]
 #[filter(component::<FullyBuilt>())]
 pub fn progress_recruitment_queue(
@@ -98,15 +134,33 @@ pub fn progress_recruitment_queue(
     side: &Side,
     #[resource] animations: &ModelAnimations,
     #[resource] delta_time: &DeltaTime,
+    #[resource] supply: &mut Supply,
+    #[resource] upgrades: &mut Upgrades,
     buffer: &mut CommandBuffer,
 ) {
-    if let Some(unit) = recruitment_queue.queue.front().cloned() {
-        let recruitment_time = unit.stats().recruitment_time;
-        recruitment_queue.percentage_progress += delta_time.0 / recruitment_time;
+    if let Some(item) = recruitment_queue.queue.front().cloned() {
+        // Recruitment is paused until the side has enough supply for the unit.
+        if let QueueItem::Unit(unit) = item {
+            if !supply.has_room_for(*side, unit) {
+                return;
+            }
+        }
+
+        recruitment_queue.percentage_progress += delta_time.0 / item.time();
         if recruitment_queue.percentage_progress > 1.0 {
             recruitment_queue.percentage_progress -= 1.0;
             recruitment_queue.queue.pop_front();
 
+            let unit = match item {
+                QueueItem::Unit(unit) => unit,
+                QueueItem::Research(upgrade) => {
+                    upgrades.add(*side, upgrade);
+                    return;
+                }
+            };
+
+            supply.add_unit(*side, unit);
+
             let start_point = nearest_point_within_building(
                 recruitment_queue.waypoint,
                 unit.stats().radius,
@@ -129,7 +183,7 @@ pub fn progress_recruitment_queue(
             );
         }
     } else {
-        // If a unit just finished off the queue and there are no more units in the queue,
+        // If a unit just finished off the queue and there is nothing else in the queue,
         // we don't want to keep the carry-over progress from the last unit around.
         recruitment_queue.percentage_progress = 0.0;
     }
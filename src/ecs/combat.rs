@@ -1,5 +1,9 @@
 use super::*;
-use crate::resources::{DeltaTime, GameStats, PlayerSide};
+use crate::resources::{
+    Alliances, CosmeticRng, DeltaTime, GameStats, Gravity, MatchSettings, PlayerSide, Upgrades,
+    Visibility,
+};
+use std::ops::RangeInclusive;
 
 #[legion::system(for_each)]
 #[read_component(Position)]
@@ -8,11 +12,10 @@ pub fn stop_actions_on_dead_entities(commands: &mut CommandQueue, world: &SubWor
         .0
         .front()
         .map(|command| {
-            if let Command::Attack { target, .. } | Command::Build { target, .. } = command {
-                world.entry_ref(*target).is_err()
-            } else {
-                false
-            }
+            command
+                .target_entity()
+                .map(|target| world.entry_ref(target).is_err())
+                .unwrap_or(false)
         })
         .unwrap_or(false)
     {
@@ -20,15 +23,28 @@ pub fn stop_actions_on_dead_entities(commands: &mut CommandQueue, world: &SubWor
     }
 }
 
+// Armour can't take a hit below this, so that everything can still be killed.
+const MIN_DAMAGE: f32 = 0.5;
+// Where shells are fired from, which is roughly where mice hold their guns.
+const SHELL_LAUNCH_HEIGHT: f32 = 1.8;
+
+#[allow(clippy::too_many_arguments)]
 #[legion::system(for_each)]
 #[filter(component::<Position>())]
 #[read_component(Position)]
+#[read_component(PreviousPosition)]
 pub fn firing(
     entity: &Entity,
+    unit: &Unit,
+    side: &Side,
     facing: &mut Facing,
     cooldown: &mut Cooldown,
     firing_range: &FiringRange,
+    min_firing_range: Option<&MinFiringRange>,
     command_queue: &CommandQueue,
+    #[resource] upgrades: &Upgrades,
+    #[resource] gravity: &Gravity,
+    #[resource] delta_time: &DeltaTime,
     world: &SubWorld,
     buffer: &mut CommandBuffer,
 ) {
@@ -47,44 +63,308 @@ pub fn firing(
 
         let vector = target_position.0 - position.0;
 
-        if vector.mag_sq() <= firing_range.0.powi(2) {
+        let too_close = min_firing_range
+            .map(|min_firing_range| vector.mag_sq() < min_firing_range.0.powi(2))
+            .unwrap_or(false);
+
+        if vector.mag_sq() <= firing_range.0.powi(2) && !too_close {
+            let stats = unit.stats();
+            let mut damage = stats.damage;
+
+            if *unit == Unit::MouseMarine {
+                damage += upgrades.amount(*side, Upgrade::MarineDamage);
+            }
+
             facing.0 = vector.y.atan2(vector.x);
 
-            buffer.push((
-                Position(position.0 + vector.normalized() * 0.5),
-                Bullet {
-                    target: *target,
-                    source: *entity,
-                    target_position: target_position.0,
-                },
-                Facing(vector.y.atan2(vector.x)),
-                MoveSpeed(20.0),
-            ));
-            cooldown.0 = 10.0 / 60.0;
+            match stats.projectile {
+                Projectile::Bullet => fire_bullet(
+                    *entity,
+                    position.0 + vector.normalized() * 0.5,
+                    *target,
+                    target_position.0,
+                    damage,
+                    stats.damage_type,
+                    buffer,
+                ),
+                Projectile::Shell {
+                    speed,
+                    splash_radius,
+                } => {
+                    // Buildings don't move, so they don't have a `PreviousPosition`.
+                    let target_velocity = <&PreviousPosition>::query()
+                        .get(world, *target)
+                        .map(|previous| (target_position.0 - previous.0) / delta_time.0)
+                        .unwrap_or_else(|_| Vec2::zero());
+
+                    let landing =
+                        predict_landing(position.0, target_position.0, target_velocity, speed);
+
+                    // Round the flight time up to a whole number of ticks, so that the shell is
+                    // on the ground exactly at the end of one.
+                    let ticks = ((landing - position.0).mag() / speed / delta_time.0)
+                        .ceil()
+                        .max(1.0);
+                    let flight_time = ticks * delta_time.0;
+                    let horizontal = (landing - position.0) / flight_time;
+                    // This accounts for gravity being applied before the shell moves each tick.
+                    let vertical = gravity.0 * (flight_time + delta_time.0) / 2.0
+                        - SHELL_LAUNCH_HEIGHT / flight_time;
+
+                    buffer.push((
+                        Position(position.0),
+                        PreviousPosition(position.0),
+                        Shell {
+                            source: *entity,
+                            velocity: Vec3::new(horizontal.x, vertical, horizontal.y),
+                            height: SHELL_LAUNCH_HEIGHT,
+                            damage,
+                            damage_type: stats.damage_type,
+                            splash_radius,
+                        },
+                    ));
+                }
+            }
+
+            cooldown.0 = 1.0 / stats.fire_rate;
         }
     }
 }
 
+// How far in front of the middle of a turret its barrel ends.
+const TURRET_BARREL_LENGTH: f32 = 2.6;
+
+// Turrets don't take commands, so they turn to face the closest enemy in range by themselves.
+#[allow(clippy::too_many_arguments)]
 #[legion::system(for_each)]
-// Need this so that entry_ref works instead of erroring for a non-obvious reason.
-#[read_component(Entity)]
-pub fn apply_bullets(
+#[filter(component::<Building>() & component::<FullyBuilt>())]
+#[read_component(Position)]
+#[read_component(Building)]
+#[read_component(Side)]
+#[read_component(Health)]
+pub fn turret_firing(
     entity: &Entity,
-    bullet: &Bullet,
-    position: &Position,
+    facing: &mut Facing,
+    cooldown: &mut Cooldown,
+    firing_range: &FiringRange,
+    #[resource] alliances: &Alliances,
+    #[resource] visibility: &Visibility,
     world: &SubWorld,
     buffer: &mut CommandBuffer,
 ) {
-    if position.0 == bullet.target_position {
-        if world.entry_ref(bullet.target).is_ok() {
-            buffer.add_component(bullet.target, DamagedThisTick(bullet.source));
+    let (position, building, side) = <(&Position, &Building, &Side)>::query()
+        .get(world, *entity)
+        .expect("We've applied a filter to this system for Building");
+
+    let target = find_best_target(
+        position.0,
+        *side,
+        Some(0.0..=firing_range.0),
+        false,
+        alliances,
+        visibility,
+        world,
+    );
+
+    let (target, target_position) = match target {
+        Some(target) => (target, <&Position>::query().get(world, target).unwrap().0),
+        None => return,
+    };
+
+    let vector = target_position - position.0;
+    facing.0 = vector.y.atan2(vector.x);
+
+    if cooldown.0 != 0.0 {
+        return;
+    }
+
+    let stats = building.stats();
+
+    fire_bullet(
+        *entity,
+        position.0 + vector.normalized() * TURRET_BARREL_LENGTH,
+        target,
+        target_position,
+        stats.damage,
+        stats.damage_type,
+        buffer,
+    );
+
+    cooldown.0 = 1.0 / stats.fire_rate;
+}
+
+fn fire_bullet(
+    source: Entity,
+    from: Vec2,
+    target: Entity,
+    target_position: Vec2,
+    damage: f32,
+    damage_type: DamageType,
+    buffer: &mut CommandBuffer,
+) {
+    let vector = target_position - from;
+
+    buffer.push((
+        Position(from),
+        PreviousPosition(from),
+        Bullet {
+            target,
+            source,
+            target_position,
+            damage,
+            damage_type,
+        },
+        Facing(vector.y.atan2(vector.x)),
+        MoveSpeed(20.0),
+    ));
+}
+
+// Bullets that reach their targets and shells that land add to the `DamagedThisTick` of whatever
+// they hit, with the armour taken off of each hit separately.
+#[legion::system]
+#[read_component(Entity)]
+#[read_component(Bullet)]
+#[read_component(Shell)]
+#[read_component(Position)]
+#[read_component(Radius)]
+#[read_component(Health)]
+#[read_component(Side)]
+#[read_component(Unit)]
+#[read_component(Building)]
+pub fn apply_projectiles(
+    #[resource] upgrades: &Upgrades,
+    #[resource] alliances: &Alliances,
+    #[resource] rng: &mut CosmeticRng,
+    world: &SubWorld,
+    buffer: &mut CommandBuffer,
+) {
+    // The target, the damage after armour and the source of every hit.
+    let mut hits: Vec<(Entity, f32, Entity)> = Vec::new();
+
+    for (entity, bullet, position) in <(Entity, &Bullet, &Position)>::query().iter(world) {
+        if position.0 != bullet.target_position {
+            continue;
+        }
+
+        buffer.remove(*entity);
+
+        let damage = damage_taken(
+            bullet.target,
+            bullet.damage,
+            bullet.damage_type,
+            upgrades,
+            world,
+        );
+
+        // The target might have already died.
+        if let Some(damage) = damage {
+            hits.push((bullet.target, damage, bullet.source));
+        }
+    }
+
+    for (entity, shell, position) in <(Entity, &Shell, &Position)>::query().iter(world) {
+        // Allow for rounding errors, so that shells don't land a tick late.
+        if shell.height > 0.001 {
+            continue;
         }
+
         buffer.remove(*entity);
+        buffer.push((Explosion::new(position.0, &mut rng.0, shell.splash_radius),));
+
+        // Everything in the blast gets hit, including the shell's own side.
+        for (target, target_position, radius) in <(Entity, &Position, &Radius)>::query()
+            .filter(component::<Health>())
+            .iter(world)
+        {
+            let distance = ((target_position.0 - position.0).mag() - radius.0).max(0.0);
+
+            if distance >= shell.splash_radius {
+                continue;
+            }
+
+            let falloff = 1.0 - distance / shell.splash_radius;
+            let damage = damage_taken(
+                *target,
+                shell.damage * falloff,
+                shell.damage_type,
+                upgrades,
+                world,
+            );
+
+            if let Some(damage) = damage {
+                hits.push((*target, damage, shell.source));
+            }
+        }
+    }
+
+    // A `Vec` instead of a `HashMap` so that the components are always added in the same order.
+    let mut damaged: Vec<(Entity, DamagedThisTick)> = Vec::new();
+
+    for (target, damage, source) in hits {
+        let by_enemy = match (
+            <&Side>::query().get(world, source),
+            <&Side>::query().get(world, target),
+        ) {
+            (Ok(source_side), Ok(target_side)) => alliances.are_enemies(*source_side, *target_side),
+            // Whatever fired it has died.
+            _ => false,
+        };
+
+        match damaged.iter_mut().find(|(entity, _)| *entity == target) {
+            Some((_, damaged)) => {
+                damaged.damage += damage;
+                // Being hit by an ally as well doesn't stop it from fighting back.
+                if by_enemy || !damaged.by_enemy {
+                    damaged.source = source;
+                    damaged.by_enemy = by_enemy;
+                }
+            }
+            None => damaged.push((
+                target,
+                DamagedThisTick {
+                    damage,
+                    source,
+                    by_enemy,
+                },
+            )),
+        }
+    }
+
+    for (target, damaged) in damaged {
+        buffer.add_component(target, damaged);
     }
 }
 
+// None if the target doesn't exist anymore.
+fn damage_taken(
+    target: Entity,
+    damage: f32,
+    damage_type: DamageType,
+    upgrades: &Upgrades,
+    world: &SubWorld,
+) -> Option<f32> {
+    let (side, unit, building) = <(&Side, Option<&Unit>, Option<&Building>)>::query()
+        .get(world, target)
+        .ok()?;
+
+    let mut damage = damage * damage_type.multiplier(building.is_some());
+
+    if let Some(unit) = unit {
+        damage -= unit.stats().armour;
+    }
+
+    if let Some(building) = building {
+        damage -= building.stats().armour;
+        damage *= 1.0 - upgrades.amount(*side, Upgrade::BuildingArmour);
+    }
+
+    Some(damage.max(MIN_DAMAGE))
+}
+
 #[legion::system(for_each)]
 #[read_component(Building)]
+#[read_component(Unit)]
+#[read_component(Side)]
 pub fn handle_damaged(
     entity: &Entity,
     position: &Position,
@@ -98,12 +378,13 @@ pub fn handle_damaged(
     map_handle: Option<&MapHandle>,
     buffer: &mut CommandBuffer,
     #[resource] player_side: &PlayerSide,
+    #[resource] alliances: &Alliances,
     #[resource] stats: &mut GameStats,
     #[resource] map: &mut Map,
-    #[resource] rng: &mut SmallRng,
+    #[resource] rng: &mut CosmeticRng,
     world: &SubWorld,
 ) {
-    health.0 = (health.0 - 2.0).max(0.0);
+    health.0 = (health.0 - damaged.damage).max(0.0);
 
     if health.0 == 0.0 {
         buffer.remove(*entity);
@@ -112,15 +393,18 @@ pub fn handle_damaged(
             map.remove(map_handle);
         }
 
+        // Allies' losses don't count either way.
         if *side == player_side.0 {
             stats.units_lost += 1;
-        } else if map_handle.is_some() {
-            stats.enemy_buildings_destroyed += 1;
-        } else {
-            stats.enemy_units_killed += 1;
+        } else if alliances.are_enemies(*side, player_side.0) {
+            if map_handle.is_some() {
+                stats.enemy_buildings_destroyed += 1;
+            } else {
+                stats.enemy_units_killed += 1;
+            }
         }
 
-        buffer.push((Explosion::new(position.0, rng, radius.0),));
+        buffer.push((Explosion::new(position.0, &mut rng.0, radius.0),));
 
         return;
     }
@@ -128,9 +412,10 @@ pub fn handle_damaged(
     // If the unit is idle and got attacked, go attack back!
     if let Some(commands) = commands {
         if can_attack.is_some()
+            && damaged.by_enemy
             && (commands.0.is_empty() || is_attacking_building(&commands, world))
         {
-            commands.0.push_front(Command::new_attack(damaged.0, false));
+            commands.0.push_front(Command::new_attack(damaged.source, false));
         }
     }
 
@@ -152,15 +437,22 @@ fn is_attacking_building(commands: &CommandQueue, world: &SubWorld) -> bool {
         .unwrap_or(false)
 }
 
+#[allow(clippy::too_many_arguments)]
 #[legion::system(for_each)]
 #[filter(component::<Position>() & component::<Side>() & component::<CanAttack>())]
 #[read_component(Entity)]
 #[read_component(Position)]
 #[read_component(Side)]
 #[read_component(Building)]
+#[read_component(Health)]
 pub fn agro_units(
     entity: &Entity,
     commands: &mut CommandQueue,
+    min_firing_range: Option<&MinFiringRange>,
+    #[resource] player_side: &PlayerSide,
+    #[resource] match_settings: &MatchSettings,
+    #[resource] alliances: &Alliances,
+    #[resource] visibility: &Visibility,
     world: &SubWorld,
     command_buffer: &mut CommandBuffer,
 ) {
@@ -179,38 +471,77 @@ pub fn agro_units(
         .expect("We've applied a filter for these components");
 
     let agro_range: f32 = 15.0;
-
-    if let Some(target) = find_best_target(position.0, *side, Some(agro_range), world) {
+    // Units with a minimum range ignore anything that they can't fire at.
+    let min_range = min_firing_range.map(|range| range.0).unwrap_or(0.0);
+
+    // The AI focuses on finishing off damaged units on higher difficulties.
+    let focus_fire = *side != player_side.0 && match_settings.difficulty.focus_fire();
+
+    if let Some(target) = find_best_target(
+        position.0,
+        *side,
+        Some(min_range..=agro_range),
+        focus_fire,
+        alliances,
+        visibility,
+        world,
+    ) {
         commands.0.push_front(Command::new_attack(target, false));
         command_buffer.add_component(*entity, Agroed::ThisTick(target));
     }
 }
 
+// Where a shell has to land to hit a target that keeps moving at the same velocity.
+fn predict_landing(from: Vec2, target: Vec2, target_velocity: Vec2, speed: f32) -> Vec2 {
+    let mut landing = target;
+
+    // Moving the landing point changes the flight time, but this settles down quickly.
+    for _ in 0..3 {
+        let flight_time = (landing - from).mag() / speed;
+        landing = target + target_velocity * flight_time;
+    }
+
+    landing
+}
+
+// With `weakest_first`, the target with the least health is picked instead of the closest one.
 fn find_best_target(
     position: Vec2,
     side: Side,
-    in_range: Option<f32>,
+    in_range: Option<RangeInclusive<f32>>,
+    weakest_first: bool,
+    alliances: &Alliances,
+    visibility: &Visibility,
     world: &SubWorld,
 ) -> Option<Entity> {
-    <(Entity, &Position, Option<&Building>, &Side)>::query()
+    <(Entity, &Position, Option<&Building>, &Side, &Health)>::query()
         .iter(world)
-        .filter(|(.., entity_side)| **entity_side != side)
+        .filter(|(_, entity_position, _, entity_side, _)| {
+            alliances.are_enemies(**entity_side, side)
+                && visibility.is_visible(side, entity_position.0)
+        })
         .filter(|(_, entity_position, ..)| {
             in_range
-                .map(|range| (position - entity_position.0).mag_sq() <= range.powi(2))
+                .as_ref()
+                .map(|range| range.contains(&(position - entity_position.0).mag()))
                 .unwrap_or(true)
         })
-        .map(|(entity, entity_position, entity_building, _)| {
+        .map(|(entity, entity_position, entity_building, _, health)| {
             let distance_sq = (position - entity_position.0).mag_sq();
+            let priority = if weakest_first {
+                health.0
+            } else {
+                distance_sq
+            };
             (
                 *entity,
-                ordered_float::OrderedFloat(distance_sq),
+                ordered_float::OrderedFloat(priority),
                 entity_building.is_some(),
             )
         })
-        .min_by(|&(_, a_pos, a_is_building), &(_, b_pos, b_is_building)| {
+        .min_by(|&(_, a_priority, a_is_building), &(_, b_priority, b_is_building)| {
             if a_is_building == b_is_building {
-                a_pos.cmp(&b_pos)
+                a_priority.cmp(&b_priority)
             // If only a is a building, then it a has less priority
             } else if a_is_building {
                 std::cmp::Ordering::Greater
@@ -222,6 +553,23 @@ fn find_best_target(
         .map(|(entity, ..)| entity)
 }
 
+// The enemy that `entity` would go for if everything was in range, going by what its side can see.
+#[cfg(test)]
+pub fn best_target(entity: Entity, world: &mut World, resources: &Resources) -> Option<Entity> {
+    let world = SubWorld::from(world);
+    let (position, side) = <(&Position, &Side)>::query().get(&world, entity).ok()?;
+
+    find_best_target(
+        position.0,
+        *side,
+        None,
+        false,
+        &resources.get::<Alliances>().unwrap(),
+        &resources.get::<Visibility>().unwrap(),
+        &world,
+    )
+}
+
 #[legion::system(for_each)]
 #[filter(component::<Position>() & component::<Side>() & component::<CanAttack>())]
 #[read_component(Entity)]
@@ -232,6 +580,7 @@ fn find_best_target(
 pub fn propagate_agro(
     entity: &Entity,
     commands: &mut CommandQueue,
+    #[resource] alliances: &Alliances,
     world: &SubWorld,
     command_buffer: &mut CommandBuffer,
 ) {
@@ -251,7 +600,7 @@ pub fn propagate_agro(
     let agro_entity = <(&Position, &Side, &Agroed)>::query()
         .iter(world)
         .filter(|(unit_pos, unit_side, _)| {
-            *unit_side == side
+            alliances.are_allies(**unit_side, *side)
                 && (unit_pos.0 - position.0).mag_sq() <= agro_propagation_distance.powi(2)
         })
         .next()
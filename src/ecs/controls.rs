@@ -1,24 +1,40 @@
 use super::*;
-use crate::assets::ModelAnimations;
 use crate::resources::{
-    CheeseCoins, CommandMode, ControlGroups, GameStats, Keypress, Keypresses, LoseCondition, Mode,
-    Objectives, RayCastLocation, SelectedUnitsAbilities, TotalTime, WinCondition,
+    Alliances, CheeseCoins, CommandMode, ControlGroups, DpiScaling, GameStats, Keypress,
+    Keypresses, LoseCondition, Mode, Objectives, PlayerCommands, RayCastLocation, Region,
+    SelectedUnitsAbilities, TotalTime, Upgrades, Visibility, WinCondition,
 };
+use crate::scenarios::GUYSER_SNAP_DISTANCE;
+
+// The ids of the player's selected entities that have a `T` component.
+fn selected<T: legion::storage::Component>(
+    world: &SubWorld,
+    player_side: &PlayerSide,
+) -> Vec<EntityId> {
+    <(&EntityId, &Side)>::query()
+        .filter(component::<Selected>() & component::<T>())
+        .iter(world)
+        .filter(|(_, side)| **side == player_side.0)
+        .map(|(id, _)| *id)
+        .collect()
+}
 
 #[legion::system]
-#[write_component(RecruitmentQueue)]
+#[read_component(EntityId)]
+#[read_component(RecruitmentQueue)]
+#[read_component(Side)]
 pub fn handle_keypresses(
     #[resource] keypresses: &mut Keypresses,
     #[resource] camera_controls: &mut CameraControls,
     #[resource] rts_controls: &mut RtsControls,
     #[resource] debug_controls: &mut DebugControls,
-    #[resource] cheese_coins: &mut CheeseCoins,
-    //#[resource] player_side: &mut PlayerSide,
+    #[resource] cheese_coins: &CheeseCoins,
+    #[resource] player_side: &PlayerSide,
+    #[resource] upgrades: &Upgrades,
     #[resource] selected_units_abilities: &SelectedUnitsAbilities,
     #[resource] mode: &mut Mode,
-    #[resource] total_time: &TotalTime,
-    #[resource] game_stats: &mut GameStats,
-    world: &mut SubWorld,
+    #[resource] player_commands: &mut PlayerCommands,
+    world: &SubWorld,
 ) {
     for Keypress {
         code,
@@ -36,34 +52,70 @@ pub fn handle_keypresses(
                             AbilityType::SetRecruitmentWaypoint => {
                                 rts_controls.mode = CommandMode::SetRecruitmentWaypoint;
                             }
+                            AbilityType::Cast(spell) => {
+                                if spell.stats().target == TargetType::NoTarget {
+                                    player_commands.0.push(PlayerCommand::Cast {
+                                        units: casters
+                                            .iter()
+                                            .filter_map(|caster| {
+                                                <&EntityId>::query().get(world, *caster).ok()
+                                            })
+                                            .cloned()
+                                            .collect(),
+                                        spell,
+                                        target: PlayerCastTarget::Nothing,
+                                        queued: rts_controls.shift_held,
+                                    });
+                                } else {
+                                    rts_controls.mode = CommandMode::CastAbility { spell };
+                                }
+                            }
                             AbilityType::Build(building) => {
                                 rts_controls.mode = CommandMode::Construct { building };
                             }
                             AbilityType::Recruit(unit) => {
-                                if unit.stats().cost <= cheese_coins.0 {
-                                    let entity_with_shortest_recruitment_queue = casters
+                                if unit.stats().cost <= cheese_coins.get(player_side.0) {
+                                    let building_with_shortest_recruitment_queue = casters
                                         .iter()
                                         .filter_map(|caster| {
-                                            <&RecruitmentQueue>::query()
+                                            <(&RecruitmentQueue, &EntityId)>::query()
                                                 .filter(component::<FullyBuilt>())
                                                 .get(world, *caster)
                                                 .ok()
-                                                .map(|queue| (caster, queue.length()))
+                                                .map(|(queue, id)| (*id, queue.length()))
                                         })
                                         .min_by_key(|(_, queue_len)| *queue_len)
-                                        .map(|(entity, _)| *entity);
+                                        .map(|(id, _)| id);
 
-                                    if let Some(entity) = entity_with_shortest_recruitment_queue {
-                                        cheese_coins.0 -= unit.stats().cost;
-                                        log::trace!(target: "command-recording", "{:?}: Recruiting {:?}", total_time.0, unit);
-
-                                        game_stats.units_recruited += 1;
+                                    if let Some(building) = building_with_shortest_recruitment_queue
+                                    {
+                                        player_commands
+                                            .0
+                                            .push(PlayerCommand::Recruit { building, unit });
+                                    }
+                                }
+                            }
+                            AbilityType::Research(upgrade) => {
+                                if upgrade.stats().cost <= cheese_coins.get(player_side.0)
+                                    && can_research(upgrade, player_side.0, upgrades, world)
+                                {
+                                    let building_with_shortest_recruitment_queue = casters
+                                        .iter()
+                                        .filter_map(|caster| {
+                                            <(&RecruitmentQueue, &EntityId)>::query()
+                                                .filter(component::<FullyBuilt>())
+                                                .get(world, *caster)
+                                                .ok()
+                                                .map(|(queue, id)| (*id, queue.length()))
+                                        })
+                                        .min_by_key(|(_, queue_len)| *queue_len)
+                                        .map(|(id, _)| id);
 
-                                        <&mut RecruitmentQueue>::query()
-                                            .get_mut(world, entity)
-                                            .unwrap()
-                                            .queue
-                                            .push_back(unit);
+                                    if let Some(building) = building_with_shortest_recruitment_queue
+                                    {
+                                        player_commands
+                                            .0
+                                            .push(PlayerCommand::Research { building, upgrade });
                                     }
                                 }
                             }
@@ -131,6 +183,8 @@ pub fn control_camera(
     #[resource] camera_controls: &mut CameraControls,
     #[resource] mouse_state: &MouseState,
     #[resource] screen_dimensions: &ScreenDimensions,
+    #[resource] dpi_scaling: &DpiScaling,
+    #[resource] mode: &Mode,
     #[resource] delta_time: &DeltaTime,
 ) {
     let speed = 45.0 * delta_time.0;
@@ -145,6 +199,13 @@ pub fn control_camera(
     let mouse_x = mouse_state.position.x;
     let mouse_y = mouse_state.position.y;
 
+    // The minimap is in the corner of the screen, so edge panning would get in the way of using it.
+    let edge_thickness =
+        match position_on_minimap(mode, screen_dimensions, dpi_scaling, mouse_state.position) {
+            Some(_) => 0.0,
+            None => edge_thickness,
+        };
+
     let right = Vec2::new(speed, 0.0);
     let forwards = Vec2::new(0.0, -speed);
 
@@ -173,6 +234,7 @@ pub fn control_camera(
     camera_controls.zoom_delta = 0.0;
 }
 
+#[allow(clippy::too_many_arguments)]
 #[legion::system]
 #[read_component(Entity)]
 #[read_component(Position)]
@@ -180,47 +242,57 @@ pub fn cast_ray(
     #[resource] camera: &Camera,
     #[resource] mouse_state: &MouseState,
     #[resource] screen_dimensions: &ScreenDimensions,
+    #[resource] dpi_scaling: &DpiScaling,
+    #[resource] mode: &Mode,
     #[resource] ray_cast_location: &mut RayCastLocation,
     #[resource] rts_controls: &RtsControls,
     world: &SubWorld,
 ) {
     ray_cast_location.pos = camera.cast_ray(mouse_state.position, screen_dimensions);
     ray_cast_location.snapped_to_guyser = None;
+
+    let minimap_position =
+        position_on_minimap(mode, screen_dimensions, dpi_scaling, mouse_state.position);
+    ray_cast_location.on_minimap = minimap_position.is_some();
+
+    // Buildings can't be placed from the minimap, but units can be sent to places on it.
+    if let Some(position) = minimap_position {
+        if !matches!(rts_controls.mode, CommandMode::Construct { .. }) {
+            ray_cast_location.pos = position;
+        }
+        return;
+    }
     if let CommandMode::Construct {
         building: Building::Pump,
     } = rts_controls.mode
     {
-        let snap_guyser = <(Entity, &Position)>::query()
-            .filter(component::<CheeseGuyser>() & !component::<CheeseGuyserBuiltOn>())
-            .iter(world)
-            .find(|(_, pos)| (ray_cast_location.pos - pos.0).mag_sq() <= 4.0_f32.powi(2));
-
-        if let Some((entity, pos)) = snap_guyser {
-            ray_cast_location.pos = pos.0;
-            ray_cast_location.snapped_to_guyser = Some(*entity);
+        if let Some((entity, pos)) = free_guyser_near(ray_cast_location.pos, world) {
+            ray_cast_location.pos = pos;
+            ray_cast_location.snapped_to_guyser = Some(entity);
         }
     }
 }
 
 #[legion::system]
 #[read_component(Entity)]
+#[read_component(EntityId)]
 #[read_component(Selected)]
 #[read_component(Position)]
 #[read_component(Side)]
 #[read_component(Radius)]
 #[read_component(Building)]
-#[write_component(CommandQueue)]
-#[write_component(RecruitmentQueue)]
+#[read_component(Unit)]
 pub fn handle_left_click(
     #[resource] mouse_state: &MouseState,
     #[resource] ray_cast_location: &RayCastLocation,
     #[resource] rts_controls: &mut RtsControls,
     #[resource] player_side: &PlayerSide,
-    #[resource] map: &mut Map,
-    #[resource] animations: &ModelAnimations,
-    #[resource] cheese_coins: &mut CheeseCoins,
-    #[resource] total_time: &TotalTime,
-    world: &mut SubWorld,
+    #[resource] alliances: &Alliances,
+    #[resource] visibility: &Visibility,
+    #[resource] map: &Map,
+    #[resource] cheese_coins: &CheeseCoins,
+    #[resource] player_commands: &mut PlayerCommands,
+    world: &SubWorld,
     commands: &mut CommandBuffer,
 ) {
     if !mouse_state.left_state.was_clicked() {
@@ -233,20 +305,27 @@ pub fn handle_left_click(
                 ray_cast_location,
                 rts_controls,
                 player_side,
+                alliances,
+                visibility,
+                player_commands,
                 world,
-                total_time.0,
             );
 
             if !rts_controls.shift_held {
                 rts_controls.mode = CommandMode::Normal;
             }
         }
+        // Clicking on the minimap moves the camera instead, in `control_camera_with_minimap`.
+        CommandMode::Normal | CommandMode::Construct { .. } if ray_cast_location.on_minimap => {}
         CommandMode::Normal => {
             let position = ray_cast_location.pos;
 
             let entity = <(Entity, &Position, Option<&Selected>, &Side, &Radius)>::query()
                 .filter(component::<Selectable>())
                 .iter(world)
+                .filter(|(_, pos, _, side, _)| {
+                    visibility.can_see(player_side.0, **side, pos.0, alliances)
+                })
                 .find(|(_, pos, .., radius)| (position - pos.0).mag_sq() < radius.0.powi(2))
                 .map(|(entity, _, selected, side, _)| (entity, selected.is_some(), side));
 
@@ -284,12 +363,10 @@ pub fn handle_left_click(
                 ray_cast_location,
                 player_side,
                 map,
-                animations,
-                commands,
                 world,
                 rts_controls,
                 cheese_coins,
-                total_time.0,
+                player_commands,
             );
 
             if built && !rts_controls.shift_held {
@@ -297,102 +374,95 @@ pub fn handle_left_click(
             }
         }
         CommandMode::SetRecruitmentWaypoint => {
-            let position = ray_cast_location.pos;
+            player_commands.0.push(PlayerCommand::SetWaypoint {
+                buildings: selected::<RecruitmentQueue>(world, player_side),
+                position: ray_cast_location.pos,
+            });
 
-            log::trace!(target: "command-recording", "{:?}: Setting waypoint {:?}", total_time.0, position);
+            rts_controls.mode = CommandMode::Normal;
+        }
+        CommandMode::CastAbility { spell } => {
+            let position = ray_cast_location.pos;
 
-            <(&mut RecruitmentQueue, &Side)>::query()
-                .filter(component::<Selected>())
-                .iter_mut(world)
-                .filter(|(_, side)| **side == player_side.0)
-                .for_each(|(queue, _)| {
-                    queue.waypoint = position;
+            let target = match spell.stats().target {
+                TargetType::NoTarget => Some(PlayerCastTarget::Nothing),
+                TargetType::Point => Some(PlayerCastTarget::Point(position)),
+                TargetType::EnemyUnit => <(&EntityId, &Position, &Side, &Radius)>::query()
+                    .filter(component::<Unit>())
+                    .iter(world)
+                    .filter(|(_, pos, side, _)| {
+                        alliances.are_enemies(**side, player_side.0)
+                            && visibility.can_see(player_side.0, **side, pos.0, alliances)
+                    })
+                    .find(|(_, pos, _, radius)| (position - pos.0).mag_sq() < radius.0.powi(2))
+                    .map(|(id, ..)| PlayerCastTarget::Entity(*id)),
+            };
+
+            // Clicking on something that the spell can't be cast on leaves the mode as it is.
+            if let Some(target) = target {
+                player_commands.0.push(PlayerCommand::Cast {
+                    units: selected::<SpellCooldowns>(world, player_side),
+                    spell,
+                    target,
+                    queued: rts_controls.shift_held,
                 });
 
-            rts_controls.mode = CommandMode::Normal;
+                if !rts_controls.shift_held {
+                    rts_controls.mode = CommandMode::Normal;
+                }
+            }
         }
     }
 }
 
+// The building is actually placed when the command is applied, but we check whether it can be
+// placed here so that we stay in construct mode if it can't.
 fn build_building_command(
     building: Building,
     ray_cast_location: &RayCastLocation,
     player_side: &PlayerSide,
-    map: &mut Map,
-    animations: &ModelAnimations,
-    commands: &mut CommandBuffer,
-    world: &mut SubWorld,
+    map: &Map,
+    world: &SubWorld,
     rts_controls: &RtsControls,
-    cheese_coins: &mut CheeseCoins,
-    total_time: f32,
+    cheese_coins: &CheeseCoins,
+    player_commands: &mut PlayerCommands,
 ) -> bool {
-    if building.stats().cost > cheese_coins.0
+    let dimensions = building.stats().dimensions;
+
+    if building.stats().cost > cheese_coins.get(player_side.0)
         || (building == Building::Pump && ray_cast_location.snapped_to_guyser.is_none())
-        || unit_under_building(ray_cast_location.pos, building.stats().dimensions, world)
+        || unit_under_building(ray_cast_location.pos, dimensions, world)
+        || !map.can_insert(ray_cast_location.pos, dimensions)
     {
         return false;
     }
 
-    if let Some(building_entity) = building.add_to_world_to_construct(
-        commands,
-        ray_cast_location.pos,
-        player_side.0,
-        animations,
-        map,
-    ) {
-        cheese_coins.0 -= building.stats().cost;
-
-        if let Building::Pump = building {
-            let guyser_entity = ray_cast_location.snapped_to_guyser.unwrap();
-            commands.add_component(
-                guyser_entity,
-                CheeseGuyserBuiltOn {
-                    pump: building_entity,
-                },
-            );
-        }
-
-        log::trace!(target: "command-recording", "{:?}: Building {:?} at {:?}", total_time, building, ray_cast_location);
-
-        let command = Command::Build {
-            target: building_entity,
-            // Kinda hacky? If we put `ActionState::OutOfRange` with an empty vec it
-            // wouldn't get updated with the current `set_movement_paths` code.
-            state: ActionState::InRange,
-        };
-
-        <(&mut CommandQueue, &Side)>::query()
-            .filter(component::<Selected>() & component::<CanBuild>())
-            .iter_mut(world)
-            .filter(|(_, side)| **side == player_side.0)
-            .for_each(|(commands, _)| {
-                if !rts_controls.shift_held {
-                    commands.0.clear();
-                }
-
-                commands.0.push_back(command.clone());
-            });
+    player_commands.0.push(PlayerCommand::PlaceBuilding {
+        units: selected::<CanBuild>(world, player_side),
+        building,
+        position: ray_cast_location.pos,
+        queued: rts_controls.shift_held,
+    });
 
-        true
-    } else {
-        false
-    }
+    true
 }
 
+#[allow(clippy::too_many_arguments)]
 #[legion::system]
-#[read_component(Entity)]
+#[read_component(EntityId)]
 #[read_component(Position)]
 #[read_component(Side)]
 #[read_component(Radius)]
 #[read_component(Building)]
-#[write_component(CommandQueue)]
 pub fn handle_right_click(
     #[resource] mouse_state: &MouseState,
     #[resource] ray_cast_location: &RayCastLocation,
     #[resource] rts_controls: &mut RtsControls,
     #[resource] player_side: &PlayerSide,
-    #[resource] total_time: &TotalTime,
-    world: &mut SubWorld,
+    #[resource] alliances: &Alliances,
+    #[resource] visibility: &Visibility,
+    #[resource] player_commands: &mut PlayerCommands,
+    world: &SubWorld,
 ) {
     if !mouse_state.right_state.was_clicked() {
         return;
@@ -409,8 +479,10 @@ pub fn handle_right_click(
         ray_cast_location,
         rts_controls,
         player_side,
+        alliances,
+        visibility,
+        player_commands,
         world,
-        total_time.0,
     )
 }
 
@@ -418,107 +490,78 @@ fn issue_command(
     ray_cast_location: &RayCastLocation,
     rts_controls: &RtsControls,
     player_side: &PlayerSide,
-    world: &mut SubWorld,
-    total_time: f32,
+    alliances: &Alliances,
+    visibility: &Visibility,
+    player_commands: &mut PlayerCommands,
+    world: &SubWorld,
 ) {
     let position = ray_cast_location.pos;
+    let queued = rts_controls.shift_held;
 
-    let entity_under_cursor = <(Entity, &Position, &Side, &Radius, Option<&Building>)>::query()
+    let entity_under_cursor = <(&EntityId, &Position, &Side, &Radius, Option<&Building>)>::query()
         .iter(world)
+        .filter(|(_, pos, side, ..)| visibility.can_see(player_side.0, **side, pos.0, alliances))
         .find(|(_, pos, _, radius, _)| (position - pos.0).mag_sq() < radius.0.powi(2))
-        .map(|(entity, _, side, .., building)| {
-            (*entity, *side == player_side.0, building.is_some())
-        });
+        // Right-clicking on an ally is the same as right-clicking on the ground under it.
+        .filter(|(_, _, side, ..)| {
+            **side == player_side.0 || alliances.are_enemies(**side, player_side.0)
+        })
+        .map(|(id, _, side, .., building)| (*id, *side == player_side.0, building.is_some()));
 
     let command = match entity_under_cursor {
-        Some((entity, false, _)) => Some(Command::new_attack(entity, true)),
-        Some((entity, true, true)) => Some(Command::Build {
-            target: entity,
-            state: ActionState::InRange,
+        Some((target, false, _)) => Some(PlayerCommand::Attack {
+            units: selected::<CanAttack>(world, player_side),
+            target,
+            queued,
+        }),
+        Some((target, true, true)) => Some(PlayerCommand::Build {
+            units: selected::<CanBuild>(world, player_side),
+            target,
+            queued,
         }),
         Some((_, true, false)) => None,
         None => match rts_controls.mode {
-            CommandMode::Normal => Some(Command::MoveTo {
+            CommandMode::Normal => Some(PlayerCommand::Move {
+                units: selected::<CommandQueue>(world, player_side),
                 target: position,
-                path: Vec::new(),
-                attack_move: false,
+                queued,
             }),
-            CommandMode::AttackMove => Some(Command::MoveTo {
+            CommandMode::AttackMove => Some(PlayerCommand::AttackMove {
+                units: selected::<CommandQueue>(world, player_side),
                 target: position,
-                path: Vec::new(),
-                attack_move: true,
+                queued,
             }),
             CommandMode::Construct { .. } => None,
-            CommandMode::SetRecruitmentWaypoint => None,
+            CommandMode::SetRecruitmentWaypoint | CommandMode::CastAbility { .. } => None,
         },
     };
 
     if let Some(command) = command {
-        log::trace!(target: "command-recording", "{:?}: Performing {:?}", total_time, command);
-
-        if let Command::Build { .. } = command {
-            <(&mut CommandQueue, &Side)>::query()
-                .filter(component::<Selected>() & component::<CanBuild>())
-                .iter_mut(world)
-                .filter(|(_, side)| **side == player_side.0)
-                .for_each(|(commands, _)| {
-                    if !rts_controls.shift_held {
-                        commands.0.clear();
-                    }
-
-                    commands.0.push_back(command.clone());
-                });
-        } else if let Command::Attack { .. } = command {
-            <(&mut CommandQueue, &Side)>::query()
-                .filter(component::<Selected>() & component::<CanAttack>())
-                .iter_mut(world)
-                .filter(|(_, side)| **side == player_side.0)
-                .for_each(|(commands, _)| {
-                    if !rts_controls.shift_held {
-                        commands.0.clear();
-                    }
-
-                    commands.0.push_back(command.clone());
-                });
-        } else {
-            <(&mut CommandQueue, &Side)>::query()
-                .filter(component::<Selected>())
-                .iter_mut(world)
-                .filter(|(_, side)| **side == player_side.0)
-                .for_each(|(commands, _)| {
-                    if !rts_controls.shift_held {
-                        commands.0.clear();
-                    }
-
-                    commands.0.push_back(command.clone());
-                });
-        }
+        player_commands.0.push(command);
     } else {
         log::debug!("Ignoring command on {:?}", entity_under_cursor);
     }
 }
 
 #[legion::system]
+#[read_component(EntityId)]
 #[read_component(Side)]
-#[write_component(CommandQueue)]
 pub fn handle_stop_command(
     #[resource] rts_controls: &RtsControls,
     #[resource] player_side: &PlayerSide,
-    world: &mut SubWorld,
+    #[resource] player_commands: &mut PlayerCommands,
+    world: &SubWorld,
 ) {
     if !rts_controls.stop_pressed {
         return;
     }
 
-    <(&mut CommandQueue, &Side)>::query()
-        .filter(component::<Selected>())
-        .iter_mut(world)
-        .filter(|(_, side)| **side == player_side.0)
-        .for_each(|(commands, _)| {
-            commands.0.clear();
-        });
+    player_commands.0.push(PlayerCommand::Stop {
+        units: selected::<CommandQueue>(world, player_side),
+    });
 }
 
+#[allow(clippy::too_many_arguments)]
 #[legion::system]
 #[read_component(Entity)]
 #[read_component(Side)]
@@ -529,10 +572,17 @@ pub fn handle_drag_selection(
     #[resource] screen_dimensions: &ScreenDimensions,
     #[resource] rts_controls: &RtsControls,
     #[resource] player_side: &PlayerSide,
+    #[resource] dpi_scaling: &DpiScaling,
+    #[resource] mode: &Mode,
     command_buffer: &mut CommandBuffer,
     world: &SubWorld,
 ) {
     if let Some(start) = mouse_state.left_state.was_dragged() {
+        // Dragging on the minimap moves the camera instead.
+        if position_on_minimap(mode, screen_dimensions, dpi_scaling, start).is_some() {
+            return;
+        }
+
         let select_box = SelectBox::new(camera, screen_dimensions, start, mouse_state.position);
 
         if !rts_controls.shift_held {
@@ -564,28 +614,37 @@ pub fn remove_dead_entities_from_control_groups(
 
 #[legion::system]
 #[read_component(Entity)]
+#[read_component(EntityId)]
 pub fn handle_control_groups(
-    #[resource] control_groups: &mut ControlGroups,
+    #[resource] control_groups: &ControlGroups,
     #[resource] rts_controls: &RtsControls,
+    #[resource] player_commands: &mut PlayerCommands,
     command_buffer: &mut CommandBuffer,
     world: &SubWorld,
 ) {
     for i in 0..10 {
         if rts_controls.control_group_key_pressed[i] {
-            if rts_controls.control_held {
-                control_groups.0[i].clear();
-                <Entity>::query()
+            let selected = || {
+                <&EntityId>::query()
                     .filter(component::<Selected>())
-                    .for_each(world, |entity| {
-                        control_groups.0[i].push(*entity);
-                    });
+                    .iter(world)
+                    .copied()
+                    .collect()
+            };
+
+            if rts_controls.control_held {
+                player_commands.0.push(PlayerCommand::SetControlGroup {
+                    group: i as u8,
+                    entities: selected(),
+                });
             } else if rts_controls.shift_held {
-                <Entity>::query()
-                    .filter(component::<Selected>())
-                    .for_each(world, |entity| {
-                        control_groups.0[i].push(*entity);
-                    });
+                player_commands.0.push(PlayerCommand::AddToControlGroup {
+                    group: i as u8,
+                    entities: selected(),
+                });
 
+                // The selected entities are already selected, so we just need to select the ones
+                // already in the group.
                 for entity in control_groups.0[i].iter() {
                     command_buffer.add_component(*entity, Selected);
                 }
@@ -628,6 +687,28 @@ pub fn update_selected_units_abilities(
         });
 }
 
+/// Enemies that go into the fog of war can't stay selected.
+#[legion::system]
+#[read_component(Entity)]
+#[read_component(Position)]
+#[read_component(Side)]
+#[read_component(Selected)]
+pub fn deselect_hidden_entities(
+    #[resource] player_side: &PlayerSide,
+    #[resource] alliances: &Alliances,
+    #[resource] visibility: &Visibility,
+    world: &SubWorld,
+    commands: &mut CommandBuffer,
+) {
+    <(Entity, &Position, &Side)>::query()
+        .filter(component::<Selected>())
+        .iter(world)
+        .filter(|(_, position, side)| {
+            !visibility.can_see(player_side.0, **side, position.0, alliances)
+        })
+        .for_each(|(entity, ..)| commands.remove_component::<Selected>(*entity));
+}
+
 fn deselect_all(world: &SubWorld, commands: &mut CommandBuffer) {
     <Entity>::query()
         .filter(component::<Selected>())
@@ -636,58 +717,182 @@ fn deselect_all(world: &SubWorld, commands: &mut CommandBuffer) {
         });
 }
 
-#[legion::system]
-#[read_component(Side)]
-#[read_component(Building)]
-pub fn update_playing_state(
-    #[resource] objectives: &Objectives,
-    #[resource] player_side: &PlayerSide,
-    #[resource] mode: &mut Mode,
-    world: &SubWorld,
-) {
-    let won = objectives
-        .win_conditions
-        .iter()
-        .all(|condition| match condition {
+// The gameplay state that objectives are checked against.
+struct ObjectivesContext<'a, 'b> {
+    world: &'a SubWorld<'b>,
+    player_side: Side,
+    alliances: &'a Alliances,
+    total_time: f32,
+    delta_time: f32,
+    cheese_coins: u32,
+    game_stats: &'a GameStats,
+}
+
+impl<'a, 'b> ObjectivesContext<'a, 'b> {
+    fn is_enemy(&self, side: Side) -> bool {
+        self.alliances.are_enemies(side, self.player_side)
+    }
+
+    fn tagged_exists(&self, tag: &str) -> bool {
+        <&Tag>::query()
+            .iter(self.world)
+            .any(|entity_tag| entity_tag.0 == tag)
+    }
+
+    fn holds_region(&self, region: &Region) -> bool {
+        let sides: Vec<Side> = <(&Position, &Side)>::query()
+            .filter(component::<Unit>())
+            .iter(self.world)
+            .filter(|(position, _)| region.contains(position.0))
+            .map(|(_, side)| *side)
+            .collect();
+
+        !sides.is_empty() && sides.iter().all(|side| !self.is_enemy(*side))
+    }
+
+    fn holds_guyser(&self, guyser: Vec2) -> bool {
+        <(&Building, &Side, &Position)>::query()
+            .filter(component::<FullyBuilt>())
+            .iter(self.world)
+            .any(|(building, side, position)| {
+                *building == Building::Pump
+                    && !self.is_enemy(*side)
+                    && (position.0 - guyser).mag() <= GUYSER_SNAP_DISTANCE
+            })
+    }
+
+    // Update the progress towards the condition and return whether it's been met.
+    fn win_condition_met(&self, condition: &mut WinCondition) -> bool {
+        match condition {
             WinCondition::DestroyAll => {
                 let all_destroyed = <&Side>::query()
-                    .iter(world)
-                    .all(|side| *side == player_side.0);
+                    .iter(self.world)
+                    .all(|side| !self.is_enemy(*side));
                 all_destroyed
             }
-            WinCondition::BuildN(num, building) => {
+            WinCondition::BuildN(num, building, built) => {
                 let num_buildings = <(&Side, &Building)>::query()
                     .filter(component::<FullyBuilt>())
-                    .iter(world)
+                    .iter(self.world)
                     .filter(|(side, building_type)| {
-                        **side == player_side.0 && building == *building_type
+                        **side == self.player_side && building == *building_type
                     })
                     .count();
-                num_buildings as u8 >= *num
+                *built = num_buildings.min(u8::MAX as usize) as u8;
+                *built >= *num
             }
-        })
-        && !objectives.win_conditions.is_empty();
-
-    if won {
-        *mode = Mode::ScenarioWon;
-        return;
+            WinCondition::Survive { seconds, elapsed } => {
+                *elapsed = self.total_time.min(*seconds);
+                *elapsed >= *seconds
+            }
+            WinCondition::HoldRegion {
+                region,
+                seconds,
+                held,
+                ..
+            } => update_held(held, *seconds, self.holds_region(region), self.delta_time),
+            WinCondition::HoldGuyser {
+                position,
+                seconds,
+                held,
+            } => update_held(
+                held,
+                *seconds,
+                self.holds_guyser(*position),
+                self.delta_time,
+            ),
+            WinCondition::GatherCoins { coins, current } => {
+                *current = self.cheese_coins;
+                *current >= *coins
+            }
+            WinCondition::DestroyTagged(tag) => !self.tagged_exists(tag),
+            WinCondition::RecruitN { count, recruited } => {
+                *recruited = self.game_stats.units_recruited;
+                *recruited >= *count
+            }
+        }
     }
 
-    let lost = objectives
-        .lose_conditions
-        .iter()
-        .any(|condition| match condition {
+    fn lose_condition_met(&self, condition: &mut LoseCondition) -> bool {
+        match condition {
             LoseCondition::LetAllUnitsDie => {
                 let all_units_dead = <&Side>::query()
                     .filter(component::<Unit>())
-                    .iter(world)
-                    .all(|side| *side != player_side.0);
+                    .iter(self.world)
+                    .all(|side| *side != self.player_side);
 
                 all_units_dead
             }
-        });
+            LoseCondition::LoseTagged(tag) => !self.tagged_exists(tag),
+            LoseCondition::TimeLimit { seconds, elapsed } => {
+                *elapsed = self.total_time.min(*seconds);
+                *elapsed >= *seconds
+            }
+        }
+    }
+}
+
+// Holding has to be uninterrupted, so the time held goes back to 0 when it's lost.
+fn update_held(held: &mut f32, seconds: f32, holding: bool, delta_time: f32) -> bool {
+    *held = if holding {
+        (*held + delta_time).min(seconds)
+    } else {
+        0.0
+    };
+
+    *held >= seconds
+}
+
+#[allow(clippy::too_many_arguments)]
+#[legion::system]
+#[read_component(Side)]
+#[read_component(Building)]
+#[read_component(Unit)]
+#[read_component(Position)]
+#[read_component(FullyBuilt)]
+#[read_component(Tag)]
+pub fn update_playing_state(
+    #[resource] objectives: &mut Objectives,
+    #[resource] player_side: &PlayerSide,
+    #[resource] mode: &mut Mode,
+    #[resource] total_time: &TotalTime,
+    #[resource] delta_time: &DeltaTime,
+    #[resource] cheese_coins: &CheeseCoins,
+    #[resource] game_stats: &GameStats,
+    #[resource] alliances: &Alliances,
+    world: &SubWorld,
+) {
+    let context = ObjectivesContext {
+        world,
+        player_side: player_side.0,
+        alliances,
+        total_time: total_time.0,
+        delta_time: delta_time.0,
+        cheese_coins: cheese_coins.get(player_side.0),
+        game_stats,
+    };
+
+    // The progress of every condition is updated, even in replays, so that it can be displayed.
+    let mut won = !objectives.win_conditions.is_empty();
 
-    if lost {
+    for condition in &mut objectives.win_conditions {
+        won &= context.win_condition_met(condition);
+    }
+
+    let mut lost = false;
+
+    for condition in &mut objectives.lose_conditions {
+        lost |= context.lose_condition_met(condition);
+    }
+
+    // Replays just play out until the end of the recording.
+    if *mode != Mode::Playing {
+        return;
+    }
+
+    if won {
+        *mode = Mode::ScenarioWon;
+    } else if lost {
         *mode = Mode::ScenarioLost;
     }
 }
@@ -697,7 +902,6 @@ fn selection_and_deselection() {
     use crate::assets::ModelAnimations;
     use crate::pathfinding::Map;
     use crate::resources::*;
-    use rand::SeedableRng;
 
     let mut world = World::default();
     let mut resources = Resources::default();
@@ -711,25 +915,40 @@ fn selection_and_deselection() {
     resources.insert(screen_dimensions);
     resources.insert(RtsControls::default());
     resources.insert(PlayerSide(Side::Green));
+    resources.insert(Alliances::default());
+    resources.insert(Visibility::default());
+    resources.insert(DpiScaling(1.0));
     resources.insert(DeltaTime(1.0 / 60.0));
     resources.insert(RayCastLocation::default());
     resources.insert(ControlGroups::default());
     resources.insert(Map::new());
     resources.insert(Gravity(5.0));
     resources.insert(DebugControls::default());
-    resources.insert(rand::rngs::SmallRng::from_entropy());
+    resources.insert(CosmeticRng::new(0));
     resources.insert(ModelAnimations::default());
+    resources.insert(Keypresses::default());
+    resources.insert(CheeseCoins::default());
+    resources.insert(SelectedUnitsAbilities::default());
+    resources.insert(Upgrades::default());
+    resources.insert(Mode::Playing);
+    resources.insert(TotalTime(0.0));
+    resources.insert(GameStats::default());
+    resources.insert(PlayerCommands::default());
 
     let mut builder = Schedule::builder();
-    super::add_gameplay_systems(&mut builder);
+    super::add_control_systems(&mut builder);
+    super::add_cleanup_systems(&mut builder);
     let mut schedule = builder.build();
+    let mut command_buffer = CommandBuffer::new(&world);
     let entity = Unit::MouseMarine.add_to_world(
-        &mut world,
+        &mut command_buffer,
         None,
         Vec2::new(0.0, 0.0),
         Facing(0.0),
         Side::Green,
+        None,
     );
+    command_buffer.flush(&mut world);
     schedule.execute(&mut world, &mut resources);
 
     let mut query = <Option<&Selected>>::query();
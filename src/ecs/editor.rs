@@ -0,0 +1,372 @@
+use super::*;
+use crate::renderer::{Font, TextAlignment, TextBuffer, TorusBuffer, TorusInstance};
+use crate::resources::{
+    CheeseCoins, CommandMode, DpiScaling, Editor, EditorTool, Keypress, Keypresses, LoseCondition,
+    Mode, Objectives, RayCastLocation, WinCondition,
+};
+use ultraviolet::Vec4;
+
+// Guysers don't have a `Radius`, so this is used for clicking on them instead.
+const GUYSER_RADIUS: f32 = 2.0;
+// Whether a position is on the map. The map is 200 by 200 units, centered at 0.
+const MAP_EDGE: f32 = 100.0;
+// How much page up and page down change the starting coins by.
+const COINS_STEP: u32 = 50;
+// The most that the `BuildN` objectives can be cycled up to.
+const MAX_BUILD_OBJECTIVE: u8 = 5;
+
+#[allow(clippy::too_many_arguments)]
+#[legion::system]
+#[read_component(Unit)]
+#[read_component(Side)]
+#[read_component(Position)]
+#[read_component(Facing)]
+#[read_component(Building)]
+#[read_component(FullyBuilt)]
+#[read_component(CheeseGuyser)]
+#[read_component(CheeseGuyserBuiltOn)]
+pub fn handle_editor_keypresses(
+    #[resource] keypresses: &mut Keypresses,
+    #[resource] camera_controls: &mut CameraControls,
+    #[resource] rts_controls: &mut RtsControls,
+    #[resource] editor: &mut Editor,
+    #[resource] cheese_coins: &mut CheeseCoins,
+    #[resource] player_side: &PlayerSide,
+    #[resource] objectives: &mut Objectives,
+    #[resource] camera: &Camera,
+    #[resource] mode: &mut Mode,
+    world: &SubWorld,
+) {
+    for Keypress { code, pressed, .. } in keypresses.0.drain(..) {
+        let code = match code {
+            Some(code) => code,
+            None => continue,
+        };
+
+        match code {
+            VirtualKeyCode::Up => camera_controls.up = pressed,
+            VirtualKeyCode::Down => camera_controls.down = pressed,
+            VirtualKeyCode::Left => camera_controls.left = pressed,
+            VirtualKeyCode::Right => camera_controls.right = pressed,
+            VirtualKeyCode::LControl => rts_controls.control_held = pressed,
+            VirtualKeyCode::S if pressed && rts_controls.control_held => {
+                let mut scenario = editor.scenario.clone();
+                scenario.camera = camera.clone();
+                scenario.cheese_coins = cheese_coins.get(player_side.0);
+                scenario.objectives = objectives.clone();
+                scenario.update_from_world(world);
+
+                editor.status = match scenario.save(&editor.name) {
+                    Ok(()) => format!(
+                        "Saved to {}",
+                        crate::scenarios::path(&editor.name).display()
+                    ),
+                    Err(error) => format!("Failed to save: {:?}", error),
+                };
+            }
+            VirtualKeyCode::G if pressed => editor.tool = EditorTool::Guyser,
+            VirtualKeyCode::M if pressed => editor.tool = EditorTool::Unit(Unit::MouseMarine),
+            VirtualKeyCode::E if pressed => editor.tool = EditorTool::Unit(Unit::Engineer),
+            VirtualKeyCode::Y if pressed => editor.tool = EditorTool::Unit(Unit::Psychic),
+            VirtualKeyCode::H if pressed => editor.tool = EditorTool::Unit(Unit::Artillery),
+            VirtualKeyCode::P if pressed => editor.tool = EditorTool::Building(Building::Pump),
+            VirtualKeyCode::A if pressed => editor.tool = EditorTool::Building(Building::Armoury),
+            VirtualKeyCode::L if pressed => editor.tool = EditorTool::Building(Building::Larder),
+            VirtualKeyCode::U if pressed => editor.tool = EditorTool::Building(Building::Turret),
+            VirtualKeyCode::Tab if pressed => editor.side = editor.side.next(),
+            // Every side starts with the same number of coins.
+            VirtualKeyCode::PageUp if pressed => {
+                *cheese_coins = CheeseCoins::new(cheese_coins.get(player_side.0) + COINS_STEP)
+            }
+            VirtualKeyCode::PageDown if pressed => {
+                let coins = cheese_coins.get(player_side.0).saturating_sub(COINS_STEP);
+                *cheese_coins = CheeseCoins::new(coins)
+            }
+            VirtualKeyCode::F1 if pressed => {
+                toggle(&mut objectives.win_conditions, WinCondition::DestroyAll)
+            }
+            VirtualKeyCode::F2 if pressed => toggle(
+                &mut objectives.lose_conditions,
+                LoseCondition::LetAllUnitsDie,
+            ),
+            VirtualKeyCode::F3 if pressed => cycle_build_objective(objectives, Building::Pump),
+            VirtualKeyCode::F4 if pressed => cycle_build_objective(objectives, Building::Armoury),
+            VirtualKeyCode::F5 if pressed => cycle_build_objective(objectives, Building::Larder),
+            VirtualKeyCode::F6 if pressed => cycle_build_objective(objectives, Building::Turret),
+            VirtualKeyCode::Escape if pressed => {
+                rts_controls.mode = CommandMode::Normal;
+                *mode = Mode::Titlescreen;
+                return;
+            }
+            _ => {}
+        }
+    }
+
+    // Construct mode is what makes `cast_ray` snap pumps to guysers and `render_building_plan`
+    // show where the building will go.
+    rts_controls.mode = match editor.tool {
+        EditorTool::Building(building) => CommandMode::Construct { building },
+        _ => CommandMode::Normal,
+    };
+}
+
+fn toggle<T: PartialEq>(conditions: &mut Vec<T>, condition: T) {
+    match conditions
+        .iter()
+        .position(|existing| *existing == condition)
+    {
+        Some(index) => {
+            conditions.remove(index);
+        }
+        None => conditions.push(condition),
+    }
+}
+
+// Goes from no objective, to building 1, 2, and so on up to `MAX_BUILD_OBJECTIVE` before going
+// back to no objective.
+fn cycle_build_objective(objectives: &mut Objectives, building: Building) {
+    let index = objectives
+        .win_conditions
+        .iter()
+        .position(|condition| matches!(condition, WinCondition::BuildN(_, b, _) if *b == building));
+
+    match index {
+        Some(index) => match objectives.win_conditions[index] {
+            WinCondition::BuildN(ref mut n, ..) if *n < MAX_BUILD_OBJECTIVE => *n += 1,
+            _ => {
+                objectives.win_conditions.remove(index);
+            }
+        },
+        None => objectives
+            .win_conditions
+            .push(WinCondition::BuildN(1, building, 0)),
+    }
+}
+
+#[legion::system]
+#[read_component(Entity)]
+#[read_component(Position)]
+#[read_component(Radius)]
+#[read_component(Building)]
+#[read_component(MapHandle)]
+#[read_component(CheeseGuyser)]
+#[read_component(CheeseGuyserBuiltOn)]
+#[read_component(Unit)]
+pub fn handle_editor_clicks(
+    #[resource] mouse_state: &MouseState,
+    #[resource] ray_cast_location: &RayCastLocation,
+    #[resource] editor: &mut Editor,
+    #[resource] map: &mut Map,
+    #[resource] animations: &ModelAnimations,
+    world: &SubWorld,
+    commands: &mut CommandBuffer,
+) {
+    let position = ray_cast_location.pos;
+
+    if mouse_state.right_state.was_clicked() {
+        if let Some(entity) = entity_under_cursor(position, world) {
+            delete(entity, world, commands, map);
+        }
+    }
+
+    if !mouse_state.left_state.was_clicked() {
+        return;
+    }
+
+    editor.status.clear();
+
+    if position.x.abs() > MAP_EDGE || position.y.abs() > MAP_EDGE {
+        editor.status = "That's off the map".into();
+        return;
+    }
+
+    match editor.tool {
+        EditorTool::Guyser => {
+            if guyser_near(position, GUYSER_RADIUS * 2.0, world).is_some() {
+                editor.status = "There's already a guyser there".into();
+            } else {
+                commands.push((Position(position), CheeseGuyser, Cooldown(0.0)));
+            }
+        }
+        EditorTool::Unit(unit) => {
+            if building_at(position, world) {
+                editor.status = "Units can't be placed inside buildings".into();
+            } else {
+                unit.add_to_world(
+                    commands,
+                    Some(animations),
+                    position,
+                    Facing(0.0),
+                    editor.side,
+                    None,
+                );
+            }
+        }
+        EditorTool::Building(building) => {
+            let guyser = ray_cast_location.snapped_to_guyser;
+            let stats = building.stats();
+
+            if building == Building::Pump && guyser.is_none() {
+                editor.status = "Pumps have to be placed on a free guyser".into();
+                return;
+            }
+
+            if unit_under_building(position, stats.dimensions, world) {
+                editor.status = "There's a unit in the way".into();
+                return;
+            }
+
+            // This is where the placement is checked against the other buildings.
+            let entity = match building.add_to_world_to_construct(
+                commands,
+                position,
+                editor.side,
+                animations,
+                map,
+            ) {
+                Some(entity) => entity,
+                None => {
+                    editor.status = "There's a building in the way".into();
+                    return;
+                }
+            };
+
+            commands.add_component(entity, FullyBuilt);
+            commands.add_component(entity, Health(stats.max_health));
+            commands.add_component(entity, BuildingCompleteness(stats.max_health));
+
+            if let Some(guyser) = guyser {
+                commands.add_component(guyser, CheeseGuyserBuiltOn { pump: entity });
+            }
+        }
+    }
+}
+
+fn entity_under_cursor(position: Vec2, world: &SubWorld) -> Option<Entity> {
+    <(Entity, &Position, &Radius)>::query()
+        .filter(component::<Selectable>())
+        .iter(world)
+        .find(|(_, pos, radius)| (position - pos.0).mag_sq() < radius.0.powi(2))
+        .map(|(entity, ..)| *entity)
+        .or_else(|| guyser_near(position, GUYSER_RADIUS, world))
+}
+
+fn guyser_near(position: Vec2, distance: f32, world: &SubWorld) -> Option<Entity> {
+    <(Entity, &Position)>::query()
+        .filter(component::<CheeseGuyser>())
+        .iter(world)
+        .find(|(_, pos)| (position - pos.0).mag_sq() < distance.powi(2))
+        .map(|(entity, _)| *entity)
+}
+
+fn building_at(position: Vec2, world: &SubWorld) -> bool {
+    <(&Position, &Building)>::query()
+        .iter(world)
+        .any(|(pos, building)| {
+            let offset = position - pos.0;
+            let half_dimensions = building.stats().dimensions / 2.0;
+            offset.x.abs() < half_dimensions.x && offset.y.abs() < half_dimensions.y
+        })
+}
+
+// Deleting a pump frees up the guyser under it, and deleting a guyser deletes the pump on it.
+fn delete(entity: Entity, world: &SubWorld, commands: &mut CommandBuffer, map: &mut Map) {
+    let pump = <&CheeseGuyserBuiltOn>::query()
+        .get(world, entity)
+        .ok()
+        .map(|built_on| built_on.pump);
+
+    if let Some(pump) = pump {
+        delete_building(pump, world, commands, map);
+    }
+
+    for (guyser, built_on) in <(Entity, &CheeseGuyserBuiltOn)>::query().iter(world) {
+        if built_on.pump == entity {
+            commands.remove_component::<CheeseGuyserBuiltOn>(*guyser);
+        }
+    }
+
+    delete_building(entity, world, commands, map);
+}
+
+// Removes the entity, and takes it off the map if it's a building.
+fn delete_building(entity: Entity, world: &SubWorld, commands: &mut CommandBuffer, map: &mut Map) {
+    if let Ok(handle) = <&MapHandle>::query().get(world, entity) {
+        map.remove(handle);
+    }
+
+    commands.remove(entity);
+}
+
+#[legion::system]
+#[read_component(Position)]
+#[read_component(CheeseGuyser)]
+pub fn render_editor_ui(
+    #[resource] editor: &Editor,
+    #[resource] dpi_scaling: &DpiScaling,
+    #[resource] text_buffer: &mut TextBuffer,
+    #[resource] torus_buffer: &mut TorusBuffer,
+    #[resource] screen_dimensions: &ScreenDimensions,
+    world: &SubWorld,
+) {
+    let blue = Vec4::new(0.091, 0.118, 0.543, 1.0);
+    let cheese = Vec3::new(0.867, 0.675, 0.086);
+    let dpi = dpi_scaling.0;
+
+    // Guysers are only visible from the cheese that they spray out while playing.
+    for position in <&Position>::query()
+        .filter(component::<CheeseGuyser>())
+        .iter(world)
+    {
+        torus_buffer.toruses.push(TorusInstance {
+            center: Vec3::new(position.0.x, 0.0, position.0.y),
+            colour: cheese,
+            radius: GUYSER_RADIUS,
+        });
+    }
+
+    let tool = match editor.tool {
+        EditorTool::Guyser => "Guyser".to_string(),
+        EditorTool::Unit(unit) => format!("{:?}", unit),
+        EditorTool::Building(building) => format!("{:?}", building),
+    };
+
+    let text = format!(
+        "{} ({})\nPlacing: {} for {:?}\n\n\
+         G: Guyser, M: Mouse Marine, E: Engineer, Y: Psychic, H: Artillery\n\
+         P: Pump, A: Armoury, L: Larder, U: Turret\n\
+         Tab: Switch side, Right click: Delete\n\
+         Page Up/Down: Starting coins\n\
+         F1: Destroy all enemies, F2: Keep a unit alive\n\
+         F3/F4/F5/F6: Pumps/Armouries/Larders/Turrets to build\n\
+         Ctrl+S: Save (the camera starts where it is now)\n\n\
+         {}",
+        editor.scenario.title,
+        crate::scenarios::path(&editor.name).display(),
+        tool,
+        editor.side,
+        editor.status
+    );
+
+    let dims = screen_dimensions.as_vec();
+
+    text_buffer.render_text(
+        Vec2::new(10.0 * dpi, dims.y - 180.0 * dpi),
+        &text,
+        Font::Ui,
+        1.0,
+        dpi,
+        TextAlignment::Default,
+        blue,
+    );
+
+    text_buffer.render_text(
+        Vec2::new(dims.x / 2.0, dims.y - 32.0 * dpi),
+        "Press escape to stop editing",
+        Font::Ui,
+        1.0,
+        dpi,
+        TextAlignment::Center,
+        blue,
+    );
+}
@@ -3,7 +3,7 @@ use super::{
     Explosion, Position,
 };
 use crate::renderer::{ModelBuffers, ModelInstance};
-use crate::resources::{DeltaTime, Gravity};
+use crate::resources::{CosmeticRng, DeltaTime, Gravity};
 use legion::{component, systems::CommandBuffer, Entity};
 use rand::Rng;
 use ultraviolet::{Mat4, Rotor3, Vec3, Vec4};
@@ -35,7 +35,7 @@ pub fn move_cheese_droplets(
 #[filter(component::<CheeseGuyser>() & !component::<CheeseGuyserBuiltOn>())]
 pub fn spawn_cheese_droplets(
     position: &Position,
-    #[resource] rng: &mut rand::rngs::SmallRng,
+    #[resource] rng: &mut CosmeticRng,
     buffer: &mut CommandBuffer,
     cooldown: &mut Cooldown,
 ) {
@@ -44,7 +44,7 @@ pub fn spawn_cheese_droplets(
     }
 
     for _ in 0..3 {
-        let rotation = rng.gen_range(0.0, std::f32::consts::TAU);
+        let rotation = rng.0.gen_range(0.0, std::f32::consts::TAU);
         let velocity = Vec3::new(rotation.cos() * 0.75, 10.0, rotation.sin() * 0.75);
         buffer.push((
             CheeseDropletPosition(Vec3::new(position.0.x, 0.0, position.0.y)),
@@ -0,0 +1,238 @@
+use super::rendering::{side_colour, COLOUR_MAX};
+use super::*;
+use crate::renderer::LineBuffers;
+use crate::resources::{
+    Alliances, CommandMode, DpiScaling, Mode, MouseButtonState, RtsControls, Visibility,
+};
+use ultraviolet::Vec4;
+
+// Everything on the map is within this distance of the middle.
+const MAP_EXTENT: f32 = 100.0;
+// The size of the minimap and its distance from the corner of the screen, before dpi scaling.
+const SIZE: f32 = 200.0;
+const MARGIN: f32 = 10.0;
+const BORDER: f32 = 2.0;
+const UNIT_SIZE: f32 = 3.0;
+const BACKGROUND: Vec4 = Vec4::new(0.05, 0.08, 0.05, 1.0);
+const GUYSER: Vec4 = Vec4::new(0.95, 0.8, 0.2, 1.0);
+const CAMERA: Vec4 = Vec4::new(1.0, 1.0, 1.0, 1.0);
+
+/// The minimap in the bottom-left corner of the screen, which shows the whole map from above.
+pub struct Minimap {
+    top_left: Vec2,
+    size: f32,
+}
+
+impl Minimap {
+    /// The minimap is only shown while playing or watching a replay.
+    pub fn new(
+        mode: &Mode,
+        screen_dimensions: &ScreenDimensions,
+        dpi_scaling: f32,
+    ) -> Option<Self> {
+        if !matches!(mode, Mode::Playing | Mode::Replay) {
+            return None;
+        }
+
+        let size = SIZE * dpi_scaling;
+        let margin = MARGIN * dpi_scaling;
+
+        Some(Self {
+            top_left: Vec2::new(margin, screen_dimensions.height as f32 - margin - size),
+            size,
+        })
+    }
+
+    fn center(&self) -> Vec2 {
+        self.top_left + Vec2::broadcast(self.size / 2.0)
+    }
+
+    fn to_screen(&self, position: Vec2) -> Vec2 {
+        let position = position.clamped(Vec2::broadcast(-MAP_EXTENT), Vec2::broadcast(MAP_EXTENT));
+        self.top_left + (position + Vec2::broadcast(MAP_EXTENT)) / (MAP_EXTENT * 2.0) * self.size
+    }
+
+    /// The position on the map under a point on the screen, if it's over the minimap.
+    pub fn to_world(&self, point: Vec2) -> Option<Vec2> {
+        let bottom_right = self.top_left + Vec2::broadcast(self.size);
+
+        if !crate::titlescreen::point_in_area(point, self.top_left, bottom_right) {
+            return None;
+        }
+
+        Some((point - self.top_left) / self.size * MAP_EXTENT * 2.0 - Vec2::broadcast(MAP_EXTENT))
+    }
+}
+
+// The position on the map under a point on the screen, if the minimap is shown and it's over it.
+pub fn position_on_minimap(
+    mode: &Mode,
+    screen_dimensions: &ScreenDimensions,
+    dpi_scaling: &DpiScaling,
+    point: Vec2,
+) -> Option<Vec2> {
+    Minimap::new(mode, screen_dimensions, dpi_scaling.0).and_then(|minimap| minimap.to_world(point))
+}
+
+/// Clicking or dragging on the minimap moves the camera there. Right-clicks are handled as
+/// commands by `cast_ray` pointing at the map position under the minimap.
+#[legion::system]
+pub fn control_camera_with_minimap(
+    #[resource] mouse_state: &MouseState,
+    #[resource] rts_controls: &RtsControls,
+    #[resource] screen_dimensions: &ScreenDimensions,
+    #[resource] dpi_scaling: &DpiScaling,
+    #[resource] mode: &Mode,
+    #[resource] camera: &mut Camera,
+) {
+    // In other modes, clicking on the minimap targets whatever is there instead.
+    if rts_controls.mode != CommandMode::Normal {
+        return;
+    }
+
+    let minimap = match Minimap::new(mode, screen_dimensions, dpi_scaling.0) {
+        Some(minimap) => minimap,
+        None => return,
+    };
+
+    let start = match mouse_state.left_state {
+        MouseButtonState::Down(_, start) | MouseButtonState::Dragging(start) => start,
+        _ => return,
+    };
+
+    // Dragging off the side of the minimap keeps the camera at the edge of the map.
+    if minimap.to_world(start).is_some() {
+        let position = mouse_state.position.clamped(
+            minimap.top_left,
+            minimap.top_left + Vec2::broadcast(minimap.size),
+        );
+
+        if let Some(position) = minimap.to_world(position) {
+            camera.looking_at = position;
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[legion::system]
+#[read_component(Position)]
+#[read_component(Side)]
+#[read_component(Unit)]
+#[read_component(Building)]
+#[read_component(CheeseGuyser)]
+pub fn render_minimap(
+    #[resource] camera: &Camera,
+    #[resource] screen_dimensions: &ScreenDimensions,
+    #[resource] dpi_scaling: &DpiScaling,
+    #[resource] mode: &Mode,
+    #[resource] player_side: &PlayerSide,
+    #[resource] alliances: &Alliances,
+    #[resource] visibility: &Visibility,
+    #[resource] line_buffers: &mut LineBuffers,
+    world: &SubWorld,
+) {
+    let minimap = match Minimap::new(mode, screen_dimensions, dpi_scaling.0) {
+        Some(minimap) => minimap,
+        None => return,
+    };
+
+    let dpi = dpi_scaling.0;
+    // Sizes on the map in terms of unscaled pixels on the minimap.
+    let scale = SIZE / (MAP_EXTENT * 2.0);
+
+    line_buffers.draw_filled_rect(
+        minimap.center(),
+        Vec2::broadcast(SIZE + BORDER * 2.0),
+        Vec4::new(0.0, 0.0, 0.0, 1.0),
+        dpi,
+    );
+
+    line_buffers.draw_filled_rect(minimap.center(), Vec2::broadcast(SIZE), BACKGROUND, dpi);
+
+    <&Position>::query()
+        .filter(component::<CheeseGuyser>())
+        .for_each(world, |position| {
+            line_buffers.draw_filled_rect(
+                minimap.to_screen(position.0),
+                Vec2::broadcast(UNIT_SIZE + 1.0),
+                GUYSER,
+                dpi,
+            );
+        });
+
+    let colour = |side: Side| {
+        let colour = side_colour(side) / COLOUR_MAX;
+        Vec4::new(colour.x, colour.y, colour.z, 1.0)
+    };
+
+    visibility
+        .ghosts_seen_by(player_side.0)
+        .filter(|ghost| !visibility.is_visible(player_side.0, ghost.position))
+        .for_each(|ghost| {
+            line_buffers.draw_filled_rect(
+                minimap.to_screen(ghost.position),
+                ghost.building.stats().dimensions * scale,
+                colour(ghost.side),
+                dpi,
+            );
+        });
+
+    <(&Position, &Side, Option<&Unit>, Option<&Building>)>::query()
+        .iter(world)
+        .filter(|(position, side, ..)| {
+            visibility.can_see(player_side.0, **side, position.0, alliances)
+        })
+        .for_each(|(position, side, unit, building)| {
+            let dimensions = match (unit, building) {
+                (Some(_), _) => Vec2::broadcast(UNIT_SIZE),
+                (_, Some(building)) => building.stats().dimensions * scale,
+                _ => return,
+            };
+
+            line_buffers.draw_filled_rect(
+                minimap.to_screen(position.0),
+                dimensions,
+                colour(*side),
+                dpi,
+            );
+        });
+
+    // The area that the camera can see, worked out by casting rays from the corners of the screen.
+    let view = SelectBox::new(
+        camera,
+        screen_dimensions,
+        Vec2::zero(),
+        screen_dimensions.as_vec(),
+    );
+
+    line_buffers.draw_quad(
+        [
+            minimap.to_screen(view.top_left),
+            minimap.to_screen(view.top_right),
+            minimap.to_screen(view.bottom_right),
+            minimap.to_screen(view.bottom_left),
+        ],
+        CAMERA,
+        dpi,
+    );
+}
+
+#[test]
+fn minimap_positions() {
+    let screen_dimensions = ScreenDimensions {
+        width: 1920,
+        height: 1080,
+    };
+
+    assert!(Minimap::new(&Mode::Editor, &screen_dimensions, 1.0).is_none());
+
+    let minimap = Minimap::new(&Mode::Playing, &screen_dimensions, 1.0).unwrap();
+
+    let position = Vec2::new(-40.0, 25.0);
+    let on_screen = minimap.to_screen(position);
+    assert!((minimap.to_world(on_screen).unwrap() - position).mag() < 0.001);
+
+    // The top of the minimap is the far side of the map from the camera.
+    assert!(minimap.to_screen(Vec2::new(0.0, -100.0)).y < minimap.center().y);
+    assert_eq!(minimap.to_world(Vec2::new(960.0, 540.0)), None);
+}
@@ -1,15 +1,18 @@
 use super::*;
 use crate::pathfinding::Map;
-use crate::resources::DeltaTime;
+use crate::resources::{DeltaTime, Gravity};
 
 // Units try to get this much closer to enemies than their firing range.
 const FIRING_RANGE_FUDGE_FACTOR: f32 = 0.05;
+// Casters following a unit only find a new path once it's this far from the end of their path.
+const CAST_TARGET_MOVED_DISTANCE: f32 = 1.0;
 
 #[legion::system]
 pub fn reset_map_updated(#[resource] map: &mut Map) {
     map.updated_this_tick = false;
 }
 
+#[allow(clippy::too_many_arguments)]
 #[legion::system(for_each)]
 #[filter(component::<Position>())]
 #[read_component(Position)]
@@ -18,6 +21,7 @@ pub fn set_movement_paths(
     entity: &Entity,
     radius: &Radius,
     firing_range: Option<&FiringRange>,
+    min_firing_range: Option<&MinFiringRange>,
     command_queue: &mut CommandQueue,
     mut movement_debugging: Option<&mut MovementDebugging>,
     world: &SubWorld,
@@ -71,9 +75,9 @@ pub fn set_movement_paths(
         }
         Some(&mut Command::Attack {
             target,
+            explicit,
             ref mut state,
             ref mut first_out_of_range,
-            ..
         }) => {
             let firing_range = firing_range.expect(
                 "It shouldn't be possible to issue attack commands to units that can't attack",
@@ -88,7 +92,37 @@ pub fn set_movement_paths(
             let out_of_range =
                 vector.mag_sq() > (firing_range.0 - FIRING_RANGE_FUDGE_FACTOR).powi(2);
 
-            if out_of_range && *first_out_of_range {
+            let too_close = min_firing_range
+                .map(|min_firing_range| {
+                    vector.mag_sq() < (min_firing_range.0 + FIRING_RANGE_FUDGE_FACTOR).powi(2)
+                })
+                .unwrap_or(false);
+
+            if too_close && (*first_out_of_range || explicit) {
+                let needs_path = match state {
+                    ActionState::OutOfRange { path } => path.is_empty() || map.updated_this_tick,
+                    ActionState::InRange => true,
+                };
+
+                if needs_path {
+                    // Back off to the middle of the unit's range, away from the target.
+                    let min_firing_range = min_firing_range.map(|range| range.0).unwrap_or(0.0);
+                    let distance = (min_firing_range + firing_range.0) / 2.0;
+                    let direction = if vector.mag_sq() > 0.0 {
+                        -vector.normalized()
+                    } else {
+                        Vec2::unit_x()
+                    };
+                    let retreat_to = target_pos.0 + direction * distance;
+
+                    match map.pathfind(position.0, retreat_to, radius.0, None, None) {
+                        Some(path) => *state = ActionState::OutOfRange { path },
+                        None => pop_front = true,
+                    }
+                }
+            } else if too_close {
+                pop_front = true;
+            } else if out_of_range && *first_out_of_range {
                 let target_pos = if let Some(building) = building {
                     nearest_point_within_building(
                         position.0,
@@ -141,6 +175,52 @@ pub fn set_movement_paths(
                 *state = ActionState::InRange
             }
         }
+        Some(&mut Command::Cast {
+            spell,
+            target,
+            ref mut progress,
+            ref mut state,
+        }) => {
+            let target_pos = match target {
+                CastTarget::Nothing => None,
+                CastTarget::Point(point) => Some(point),
+                CastTarget::Entity(entity) => Some(
+                    <&Position>::query()
+                        .get(world, entity)
+                        .expect("We've cancelled actions on dead entities")
+                        .0,
+                ),
+            };
+
+            let range = spell.stats().range - FIRING_RANGE_FUDGE_FACTOR;
+
+            match target_pos {
+                Some(target_pos) if (target_pos - position.0).mag_sq() > range.powi(2) => {
+                    *progress = 0.0;
+
+                    // Units can walk away, so keep following them until they're in range.
+                    let needs_path = match state {
+                        ActionState::OutOfRange { path } => match path.last() {
+                            Some(end) => {
+                                map.updated_this_tick
+                                    || (target_pos - *end).mag_sq()
+                                        > CAST_TARGET_MOVED_DISTANCE.powi(2)
+                            }
+                            None => true,
+                        },
+                        ActionState::InRange => true,
+                    };
+
+                    if needs_path {
+                        match map.pathfind(position.0, target_pos, radius.0, None, None) {
+                            Some(path) => *state = ActionState::OutOfRange { path },
+                            None => pop_front = true,
+                        }
+                    }
+                }
+                _ => *state = ActionState::InRange,
+            }
+        }
         None => {}
     }
     if pop_front {
@@ -187,57 +267,63 @@ pub fn move_units(
     }
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Avoidance(pub Vec2);
+#[derive(Clone)]
 pub struct Avoids;
+#[derive(Clone)]
 pub struct Avoidable;
 
-#[legion::system]
+// Each unit's avoidance vector is written in place instead of adding and removing a component.
+// Adding components from multiple threads would happen in a different order each time, which
+// shuffles the order that entities are iterated in and stops the simulation from being
+// deterministic.
+#[legion::system(par_for_each)]
+#[filter(component::<Avoids>())]
 #[read_component(Position)]
 #[read_component(Radius)]
-pub fn avoidance(world: &SubWorld, command_buffer: &mut CommandBuffer) {
-    let command_buffer = std::sync::Mutex::new(command_buffer);
-
-    <(Entity, &Position, &Radius)>::query()
-        .filter(component::<Avoids>())
-        .par_for_each(world, |(entity, position, radius)| {
-            let mut avoidance_direction = Vec2::new(0.0, 0.0);
-            let mut count = 0;
-
-            for (other_position, other_radius) in <(&Position, &Radius)>::query()
-                .filter(component::<Avoidable>())
-                .iter(world)
-            {
-                let away_vector = position.0 - other_position.0;
-                let distance_sq = away_vector.mag_sq();
-                let desired_seperation = radius.0 + other_radius.0;
-
-                if distance_sq > 0.0 && distance_sq < desired_seperation.powi(2) {
-                    let distance = distance_sq.sqrt();
-
-                    avoidance_direction += away_vector.normalized() / distance;
-                    count += 1;
-                }
-            }
+pub fn avoidance(entity: &Entity, avoidance: &mut Avoidance, world: &SubWorld) {
+    let (position, radius) = <(&Position, &Radius)>::query()
+        .get(world, *entity)
+        .expect("Units that avoid have positions and radii");
 
-            if count > 0 {
-                avoidance_direction /= count as f32;
-                command_buffer
-                    .lock()
-                    .unwrap()
-                    .add_component(*entity, Avoidance(avoidance_direction));
-            }
-        })
+    let mut avoidance_direction = Vec2::new(0.0, 0.0);
+    let mut count = 0;
+
+    for (other_position, other_radius) in <(&Position, &Radius)>::query()
+        .filter(component::<Avoidable>())
+        .iter(world)
+    {
+        let away_vector = position.0 - other_position.0;
+        let distance_sq = away_vector.mag_sq();
+        let desired_seperation = radius.0 + other_radius.0;
+
+        if distance_sq > 0.0 && distance_sq < desired_seperation.powi(2) {
+            let distance = distance_sq.sqrt();
+
+            avoidance_direction += away_vector.normalized() / distance;
+            count += 1;
+        }
+    }
+
+    if count > 0 {
+        avoidance_direction /= count as f32;
+    }
+
+    avoidance.0 = avoidance_direction;
 }
 
 #[legion::system(for_each)]
 pub fn apply_steering(
-    entity: &Entity,
     position: &mut Position,
     avoidance: &Avoidance,
     #[resource] map: &Map,
-    command_buffer: &mut CommandBuffer,
     #[resource] delta_time: &DeltaTime,
 ) {
+    if avoidance.0 == Vec2::zero() {
+        return;
+    }
+
     let avoidance_movement_per_second = 6.0;
     let new_position = position.0 + avoidance.0 * avoidance_movement_per_second * delta_time.0;
 
@@ -247,7 +333,11 @@ pub fn apply_steering(
     }
 
     position.0 = new_position;
-    command_buffer.remove_component::<Avoidance>(*entity);
+}
+
+#[legion::system(for_each)]
+pub fn store_previous_positions(position: &Position, previous: &mut PreviousPosition) {
+    previous.0 = position.0;
 }
 
 #[legion::system(for_each)]
@@ -275,6 +365,18 @@ pub fn move_bullets(
     );
 }
 
+#[legion::system(for_each)]
+pub fn move_shells(
+    position: &mut Position,
+    shell: &mut Shell,
+    #[resource] gravity: &Gravity,
+    #[resource] delta_time: &DeltaTime,
+) {
+    shell.velocity.y -= gravity.0 * delta_time.0;
+    position.0 += Vec2::new(shell.velocity.x, shell.velocity.z) * delta_time.0;
+    shell.height += shell.velocity.y * delta_time.0;
+}
+
 fn move_towards(pos: &mut Vec2, facing: &mut f32, target: Vec2, speed: f32, delta_time: f32) {
     let direction = target - *pos;
     if direction.mag_sq() > 0.0 {
@@ -0,0 +1,439 @@
+use super::*;
+use crate::replay::{RecordedCommand, Replay};
+use crate::resources::{
+    Alliances, CheeseCoins, ControlGroups, GameStats, PlayerCommands, Tick, Upgrades,
+};
+use std::collections::HashMap;
+
+/// Something the player has told their units or buildings to do.
+///
+/// The control systems don't change the world directly. Instead they push these into
+/// `PlayerCommands` and they get applied at the start of the next tick, so that the same commands
+/// on the same ticks always play out the same way. This is what replays are made of.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PlayerCommand {
+    Move {
+        units: Vec<EntityId>,
+        target: Vec2,
+        queued: bool,
+    },
+    AttackMove {
+        units: Vec<EntityId>,
+        target: Vec2,
+        queued: bool,
+    },
+    Attack {
+        units: Vec<EntityId>,
+        target: EntityId,
+        queued: bool,
+    },
+    // Place a new building and have the units start building it.
+    PlaceBuilding {
+        units: Vec<EntityId>,
+        building: Building,
+        position: Vec2,
+        queued: bool,
+    },
+    // Have the units help build (or repair) an existing building.
+    Build {
+        units: Vec<EntityId>,
+        target: EntityId,
+        queued: bool,
+    },
+    // Only one of the units is needed to cast a spell with a target: whichever one is closest and
+    // can cast it. Spells without a target are cast by all of them.
+    Cast {
+        units: Vec<EntityId>,
+        spell: Spell,
+        target: PlayerCastTarget,
+        queued: bool,
+    },
+    Recruit {
+        building: EntityId,
+        unit: Unit,
+    },
+    Research {
+        building: EntityId,
+        upgrade: Upgrade,
+    },
+    SetWaypoint {
+        buildings: Vec<EntityId>,
+        position: Vec2,
+    },
+    Stop {
+        units: Vec<EntityId>,
+    },
+    SetControlGroup {
+        group: u8,
+        entities: Vec<EntityId>,
+    },
+    AddToControlGroup {
+        group: u8,
+        entities: Vec<EntityId>,
+    },
+}
+
+/// What a `PlayerCommand::Cast` is aimed at. The same as `CastTarget`, but with an `EntityId`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PlayerCastTarget {
+    Nothing,
+    Point(Vec2),
+    Entity(EntityId),
+}
+
+#[allow(clippy::too_many_arguments)]
+#[legion::system]
+#[read_component(Entity)]
+#[read_component(EntityId)]
+#[read_component(Position)]
+#[read_component(Side)]
+#[read_component(Unit)]
+#[read_component(Building)]
+#[read_component(Energy)]
+#[read_component(Abilities)]
+#[read_component(SpellCooldowns)]
+#[write_component(CommandQueue)]
+#[write_component(RecruitmentQueue)]
+pub fn apply_player_commands(
+    #[resource] player_commands: &mut PlayerCommands,
+    #[resource] replay: &mut Replay,
+    #[resource] tick: &Tick,
+    #[resource] player_side: &PlayerSide,
+    #[resource] alliances: &Alliances,
+    #[resource] map: &mut Map,
+    #[resource] animations: &ModelAnimations,
+    #[resource] cheese_coins: &mut CheeseCoins,
+    #[resource] upgrades: &Upgrades,
+    #[resource] game_stats: &mut GameStats,
+    #[resource] control_groups: &mut ControlGroups,
+    world: &mut SubWorld,
+    buffer: &mut CommandBuffer,
+) {
+    if player_commands.0.is_empty() {
+        return;
+    }
+
+    let entities: HashMap<EntityId, Entity> = <(Entity, &EntityId)>::query()
+        .iter(world)
+        .map(|(entity, id)| (*id, *entity))
+        .collect();
+
+    for command in player_commands.0.drain(..) {
+        replay.commands.push(RecordedCommand {
+            tick: tick.0,
+            command: command.clone(),
+        });
+
+        match command {
+            PlayerCommand::Move {
+                units,
+                target,
+                queued,
+            } => {
+                let command = Command::MoveTo {
+                    target,
+                    path: Vec::new(),
+                    attack_move: false,
+                };
+                order_units(&units, command, queued, &entities, player_side, world);
+            }
+            PlayerCommand::AttackMove {
+                units,
+                target,
+                queued,
+            } => {
+                let command = Command::MoveTo {
+                    target,
+                    path: Vec::new(),
+                    attack_move: true,
+                };
+                order_units(&units, command, queued, &entities, player_side, world);
+            }
+            PlayerCommand::Attack {
+                units,
+                target,
+                queued,
+            } => {
+                if let Some(target) = entities.get(&target) {
+                    let command = Command::new_attack(*target, true);
+                    order_units(&units, command, queued, &entities, player_side, world);
+                }
+            }
+            PlayerCommand::PlaceBuilding {
+                units,
+                building,
+                position,
+                queued,
+            } => {
+                let stats = building.stats();
+
+                let guyser = match building {
+                    Building::Pump => match free_guyser_near(position, world) {
+                        Some(guyser) => Some(guyser),
+                        None => continue,
+                    },
+                    Building::Armoury | Building::Larder | Building::Turret => None,
+                };
+
+                let position = guyser.map(|(_, position)| position).unwrap_or(position);
+
+                if stats.cost > cheese_coins.get(player_side.0)
+                    || unit_under_building(position, stats.dimensions, world)
+                {
+                    continue;
+                }
+
+                if let Some(building_entity) = building.add_to_world_to_construct(
+                    buffer,
+                    position,
+                    player_side.0,
+                    animations,
+                    map,
+                ) {
+                    cheese_coins.spend(player_side.0, stats.cost);
+
+                    if let Some((guyser_entity, _)) = guyser {
+                        buffer.add_component(
+                            guyser_entity,
+                            CheeseGuyserBuiltOn {
+                                pump: building_entity,
+                            },
+                        );
+                    }
+
+                    let command = Command::Build {
+                        target: building_entity,
+                        // Kinda hacky? If we put `ActionState::OutOfRange` with an empty vec it
+                        // wouldn't get updated with the current `set_movement_paths` code.
+                        state: ActionState::InRange,
+                    };
+                    order_units(&units, command, queued, &entities, player_side, world);
+                }
+            }
+            PlayerCommand::Build {
+                units,
+                target,
+                queued,
+            } => {
+                if let Some(target) = entities.get(&target) {
+                    let command = Command::Build {
+                        target: *target,
+                        state: ActionState::InRange,
+                    };
+                    order_units(&units, command, queued, &entities, player_side, world);
+                }
+            }
+            PlayerCommand::Cast {
+                units,
+                spell,
+                target,
+                queued,
+            } => {
+                let target_type = spell.stats().target;
+
+                let target = match (target_type, target) {
+                    (TargetType::NoTarget, PlayerCastTarget::Nothing) => CastTarget::Nothing,
+                    (TargetType::Point, PlayerCastTarget::Point(point)) => CastTarget::Point(point),
+                    (TargetType::EnemyUnit, PlayerCastTarget::Entity(id)) => {
+                        let is_enemy_unit = entities.get(&id).and_then(|entity| {
+                            <&Side>::query()
+                                .filter(component::<Unit>())
+                                .get(world, *entity)
+                                .ok()
+                                .filter(|side| alliances.are_enemies(**side, player_side.0))
+                                .map(|_| *entity)
+                        });
+
+                        match is_enemy_unit {
+                            Some(entity) => CastTarget::Entity(entity),
+                            None => continue,
+                        }
+                    }
+                    _ => continue,
+                };
+
+                let target_position = match target {
+                    CastTarget::Nothing => None,
+                    CastTarget::Point(point) => Some(point),
+                    CastTarget::Entity(entity) => <&Position>::query()
+                        .get(world, entity)
+                        .ok()
+                        .map(|pos| pos.0),
+                };
+
+                let casters: Vec<(EntityId, Vec2)> = units
+                    .iter()
+                    .filter_map(|id| {
+                        let entity = entities.get(id)?;
+                        let (position, side) =
+                            <(&Position, &Side)>::query().get(world, *entity).ok()?;
+
+                        if *side != player_side.0 || !can_cast(spell, *entity, world) {
+                            return None;
+                        }
+
+                        Some((*id, position.0))
+                    })
+                    .collect();
+
+                let command = Command::new_cast(spell, target);
+
+                match target_position {
+                    // Spells without a target are cast by every unit that can.
+                    None => {
+                        let casters: Vec<EntityId> = casters.iter().map(|(id, _)| *id).collect();
+                        order_units(&casters, command, queued, &entities, player_side, world);
+                    }
+                    // Otherwise only the closest unit needs to cast it.
+                    Some(target_position) => {
+                        let caster = casters
+                            .iter()
+                            .min_by_key(|(_, position)| {
+                                ordered_float::OrderedFloat((*position - target_position).mag_sq())
+                            })
+                            .map(|(id, _)| *id);
+
+                        if let Some(caster) = caster {
+                            order_units(&[caster], command, queued, &entities, player_side, world);
+                        }
+                    }
+                }
+            }
+            PlayerCommand::Recruit { building, unit } => {
+                let cost = unit.stats().cost;
+
+                if cost > cheese_coins.get(player_side.0) {
+                    continue;
+                }
+
+                let queue = entities.get(&building).and_then(|entity| {
+                    <(&mut RecruitmentQueue, &Side, &Building)>::query()
+                        .filter(component::<FullyBuilt>())
+                        .get_mut(world, *entity)
+                        .ok()
+                });
+
+                if let Some((queue, side, building)) = queue {
+                    if *side == player_side.0 && building.stats().recruits.contains(&unit) {
+                        cheese_coins.spend(player_side.0, cost);
+                        game_stats.units_recruited += 1;
+                        queue.queue.push_back(QueueItem::Unit(unit));
+                    }
+                }
+            }
+            PlayerCommand::Research { building, upgrade } => {
+                let cost = upgrade.stats().cost;
+
+                let building = entities.get(&building).and_then(|entity| {
+                    <(&Building, &Side)>::query()
+                        .filter(component::<FullyBuilt>() & component::<RecruitmentQueue>())
+                        .get(world, *entity)
+                        .ok()
+                        .filter(|(building, side)| {
+                            **side == player_side.0
+                                && building.stats().researches.contains(&upgrade)
+                        })
+                        .map(|_| *entity)
+                });
+
+                let building = match building {
+                    Some(building) => building,
+                    None => continue,
+                };
+
+                if cost > cheese_coins.get(player_side.0)
+                    || !can_research(upgrade, player_side.0, upgrades, world)
+                {
+                    continue;
+                }
+
+                if let Ok(queue) = <&mut RecruitmentQueue>::query().get_mut(world, building) {
+                    cheese_coins.spend(player_side.0, cost);
+                    queue.queue.push_back(QueueItem::Research(upgrade));
+                }
+            }
+            PlayerCommand::SetWaypoint {
+                buildings,
+                position,
+            } => {
+                for building in &buildings {
+                    let queue = entities.get(building).and_then(|entity| {
+                        <(&mut RecruitmentQueue, &Side)>::query()
+                            .get_mut(world, *entity)
+                            .ok()
+                    });
+
+                    if let Some((queue, side)) = queue {
+                        if *side == player_side.0 {
+                            queue.waypoint = position;
+                        }
+                    }
+                }
+            }
+            PlayerCommand::Stop { units } => {
+                for unit in &units {
+                    let queue = entities.get(unit).and_then(|entity| {
+                        <(&mut CommandQueue, &Side)>::query()
+                            .get_mut(world, *entity)
+                            .ok()
+                    });
+
+                    if let Some((queue, side)) = queue {
+                        if *side == player_side.0 {
+                            queue.0.clear();
+                        }
+                    }
+                }
+            }
+            PlayerCommand::SetControlGroup {
+                group,
+                entities: ids,
+            } => {
+                if let Some(control_group) = control_groups.0.get_mut(group as usize) {
+                    *control_group = ids
+                        .iter()
+                        .filter_map(|id| entities.get(id))
+                        .copied()
+                        .collect();
+                }
+            }
+            PlayerCommand::AddToControlGroup {
+                group,
+                entities: ids,
+            } => {
+                if let Some(control_group) = control_groups.0.get_mut(group as usize) {
+                    control_group.extend(ids.iter().filter_map(|id| entities.get(id)));
+                }
+            }
+        }
+    }
+}
+
+fn order_units(
+    units: &[EntityId],
+    command: Command,
+    queued: bool,
+    entities: &HashMap<EntityId, Entity>,
+    player_side: &PlayerSide,
+    world: &mut SubWorld,
+) {
+    for unit in units {
+        let queue = entities.get(unit).and_then(|entity| {
+            <(&mut CommandQueue, &Side)>::query()
+                .get_mut(world, *entity)
+                .ok()
+        });
+
+        if let Some((commands, side)) = queue {
+            if *side != player_side.0 {
+                continue;
+            }
+
+            if !queued {
+                commands.0.clear();
+            }
+
+            commands.0.push_back(command.clone());
+        }
+    }
+}
@@ -1,17 +1,23 @@
+use crate::ecs::Side;
 use crate::renderer::{Font, LineBuffers, TextAlignment, TextBuffer};
 use crate::resources::{
-    CursorIcon, DpiScaling, GameStats, Keypress, Keypresses, Mode, MouseState, ScreenDimensions,
+    AiDifficulty, Alliances, CursorIcon, DpiScaling, GameStats, Keypress, Keypresses,
+    MatchSettings, Mode, MouseState, PlayerSide, ScreenDimensions,
 };
 use crate::titlescreen::{point_in_area, selected_colour, text_selection_area, TEXT_COLOUR};
 use ultraviolet::Vec2;
 use winit::event::VirtualKeyCode;
 
-const WIN_LOSE_MENU: &'static [(&'static str, Vec2)] =
-    &[("Back to main menu", Vec2::new(0.5, 0.6))];
+const WIN_LOSE_MENU: &'static [(&'static str, Vec2)] = &[
+    ("Restart with this seed", Vec2::new(0.5, 0.55)),
+    ("Back to main menu", Vec2::new(0.5, 0.62)),
+];
 
 const PLAYING_MENU: &'static [(&'static str, Vec2)] = &[
-    ("Continue", Vec2::new(0.5, 0.6)),
-    ("Back to main menu", Vec2::new(0.5, 0.7)),
+    ("Continue", Vec2::new(0.5, 0.55)),
+    ("Save game", Vec2::new(0.5, 0.62)),
+    ("Load game", Vec2::new(0.5, 0.69)),
+    ("Back to main menu", Vec2::new(0.5, 0.76)),
 ];
 
 fn buttons(mode: &Mode) -> Option<&'static [(&'static str, Vec2)]> {
@@ -29,6 +35,8 @@ pub fn handle_playing_menu_controls(
     #[resource] mouse_state: &MouseState,
     #[resource] mode: &mut Mode,
     #[resource] keypresses: &mut Keypresses,
+    #[resource] match_settings: &MatchSettings,
+    #[resource] difficulty: &mut AiDifficulty,
 ) {
     // Allow double-pressing escape to toggle the menu.
     for Keypress { code, pressed, .. } in keypresses.0.drain(..) {
@@ -53,7 +61,16 @@ pub fn handle_playing_menu_controls(
             if point_in_area(mouse_state.position, top_left, bottom_right) {
                 match text {
                     "Continue" => *mode = Mode::Playing,
+                    "Save game" => *mode = Mode::SaveGame,
+                    "Load game" => *mode = Mode::LoadGame,
                     "Back to main menu" => *mode = Mode::Titlescreen,
+                    "Restart with this seed" => {
+                        *difficulty = match_settings.difficulty;
+                        *mode = Mode::StartScenario(
+                            match_settings.scenario.clone(),
+                            Some(match_settings.seed),
+                        )
+                    }
                     _ => {}
                 }
                 return;
@@ -62,6 +79,7 @@ pub fn handle_playing_menu_controls(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 #[legion::system]
 pub fn render_playing_menu(
     #[resource] mode: &Mode,
@@ -71,6 +89,9 @@ pub fn render_playing_menu(
     #[resource] mouse_state: &MouseState,
     #[resource] cursor_icon: &mut CursorIcon,
     #[resource] stats: &GameStats,
+    #[resource] match_settings: &MatchSettings,
+    #[resource] player_side: &PlayerSide,
+    #[resource] alliances: &Alliances,
 ) {
     let text = match mode {
         Mode::ScenarioWon => "Scenario Won",
@@ -118,6 +139,13 @@ pub fn render_playing_menu(
     }
 
     if matches!(mode, Mode::ScenarioWon | Mode::ScenarioLost) {
+        let best_enemy_income = Side::ALL
+            .iter()
+            .filter(|side| alliances.are_enemies(**side, player_side.0))
+            .map(|side| stats.coins_earned[side.index()])
+            .max()
+            .unwrap_or(0);
+
         let items = [
             format!("Units recruited: {}", stats.units_recruited),
             format!("Units lost: {}", stats.units_lost),
@@ -127,8 +155,15 @@ pub fn render_playing_menu(
                 "Enemy buildings destoyed: {}",
                 stats.enemy_buildings_destroyed
             ),
+            format!(
+                "Coins earned: {} (best enemy: {})",
+                stats.coins_earned[player_side.0.index()],
+                best_enemy_income
+            ),
+            format!("Seed: {}", match_settings.seed),
+            format!("AI difficulty: {}", match_settings.difficulty),
         ];
-        let mut y = 0.7;
+        let mut y = 0.68;
 
         for text in &items {
             text_buffer.render_text(
@@ -140,7 +175,7 @@ pub fn render_playing_menu(
                 TextAlignment::Center,
                 TEXT_COLOUR,
             );
-            y += 0.05;
+            y += 0.04;
         }
     }
 }
@@ -5,28 +5,70 @@ use crate::renderer::{
     TorusInstance,
 };
 use crate::resources::{
-    CheeseCoins, CommandMode, CursorIcon, DpiScaling, Mode, Objectives, RayCastLocation,
-    SelectedUnitsAbilities,
+    Alliances, CheeseCoins, CommandMode, CursorIcon, DpiScaling, Mode, Objectives, RayCastLocation,
+    ScenarioMessage, SelectedUnitsAbilities, Supply, TickInterpolation, TotalTime, Upgrades,
+    Visibility,
 };
-use ultraviolet::Vec4;
+use ultraviolet::{Rotor3, Vec4};
 
-const COLOUR_MAX: Vec3 = Vec3::new(255.0, 255.0, 255.0);
+pub(super) const COLOUR_MAX: Vec3 = Vec3::new(255.0, 255.0, 255.0);
 const GREEN: Vec3 = Vec3::new(43.0, 140.0, 0.0);
 const PURPLE: Vec3 = Vec3::new(196.0, 0.0, 109.0);
+const BLUE: Vec3 = Vec3::new(30.0, 90.0, 210.0);
+const ORANGE: Vec3 = Vec3::new(235.0, 120.0, 0.0);
+const TEAL: Vec3 = Vec3::new(0.0, 150.0, 150.0);
+const BROWN: Vec3 = Vec3::new(120.0, 72.0, 30.0);
+const PINK: Vec3 = Vec3::new(240.0, 110.0, 170.0);
+const GREY: Vec3 = Vec3::new(120.0, 120.0, 120.0);
+// Selected units that aren't the player's are circled in these, depending on the alliances.
+const ALLY_SELECTION: Vec3 = Vec3::new(230.0, 200.0, 40.0);
+const ENEMY_SELECTION: Vec3 = Vec3::new(220.0, 40.0, 40.0);
+const SPELL_RANGE: Vec3 = Vec3::new(190.0, 64.0, 255.0);
+const FIRING_RANGE: Vec3 = Vec3::new(220.0, 90.0, 40.0);
 const BLACK: Vec4 = Vec4::new(0.0, 0.0, 0.0, 1.0);
 const WHITE: Vec3 = Vec3::new(1.0, 1.0, 1.0);
 
+pub(super) fn side_colour(side: Side) -> Vec3 {
+    match side {
+        Side::Green => GREEN,
+        Side::Purple => PURPLE,
+        Side::Blue => BLUE,
+        Side::Orange => ORANGE,
+        Side::Teal => TEAL,
+        Side::Brown => BROWN,
+        Side::Pink => PINK,
+        Side::Grey => GREY,
+    }
+}
+
 fn mix(colour_a: Vec3, colour_b: Vec3, factor: f32) -> Vec3 {
     colour_a * (1.0 - factor) + colour_b * factor
 }
 
+// Gameplay runs at a fixed tick rate, so things that move are drawn partway between where they
+// were last tick and where they are now to keep them smooth at any framerate.
+fn interpolate(
+    position: &Position,
+    previous: Option<&PreviousPosition>,
+    interpolation: &TickInterpolation,
+) -> Vec2 {
+    match previous {
+        Some(previous) => previous.0 + (position.0 - previous.0) * interpolation.0,
+        None => position.0,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 #[legion::system]
 #[read_component(Position)]
 pub fn render_building_plan(
     #[resource] ray_cast_location: &RayCastLocation,
     #[resource] rts_controls: &RtsControls,
     #[resource] cheese_coins: &CheeseCoins,
+    #[resource] player_side: &PlayerSide,
     #[resource] model_buffers: &mut ModelBuffers,
+    #[resource] torus_buffer: &mut TorusBuffer,
+    #[resource] mode: &Mode,
     world: &SubWorld,
 ) {
     let allowed = Vec4::new(0.0, 1.0, 0.0, 0.25);
@@ -34,15 +76,17 @@ pub fn render_building_plan(
     let cant_afford = Vec4::new(1.0, 0.0, 0.0, 1.0 / 3.0);
 
     if let CommandMode::Construct { building } = rts_controls.mode {
-        let colour = if building.stats().cost > cheese_coins.0 {
-            cant_afford
-        } else if (building == Building::Pump && ray_cast_location.snapped_to_guyser.is_none())
-            || unit_under_building(ray_cast_location.pos, building.stats().dimensions, world)
-        {
-            not_allowed
-        } else {
-            allowed
-        };
+        // Buildings are free in the editor.
+        let colour =
+            if building.stats().cost > cheese_coins.get(player_side.0) && *mode != Mode::Editor {
+                cant_afford
+            } else if (building == Building::Pump && ray_cast_location.snapped_to_guyser.is_none())
+                || unit_under_building(ray_cast_location.pos, building.stats().dimensions, world)
+            {
+                not_allowed
+            } else {
+                allowed
+            };
 
         model_buffers.building_plan.set(
             building,
@@ -55,41 +99,71 @@ pub fn render_building_plan(
                 flat_colour: colour,
             },
         );
+
+        // Show how far the building will be able to fire once it's built.
+        if let Some(firing_range) = building.stats().firing_range {
+            torus_buffer.toruses.push(TorusInstance {
+                center: Vec3::new(ray_cast_location.pos.x, 0.0, ray_cast_location.pos.y),
+                colour: FIRING_RANGE / COLOUR_MAX,
+                radius: firing_range,
+            });
+        }
     } else {
         model_buffers.building_plan.clear();
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 #[legion::system(for_each)]
 pub fn render_units(
     position: &Position,
+    previous_position: Option<&PreviousPosition>,
     side: &Side,
     facing: &Facing,
     skin: &Skin,
     unit: &Unit,
+    #[resource] interpolation: &TickInterpolation,
     #[resource] model_buffers: &mut ModelBuffers,
+    #[resource] player_side: &PlayerSide,
+    #[resource] alliances: &Alliances,
+    #[resource] visibility: &Visibility,
 ) {
-    let translation = Mat4::from_translation(Vec3::new(position.0.x, 0.0, position.0.y));
+    if !visibility.can_see(player_side.0, *side, position.0, alliances) {
+        return;
+    }
+
+    let position = interpolate(position, previous_position, interpolation);
+    let translation = Mat4::from_translation(Vec3::new(position.x, 0.0, position.y));
     let rotation = Mat4::from_rotation_y(facing.0);
 
-    let (instance_buffer, joint_buffer) = match unit {
+    let (instance_buffer, joint_buffer, scale) = match unit {
         Unit::MouseMarine => (
             &mut model_buffers.mice_marines,
             &mut model_buffers.mice_marines_joints,
+            1.0,
         ),
         Unit::Engineer => (
             &mut model_buffers.mice_engineers,
             &mut model_buffers.mice_engineers_joints,
+            1.0,
+        ),
+        Unit::Psychic => (
+            &mut model_buffers.mice_psychics,
+            &mut model_buffers.mice_psychics_joints,
+            1.0,
+        ),
+        // Artillery mice are bigger mice with the same blasters.
+        Unit::Artillery => (
+            &mut model_buffers.mice_artillery,
+            &mut model_buffers.mice_artillery_joints,
+            1.25,
         ),
     };
 
     instance_buffer.push(ModelInstance {
-        transform: translation * rotation,
+        transform: translation * rotation * Mat4::from_scale(scale),
         flat_colour: {
-            let colour = match side {
-                Side::Green => GREEN,
-                Side::Purple => PURPLE,
-            } / COLOUR_MAX;
+            let colour = side_colour(*side) / COLOUR_MAX;
             let colour = mix(colour, WHITE, 0.25);
 
             Vec4::new(colour.x, colour.y, colour.z, 0.2)
@@ -100,24 +174,78 @@ pub fn render_units(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 #[legion::system(for_each)]
 #[filter(component::<Selected>())]
 pub fn render_selections(
     position: &Position,
+    previous_position: Option<&PreviousPosition>,
     side: &Side,
     radius: &Radius,
+    #[resource] interpolation: &TickInterpolation,
     #[resource] torus_buffer: &mut TorusBuffer,
+    #[resource] player_side: &PlayerSide,
+    #[resource] alliances: &Alliances,
+    #[resource] visibility: &Visibility,
 ) {
+    if !visibility.can_see(player_side.0, *side, position.0, alliances) {
+        return;
+    }
+
+    let colour = if *side == player_side.0 {
+        side_colour(*side)
+    } else if alliances.are_allies(*side, player_side.0) {
+        ALLY_SELECTION
+    } else {
+        ENEMY_SELECTION
+    };
+
+    let position = interpolate(position, previous_position, interpolation);
     torus_buffer.toruses.push(TorusInstance {
-        center: Vec3::new(position.0.x, 0.0, position.0.y),
-        colour: match side {
-            Side::Green => GREEN / COLOUR_MAX,
-            Side::Purple => PURPLE / COLOUR_MAX,
-        },
+        center: Vec3::new(position.x, 0.0, position.y),
+        colour: colour / COLOUR_MAX,
         radius: radius.0,
     });
 }
 
+// While the target of a spell is being picked, show how far away from each selected caster it can
+// be.
+#[allow(clippy::too_many_arguments)]
+#[legion::system(for_each)]
+#[filter(component::<Selected>())]
+pub fn render_spell_ranges(
+    position: &Position,
+    previous_position: Option<&PreviousPosition>,
+    side: &Side,
+    abilities: &Abilities,
+    #[resource] rts_controls: &RtsControls,
+    #[resource] interpolation: &TickInterpolation,
+    #[resource] torus_buffer: &mut TorusBuffer,
+    #[resource] player_side: &PlayerSide,
+) {
+    let spell = match rts_controls.mode {
+        CommandMode::CastAbility { spell } => spell,
+        _ => return,
+    };
+
+    let can_cast = abilities
+        .0
+        .iter()
+        .any(|ability| ability.ability_type == AbilityType::Cast(spell));
+
+    if *side != player_side.0 || !can_cast {
+        return;
+    }
+
+    let position = interpolate(position, previous_position, interpolation);
+    torus_buffer.toruses.push(TorusInstance {
+        center: Vec3::new(position.x, 0.0, position.y),
+        colour: SPELL_RANGE / COLOUR_MAX,
+        radius: spell.stats().range,
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
 #[legion::system]
 #[read_component(Position)]
 #[read_component(Radius)]
@@ -128,6 +256,7 @@ pub fn render_under_select_box(
     #[resource] screen_dimensions: &ScreenDimensions,
     #[resource] player_side: &PlayerSide,
     #[resource] torus_buffer: &mut TorusBuffer,
+    #[resource] dpi_scaling: &DpiScaling,
     #[resource] mode: &Mode,
     world: &SubWorld,
 ) {
@@ -136,6 +265,10 @@ pub fn render_under_select_box(
     }
 
     if let Some(start) = mouse_state.left_state.is_being_dragged() {
+        if position_on_minimap(mode, screen_dimensions, dpi_scaling, start).is_some() {
+            return;
+        }
+
         let select_box = SelectBox::new(camera, screen_dimensions, start, mouse_state.position);
 
         <(&Position, &Radius, &Side)>::query()
@@ -156,14 +289,26 @@ pub fn render_under_select_box(
 #[legion::system(for_each)]
 pub fn render_health_bars(
     position: &Position,
+    previous_position: Option<&PreviousPosition>,
     health: &Health,
     unit: Option<&Unit>,
     building: Option<&Building>,
+    side: Option<&Side>,
+    #[resource] interpolation: &TickInterpolation,
     #[resource] camera: &Camera,
     #[resource] screen_dimensions: &ScreenDimensions,
     #[resource] dpi_scaling: &DpiScaling,
     #[resource] line_buffers: &mut LineBuffers,
+    #[resource] player_side: &PlayerSide,
+    #[resource] alliances: &Alliances,
+    #[resource] visibility: &Visibility,
 ) {
+    if let Some(side) = side {
+        if !visibility.can_see(player_side.0, *side, position.0, alliances) {
+            return;
+        }
+    }
+
     let stats = {
         let unit_stats = unit.map(|unit| {
             let stats = unit.stats();
@@ -178,7 +323,8 @@ pub fn render_health_bars(
 
     if let Some((max_health, health_bar_height)) = stats {
         if health.0 != max_health {
-            let floating = Vec3::new(position.0.x, health_bar_height, position.0.y);
+            let position = interpolate(position, previous_position, interpolation);
+            let floating = Vec3::new(position.x, health_bar_height, position.y);
             let location = screen_location(floating, camera, screen_dimensions);
 
             let health_percentage = health.0 as f32 / max_health as f32;
@@ -201,6 +347,45 @@ pub fn render_health_bars(
     }
 }
 
+// Only the player's own units show their energy, underneath where their health bar goes.
+#[allow(clippy::too_many_arguments)]
+#[legion::system(for_each)]
+pub fn render_energy_bars(
+    position: &Position,
+    previous_position: Option<&PreviousPosition>,
+    energy: &Energy,
+    unit: &Unit,
+    side: &Side,
+    #[resource] interpolation: &TickInterpolation,
+    #[resource] camera: &Camera,
+    #[resource] screen_dimensions: &ScreenDimensions,
+    #[resource] dpi_scaling: &DpiScaling,
+    #[resource] line_buffers: &mut LineBuffers,
+    #[resource] player_side: &PlayerSide,
+) {
+    let stats = unit.stats();
+
+    if *side != player_side.0 || energy.0 >= stats.max_energy {
+        return;
+    }
+
+    let position = interpolate(position, previous_position, interpolation);
+    let floating = Vec3::new(position.x, stats.health_bar_height, position.y);
+    let location =
+        screen_location(floating, camera, screen_dimensions) + Vec2::new(0.0, 12.0) * dpi_scaling.0;
+
+    let length = 60.0 * energy.0 / stats.max_energy;
+
+    line_buffers.draw_filled_rect(location, Vec2::new(length + 2.0, 8.0), BLACK, dpi_scaling.0);
+
+    line_buffers.draw_filled_rect(
+        location,
+        Vec2::new(length, 6.0),
+        Vec4::new(0.4, 0.3, 1.0, 1.0),
+        dpi_scaling.0,
+    );
+}
+
 fn screen_location(position: Vec3, camera: &Camera, screen_dimensions: &ScreenDimensions) -> Vec2 {
     let &ScreenDimensions { width, height } = screen_dimensions;
     let view = camera.to_matrix();
@@ -214,6 +399,9 @@ fn wgpu_to_screen(wgpu: Vec2, width: f32, height: f32) -> Vec2 {
     Vec2::new((wgpu.x + 1.0) / 2.0 * width, (1.0 - wgpu.y) / 2.0 * height)
 }
 
+// How many seconds messages from triggers are shown for.
+const SCENARIO_MESSAGE_DURATION: f32 = 10.0;
+
 #[legion::system]
 #[read_component(RecruitmentQueue)]
 #[read_component(Side)]
@@ -222,18 +410,21 @@ fn wgpu_to_screen(wgpu: Vec2, width: f32, height: f32) -> Vec2 {
 pub fn render_ui(
     #[resource] dpi_scaling: &DpiScaling,
     #[resource] cheese_coins: &CheeseCoins,
+    #[resource] supply: &Supply,
     #[resource] text_buffer: &mut TextBuffer,
     #[resource] player_side: &PlayerSide,
     #[resource] objectives: &Objectives,
     #[resource] mode: &Mode,
     #[resource] screen_dimensions: &ScreenDimensions,
     #[resource] line_buffers: &mut LineBuffers,
+    #[resource] scenario_message: &ScenarioMessage,
+    #[resource] total_time: &TotalTime,
     world: &SubWorld,
 ) {
     let blue = Vec4::new(0.091, 0.118, 0.543, 1.0);
     let dpi = dpi_scaling.0;
 
-    if *mode != Mode::Playing {
+    if !matches!(mode, Mode::Playing | Mode::Editor) {
         return;
     }
 
@@ -269,7 +460,7 @@ pub fn render_ui(
 
     text_buffer.render_text(
         Vec2::new(dims.x - 32.0 * dpi, y_offset * dpi),
-        &format!("{}", cheese_coins.0),
+        &format!("{}", cheese_coins.get(player_side.0)),
         Font::Ui,
         1.0,
         dpi_scaling.0,
@@ -285,6 +476,36 @@ pub fn render_ui(
         dpi_scaling.0,
     );
 
+    // Supply is only counted while the game is running.
+    if let Mode::Playing = mode {
+        let (used, cap) = (supply.used(player_side.0), supply.cap(player_side.0));
+        let capped = Vec4::new(0.8, 0.1, 0.1, 1.0);
+
+        text_buffer.render_text(
+            Vec2::new(dims.x - 128.0 * dpi, y_offset * dpi),
+            &format!("Supply: {}/{}", used, cap),
+            Font::Ui,
+            1.0,
+            dpi_scaling.0,
+            TextAlignment::HorizontalRight,
+            if used >= cap { capped } else { blue },
+        );
+    }
+
+    if let Some((message, shown_at)) = &scenario_message.0 {
+        if total_time.0 - shown_at < SCENARIO_MESSAGE_DURATION {
+            text_buffer.render_text(
+                Vec2::new(dims.x / 2.0, 64.0 * dpi),
+                message,
+                Font::Ui,
+                1.0,
+                dpi_scaling.0,
+                TextAlignment::Center,
+                blue,
+            );
+        }
+    }
+
     // Recruitment queue rendering
 
     let max_queues_we_can_fit_on_a_1080p_monitor = 28;
@@ -363,7 +584,7 @@ pub fn render_ui(
             line_buffers.draw_image(
                 Vec2::new(dims.x - bar_offset - 16.0 * dpi, y),
                 Vec2::new(32.0, 32.0),
-                building.stats().image,
+                building.image(),
                 !built,
                 dpi_scaling.0,
             );
@@ -448,6 +669,17 @@ pub fn render_command_paths(
                     .0;
                 Some(position)
             }
+            Command::Cast { target, .. } => match target {
+                CastTarget::Nothing => None,
+                CastTarget::Point(point) => Some(*point),
+                CastTarget::Entity(target) => {
+                    let position = <&Position>::query()
+                        .get(world, *target)
+                        .expect("We've cancelled actions on dead entities")
+                        .0;
+                    Some(position)
+                }
+            },
             Command::Attack {
                 explicit: false, ..
             } => None,
@@ -456,6 +688,7 @@ pub fn render_command_paths(
         let move_colour = Vec4::new(0.25, 0.25, 1.0, 1.0);
         let attack_colour = Vec4::new(1.0, 0.0, 0.0, 1.0);
         let build_colour = Vec4::new(0.25, 1.0, 0.25, 1.0);
+        let cast_colour = Vec4::new(0.75, 0.25, 1.0, 1.0);
 
         let colour = match command {
             Command::MoveTo { attack_move, .. } => {
@@ -467,6 +700,7 @@ pub fn render_command_paths(
             }
             Command::Attack { .. } => attack_colour,
             Command::Build { .. } => build_colour,
+            Command::Cast { .. } => cast_colour,
         };
 
         if let Some(position) = position {
@@ -492,27 +726,50 @@ pub fn render_command_paths(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 #[legion::system(for_each)]
 pub fn render_buildings(
     position: &Position,
     building: &Building,
     building_completeness: &BuildingCompleteness,
+    side: &Side,
     skin: Option<&Skin>,
+    facing: Option<&Facing>,
     #[resource] model_buffers: &mut ModelBuffers,
+    #[resource] player_side: &PlayerSide,
+    #[resource] alliances: &Alliances,
+    #[resource] visibility: &Visibility,
 ) {
+    // Buildings in the fog of war are drawn as they were last seen by `render_building_ghosts`.
+    if !visibility.can_see(player_side.0, *side, position.0, alliances) {
+        return;
+    }
+
     let buffer = match building {
         Building::Armoury => &mut model_buffers.armouries,
         Building::Pump => &mut model_buffers.pumps,
+        Building::Larder => &mut model_buffers.larders,
+        Building::Turret => &mut model_buffers.turrets,
     };
 
-    let scale = (building_completeness.0 as f32 / building.stats().max_health as f32).max(0.01);
+    let completeness =
+        (building_completeness.0 as f32 / building.stats().max_health as f32).max(0.01);
+    let translation = Mat4::from_translation(Vec3::new(position.0.x, 0.0, position.0.y));
+    let scale = Mat4::from_nonuniform_scale(Vec3::new(1.0, completeness, 1.0));
 
     buffer.push(ModelInstance {
-        transform: Mat4::from_translation(Vec3::new(position.0.x, 0.0, position.0.y))
-            * Mat4::from_nonuniform_scale(Vec3::new(1.0, scale, 1.0)),
+        transform: translation * scale,
         flat_colour: Vec4::new(1.0, 1.0, 1.0, 1.0),
     });
 
+    // The head of a turret turns to face whatever it's firing at.
+    if let (Building::Turret, Some(facing)) = (building, facing) {
+        model_buffers.turret_heads.push(ModelInstance {
+            transform: translation * Mat4::from_rotation_y(facing.0) * scale,
+            flat_colour: Vec4::new(1.0, 1.0, 1.0, 1.0),
+        });
+    }
+
     if let Some(skin) = skin {
         for joint in &skin.joints {
             model_buffers.pump_joints.push(joint.matrix);
@@ -520,9 +777,51 @@ pub fn render_buildings(
     }
 }
 
+#[legion::system]
+pub fn render_building_ghosts(
+    #[resource] model_buffers: &mut ModelBuffers,
+    #[resource] animations: &ModelAnimations,
+    #[resource] player_side: &PlayerSide,
+    #[resource] visibility: &Visibility,
+) {
+    // The buildings that can be seen are drawn by `render_buildings`.
+    let ghosts = visibility
+        .ghosts_seen_by(player_side.0)
+        .filter(|ghost| !visibility.is_visible(player_side.0, ghost.position));
+
+    for ghost in ghosts {
+        let buffer = match ghost.building {
+            Building::Armoury => &mut model_buffers.armouries,
+            Building::Pump => &mut model_buffers.pumps,
+            Building::Larder => &mut model_buffers.larders,
+            Building::Turret => &mut model_buffers.turrets,
+        };
+
+        let instance = ModelInstance {
+            transform: Mat4::from_translation(Vec3::new(ghost.position.x, 0.0, ghost.position.y)),
+            flat_colour: Vec4::new(1.0, 1.0, 1.0, 1.0),
+        };
+
+        buffer.push(instance);
+
+        // Which way the head was pointing isn't remembered.
+        if ghost.building == Building::Turret {
+            model_buffers.turret_heads.push(instance);
+        }
+
+        // Pumps are animated, so they need a pose even when they're frozen in time.
+        if ghost.building == Building::Pump {
+            for joint in &animations.pump.skin.joints {
+                model_buffers.pump_joints.push(joint.matrix);
+            }
+        }
+    }
+}
+
 #[legion::system]
 pub fn render_drag_box(
     #[resource] mouse_state: &MouseState,
+    #[resource] screen_dimensions: &ScreenDimensions,
     #[resource] dpi_scaling: &DpiScaling,
     #[resource] mode: &Mode,
     #[resource] line_buffers: &mut LineBuffers,
@@ -532,6 +831,10 @@ pub fn render_drag_box(
     }
 
     if let Some(start) = mouse_state.left_state.is_being_dragged() {
+        if position_on_minimap(mode, screen_dimensions, dpi_scaling, start).is_some() {
+            return;
+        }
+
         let (top_left, bottom_right) = sort_points(start, mouse_state.position);
         line_buffers.draw_rect(top_left, bottom_right, dpi_scaling.0);
     }
@@ -541,11 +844,20 @@ pub fn render_drag_box(
 #[filter(component::<Bullet>())]
 pub fn render_bullets(
     position: &Position,
+    previous_position: Option<&PreviousPosition>,
     facing: &Facing,
+    #[resource] interpolation: &TickInterpolation,
     #[resource] model_buffers: &mut ModelBuffers,
+    #[resource] player_side: &PlayerSide,
+    #[resource] visibility: &Visibility,
 ) {
+    if !visibility.is_visible(player_side.0, position.0) {
+        return;
+    }
+
+    let position = interpolate(position, previous_position, interpolation);
     let gun_height = 1.8;
-    let translation = Mat4::from_translation(Vec3::new(position.0.x, gun_height, position.0.y));
+    let translation = Mat4::from_translation(Vec3::new(position.x, gun_height, position.y));
     let rotation = Mat4::from_rotation_y(facing.0);
 
     model_buffers.bullets.push(ModelInstance {
@@ -554,16 +866,59 @@ pub fn render_bullets(
     });
 }
 
+// Shells are big globs of cheese, so they're drawn with the cheese droplet model.
+#[legion::system(for_each)]
+pub fn render_shells(
+    position: &Position,
+    previous_position: Option<&PreviousPosition>,
+    shell: &Shell,
+    #[resource] interpolation: &TickInterpolation,
+    #[resource] model_buffers: &mut ModelBuffers,
+    #[resource] player_side: &PlayerSide,
+    #[resource] visibility: &Visibility,
+) {
+    if !visibility.is_visible(player_side.0, position.0) {
+        return;
+    }
+
+    let position = interpolate(position, previous_position, interpolation);
+    let translation = Mat4::from_translation(Vec3::new(position.x, shell.height, position.y));
+    let rotation = Rotor3::from_rotation_between(-Vec3::unit_y(), shell.velocity.normalized())
+        .into_matrix()
+        .into_homogeneous();
+
+    model_buffers.cheese_droplets.push(ModelInstance {
+        transform: translation * rotation * Mat4::from_scale(2.0),
+        flat_colour: Vec4::one(),
+    });
+}
+
 #[legion::system]
 #[read_component(Position)]
 #[read_component(Radius)]
+#[read_component(Side)]
 pub fn render_unit_under_cursor(
     #[resource] ray_cast_location: &RayCastLocation,
     #[resource] cursor_icon: &mut CursorIcon,
     #[resource] torus_buffer: &mut TorusBuffer,
+    #[resource] player_side: &PlayerSide,
+    #[resource] alliances: &Alliances,
+    #[resource] visibility: &Visibility,
     world: &SubWorld,
 ) {
-    if let Some((pos, radius)) = unit_under_cursor(ray_cast_location, world) {
+    let position = ray_cast_location.pos;
+
+    let under_cursor = <(&Position, &Radius, Option<&Side>)>::query()
+        .iter(world)
+        .filter(|(pos, _, side)| match side {
+            Some(side) => visibility.can_see(player_side.0, **side, pos.0, alliances),
+            // Guysers aren't hidden by the fog of war.
+            None => true,
+        })
+        .find(|(pos, radius, _)| (position - pos.0).mag_sq() < radius.0.powi(2))
+        .map(|(pos, radius, _)| (pos.0, radius.0));
+
+    if let Some((pos, radius)) = under_cursor {
         cursor_icon.0 = winit::window::CursorIcon::Hand;
         torus_buffer.toruses.push(TorusInstance {
             center: Vec3::new(pos.x, 0.0, pos.y),
@@ -573,24 +928,25 @@ pub fn render_unit_under_cursor(
     }
 }
 
-fn unit_under_cursor(ray_cast_location: &RayCastLocation, world: &SubWorld) -> Option<(Vec2, f32)> {
-    let position = ray_cast_location.pos;
-
-    <(&Position, &Radius)>::query()
-        .iter(world)
-        .find(|(pos, radius)| (position - pos.0).mag_sq() < radius.0.powi(2))
-        .map(|(pos, radius)| (pos.0, radius.0))
-}
-
+#[allow(clippy::too_many_arguments)]
 #[legion::system]
+#[read_component(RecruitmentQueue)]
+#[read_component(Side)]
+#[read_component(Energy)]
+#[read_component(SpellCooldowns)]
+#[read_component(Abilities)]
 pub fn render_abilities(
     #[resource] dpi_scaling: &DpiScaling,
     #[resource] line_buffers: &mut LineBuffers,
     #[resource] screen_dimensions: &ScreenDimensions,
     #[resource] cheese_coins: &CheeseCoins,
+    #[resource] player_side: &PlayerSide,
+    #[resource] upgrades: &Upgrades,
     #[resource] text_buffer: &mut TextBuffer,
     #[resource] selected_units_abilities: &SelectedUnitsAbilities,
+    world: &SubWorld,
 ) {
+    let coins = cheese_coins.get(player_side.0);
     let dims = screen_dimensions.as_vec();
     let dpi = dpi_scaling.0;
     let ability_size = 64.0 * 1.5;
@@ -607,7 +963,7 @@ pub fn render_abilities(
         )
     };
 
-    for (i, (ability, _)) in selected_units_abilities.0.iter().enumerate() {
+    for (i, (ability, casters)) in selected_units_abilities.0.iter().enumerate() {
         line_buffers.draw_filled_rect(
             position(i),
             Vec2::new(ability_size + border * 2.0, ability_size + border * 2.0),
@@ -616,8 +972,15 @@ pub fn render_abilities(
         );
 
         let can_use = match ability.ability_type {
-            AbilityType::Build(building) => building.stats().cost <= cheese_coins.0,
-            AbilityType::Recruit(unit) => unit.stats().cost <= cheese_coins.0,
+            AbilityType::Build(building) => building.stats().cost <= coins,
+            AbilityType::Recruit(unit) => unit.stats().cost <= coins,
+            AbilityType::Research(upgrade) => {
+                upgrade.stats().cost <= coins
+                    && can_research(upgrade, player_side.0, upgrades, world)
+            }
+            AbilityType::Cast(spell) => {
+                casters.iter().any(|caster| can_cast(spell, *caster, world))
+            }
             AbilityType::SetRecruitmentWaypoint => true,
         };
 
@@ -644,7 +1007,8 @@ pub fn render_abilities(
         let cost = match ability.ability_type {
             AbilityType::Build(building) => Some(building.stats().cost),
             AbilityType::Recruit(unit) => Some(unit.stats().cost),
-            AbilityType::SetRecruitmentWaypoint => None,
+            AbilityType::Research(upgrade) => Some(upgrade.stats().cost),
+            AbilityType::Cast(_) | AbilityType::SetRecruitmentWaypoint => None,
         };
 
         if let Some(cost) = cost {
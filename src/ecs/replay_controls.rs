@@ -0,0 +1,100 @@
+use crate::renderer::{Font, TextAlignment, TextBuffer};
+use crate::resources::{
+    CameraControls, DpiScaling, Keypress, Keypresses, Mode, ReplayPlayback, ScreenDimensions, Tick,
+};
+use crate::simulation::TICK_DURATION;
+use ultraviolet::{Vec2, Vec4};
+use winit::event::VirtualKeyCode;
+
+// How far the bracket keys seek.
+const SEEK_TICKS: u64 = 60 * 10;
+
+#[legion::system]
+pub fn handle_replay_keypresses(
+    #[resource] keypresses: &mut Keypresses,
+    #[resource] camera_controls: &mut CameraControls,
+    #[resource] playback: &mut ReplayPlayback,
+    #[resource] tick: &Tick,
+    #[resource] mode: &mut Mode,
+) {
+    for Keypress { code, pressed, .. } in keypresses.0.drain(..) {
+        let code = match code {
+            Some(code) => code,
+            None => continue,
+        };
+
+        // Where to seek from. Pressing seek twice in one frame should seek twice as far.
+        let from = playback.seek_to.unwrap_or(tick.0);
+
+        match code {
+            VirtualKeyCode::Up => camera_controls.up = pressed,
+            VirtualKeyCode::Down => camera_controls.down = pressed,
+            VirtualKeyCode::Left => camera_controls.left = pressed,
+            VirtualKeyCode::Right => camera_controls.right = pressed,
+            VirtualKeyCode::Key1 if pressed => playback.speed = ReplayPlayback::SPEEDS[0],
+            VirtualKeyCode::Key2 if pressed => playback.speed = ReplayPlayback::SPEEDS[1],
+            VirtualKeyCode::Key3 if pressed => playback.speed = ReplayPlayback::SPEEDS[2],
+            VirtualKeyCode::Key4 if pressed => playback.speed = ReplayPlayback::SPEEDS[3],
+            VirtualKeyCode::Space if pressed => playback.paused = !playback.paused,
+            VirtualKeyCode::LBracket if pressed => {
+                playback.seek_to = Some(from.saturating_sub(SEEK_TICKS))
+            }
+            VirtualKeyCode::RBracket if pressed => playback.seek_to = Some(from + SEEK_TICKS),
+            VirtualKeyCode::Escape if pressed => *mode = Mode::Titlescreen,
+            _ => {}
+        }
+    }
+}
+
+#[legion::system]
+pub fn render_replay_ui(
+    #[resource] dpi_scaling: &DpiScaling,
+    #[resource] text_buffer: &mut TextBuffer,
+    #[resource] screen_dimensions: &ScreenDimensions,
+    #[resource] playback: &ReplayPlayback,
+    #[resource] tick: &Tick,
+) {
+    let blue = Vec4::new(0.091, 0.118, 0.543, 1.0);
+    let dpi = dpi_scaling.0;
+
+    let status = if playback.paused {
+        "Paused".to_string()
+    } else {
+        format!("{}x", playback.speed)
+    };
+
+    let text = format!(
+        "Replay - {}\n{} / {}\n\n1-4: Speed\nSpace: Pause\n[ and ]: Seek",
+        status,
+        format_ticks(tick.0),
+        format_ticks(playback.length)
+    );
+
+    text_buffer.render_text(
+        Vec2::new(10.0, 4.0) * dpi,
+        &text,
+        Font::Ui,
+        1.0,
+        dpi,
+        TextAlignment::Default,
+        blue,
+    );
+
+    let dims = screen_dimensions.as_vec();
+
+    text_buffer.render_text(
+        Vec2::new(dims.x / 2.0, dims.y - 32.0 * dpi),
+        "Press escape to stop watching",
+        Font::Ui,
+        1.0,
+        dpi,
+        TextAlignment::Center,
+        blue,
+    );
+}
+
+// As minutes:seconds.
+fn format_ticks(ticks: u64) -> String {
+    let seconds = (ticks as f32 * TICK_DURATION) as u64;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
@@ -0,0 +1,37 @@
+use super::*;
+use crate::resources::Upgrades;
+
+/// Whether `side` can start researching `upgrade`, leaving aside whether it can afford it. Each
+/// upgrade can only be researched once, and only after the upgrades that it requires.
+pub fn can_research(upgrade: Upgrade, side: Side, upgrades: &Upgrades, world: &SubWorld) -> bool {
+    let being_researched = <(&RecruitmentQueue, &Side)>::query()
+        .iter(world)
+        .filter(|(_, queue_side)| **queue_side == side)
+        .any(|(queue, _)| queue.queue.contains(&QueueItem::Research(upgrade)));
+
+    !being_researched
+        && !upgrades.has(side, upgrade)
+        && upgrade
+            .stats()
+            .requires
+            .iter()
+            .all(|required| upgrades.has(side, *required))
+}
+
+/// Units are given their side's extra firing range here rather than when they're recruited, so
+/// that units which already exist get it too.
+#[legion::system(for_each)]
+pub fn upgrade_firing_ranges(
+    unit: &Unit,
+    side: &Side,
+    firing_range: &mut FiringRange,
+    #[resource] upgrades: &Upgrades,
+) {
+    if *unit != Unit::MouseMarine {
+        return;
+    }
+
+    if let Some(range) = unit.stats().firing_range {
+        firing_range.0 = range + upgrades.amount(*side, Upgrade::MarineRange);
+    }
+}
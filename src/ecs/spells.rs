@@ -0,0 +1,195 @@
+use super::*;
+use crate::resources::{Alliances, ControlGroups, DeltaTime};
+
+#[legion::system(for_each)]
+pub fn regenerate_energy(unit: &Unit, energy: &mut Energy, #[resource] delta_time: &DeltaTime) {
+    let stats = unit.stats();
+    energy.0 = (energy.0 + stats.energy_regeneration * delta_time.0).min(stats.max_energy);
+}
+
+#[legion::system(for_each)]
+pub fn reduce_spell_cooldowns(cooldowns: &mut SpellCooldowns, #[resource] delta_time: &DeltaTime) {
+    for (_, cooldown) in cooldowns.0.iter_mut() {
+        *cooldown = (*cooldown - delta_time.0).max(0.0);
+    }
+
+    cooldowns.0.retain(|(_, cooldown)| *cooldown > 0.0);
+}
+
+/// Whether `caster` has `spell`, has enough energy for it and isn't waiting for it to cool down.
+pub fn can_cast(spell: Spell, caster: Entity, world: &SubWorld) -> bool {
+    let has_spell = <&Abilities>::query()
+        .get(world, caster)
+        .map(|abilities| {
+            abilities
+                .0
+                .iter()
+                .any(|ability| ability.ability_type == AbilityType::Cast(spell))
+        })
+        .unwrap_or(false);
+
+    let energy = <&Energy>::query()
+        .get(world, caster)
+        .map(|energy| energy.0)
+        .unwrap_or(0.0);
+
+    let cooled_down = <&SpellCooldowns>::query()
+        .get(world, caster)
+        .map(|cooldowns| cooldowns.get(spell) == 0.0)
+        .unwrap_or(false);
+
+    has_spell && energy >= spell.stats().energy_cost && cooled_down
+}
+
+/// Units that are in range of what they're casting a spell on channel until the spell's
+/// `channel_time` is up, and then the spell happens.
+#[legion::system]
+#[read_component(Entity)]
+#[read_component(Position)]
+#[read_component(Abilities)]
+#[read_component(Agroed)]
+#[write_component(Side)]
+#[write_component(Facing)]
+#[write_component(Energy)]
+#[write_component(SpellCooldowns)]
+#[write_component(CommandQueue)]
+pub fn cast_spells(
+    #[resource] delta_time: &DeltaTime,
+    #[resource] alliances: &Alliances,
+    #[resource] control_groups: &mut ControlGroups,
+    world: &mut SubWorld,
+    buffer: &mut CommandBuffer,
+) {
+    let casting: Vec<(Entity, Spell, CastTarget)> = <(Entity, &CommandQueue)>::query()
+        .iter(world)
+        .filter_map(|(entity, queue)| match queue.0.front() {
+            Some(&Command::Cast {
+                spell,
+                target,
+                state: ActionState::InRange,
+                ..
+            }) => Some((*entity, spell, target)),
+            _ => None,
+        })
+        .collect();
+
+    for (caster, spell, target) in casting {
+        let stats = spell.stats();
+
+        let side = match <&Side>::query().get(world, caster) {
+            Ok(side) => *side,
+            Err(_) => continue,
+        };
+
+        let target_position = match target {
+            CastTarget::Nothing => None,
+            CastTarget::Point(point) => Some(point),
+            // The target might have been taken over by another unit already this tick.
+            CastTarget::Entity(entity) => match <(&Position, &Side)>::query().get(world, entity) {
+                Ok((position, target_side)) if alliances.are_enemies(side, *target_side) => {
+                    Some(position.0)
+                }
+                _ => {
+                    stop_casting(caster, world);
+                    continue;
+                }
+            },
+        };
+
+        if !can_cast(spell, caster, world) {
+            stop_casting(caster, world);
+            continue;
+        }
+
+        let (position, facing, queue) = <(&Position, &mut Facing, &mut CommandQueue)>::query()
+            .get_mut(world, caster)
+            .expect("We've checked that the caster exists");
+
+        if let Some(target_position) = target_position {
+            let vector = target_position - position.0;
+            facing.0 = vector.y.atan2(vector.x);
+        }
+
+        if let Some(Command::Cast { progress, .. }) = queue.0.front_mut() {
+            *progress += delta_time.0;
+
+            if *progress < stats.channel_time {
+                continue;
+            }
+        }
+
+        queue.0.pop_front();
+
+        if let Ok(energy) = <&mut Energy>::query().get_mut(world, caster) {
+            energy.0 -= stats.energy_cost;
+        }
+
+        if stats.cooldown > 0.0 {
+            if let Ok(cooldowns) = <&mut SpellCooldowns>::query().get_mut(world, caster) {
+                cooldowns.0.push((spell, stats.cooldown));
+            }
+        }
+
+        match (spell, target) {
+            (Spell::MindControl, CastTarget::Entity(target)) => {
+                take_control(target, side, alliances, control_groups, world, buffer)
+            }
+            (Spell::MindControl, _) => log::warn!("MindControl can only be cast on units"),
+        }
+    }
+}
+
+fn stop_casting(caster: Entity, world: &mut SubWorld) {
+    if let Ok(queue) = <&mut CommandQueue>::query().get_mut(world, caster) {
+        queue.0.pop_front();
+    }
+}
+
+// Switch `target` over to `side`, and clean up everything that still treats it as being on its
+// old side.
+fn take_control(
+    target: Entity,
+    side: Side,
+    alliances: &Alliances,
+    control_groups: &mut ControlGroups,
+    world: &mut SubWorld,
+    buffer: &mut CommandBuffer,
+) {
+    if let Ok((target_side, queue)) =
+        <(&mut Side, &mut CommandQueue)>::query().get_mut(world, target)
+    {
+        *target_side = side;
+        // Whatever it was doing, it was doing for its old side.
+        queue.0.clear();
+    }
+
+    // Neither its old side nor its new side should have it selected or in a control group.
+    buffer.remove_component::<Selected>(target);
+    buffer.remove_component::<Agroed>(target);
+
+    for group in control_groups.0.iter_mut() {
+        group.retain(|entity| *entity != target);
+    }
+
+    // Its new allies stop attacking it, and stop pulling each other in to attack it.
+    for (entity, unit_side, queue, agroed) in
+        <(Entity, &Side, &mut CommandQueue, Option<&Agroed>)>::query().iter_mut(world)
+    {
+        if alliances.are_enemies(*unit_side, side) {
+            continue;
+        }
+
+        queue.0.retain(|command| match command {
+            Command::Attack {
+                target: attacking, ..
+            } => *attacking != target,
+            _ => true,
+        });
+
+        if let Some(Agroed::ThisTick(agroed_by) | Agroed::LastTick(agroed_by)) = agroed {
+            if *agroed_by == target {
+                buffer.remove_component::<Agroed>(*entity);
+            }
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use super::*;
+use crate::assets::ModelAnimations;
+use crate::resources::{
+    AiBuildOrders, Camera, CheeseCoins, Objectives, ScenarioMessage, TotalTime, TriggerAction,
+    TriggerCondition, Triggers,
+};
+
+#[allow(clippy::too_many_arguments)]
+#[legion::system]
+#[read_component(Position)]
+#[read_component(Side)]
+#[read_component(Unit)]
+#[read_component(Building)]
+#[read_component(FullyBuilt)]
+#[read_component(Tag)]
+#[read_component(CheeseGuyser)]
+pub fn check_triggers(
+    #[resource] triggers: &mut Triggers,
+    #[resource] total_time: &TotalTime,
+    #[resource] cheese_coins: &mut CheeseCoins,
+    #[resource] player_side: &PlayerSide,
+    #[resource] objectives: &mut Objectives,
+    #[resource] camera: &mut Camera,
+    #[resource] message: &mut ScenarioMessage,
+    #[resource] build_orders: &mut AiBuildOrders,
+    #[resource] animations: &ModelAnimations,
+    world: &SubWorld,
+    commands: &mut CommandBuffer,
+) {
+    let mut index = 0;
+
+    while index < triggers.0.len() {
+        if !condition_met(
+            &triggers.0[index].condition,
+            total_time,
+            cheese_coins.get(player_side.0),
+            world,
+        ) {
+            index += 1;
+            continue;
+        }
+
+        let trigger = triggers.0.remove(index);
+
+        for action in trigger.actions {
+            match action {
+                TriggerAction::Spawn(placement) => placement.spawn(commands, animations),
+                TriggerAction::Message(text) => message.0 = Some((text, total_time.0)),
+                TriggerAction::GrantCoins(coins) => cheese_coins.add(player_side.0, coins),
+                TriggerAction::SetObjectives(new_objectives) => *objectives = new_objectives,
+                TriggerAction::MoveCamera(position) => camera.looking_at = position,
+                TriggerAction::AiOrder(item) => {
+                    let guysers: Vec<_> = <(Entity, &Position)>::query()
+                        .filter(component::<CheeseGuyser>())
+                        .iter(world)
+                        .map(|(entity, position)| (*entity, position.0))
+                        .collect();
+
+                    match item.resolve(&guysers) {
+                        // Do it straight away, ahead of the pre-recorded orders.
+                        Some(item) => build_orders.0.insert(0, (total_time.0, item)),
+                        None => log::warn!("Couldn't find the guyser for {:?}", item),
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn condition_met(
+    condition: &TriggerCondition,
+    total_time: &TotalTime,
+    cheese_coins: u32,
+    world: &SubWorld,
+) -> bool {
+    match condition {
+        TriggerCondition::Time(time) => total_time.0 >= *time,
+        TriggerCondition::UnitsInRegion {
+            side,
+            region,
+            count,
+        } => {
+            <(&Position, &Side)>::query()
+                .filter(component::<Unit>())
+                .iter(world)
+                .filter(|(position, unit_side)| *unit_side == side && region.contains(position.0))
+                .count()
+                >= *count as usize
+        }
+        TriggerCondition::BuildingsCompleted {
+            side,
+            building,
+            count,
+        } => {
+            <(&Building, &Side)>::query()
+                .filter(component::<FullyBuilt>())
+                .iter(world)
+                .filter(|(building_kind, building_side)| {
+                    *building_kind == building && *building_side == side
+                })
+                .count()
+                >= *count as usize
+        }
+        TriggerCondition::Destroyed(tag) => !<&Tag>::query()
+            .iter(world)
+            .any(|entity_tag| entity_tag.0 == *tag),
+        TriggerCondition::CoinsReached(coins) => cheese_coins >= *coins,
+    }
+}
@@ -0,0 +1,68 @@
+use super::*;
+use crate::resources::{Alliances, BuildingGhost, Visibility};
+
+#[legion::system]
+#[read_component(Position)]
+#[read_component(Side)]
+#[read_component(Unit)]
+#[read_component(Building)]
+pub fn update_visibility(
+    #[resource] visibility: &mut Visibility,
+    #[resource] alliances: &Alliances,
+    world: &SubWorld,
+) {
+    if !visibility.fog_of_war {
+        return;
+    }
+
+    visibility.clear();
+
+    let mut sides: Vec<Side> = Vec::new();
+    for side in <&Side>::query().iter(world) {
+        if !sides.contains(side) {
+            sides.push(*side);
+        }
+    }
+
+    <(&Position, &Side, Option<&Unit>, Option<&Building>)>::query().for_each(
+        world,
+        |(position, side, unit, building)| {
+            let sight_range = match (unit, building) {
+                (Some(unit), _) => unit.stats().sight_range,
+                (_, Some(building)) => building.stats().sight_range,
+                // Bullets and so on.
+                _ => return,
+            };
+
+            for viewer in &sides {
+                if alliances.are_allies(*viewer, *side) {
+                    visibility.reveal(*viewer, position.0, sight_range);
+                }
+            }
+        },
+    );
+
+    // Forget about buildings in places that can be seen again, then remember the ones that are
+    // there now.
+    let mut ghosts: Vec<BuildingGhost> = visibility
+        .ghosts
+        .iter()
+        .filter(|ghost| !visibility.is_visible(ghost.seen_by, ghost.position))
+        .cloned()
+        .collect();
+
+    for (position, building, side) in <(&Position, &Building, &Side)>::query().iter(world) {
+        for viewer in &sides {
+            if alliances.are_enemies(*viewer, *side) && visibility.is_visible(*viewer, position.0) {
+                ghosts.push(BuildingGhost {
+                    seen_by: *viewer,
+                    building: *building,
+                    side: *side,
+                    position: position.0,
+                });
+            }
+        }
+    }
+
+    visibility.ghosts = ghosts;
+}
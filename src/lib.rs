@@ -0,0 +1,17 @@
+#![allow(clippy::float_cmp)]
+
+mod animation;
+pub mod assets;
+pub mod definitions;
+pub mod ecs;
+pub mod pathfinding;
+pub mod renderer;
+pub mod replay;
+pub mod resources;
+mod save;
+pub mod scenarios;
+mod simulation;
+mod snapshot;
+pub mod titlescreen;
+
+pub use simulation::{random_seed, Simulation};
@@ -2,26 +2,18 @@
 // Needed to stop a console window from appearing on windows.
 #![windows_subsystem = "windows"]
 
-mod animation;
-mod assets;
-mod ecs;
-mod pathfinding;
-mod renderer;
-mod resources;
-mod scenarios;
-mod titlescreen;
-
-use crate::assets::Assets;
-use crate::renderer::{
+use cheese::assets::Assets;
+use cheese::renderer::{
     LineBuffers, Lines3dBuffer, Lines3dPipeline, LinesPipeline, ModelBuffers, ModelPipelines,
     RenderContext, ShadowPipeline, TextBuffer, TitlescreenBuffer, TorusBuffer, TorusPipeline,
 };
-use crate::resources::{
-    AiBuildOrders, Camera, CameraControls, CheeseCoins, ControlGroups, CursorIcon, DebugControls,
-    DeltaTime, DpiScaling, GameStats, Gravity, Keypress, Keypresses, Mode, MouseState, Objectives,
-    PlayerSide, RayCastLocation, RtsControls, ScreenDimensions, SelectedUnitsAbilities, Settings,
-    TotalTime,
+use cheese::replay::Replay;
+use cheese::resources::{
+    Camera, CameraControls, CursorIcon, DebugControls, DeltaTime, DpiScaling, Keypress, Keypresses,
+    Mode, MouseState, RayCastLocation, RtsControls, ScreenDimensions, SelectedUnitsAbilities,
+    Settings,
 };
+use cheese::{definitions, ecs, random_seed, titlescreen, Simulation};
 use legion::*;
 use rand::{rngs::SmallRng, SeedableRng};
 use ultraviolet::Vec2;
@@ -31,6 +23,9 @@ use winit::{
     event_loop::{ControlFlow, EventLoop},
 };
 
+// There's just the one save slot for now.
+const SAVE_PATH: &str = "saves/quicksave.ron";
+
 fn main() -> anyhow::Result<()> {
     futures::executor::block_on(run())
 }
@@ -38,10 +33,40 @@ fn main() -> anyhow::Result<()> {
 async fn run() -> anyhow::Result<()> {
     env_logger::init();
 
+    definitions::load()?;
+
+    // `--build-orders <replay path>` prints the player's commands in that replay as build orders
+    // for a scenario file, mirrored for the opposite corner unless `--no-mirror` is given.
+    if let Some(path) = std::env::args()
+        .skip_while(|arg| arg != "--build-orders")
+        .nth(1)
+    {
+        let replay = Replay::load(std::path::Path::new(&path))?;
+        let mirror = !std::env::args().any(|arg| arg == "--no-mirror");
+
+        println!("ai_build_orders: [");
+        for order in replay.to_build_orders(mirror)? {
+            println!("    {},", ron::ser::to_string(&order)?);
+        }
+        println!("],");
+
+        return Ok(());
+    }
+
     let event_loop = EventLoop::new();
 
     let mut rng = SmallRng::from_entropy();
     let settings = Settings::default();
+    // `--seed <seed>` makes every match use the same seed, for reproducing bugs.
+    let seed_override: Option<u64> = std::env::args()
+        .skip_while(|arg| arg != "--seed")
+        .nth(1)
+        .and_then(|seed| seed.parse().ok());
+    // `--replay <path>` watches that replay instead of the latest one.
+    let replay_override: Option<std::path::PathBuf> = std::env::args()
+        .skip_while(|arg| arg != "--replay")
+        .nth(1)
+        .map(Into::into);
 
     let mut render_context = RenderContext::new(&event_loop, &settings).await?;
     let (assets, animations, command_buffer) = Assets::new(&render_context.device())?;
@@ -58,8 +83,8 @@ async fn run() -> anyhow::Result<()> {
     let lines_3d_buffer = Lines3dBuffer::new(render_context.device());
     let titlescreen_buffer = TitlescreenBuffer::new(render_context.device(), &mut rng);
 
-    let mut world = World::default();
-    let mut resources = Resources::default();
+    let mut simulation = Simulation::new();
+    let resources = &mut simulation.resources;
     resources.insert(model_buffers);
     resources.insert(torus_buffer);
     resources.insert(lines_buffers);
@@ -68,23 +93,14 @@ async fn run() -> anyhow::Result<()> {
     resources.insert(titlescreen_buffer);
     resources.insert(render_context.screen_dimensions());
     resources.insert(CameraControls::default());
-    resources.insert(Camera::default());
     resources.insert(MouseState::new(&render_context.screen_dimensions()));
     resources.insert(RtsControls::default());
     resources.insert(RayCastLocation::default());
-    resources.insert(PlayerSide(ecs::Side::Green));
-    resources.insert(ControlGroups::default());
     resources.insert(titlescreen::TitlescreenMoon::default());
     resources.insert(titlescreen::Menu::Main);
-    resources.insert(Mode::Titlescreen);
     resources.insert(DebugControls::default());
-    resources.insert(Gravity(5.0));
-    resources.insert(CheeseCoins(0));
     resources.insert(SelectedUnitsAbilities::default());
     resources.insert(Keypresses::default());
-    resources.insert(TotalTime(0.0));
-    resources.insert(AiBuildOrders::default());
-    resources.insert(GameStats::default());
     // Dpi scale factors are wierd. One of my laptops has it set at 1.33 and the other has it at 2.0.
     // Scaling things like selection boxes by 1.33 looks bad because one side can take up 1 pixel
     // and the other can take up 2 pixels. So I guess the best solution is to just floor the value
@@ -93,17 +109,17 @@ async fn run() -> anyhow::Result<()> {
         render_context.window.scale_factor().floor() as f32
     ));
     resources.insert(animations);
-    resources.insert(pathfinding::Map::new());
-    resources.insert(rng);
-    resources.insert(Objectives::default());
 
     let mut titlescreen_schedule = titlescreen::titlescreen_schedule();
 
-    let mut builder = Schedule::builder();
-    ecs::add_gameplay_systems(&mut builder);
-    ecs::add_rendering_systems(&mut builder);
-    ecs::add_cleanup_systems(&mut builder);
-    let mut schedule = builder.build();
+    let mut control_schedule = Schedule::builder();
+    ecs::add_control_systems(&mut control_schedule);
+    let mut control_schedule = control_schedule.build();
+
+    let mut rendering_schedule = Schedule::builder();
+    ecs::add_rendering_systems(&mut rendering_schedule);
+    ecs::add_cleanup_systems(&mut rendering_schedule);
+    let mut rendering_schedule = rendering_schedule.build();
 
     let mut playing_menu_system = Schedule::builder();
     ecs::add_rendering_systems(&mut playing_menu_system);
@@ -111,172 +127,224 @@ async fn run() -> anyhow::Result<()> {
     ecs::add_cleanup_systems(&mut playing_menu_system);
     let mut playing_menu_system = playing_menu_system.build();
 
+    let mut replay_schedule = Schedule::builder();
+    ecs::add_rendering_systems(&mut replay_schedule);
+    ecs::add_replay_systems(&mut replay_schedule);
+    ecs::add_cleanup_systems(&mut replay_schedule);
+    let mut replay_schedule = replay_schedule.build();
+
+    let mut editor_schedule = Schedule::builder();
+    ecs::add_editor_systems(&mut editor_schedule);
+    ecs::add_rendering_systems(&mut editor_schedule);
+    ecs::add_cleanup_systems(&mut editor_schedule);
+    let mut editor_schedule = editor_schedule.build();
+
     let mut time = std::time::Instant::now();
 
     event_loop.run(move |event, _, control_flow| {
         match event {
-            Event::WindowEvent { ref event, .. } => match event {
-                WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
-                WindowEvent::Resized(size) => {
-                    render_context.resize(size.width as u32, size.height as u32);
-                    resources.insert(ScreenDimensions {
-                        width: size.width as u32,
-                        height: size.height as u32,
-                    })
-                }
-                WindowEvent::KeyboardInput {
-                    input:
-                        KeyboardInput {
-                            state,
-                            virtual_keycode: code,
-                            scancode,
-                            ..
-                        },
-                    ..
-                } => {
-                    let pressed = *state == ElementState::Pressed;
-                    let mode = resources.get::<Mode>().unwrap();
-                    let mut keypresses = resources.get_mut::<Keypresses>().unwrap();
-                    // We only push keypresses in modes that consume them. This is a bit messy.
-                    if matches!(*mode, Mode::Playing | Mode::PlayingMenu) {
-                        keypresses.0.push(Keypress {
-                            code: *code,
-                            scancode: *scancode,
-                            pressed,
-                        });
+            Event::WindowEvent { ref event, .. } => {
+                let resources = &mut simulation.resources;
+
+                match event {
+                    WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
+                    WindowEvent::Resized(size) => {
+                        render_context.resize(size.width as u32, size.height as u32);
+                        resources.insert(ScreenDimensions {
+                            width: size.width as u32,
+                            height: size.height as u32,
+                        })
                     }
-                }
-                WindowEvent::MouseWheel { delta, .. } => {
-                    let mut camera_controls = resources.get_mut::<CameraControls>().unwrap();
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                state,
+                                virtual_keycode: code,
+                                scancode,
+                                ..
+                            },
+                        ..
+                    } => {
+                        let pressed = *state == ElementState::Pressed;
+                        let mode = resources.get::<Mode>().unwrap();
+                        let mut keypresses = resources.get_mut::<Keypresses>().unwrap();
+                        // We only push keypresses in modes that consume them. This is a bit messy.
+                        if matches!(
+                            *mode,
+                            Mode::Playing | Mode::PlayingMenu | Mode::Replay | Mode::Editor
+                        ) {
+                            keypresses.0.push(Keypress {
+                                code: *code,
+                                scancode: *scancode,
+                                pressed,
+                            });
+                        }
+                    }
+                    WindowEvent::MouseWheel { delta, .. } => {
+                        let mut camera_controls = resources.get_mut::<CameraControls>().unwrap();
 
-                    camera_controls.zoom_delta += match delta {
-                        MouseScrollDelta::LineDelta(_, y) => y * 200.0,
-                        MouseScrollDelta::PixelDelta(PhysicalPosition { y, .. }) => *y as f32,
-                    };
-                }
-                WindowEvent::CursorMoved { position, .. } => {
-                    let mut mouse_state = resources.get_mut::<MouseState>().unwrap();
-                    mouse_state.position = Vec2::new(position.x as f32, position.y as f32);
-                }
-                WindowEvent::MouseInput { state, button, .. } => {
-                    let pressed = *state == ElementState::Pressed;
-
-                    let mut mouse_state = resources.get_mut::<MouseState>().unwrap();
-                    let position = mouse_state.position;
-                    match button {
-                        MouseButton::Left => mouse_state.left_state.handle(position, pressed),
-                        MouseButton::Right => mouse_state.right_state.handle(position, pressed),
-                        _ => {}
+                        camera_controls.zoom_delta += match delta {
+                            MouseScrollDelta::LineDelta(_, y) => y * 200.0,
+                            MouseScrollDelta::PixelDelta(PhysicalPosition { y, .. }) => *y as f32,
+                        };
+                    }
+                    WindowEvent::CursorMoved { position, .. } => {
+                        let mut mouse_state = resources.get_mut::<MouseState>().unwrap();
+                        mouse_state.position = Vec2::new(position.x as f32, position.y as f32);
                     }
+                    WindowEvent::MouseInput { state, button, .. } => {
+                        let pressed = *state == ElementState::Pressed;
+
+                        let mut mouse_state = resources.get_mut::<MouseState>().unwrap();
+                        let position = mouse_state.position;
+                        match button {
+                            MouseButton::Left => mouse_state.left_state.handle(position, pressed),
+                            MouseButton::Right => mouse_state.right_state.handle(position, pressed),
+                            _ => {}
+                        }
+                    }
+                    _ => {}
                 }
-                _ => {}
-            },
+            }
             Event::MainEventsCleared => {
+                // Changing the stats partway through a match would make it play out differently to
+                // its replay, so changes are only picked up on the title screen.
+                if cfg!(debug_assertions) && simulation.mode() == Mode::Titlescreen {
+                    definitions::reload_if_changed();
+                }
+
                 let now = std::time::Instant::now();
                 let elapsed = (now - time).as_secs_f32();
                 time = now;
+                let resources = &mut simulation.resources;
                 resources.insert(DeltaTime(elapsed));
                 resources.insert(CursorIcon(winit::window::CursorIcon::default()));
 
-                let mut mode: Mode = *resources.get_mut::<Mode>().unwrap();
-
-                if let Mode::StartScenario(scenario) = mode {
-                    let animations = resources.get::<assets::ModelAnimations>().unwrap();
-                    let mut map = resources.get_mut::<pathfinding::Map>().unwrap();
-                    let mut rng = resources.get_mut::<SmallRng>().unwrap();
-                    let mut objectives = resources.get_mut::<Objectives>().unwrap();
-                    let mut camera = resources.get_mut::<Camera>().unwrap();
-                    let mut cheese_coins = resources.get_mut::<CheeseCoins>().unwrap();
-                    let mut ai_build_orders = resources.get_mut::<AiBuildOrders>().unwrap();
-                    resources.get_mut::<TotalTime>().unwrap().0 = 0.0;
-                    *resources.get_mut::<GameStats>().unwrap() = GameStats::default();
-                    *map = pathfinding::Map::new();
-
-                    world.clear();
-
-                    match scenario {
-                        1 => {
-                            scenarios::one(
-                                &mut world,
-                                &animations,
-                                &mut map,
-                                &mut rng,
-                                &mut objectives,
-                                &mut camera,
-                                &mut cheese_coins,
-                                &mut ai_build_orders,
-                            );
-                        }
-                        2 => {
-                            scenarios::two(
-                                &mut world,
-                                &animations,
-                                &mut map,
-                                &mut rng,
-                                &mut objectives,
-                                &mut camera,
-                                &mut cheese_coins,
-                                &mut ai_build_orders,
-                            );
+                let mut mode = simulation.mode();
+
+                if let Mode::StartScenario(scenario, seed) = &mode {
+                    let seed = seed.or(seed_override).unwrap_or_else(random_seed);
+                    log::info!("Starting scenario {} with seed {}", scenario, seed);
+                    if let Err(error) = simulation.start_scenario(scenario, seed) {
+                        log::error!("{:?}", error);
+                        *simulation.resources.get_mut::<Mode>().unwrap() = Mode::Titlescreen;
+                    }
+                    mode = simulation.mode();
+                }
+
+                if let Mode::StartEditor(scenario) = &mode {
+                    if let Err(error) = simulation.start_editor(scenario.as_deref()) {
+                        log::error!("{:?}", error);
+                        *simulation.resources.get_mut::<Mode>().unwrap() = Mode::Titlescreen;
+                    }
+                    mode = simulation.mode();
+                }
+
+                match mode {
+                    Mode::SaveGame => {
+                        match simulation.save_game(SAVE_PATH.as_ref()) {
+                            Ok(()) => log::info!("Saved the game to {}", SAVE_PATH),
+                            Err(error) => log::error!("Failed to save the game: {}", error),
                         }
-                        3 => {
-                            scenarios::three(
-                                &mut world,
-                                &animations,
-                                &mut map,
-                                &mut rng,
-                                &mut objectives,
-                                &mut camera,
-                                &mut cheese_coins,
-                                &mut ai_build_orders,
-                            );
+                        *simulation.resources.get_mut::<Mode>().unwrap() = Mode::PlayingMenu;
+                        mode = simulation.mode();
+                    }
+                    Mode::LoadGame => {
+                        match simulation.load_game(SAVE_PATH.as_ref()) {
+                            Ok(()) => log::info!("Loaded the game from {}", SAVE_PATH),
+                            Err(error) => {
+                                log::error!("Failed to load the game: {}", error);
+                                *simulation.resources.get_mut::<Mode>().unwrap() =
+                                    Mode::PlayingMenu;
+                            }
                         }
-                        255 => scenarios::sandbox(
-                            &mut world,
-                            &animations,
-                            &mut map,
-                            &mut rng,
-                            &mut objectives,
-                            &mut camera,
-                            &mut cheese_coins,
-                            &mut ai_build_orders,
-                        ),
-                        _ => {}
+                        mode = simulation.mode();
                     }
-                    // Gotta change both the Mode in resources and the local copy.
-                    *resources.get_mut::<Mode>().unwrap() = Mode::Playing;
-                    mode = Mode::Playing;
+                    _ => {}
                 }
 
+                if let Mode::StartReplay = mode {
+                    match replay_override.clone().or_else(Replay::latest_path) {
+                        Some(path) => match Replay::load(&path) {
+                            Ok(replay) => {
+                                log::info!("Watching {}", path.display());
+                                if let Err(error) = simulation.start_replay(replay) {
+                                    log::error!("Failed to start replay: {:?}", error);
+                                }
+                            }
+                            Err(error) => log::error!("Failed to load replay: {}", error),
+                        },
+                        None => log::warn!("There are no replays to watch"),
+                    }
+
+                    if simulation.mode() == Mode::StartReplay {
+                        *simulation.resources.get_mut::<Mode>().unwrap() = Mode::Titlescreen;
+                    }
+                    mode = simulation.mode();
+                }
+
+                let world = &mut simulation.world;
+                let resources = &mut simulation.resources;
+
                 match mode {
-                    Mode::Playing => schedule.execute(&mut world, &mut resources),
-                    Mode::Titlescreen => titlescreen_schedule.execute(&mut world, &mut resources),
+                    Mode::Playing => {
+                        control_schedule.execute(world, resources);
+                        simulation.advance(elapsed);
+                        rendering_schedule
+                            .execute(&mut simulation.world, &mut simulation.resources);
+
+                        if let Mode::ScenarioWon | Mode::ScenarioLost = simulation.mode() {
+                            save_replay(&simulation);
+                        }
+                    }
+                    Mode::Titlescreen => titlescreen_schedule.execute(world, resources),
                     Mode::Quit => *control_flow = ControlFlow::Exit,
-                    Mode::ScenarioWon | Mode::ScenarioLost | Mode::PlayingMenu => {
-                        playing_menu_system.execute(&mut world, &mut resources)
+                    Mode::ScenarioWon | Mode::ScenarioLost => {
+                        playing_menu_system.execute(world, resources)
                     }
-                    Mode::StartScenario(_) => unreachable!(),
-                }
+                    Mode::PlayingMenu => {
+                        playing_menu_system.execute(world, resources);
 
-                resources.get_mut::<TotalTime>().unwrap().0 += elapsed;
+                        // The match was abandoned from the menu.
+                        if let Mode::Titlescreen | Mode::StartScenario(..) = simulation.mode() {
+                            save_replay(&simulation);
+                        }
+                    }
+                    Mode::Replay => {
+                        simulation.advance(elapsed);
+                        replay_schedule.execute(&mut simulation.world, &mut simulation.resources);
+                    }
+                    Mode::Editor => editor_schedule.execute(world, resources),
+                    Mode::StartScenario(..)
+                    | Mode::StartEditor(..)
+                    | Mode::StartReplay
+                    | Mode::SaveGame
+                    | Mode::LoadGame => unreachable!(),
+                }
 
+                let resources = &simulation.resources;
                 let cursor_icon = resources.get::<CursorIcon>().unwrap();
                 render_context.set_cursor_icon(cursor_icon.0);
                 render_context.request_redraw();
             }
             Event::RedrawRequested(_) => {
+                let resources = &simulation.resources;
                 let mut model_buffers = resources.get_mut::<ModelBuffers>().unwrap();
                 let mut torus_buffer = resources.get_mut::<TorusBuffer>().unwrap();
                 let mut line_buffers = resources.get_mut::<LineBuffers>().unwrap();
                 let mut text_buffer = resources.get_mut::<TextBuffer>().unwrap();
                 let mut lines_3d_buffer = resources.get_mut::<Lines3dBuffer>().unwrap();
                 let titlescreen_buffer = resources.get::<TitlescreenBuffer>().unwrap();
-                let mode = *resources.get::<Mode>().unwrap();
+                let mode = resources.get::<Mode>().unwrap().clone();
 
                 // Upload buffers to the gpu.
 
                 match mode {
-                    Mode::Playing | Mode::ScenarioWon | Mode::ScenarioLost => {
+                    Mode::Playing
+                    | Mode::ScenarioWon
+                    | Mode::ScenarioLost
+                    | Mode::Replay
+                    | Mode::Editor => {
                         let camera = resources.get::<Camera>().unwrap();
                         render_context.update_from_camera(&camera);
                     }
@@ -532,6 +600,13 @@ fn render_shadows<'a>(
     assets: &'a Assets,
 ) {
     shadow_pipeline.render_static(shadow_pass, &assets.armoury_model, &model_buffers.armouries);
+    shadow_pipeline.render_static(shadow_pass, &assets.larder_model, &model_buffers.larders);
+    shadow_pipeline.render_static(shadow_pass, &assets.turret_model, &model_buffers.turrets);
+    shadow_pipeline.render_static(
+        shadow_pass,
+        &assets.turret_head_model,
+        &model_buffers.turret_heads,
+    );
     shadow_pipeline.render_animated(
         shadow_pass,
         &assets.pump_model,
@@ -570,6 +645,26 @@ fn render_shadows<'a>(
         &model_buffers.mice_engineers_joints.bind_group,
         &model_buffers.mice_engineers,
     );
+    // Psychics
+    shadow_pipeline.render_animated(
+        shadow_pass,
+        &assets.mouse_model,
+        &model_buffers.mice_psychics_joints.bind_group,
+        &model_buffers.mice_psychics,
+    );
+    // Artillery
+    shadow_pipeline.render_animated(
+        shadow_pass,
+        &assets.mouse_model,
+        &model_buffers.mice_artillery_joints.bind_group,
+        &model_buffers.mice_artillery,
+    );
+    shadow_pipeline.render_animated(
+        shadow_pass,
+        &assets.blaster_model,
+        &model_buffers.mice_artillery_joints.bind_group,
+        &model_buffers.mice_artillery,
+    );
 }
 
 fn render_playing<'a>(
@@ -591,6 +686,24 @@ fn render_playing<'a>(
         &assets.armoury_texture,
         &assets.armoury_model,
     );
+    model_pipelines.render_instanced(
+        &mut render_pass,
+        &model_buffers.larders,
+        &assets.armoury_texture,
+        &assets.larder_model,
+    );
+    model_pipelines.render_instanced(
+        &mut render_pass,
+        &model_buffers.turrets,
+        &assets.armoury_texture,
+        &assets.turret_model,
+    );
+    model_pipelines.render_instanced(
+        &mut render_pass,
+        &model_buffers.turret_heads,
+        &assets.armoury_texture,
+        &assets.turret_head_model,
+    );
     model_pipelines.render_animated(
         &mut render_pass,
         &model_buffers.pumps,
@@ -634,6 +747,29 @@ fn render_playing<'a>(
         &assets.wrench_model,
         &model_buffers.mice_engineers_joints.bind_group,
     );
+    // Mice psychics don't carry anything.
+    model_pipelines.render_animated(
+        &mut render_pass,
+        &model_buffers.mice_psychics,
+        &assets.mouse_texture,
+        &assets.mouse_model,
+        &model_buffers.mice_psychics_joints.bind_group,
+    );
+    // Mice artillery
+    model_pipelines.render_animated(
+        &mut render_pass,
+        &model_buffers.mice_artillery,
+        &assets.mouse_texture,
+        &assets.mouse_model,
+        &model_buffers.mice_artillery_joints.bind_group,
+    );
+    model_pipelines.render_animated(
+        &mut render_pass,
+        &model_buffers.mice_artillery,
+        &assets.blaster_texture,
+        &assets.blaster_model,
+        &model_buffers.mice_artillery_joints.bind_group,
+    );
     // Bullets
     model_pipelines.render_transparent_textured_with_bloom(
         &mut render_pass,
@@ -684,6 +820,20 @@ fn render_playing<'a>(
         &assets.mouse_helmet_model,
         &model_buffers.mice_engineers_joints.bind_group,
     );
+    model_pipelines.render_transparent_animated(
+        &mut render_pass,
+        &model_buffers.mice_psychics,
+        &assets.mouse_texture,
+        &assets.mouse_helmet_model,
+        &model_buffers.mice_psychics_joints.bind_group,
+    );
+    model_pipelines.render_transparent_animated(
+        &mut render_pass,
+        &model_buffers.mice_artillery,
+        &assets.mouse_texture,
+        &assets.mouse_helmet_model,
+        &model_buffers.mice_artillery_joints.bind_group,
+    );
 
     if let Some((building, buffer)) = model_buffers.building_plan.get() {
         model_pipelines.render_transparent_buffer(
@@ -691,6 +841,8 @@ fn render_playing<'a>(
             match building {
                 ecs::Building::Pump => &assets.pump_static_model,
                 ecs::Building::Armoury => &assets.armoury_model,
+                ecs::Building::Larder => &assets.larder_model,
+                ecs::Building::Turret => &assets.turret_model,
             },
             buffer,
             1,
@@ -700,3 +852,10 @@ fn render_playing<'a>(
     // Render 2D items.
     lines_pipeline.render(&mut render_pass, &line_buffers, &assets);
 }
+
+fn save_replay(simulation: &Simulation) {
+    match simulation.save_replay() {
+        Ok(path) => log::info!("Saved replay to {}", path.display()),
+        Err(error) => log::error!("Failed to save replay: {}", error),
+    }
+}
@@ -10,6 +10,7 @@ use spade::{
 use std::hash::{Hash, Hasher};
 use ultraviolet::Vec2;
 
+#[derive(Clone)]
 pub struct MapHandle {
     top_left: Point2<f32>,
     top_right: Point2<f32>,
@@ -17,6 +18,7 @@ pub struct MapHandle {
     bottom_right: Point2<f32>,
 }
 
+#[derive(Clone)]
 pub struct Map {
     dlt: ConstrainedDelaunayTriangulation<Point2<f32>, FloatKernel>,
     pub updated_this_tick: bool,
@@ -55,23 +57,23 @@ impl Map {
         }
     }
 
-    pub fn insert(&mut self, center: Vec2, dimensions: Vec2) -> Option<MapHandle> {
-        let tl = center - dimensions / 2.0;
-        let br = center + dimensions / 2.0;
-
-        let top_left = Point2::new(tl.x, tl.y);
-        let top_right = Point2::new(br.x, tl.y);
-        let bottom_left = Point2::new(tl.x, br.y);
-        let bottom_right = Point2::new(br.x, br.y);
+    /// Whether a building with these dimensions can be placed here without overlapping another.
+    pub fn can_insert(&self, center: Vec2, dimensions: Vec2) -> bool {
+        let [top_left, top_right, bottom_left, bottom_right] = corners(center, dimensions);
 
-        if self.dlt.intersects_constraint(&top_left, &top_right)
+        !(self.dlt.intersects_constraint(&top_left, &top_right)
             || self.dlt.intersects_constraint(&top_right, &bottom_right)
             || self.dlt.intersects_constraint(&bottom_right, &bottom_left)
-            || self.dlt.intersects_constraint(&bottom_left, &top_left)
-        {
+            || self.dlt.intersects_constraint(&bottom_left, &top_left))
+    }
+
+    pub fn insert(&mut self, center: Vec2, dimensions: Vec2) -> Option<MapHandle> {
+        if !self.can_insert(center, dimensions) {
             return None;
         }
 
+        let [top_left, top_right, bottom_left, bottom_right] = corners(center, dimensions);
+
         {
             let top_left = self.dlt.insert(top_left);
             let top_right = self.dlt.insert(top_right);
@@ -236,6 +238,18 @@ fn triarea2(a: Vec2, b: Vec2, c: Vec2) -> f32 {
     -area
 }
 
+fn corners(center: Vec2, dimensions: Vec2) -> [Point2<f32>; 4] {
+    let tl = center - dimensions / 2.0;
+    let br = center + dimensions / 2.0;
+
+    [
+        Point2::new(tl.x, tl.y),
+        Point2::new(br.x, tl.y),
+        Point2::new(tl.x, br.y),
+        Point2::new(br.x, br.y),
+    ]
+}
+
 pub fn funnel(portals: &[(Vec2, Vec2)]) -> Vec<Vec2> {
     // Implementation of the Simple Stupid Funnel Algorithm
     // http://digestingduck.blogspot.com/2010/03/simple-stupid-funnel-algorithm.html
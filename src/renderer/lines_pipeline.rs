@@ -126,8 +126,8 @@ impl LinesPipeline {
 }
 
 use lyon_tessellation::{
-    basic_shapes::{fill_rectangle, stroke_rectangle},
-    math::{rect, Point},
+    basic_shapes::{fill_rectangle, stroke_quad, stroke_rectangle},
+    math::{point, rect, Point},
     BasicVertexConstructor, BuffersBuilder, FillOptions, StrokeAttributes, StrokeOptions,
     StrokeVertexConstructor, VertexBuffers,
 };
@@ -167,21 +167,41 @@ pub struct LineBuffers {
 pub enum Image {
     BuildPump,
     BuildArmoury,
+    BuildLarder,
+    BuildTurret,
     RecruitEngineer,
     RecruitMouseMarine,
+    RecruitPsychic,
+    RecruitArtillery,
+    MindControl,
     SetRecruitmentWaypoint,
     CheeseCoins,
+    ResearchMarineDamage,
+    ResearchMarineRange,
+    ResearchEngineerBuildSpeed,
+    ResearchPumpIncome,
+    ResearchBuildingArmour,
 }
 
 impl Image {
     fn uv(&self) -> (Vec2, Vec2) {
         match self {
-            Self::BuildPump => (Vec2::new(0.0, 0.0), Vec2::new(0.25, 0.5)),
-            Self::BuildArmoury => (Vec2::new(0.25, 0.0), Vec2::new(0.25, 0.5)),
-            Self::RecruitEngineer => (Vec2::new(0.0, 0.5), Vec2::new(0.25, 0.5)),
-            Self::RecruitMouseMarine => (Vec2::new(0.25, 0.5), Vec2::new(0.25, 0.5)),
-            Self::SetRecruitmentWaypoint => (Vec2::new(0.5, 0.0), Vec2::new(0.25, 0.5)),
-            Self::CheeseCoins => (Vec2::new(0.75, 0.5), Vec2::new(0.125, 0.25)),
+            Self::BuildPump => (Vec2::new(0.0, 0.0), Vec2::new(0.25, 0.25)),
+            Self::BuildArmoury => (Vec2::new(0.25, 0.0), Vec2::new(0.25, 0.25)),
+            Self::BuildLarder => (Vec2::new(0.5, 0.25), Vec2::new(0.25, 0.25)),
+            Self::BuildTurret => (Vec2::new(0.75, 0.75), Vec2::new(0.25, 0.25)),
+            Self::RecruitEngineer => (Vec2::new(0.0, 0.25), Vec2::new(0.25, 0.25)),
+            Self::RecruitMouseMarine => (Vec2::new(0.25, 0.25), Vec2::new(0.25, 0.25)),
+            Self::RecruitPsychic => (Vec2::new(0.75, 0.0), Vec2::new(0.25, 0.25)),
+            Self::RecruitArtillery => (Vec2::new(0.5, 0.75), Vec2::new(0.25, 0.25)),
+            Self::MindControl => (Vec2::new(0.25, 0.75), Vec2::new(0.25, 0.25)),
+            Self::SetRecruitmentWaypoint => (Vec2::new(0.5, 0.0), Vec2::new(0.25, 0.25)),
+            Self::CheeseCoins => (Vec2::new(0.75, 0.25), Vec2::new(0.125, 0.125)),
+            Self::ResearchMarineDamage => (Vec2::new(0.0, 0.5), Vec2::new(0.25, 0.25)),
+            Self::ResearchMarineRange => (Vec2::new(0.25, 0.5), Vec2::new(0.25, 0.25)),
+            Self::ResearchEngineerBuildSpeed => (Vec2::new(0.5, 0.5), Vec2::new(0.25, 0.25)),
+            Self::ResearchPumpIncome => (Vec2::new(0.75, 0.5), Vec2::new(0.25, 0.25)),
+            Self::ResearchBuildingArmour => (Vec2::new(0.0, 0.75), Vec2::new(0.25, 0.25)),
         }
     }
 }
@@ -286,6 +306,27 @@ impl LineBuffers {
         self.buffer();
     }
 
+    /// Draw the outline of a quad with corners in clockwise or anticlockwise order.
+    pub fn draw_quad(&mut self, corners: [Vec2; 4], colour: Vec4, dpi_scaling: f32) {
+        let mut options = StrokeOptions::default();
+        options.line_width = dpi_scaling;
+
+        let [a, b, c, d] = corners;
+        let to_point = |corner: Vec2| point(corner.x, corner.y);
+
+        stroke_quad(
+            to_point(a),
+            to_point(b),
+            to_point(c),
+            to_point(d),
+            &options,
+            &mut BuffersBuilder::new(&mut self.lyon_buffers, Constructor { colour }),
+        )
+        .unwrap();
+
+        self.buffer();
+    }
+
     fn buffer(&mut self) {
         let num_vertices = self.vertices.len_waiting();
 
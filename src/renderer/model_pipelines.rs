@@ -479,6 +479,11 @@ pub struct ModelBuffers {
     pub mice_engineers: DynamicBuffer<ModelInstance>,
     pub mice_engineers_joints: JointBuffer,
 
+    pub mice_psychics: DynamicBuffer<ModelInstance>,
+    pub mice_psychics_joints: JointBuffer,
+    pub mice_artillery: DynamicBuffer<ModelInstance>,
+    pub mice_artillery_joints: JointBuffer,
+
     pub pumps: DynamicBuffer<ModelInstance>,
     pub pump_joints: JointBuffer,
 
@@ -486,6 +491,9 @@ pub struct ModelBuffers {
     pub command_indicators: DynamicBuffer<ModelInstance>,
     pub command_paths: DynamicBuffer<ModelInstance>,
     pub armouries: DynamicBuffer<ModelInstance>,
+    pub larders: DynamicBuffer<ModelInstance>,
+    pub turrets: DynamicBuffer<ModelInstance>,
+    pub turret_heads: DynamicBuffer<ModelInstance>,
     pub cheese_droplets: DynamicBuffer<ModelInstance>,
     pub explosions: DynamicBuffer<ModelInstance>,
 
@@ -521,6 +529,32 @@ impl ModelBuffers {
                 "Cheese mice engineers joints bind group",
                 &assets.mouse_model,
             ),
+            mice_psychics: DynamicBuffer::new(
+                &context.device,
+                50,
+                "Cheese mice psychics instance buffer",
+                wgpu::BufferUsage::VERTEX,
+            ),
+            mice_psychics_joints: JointBuffer::new(
+                context,
+                400,
+                "Cheese mice psychics joints buffer",
+                "Cheese mice psychics joints bind group",
+                &assets.mouse_model,
+            ),
+            mice_artillery: DynamicBuffer::new(
+                &context.device,
+                50,
+                "Cheese mice artillery instance buffer",
+                wgpu::BufferUsage::VERTEX,
+            ),
+            mice_artillery_joints: JointBuffer::new(
+                context,
+                400,
+                "Cheese mice artillery joints buffer",
+                "Cheese mice artillery joints bind group",
+                &assets.mouse_model,
+            ),
             pumps: DynamicBuffer::new(
                 &context.device,
                 10,
@@ -559,6 +593,24 @@ impl ModelBuffers {
                 "Cheese armoury buffer",
                 wgpu::BufferUsage::VERTEX,
             ),
+            larders: DynamicBuffer::new(
+                &context.device,
+                10,
+                "Cheese larder buffer",
+                wgpu::BufferUsage::VERTEX,
+            ),
+            turrets: DynamicBuffer::new(
+                &context.device,
+                20,
+                "Cheese turret buffer",
+                wgpu::BufferUsage::VERTEX,
+            ),
+            turret_heads: DynamicBuffer::new(
+                &context.device,
+                20,
+                "Cheese turret head buffer",
+                wgpu::BufferUsage::VERTEX,
+            ),
             cheese_droplets: DynamicBuffer::new(
                 &context.device,
                 5000,
@@ -588,16 +640,25 @@ impl ModelBuffers {
         self.command_indicators.upload(context);
         self.command_paths.upload(context);
         self.armouries.upload(context);
+        self.larders.upload(context);
+        self.turrets.upload(context);
+        self.turret_heads.upload(context);
         self.cheese_droplets.upload(context);
         self.pumps.upload(context);
         self.building_plan.upload(context);
         self.mice_marines.upload(context);
         self.mice_engineers.upload(context);
+        self.mice_psychics.upload(context);
+        self.mice_artillery.upload(context);
         self.explosions.upload(context);
         self.mice_marines_joints
             .upload(context, &assets.mouse_model);
         self.mice_engineers_joints
             .upload(context, &assets.mouse_model);
+        self.mice_psychics_joints
+            .upload(context, &assets.mouse_model);
+        self.mice_artillery_joints
+            .upload(context, &assets.mouse_model);
         self.pump_joints.upload(context, &assets.pump_model);
     }
 }
@@ -0,0 +1,313 @@
+use crate::ecs::{self, EntityId, PlayerCommand, Unit};
+use crate::resources::{AiDifficulty, Tick, TotalTime};
+use crate::scenarios::BuildOrderItem;
+use crate::Simulation;
+use legion::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use ultraviolet::Vec2;
+
+/// Bumped whenever a change to the format (or to the simulation) means that old replays won't play
+/// back correctly.
+pub const REPLAY_VERSION: u32 = 5;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedCommand {
+    pub tick: u64,
+    pub command: PlayerCommand,
+}
+
+/// Everything needed to play a match back: the scenario, seed and difficulty it was started with,
+/// and every command the player gave along with the tick it was applied on.
+///
+/// While a match is being played this is kept as a resource and commands are added to it as they
+/// are applied.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Replay {
+    pub version: u32,
+    pub scenario: String,
+    pub seed: u64,
+    pub difficulty: AiDifficulty,
+    // The hash of the definitions that the match was played with. Different stats would make it play
+    // out differently.
+    pub definitions_hash: u64,
+    // How many ticks the match lasted for. Only set once the match is over.
+    pub ticks: u64,
+    pub commands: Vec<RecordedCommand>,
+}
+
+impl Replay {
+    pub fn new(scenario: &str, seed: u64, difficulty: AiDifficulty) -> Self {
+        Self {
+            version: REPLAY_VERSION,
+            scenario: scenario.to_string(),
+            seed,
+            difficulty,
+            definitions_hash: crate::definitions::hash(),
+            ticks: 0,
+            commands: Vec::new(),
+        }
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let string = std::fs::read_to_string(path)?;
+        let replay: Self = ron::de::from_str(&string)?;
+
+        if replay.version != REPLAY_VERSION {
+            return Err(anyhow::anyhow!(
+                "{} is version {} but only version {} replays are supported",
+                path.display(),
+                replay.version,
+                REPLAY_VERSION
+            ));
+        }
+
+        if replay.definitions_hash != crate::definitions::hash() {
+            log::warn!(
+                "{} was recorded with different definitions, so it may not play back correctly",
+                path.display()
+            );
+        }
+
+        Ok(replay)
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let string = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        std::fs::write(path, string)?;
+        Ok(())
+    }
+
+    /// Where a replay for this match would be saved to. Includes the time so that playing the same
+    /// scenario with the same seed twice doesn't overwrite the first replay.
+    pub fn default_path(&self) -> PathBuf {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+
+        PathBuf::from("replays").join(format!(
+            "scenario-{}-seed-{}-{}.ron",
+            self.scenario, self.seed, timestamp
+        ))
+    }
+
+    /// The most recently saved replay in the `replays` directory, if there are any.
+    pub fn latest_path() -> Option<PathBuf> {
+        std::fs::read_dir("replays")
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let modified = entry.metadata().and_then(|metadata| metadata.modified());
+                modified.ok().map(|modified| (modified, entry.path()))
+            })
+            .max()
+            .map(|(_, path)| path)
+    }
+
+    /// Turn the player's commands into build orders that the enemy can follow in a scenario, so
+    /// that AI behaviour can be written by playing. The match is played back to find out what the
+    /// commands referred to. If `mirror` is set, positions are flipped through the center of the
+    /// map, so that the orders work for the opposite corner.
+    ///
+    /// Only the commands that have a matching build order are converted. Commands given to units
+    /// other than marines, such as moving engineers around, are left out.
+    pub fn to_build_orders(&self, mirror: bool) -> anyhow::Result<Vec<(f32, BuildOrderItem)>> {
+        let mirrored = |position: Vec2| if mirror { -position } else { position };
+
+        let mut simulation = Simulation::new();
+        simulation.start_replay(self.clone())?;
+
+        let mut orders = Vec::new();
+
+        for recorded in &self.commands {
+            // Stop just before the tick that the command is applied on.
+            while simulation.resources.get::<Tick>().unwrap().0 < recorded.tick {
+                simulation.tick();
+            }
+
+            let world = &simulation.world;
+            let time = simulation.resources.get::<TotalTime>().unwrap().0;
+
+            let entities: HashMap<EntityId, Entity> = <(Entity, &EntityId)>::query()
+                .iter(world)
+                .map(|(entity, id)| (*id, *entity))
+                .collect();
+
+            let includes_marines = |units: &[EntityId]| {
+                units.iter().any(|id| {
+                    entities
+                        .get(id)
+                        .and_then(|entity| <&Unit>::query().get(world, *entity).ok())
+                        == Some(&Unit::MouseMarine)
+                })
+            };
+
+            let item = match &recorded.command {
+                PlayerCommand::PlaceBuilding {
+                    building: ecs::Building::Pump,
+                    position,
+                    ..
+                } => guyser_near(*position, world)
+                    .map(|guyser| BuildOrderItem::BuildPump(mirrored(guyser))),
+                PlayerCommand::PlaceBuilding {
+                    building: ecs::Building::Armoury,
+                    position,
+                    ..
+                } => Some(BuildOrderItem::BuildArmoury(mirrored(*position))),
+                PlayerCommand::PlaceBuilding {
+                    building: ecs::Building::Larder,
+                    position,
+                    ..
+                } => Some(BuildOrderItem::BuildLarder(mirrored(*position))),
+                PlayerCommand::PlaceBuilding {
+                    building: ecs::Building::Turret,
+                    position,
+                    ..
+                } => Some(BuildOrderItem::BuildTurret(mirrored(*position))),
+                PlayerCommand::Recruit {
+                    unit: Unit::MouseMarine,
+                    ..
+                } => {
+                    // Merge marines recruited at the same time.
+                    if let Some((last_time, BuildOrderItem::RecruitMarine(count))) =
+                        orders.last_mut()
+                    {
+                        if *last_time == time {
+                            *count += 1;
+                            continue;
+                        }
+                    }
+
+                    Some(BuildOrderItem::RecruitMarine(1))
+                }
+                PlayerCommand::SetWaypoint { position, .. } => {
+                    Some(BuildOrderItem::SetWaypoint(mirrored(*position)))
+                }
+                PlayerCommand::Move { units, target, .. }
+                | PlayerCommand::AttackMove { units, target, .. }
+                    if includes_marines(units) =>
+                {
+                    Some(BuildOrderItem::AttackMove(mirrored(*target)))
+                }
+                PlayerCommand::Attack { units, target, .. } if includes_marines(units) => entities
+                    .get(target)
+                    .and_then(|entity| <&ecs::Position>::query().get(world, *entity).ok())
+                    .map(|position| BuildOrderItem::AttackMove(mirrored(position.0))),
+                command => {
+                    log::debug!("Leaving {:?} out of the build orders", command);
+                    None
+                }
+            };
+
+            orders.extend(item.map(|item| (time, item)));
+        }
+
+        Ok(orders)
+    }
+}
+
+// The position of the guyser that a pump placed at `position` would be built on.
+fn guyser_near(position: Vec2, world: &World) -> Option<Vec2> {
+    <&ecs::Position>::query()
+        .filter(component::<ecs::CheeseGuyser>())
+        .iter(world)
+        .map(|guyser| guyser.0)
+        .find(|guyser| (*guyser - position).mag_sq() <= 4.0_f32.powi(2))
+}
+
+#[test]
+fn round_trip() {
+    use crate::ecs::{Building, EntityId};
+    use ultraviolet::Vec2;
+
+    let mut replay = Replay::new("3-skirmish", 1234, AiDifficulty::Hard);
+    replay.ticks = 6000;
+    replay.commands.push(RecordedCommand {
+        tick: 10,
+        command: PlayerCommand::PlaceBuilding {
+            units: vec![EntityId(4), EntityId(5)],
+            building: Building::Armoury,
+            position: Vec2::new(-12.5, 3.0),
+            queued: false,
+        },
+    });
+    replay.commands.push(RecordedCommand {
+        tick: 600,
+        command: PlayerCommand::SetControlGroup {
+            group: 1,
+            entities: vec![EntityId(4)],
+        },
+    });
+
+    let string = ron::ser::to_string(&replay).unwrap();
+    let loaded: Replay = ron::de::from_str(&string).unwrap();
+
+    assert_eq!(replay, loaded);
+}
+
+#[test]
+fn converts_to_build_orders() {
+    use crate::ecs::{Building, CheeseGuyser, CheeseGuyserBuiltOn, Position, Side};
+
+    let mut simulation = Simulation::new();
+    simulation.start_scenario("3-skirmish", 0).unwrap();
+    simulation.tick();
+
+    let engineers: Vec<EntityId> = <(&EntityId, &Unit, &Side)>::query()
+        .iter(&simulation.world)
+        .filter(|(_, unit, side)| **unit == Unit::Engineer && **side == Side::Green)
+        .map(|(id, ..)| *id)
+        .collect();
+    let guyser = <&Position>::query()
+        .filter(component::<CheeseGuyser>() & !component::<CheeseGuyserBuiltOn>())
+        .iter(&simulation.world)
+        .next()
+        .unwrap()
+        .0;
+
+    let mut replay = Replay::new("3-skirmish", 0, AiDifficulty::Normal);
+    replay.commands.push(RecordedCommand {
+        tick: 30,
+        command: PlayerCommand::PlaceBuilding {
+            units: engineers.clone(),
+            building: Building::Pump,
+            position: guyser + Vec2::new(1.0, 0.5),
+            queued: false,
+        },
+    });
+    replay.commands.push(RecordedCommand {
+        tick: 45,
+        command: PlayerCommand::PlaceBuilding {
+            units: engineers,
+            building: Building::Turret,
+            position: Vec2::new(-40.0, -45.0),
+            queued: true,
+        },
+    });
+    replay.commands.push(RecordedCommand {
+        tick: 60,
+        command: PlayerCommand::SetWaypoint {
+            buildings: Vec::new(),
+            position: Vec2::new(5.0, -10.0),
+        },
+    });
+    replay.ticks = 61;
+
+    let orders = replay.to_build_orders(true).unwrap();
+
+    assert_eq!(orders.len(), 3);
+    assert!(matches!(orders[0].1, BuildOrderItem::BuildPump(position) if position == -guyser));
+    assert!(
+        matches!(orders[1].1, BuildOrderItem::BuildTurret(position) if position == Vec2::new(40.0, 45.0))
+    );
+    assert!(
+        matches!(orders[2].1, BuildOrderItem::SetWaypoint(position) if position == Vec2::new(-5.0, 10.0))
+    );
+    assert!(orders[0].0 < orders[1].0 && orders[1].0 < orders[2].0);
+}
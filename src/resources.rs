@@ -1,5 +1,9 @@
 use crate::ecs;
+use crate::scenarios::{BuildOrderItem, Scenario, UnitPlacement};
 use legion::Entity;
+use rand::SeedableRng;
+use rand_pcg::Pcg64Mcg;
+use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use ultraviolet::{Mat4, Vec2, Vec3, Vec4};
 use winit::event::VirtualKeyCode;
@@ -35,6 +39,8 @@ pub enum CommandMode {
     AttackMove,
     Construct { building: ecs::Building },
     SetRecruitmentWaypoint,
+    // Picking a target for the selected units to cast the spell on.
+    CastAbility { spell: ecs::Spell },
 }
 
 impl Default for CommandMode {
@@ -43,6 +49,7 @@ impl Default for CommandMode {
     }
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Camera {
     pub distance: f32,
     pub looking_at: Vec2,
@@ -219,34 +226,205 @@ impl MouseButtonState {
 }
 
 pub struct PlayerSide(pub ecs::Side);
+
+/// Which team each side is on. Sides on the same team are allies: they don't attack each other and
+/// win or lose together. Sides on different teams are enemies.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Alliances([u8; ecs::Side::ALL.len()]);
+
+impl Default for Alliances {
+    // A free-for-all, with every side on its own team.
+    fn default() -> Self {
+        let mut teams = [0; ecs::Side::ALL.len()];
+        for (i, team) in teams.iter_mut().enumerate() {
+            *team = i as u8;
+        }
+        Self(teams)
+    }
+}
+
+impl Alliances {
+    /// Sides that aren't in any of the teams play on their own.
+    pub fn new(teams: &[Vec<ecs::Side>]) -> Self {
+        let mut alliances = Self::default();
+
+        for team in teams {
+            if let Some(first) = team.first() {
+                let team_number = alliances.0[first.index()];
+
+                for side in team {
+                    alliances.0[side.index()] = team_number;
+                }
+            }
+        }
+
+        alliances
+    }
+
+    pub fn are_allies(&self, a: ecs::Side, b: ecs::Side) -> bool {
+        self.0[a.index()] == self.0[b.index()]
+    }
+
+    pub fn are_enemies(&self, a: ecs::Side, b: ecs::Side) -> bool {
+        !self.are_allies(a, b)
+    }
+}
+
+// The visibility grid covers this far from the center of the map in each direction.
+const VISIBILITY_EXTENT: f32 = 100.0;
+const VISIBILITY_CELL_SIZE: f32 = 2.0;
+const VISIBILITY_CELLS: usize = (VISIBILITY_EXTENT * 2.0 / VISIBILITY_CELL_SIZE) as usize;
+
+/// The fog of war: which parts of the map each side can see at the moment, and where it last saw
+/// enemy buildings. Allies share what they see.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Visibility {
+    // Without fog of war (e.g. in the editor), everything is visible to every side.
+    pub fog_of_war: bool,
+    // A grid of cells for each side. This is worked out from scratch every tick, so it isn't saved.
+    #[serde(skip)]
+    grids: Vec<Vec<bool>>,
+    pub ghosts: Vec<BuildingGhost>,
+}
+
+/// An enemy building as a side last saw it. It stays until the side sees that spot again.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BuildingGhost {
+    pub seen_by: ecs::Side,
+    pub building: ecs::Building,
+    pub side: ecs::Side,
+    pub position: Vec2,
+}
+
+impl Visibility {
+    pub fn new(fog_of_war: bool) -> Self {
+        Self {
+            fog_of_war,
+            ..Default::default()
+        }
+    }
+
+    /// Hide everything from every side.
+    pub fn clear(&mut self) {
+        self.grids.resize(ecs::Side::ALL.len(), Vec::new());
+
+        for grid in &mut self.grids {
+            grid.clear();
+            grid.resize(VISIBILITY_CELLS * VISIBILITY_CELLS, false);
+        }
+    }
+
+    /// Let `side` see the cells within `radius` of `center`.
+    pub fn reveal(&mut self, side: ecs::Side, center: Vec2, radius: f32) {
+        let grid = &mut self.grids[side.index()];
+
+        let cell_range = |center: f32| {
+            let cell = |position: f32| {
+                ((position + VISIBILITY_EXTENT) / VISIBILITY_CELL_SIZE)
+                    .max(0.0)
+                    .min((VISIBILITY_CELLS - 1) as f32) as usize
+            };
+            cell(center - radius)..=cell(center + radius)
+        };
+
+        for y in cell_range(center.y) {
+            for x in cell_range(center.x) {
+                let cell_center = Vec2::new(x as f32 + 0.5, y as f32 + 0.5) * VISIBILITY_CELL_SIZE
+                    - Vec2::broadcast(VISIBILITY_EXTENT);
+
+                if (cell_center - center).mag_sq() <= radius.powi(2) {
+                    grid[y * VISIBILITY_CELLS + x] = true;
+                }
+            }
+        }
+    }
+
+    pub fn is_visible(&self, side: ecs::Side, position: Vec2) -> bool {
+        if !self.fog_of_war {
+            return true;
+        }
+
+        let cell = (position + Vec2::broadcast(VISIBILITY_EXTENT)) / VISIBILITY_CELL_SIZE;
+        let in_grid = |value: f32| value >= 0.0 && value < VISIBILITY_CELLS as f32;
+
+        if !in_grid(cell.x) || !in_grid(cell.y) {
+            return false;
+        }
+
+        self.grids
+            .get(side.index())
+            .and_then(|grid| grid.get(cell.y as usize * VISIBILITY_CELLS + cell.x as usize))
+            .copied()
+            .unwrap_or(false)
+    }
+
+    /// Whether `viewer` can see something belonging to `side` at `position`. Allies can always see
+    /// each other's things.
+    pub fn can_see(
+        &self,
+        viewer: ecs::Side,
+        side: ecs::Side,
+        position: Vec2,
+        alliances: &Alliances,
+    ) -> bool {
+        alliances.are_allies(viewer, side) || self.is_visible(viewer, position)
+    }
+
+    /// The enemy buildings that `side` knows about, including the ones it can currently see.
+    pub fn ghosts_seen_by(&self, side: ecs::Side) -> impl Iterator<Item = &BuildingGhost> {
+        self.ghosts
+            .iter()
+            .filter(move |ghost| ghost.seen_by == side)
+    }
+}
+
 pub struct DeltaTime(pub f32);
 pub struct CursorIcon(pub winit::window::CursorIcon);
 #[derive(Default, Debug)]
 pub struct RayCastLocation {
     pub pos: Vec2,
     pub snapped_to_guyser: Option<Entity>,
+    // Whether the mouse is over the minimap, in which case `pos` is the position on the map that
+    // it's pointing at.
+    pub on_minimap: bool,
 }
 pub struct DpiScaling(pub f32);
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct ControlGroups(pub [Vec<legion::Entity>; 10]);
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Mode {
     Titlescreen,
     Playing,
     PlayingMenu,
     Quit,
-    StartScenario(u8),
+    // The name of the scenario to start and the seed to start it with. A random seed is picked if
+    // it's `None`.
+    StartScenario(String, Option<u64>),
     ScenarioWon,
     ScenarioLost,
+    // Load a replay and start watching it.
+    StartReplay,
+    Replay,
+    // Save or load the match from the playing menu.
+    SaveGame,
+    LoadGame,
+    // Open the scenario with this name in the editor, or a new scenario if it's `None`.
+    StartEditor(Option<String>),
+    Editor,
 }
 
 impl Mode {
     pub fn should_render(&self) -> bool {
         matches!(
             self,
-            Self::Playing | Self::PlayingMenu | Self::ScenarioWon | Self::ScenarioLost
+            Self::Playing
+                | Self::PlayingMenu
+                | Self::ScenarioWon
+                | Self::ScenarioLost
+                | Self::Replay
+                | Self::Editor
         )
     }
 
@@ -259,7 +437,117 @@ impl Mode {
 }
 
 pub struct Gravity(pub f32);
-pub struct CheeseCoins(pub u32);
+
+/// How many cheese coins each side has. Every side pays for its own buildings and units.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct CheeseCoins {
+    coins: [u32; ecs::Side::ALL.len()],
+    // Fractions of a coin that have been earned but not added yet.
+    remainders: [f32; ecs::Side::ALL.len()],
+}
+
+impl CheeseCoins {
+    /// Every side starts with the same amount.
+    pub fn new(coins: u32) -> Self {
+        Self {
+            coins: [coins; ecs::Side::ALL.len()],
+            remainders: [0.0; ecs::Side::ALL.len()],
+        }
+    }
+
+    pub fn get(&self, side: ecs::Side) -> u32 {
+        self.coins[side.index()]
+    }
+
+    pub fn add(&mut self, side: ecs::Side, coins: u32) {
+        self.coins[side.index()] += coins;
+    }
+
+    /// Add `income` to what `side` has, keeping any fraction of a coin until it adds up to a whole
+    /// one. Returns the number of whole coins that were added.
+    pub fn add_income(&mut self, side: ecs::Side, income: f32) -> u32 {
+        let remainder = &mut self.remainders[side.index()];
+        let income = income + *remainder;
+        *remainder = income.fract();
+
+        let coins = income as u32;
+        self.add(side, coins);
+        coins
+    }
+
+    /// Take `cost` away from what `side` has, if it can afford it.
+    pub fn spend(&mut self, side: ecs::Side, cost: u32) -> bool {
+        let coins = &mut self.coins[side.index()];
+
+        if *coins < cost {
+            return false;
+        }
+
+        *coins -= cost;
+        true
+    }
+}
+
+/// The upgrades that each side has finished researching.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Upgrades([Vec<ecs::Upgrade>; ecs::Side::ALL.len()]);
+
+impl Upgrades {
+    pub fn has(&self, side: ecs::Side, upgrade: ecs::Upgrade) -> bool {
+        self.0[side.index()].contains(&upgrade)
+    }
+
+    pub fn add(&mut self, side: ecs::Side, upgrade: ecs::Upgrade) {
+        if !self.has(side, upgrade) {
+            self.0[side.index()].push(upgrade);
+        }
+    }
+
+    /// How big the upgrade's effect is for `side`, or 0 if it hasn't researched it.
+    pub fn amount(&self, side: ecs::Side, upgrade: ecs::Upgrade) -> f32 {
+        if self.has(side, upgrade) {
+            upgrade.stats().amount
+        } else {
+            0.0
+        }
+    }
+}
+
+/// How much supply each side's units take up, and how much its buildings provide. This is counted
+/// up again at the start of every tick, so it isn't saved.
+#[derive(Clone, Debug, Default)]
+pub struct Supply {
+    used: [u32; ecs::Side::ALL.len()],
+    provided: [u32; ecs::Side::ALL.len()],
+}
+
+impl Supply {
+    /// The cap that every side has without any buildings.
+    pub const BASE: u32 = 10;
+    /// The cap can't be raised past this, no matter how many buildings a side has.
+    pub const MAX: u32 = 100;
+
+    pub fn used(&self, side: ecs::Side) -> u32 {
+        self.used[side.index()]
+    }
+
+    pub fn cap(&self, side: ecs::Side) -> u32 {
+        (Self::BASE + self.provided[side.index()]).min(Self::MAX)
+    }
+
+    pub fn has_room_for(&self, side: ecs::Side, unit: ecs::Unit) -> bool {
+        self.used(side) + unit.stats().supply <= self.cap(side)
+    }
+
+    pub fn add_unit(&mut self, side: ecs::Side, unit: ecs::Unit) {
+        self.used[side.index()] += unit.stats().supply;
+    }
+
+    pub fn add_building(&mut self, side: ecs::Side, building: ecs::Building) {
+        self.provided[side.index()] += building.stats().supply_provided;
+    }
+}
+
 #[derive(Default)]
 pub struct SelectedUnitsAbilities(pub BTreeMap<&'static ecs::Ability, Vec<Entity>>);
 
@@ -272,26 +560,183 @@ pub struct Keypress {
 #[derive(Default)]
 pub struct Keypresses(pub Vec<Keypress>);
 
-#[derive(derive_more::Display)]
+// The `#[serde(default)]` fields track progress towards the conditions. They're updated by
+// `update_playing_state`, and are only ever set in save games, not in scenarios.
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, derive_more::Display)]
 pub enum WinCondition {
-    #[display(fmt = "Build {} {}", _0, "_1.maybe_plural(*_0)")]
-    BuildN(u8, ecs::Building),
+    #[display(fmt = "Build {} {} ({}/{})", _0, "_1.maybe_plural(*_0)", _2, _0)]
+    BuildN(u8, ecs::Building, #[serde(default)] u8),
     #[display(fmt = "Destroy all enemies")]
     DestroyAll,
+    #[display(
+        fmt = "Survive for {:.0} seconds ({:.0}/{:.0})",
+        seconds,
+        elapsed,
+        seconds
+    )]
+    Survive {
+        seconds: f32,
+        #[serde(default)]
+        elapsed: f32,
+    },
+    // Have units in the region without any enemies in it.
+    #[display(
+        fmt = "Hold {} for {:.0} seconds ({:.0}/{:.0})",
+        name,
+        seconds,
+        held,
+        seconds
+    )]
+    HoldRegion {
+        name: String,
+        region: Region,
+        seconds: f32,
+        #[serde(default)]
+        held: f32,
+    },
+    // Have a fully built pump on the guyser at `position`.
+    #[display(
+        fmt = "Hold the guyser at ({:.0}, {:.0}) for {:.0} seconds ({:.0}/{:.0})",
+        "position.x",
+        "position.y",
+        seconds,
+        held,
+        seconds
+    )]
+    HoldGuyser {
+        position: Vec2,
+        seconds: f32,
+        #[serde(default)]
+        held: f32,
+    },
+    #[display(
+        fmt = "Gather {} cheese coins ({}/{})",
+        coins,
+        "current.min(coins)",
+        coins
+    )]
+    GatherCoins {
+        coins: u32,
+        #[serde(default)]
+        current: u32,
+    },
+    // Destroy everything with this `Tag`.
+    #[display(fmt = "Destroy the {}", _0)]
+    DestroyTagged(String),
+    #[display(fmt = "Recruit {} units ({}/{})", count, "recruited.min(count)", count)]
+    RecruitN {
+        count: u32,
+        #[serde(default)]
+        recruited: u32,
+    },
 }
 
-#[derive(derive_more::Display)]
+#[derive(Clone, PartialEq, Serialize, Deserialize, derive_more::Display)]
 pub enum LoseCondition {
     #[display(fmt = "Keep at least one unit alive")]
     LetAllUnitsDie,
+    // Lose if everything with this `Tag` is destroyed.
+    #[display(fmt = "Protect the {}", _0)]
+    LoseTagged(String),
+    #[display(
+        fmt = "Win within {:.0} seconds ({:.0} left)",
+        seconds,
+        "(seconds - elapsed).max(0.0)"
+    )]
+    TimeLimit {
+        seconds: f32,
+        #[serde(default)]
+        elapsed: f32,
+    },
 }
 
-#[derive(Default)]
+impl WinCondition {
+    /// The tag that this condition refers to, if any.
+    pub fn tag(&self) -> Option<&String> {
+        match self {
+            Self::DestroyTagged(tag) => Some(tag),
+            _ => None,
+        }
+    }
+}
+
+impl LoseCondition {
+    /// The tag that this condition refers to, if any.
+    pub fn tag(&self) -> Option<&String> {
+        match self {
+            Self::LoseTagged(tag) => Some(tag),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct Objectives {
     pub win_conditions: Vec<WinCondition>,
     pub lose_conditions: Vec<LoseCondition>,
 }
 
+/// A circular area of the map.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Region {
+    pub center: Vec2,
+    pub radius: f32,
+}
+
+impl Region {
+    pub fn contains(&self, point: Vec2) -> bool {
+        (point - self.center).mag_sq() <= self.radius.powi(2)
+    }
+}
+
+/// Something that happens during a scenario once a condition is met. Triggers only fire once.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Trigger {
+    pub condition: TriggerCondition,
+    pub actions: Vec<TriggerAction>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum TriggerCondition {
+    // Seconds since the start of the match.
+    Time(f32),
+    // At least `count` units of `side` are in `region`.
+    UnitsInRegion {
+        side: ecs::Side,
+        region: Region,
+        count: u32,
+    },
+    // `side` has at least `count` fully built buildings of this kind.
+    BuildingsCompleted {
+        side: ecs::Side,
+        building: ecs::Building,
+        count: u32,
+    },
+    // Every entity with this `Tag` has been destroyed.
+    Destroyed(String),
+    CoinsReached(u32),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum TriggerAction {
+    Spawn(UnitPlacement),
+    Message(String),
+    GrantCoins(u32),
+    SetObjectives(Objectives),
+    MoveCamera(Vec2),
+    // Give the AI an order to follow straight away.
+    AiOrder(BuildOrderItem),
+}
+
+/// The triggers in a scenario that haven't fired yet.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Triggers(pub Vec<Trigger>);
+
+/// The most recent message shown by a trigger, and the `TotalTime` that it was shown at.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct ScenarioMessage(pub Option<(String, f32)>);
+
 pub struct Settings {
     pub bloom: bool,
     pub shadow_resolution: u32,
@@ -306,25 +751,249 @@ impl Default for Settings {
     }
 }
 
+#[derive(Clone)]
 pub struct TotalTime(pub f32);
+/// The `EntityId` that will be given to the next unit or building.
+#[derive(Clone)]
+pub struct NextEntityId(pub u32);
+/// Commands from the player that will be applied at the start of the next tick.
+#[derive(Default)]
+pub struct PlayerCommands(pub Vec<ecs::PlayerCommand>);
+
+/// The scenario that's being played and the seed that the match was started with. Starting the
+/// same scenario with the same seed gives the same random numbers.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MatchSettings {
+    pub scenario: String,
+    pub seed: u64,
+    pub difficulty: AiDifficulty,
+}
 
-#[derive(Debug)]
+/// How well the enemy plays, whether it follows build orders or is the reactive `AiPlayer`. As a
+/// resource, this is the difficulty that the next match is started with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, derive_more::Display)]
+pub enum AiDifficulty {
+    Easy,
+    #[default]
+    Normal,
+    Hard,
+    Insane,
+}
+
+impl AiDifficulty {
+    pub fn next(self) -> Self {
+        match self {
+            Self::Easy => Self::Normal,
+            Self::Normal => Self::Hard,
+            Self::Hard => Self::Insane,
+            Self::Insane => Self::Easy,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Easy => "Difficulty: Easy",
+            Self::Normal => "Difficulty: Normal",
+            Self::Hard => "Difficulty: Hard",
+            Self::Insane => "Difficulty: Insane",
+        }
+    }
+
+    /// What the times of build orders are multiplied by.
+    pub fn build_order_time_scale(self) -> f32 {
+        match self {
+            Self::Easy => 1.5,
+            Self::Normal => 1.0,
+            Self::Hard => 0.85,
+            Self::Insane => 0.7,
+        }
+    }
+
+    /// What the AI's income is multiplied by.
+    pub fn income_multiplier(self) -> f32 {
+        match self {
+            Self::Easy => 0.75,
+            Self::Normal => 1.0,
+            Self::Hard => 1.25,
+            Self::Insane => 1.5,
+        }
+    }
+
+    /// Whether the AI's units go for the weakest enemy in range instead of the closest one.
+    pub fn focus_fire(self) -> bool {
+        matches!(self, Self::Hard | Self::Insane)
+    }
+
+    /// The fraction of their health that the AI's units retreat at, if they retreat at all.
+    pub fn retreat_health(self) -> Option<f32> {
+        match self {
+            Self::Easy | Self::Normal => None,
+            Self::Hard => Some(0.25),
+            Self::Insane => Some(0.4),
+        }
+    }
+
+    /// The smallest army that the reactive AI attacks with, and the earliest that it attacks.
+    pub fn attack_timing(self) -> (usize, f32) {
+        match self {
+            Self::Easy => (8, 300.0),
+            Self::Normal => (5, 120.0),
+            Self::Hard => (4, 60.0),
+            Self::Insane => (3, 0.0),
+        }
+    }
+}
+
+/// Randomness that affects the outcome of a match. Only gameplay systems should draw from this.
+///
+/// Both streams use `Pcg64Mcg` (what `SmallRng` is on 64-bit platforms) because its state can be
+/// saved, so that a loaded match carries on with the same random numbers.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GameplayRng(pub Pcg64Mcg);
+/// Randomness for visual effects (explosions, cheese droplets, animation offsets). It's a separate
+/// stream so that how many effects are spawned can never change the gameplay random numbers.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CosmeticRng(pub Pcg64Mcg);
+
+impl GameplayRng {
+    pub fn new(seed: u64) -> Self {
+        Self(Pcg64Mcg::seed_from_u64(seed))
+    }
+}
+
+impl CosmeticRng {
+    pub fn new(seed: u64) -> Self {
+        // Any constant works here, it just needs the stream to be different from the gameplay one.
+        Self(Pcg64Mcg::seed_from_u64(seed ^ 0xC4EE_5E00_C4EE_5E00))
+    }
+}
+/// The number of fixed gameplay ticks that have run since the scenario started.
+#[derive(Clone)]
+pub struct Tick(pub u64);
+/// How far between the previous tick and the current one the current frame is, from 0 to 1.
+pub struct TickInterpolation(pub f32);
+
+/// How a replay is being watched.
+pub struct ReplayPlayback {
+    // How many times faster than realtime the replay plays.
+    pub speed: f32,
+    pub paused: bool,
+    // Set by the controls to jump to a tick. Handled at the start of the next frame.
+    pub seek_to: Option<u64>,
+    // The number of ticks that the recorded match lasted for.
+    pub length: u64,
+}
+
+impl ReplayPlayback {
+    pub const SPEEDS: [f32; 4] = [0.5, 1.0, 2.0, 8.0];
+
+    pub fn new(length: u64) -> Self {
+        Self {
+            speed: 1.0,
+            paused: false,
+            seek_to: None,
+            length,
+        }
+    }
+}
+
+/// What left clicking places in the scenario editor.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EditorTool {
+    Guyser,
+    Unit(ecs::Unit),
+    Building(ecs::Building),
+}
+
+/// The scenario editor. The scenario that's being edited is the world itself (along with the
+/// `CheeseCoins`, `Objectives` and `Camera` resources), which gets turned back into a `Scenario`
+/// when it's saved.
+pub struct Editor {
+    // The file name that the scenario is saved as, without the extension.
+    pub name: String,
+    // The scenario as it was loaded. Things that can't be edited in the editor, like the triggers,
+    // are saved from this.
+    pub scenario: Scenario,
+    pub tool: EditorTool,
+    pub side: ecs::Side,
+    // Shown at the bottom of the screen, e.g. when saving fails.
+    pub status: String,
+}
+
+impl Editor {
+    pub fn new(name: String, scenario: Scenario) -> Self {
+        Self {
+            name,
+            scenario,
+            tool: EditorTool::Guyser,
+            side: ecs::Side::Green,
+            status: String::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum AiBuildOrderItem {
-    BuildPump(Entity),
+    BuildPump(#[serde(with = "crate::save::entity")] Entity),
     BuildArmoury(Vec2),
+    BuildLarder(Vec2),
+    BuildTurret(Vec2),
     RecruitMarine(u32),
     AttackMove(Vec2),
     SetWaypoint(Vec2),
 }
 
-#[derive(Default)]
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct AiBuildOrders(pub Vec<(f32, AiBuildOrderItem)>);
 
-#[derive(Default)]
+/// A computer-controlled side. If it's `enabled`, the reactive AI plays it. Either way, it pays for
+/// everything out of its own `CheeseCoins`, which its pumps generate in the same way as the player's.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AiPlayer {
+    pub side: ecs::Side,
+    pub enabled: bool,
+    // Counts down to the next time that the AI makes decisions.
+    pub think_cooldown: f32,
+    // Where the AI's buildings were last attacked from, and the `TotalTime` that it happened at.
+    pub attacked_from: Option<(Vec2, f32)>,
+    // Where the AI's army was last sent.
+    pub army_target: Option<Vec2>,
+}
+
+impl AiPlayer {
+    pub fn new(side: ecs::Side, enabled: bool) -> Self {
+        Self {
+            side,
+            enabled,
+            think_cooldown: 0.0,
+            attacked_from: None,
+            army_target: None,
+        }
+    }
+}
+
+/// Every side in the match apart from the player's, in the order of `Side::ALL`.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct AiPlayers(pub Vec<AiPlayer>);
+
+impl AiPlayers {
+    /// Scenarios with build orders only have the one computer opponent that follows them.
+    pub fn scripted_side(&self) -> Option<ecs::Side> {
+        self.0.first().map(|ai| ai.side)
+    }
+
+    pub fn get(&self, side: ecs::Side) -> Option<&AiPlayer> {
+        self.0.iter().find(|ai| ai.side == side)
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct GameStats {
     pub units_recruited: u32,
     pub units_lost: u32,
     pub buildings_built: u32,
     pub enemy_buildings_destroyed: u32,
     pub enemy_units_killed: u32,
+    // How many cheese coins each side's pumps have made, so that economies can be compared.
+    pub coins_earned: [u32; ecs::Side::ALL.len()],
 }
@@ -0,0 +1,420 @@
+use crate::assets::ModelAnimations;
+use crate::ecs::*;
+use crate::pathfinding::Map;
+use crate::resources::{
+    AiBuildOrders, AiPlayers, Alliances, Camera, CheeseCoins, ControlGroups, CosmeticRng,
+    GameStats, GameplayRng, MatchSettings, NextEntityId, Objectives, ScenarioMessage, Tick,
+    TotalTime, Triggers, Upgrades, Visibility,
+};
+use crate::snapshot::copy_world_with_ids;
+use legion::world::{Allocate, EntryRef};
+use legion::*;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::Path;
+use ultraviolet::Vec2;
+
+/// Bumped whenever a change means that old saves can't be loaded.
+pub const SAVE_VERSION: u32 = 13;
+
+/// An in-progress match, written to disk.
+///
+/// Entities are stored in a list, and references to other entities (such as the target of an
+/// attack command) are stored as indices into it. References to entities that no longer exist get
+/// indices past the end of the list, so they stay references to entities that don't exist.
+///
+/// The `Map` isn't saved. It's rebuilt from the buildings when loading.
+#[derive(Serialize, Deserialize)]
+struct SaveGame {
+    version: u32,
+    definitions_hash: u64,
+    match_settings: MatchSettings,
+    tick: u64,
+    total_time: f32,
+    cheese_coins: CheeseCoins,
+    upgrades: Upgrades,
+    next_entity_id: u32,
+    objectives: Objectives,
+    ai_build_orders: AiBuildOrders,
+    ai_players: AiPlayers,
+    alliances: Alliances,
+    visibility: Visibility,
+    triggers: Triggers,
+    scenario_message: ScenarioMessage,
+    game_stats: GameStats,
+    control_groups: Vec<Vec<EntityRef>>,
+    camera: Camera,
+    gameplay_rng: GameplayRng,
+    cosmetic_rng: CosmeticRng,
+    entities: Vec<SavedEntity>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EntityRef(#[serde(with = "entity")] Entity);
+
+// Every gameplay component that an entity can have. `Skin`s and `MapHandle`s aren't saved, they're
+// added back when loading.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct SavedEntity {
+    entity_id: Option<EntityId>,
+    position: Option<Position>,
+    previous_position: Option<PreviousPosition>,
+    facing: Option<Facing>,
+    side: Option<Side>,
+    unit: Option<Unit>,
+    building: Option<Building>,
+    health: Option<Health>,
+    building_completeness: Option<BuildingCompleteness>,
+    command_queue: Option<CommandQueue>,
+    recruitment_queue: Option<RecruitmentQueue>,
+    abilities: Option<Abilities>,
+    cooldown: Option<Cooldown>,
+    energy: Option<Energy>,
+    spell_cooldowns: Option<SpellCooldowns>,
+    move_speed: Option<MoveSpeed>,
+    radius: Option<Radius>,
+    firing_range: Option<FiringRange>,
+    min_firing_range: Option<MinFiringRange>,
+    avoidance: Option<Avoidance>,
+    animation_state: Option<AnimationState>,
+    cheese_guyser_built_on: Option<CheeseGuyserBuiltOn>,
+    damaged_this_tick: Option<DamagedThisTick>,
+    agroed: Option<Agroed>,
+    bullet: Option<Bullet>,
+    shell: Option<Shell>,
+    explosion: Option<Explosion>,
+    cheese_droplet_position: Option<CheeseDropletPosition>,
+    cheese_droplet_velocity: Option<CheeseDropletVelocity>,
+    tag: Option<Tag>,
+    cheese_guyser: bool,
+    avoids: bool,
+    avoidable: bool,
+    selectable: bool,
+    selected: bool,
+    can_build: bool,
+    can_attack: bool,
+    fully_built: bool,
+}
+
+impl SavedEntity {
+    fn new(entry: &EntryRef) -> Self {
+        fn get<T: Clone + legion::storage::Component>(entry: &EntryRef) -> Option<T> {
+            entry.get_component::<T>().ok().cloned()
+        }
+
+        fn has<T: legion::storage::Component>(entry: &EntryRef) -> bool {
+            entry.get_component::<T>().is_ok()
+        }
+
+        Self {
+            entity_id: get(entry),
+            position: get(entry),
+            previous_position: get(entry),
+            facing: get(entry),
+            side: get(entry),
+            unit: get(entry),
+            building: get(entry),
+            health: get(entry),
+            building_completeness: get(entry),
+            command_queue: get(entry),
+            recruitment_queue: get(entry),
+            abilities: get(entry),
+            cooldown: get(entry),
+            energy: get(entry),
+            spell_cooldowns: get(entry),
+            move_speed: get(entry),
+            radius: get(entry),
+            firing_range: get(entry),
+            min_firing_range: get(entry),
+            avoidance: get(entry),
+            animation_state: get(entry),
+            cheese_guyser_built_on: get(entry),
+            damaged_this_tick: get(entry),
+            agroed: get(entry),
+            bullet: get(entry),
+            shell: get(entry),
+            explosion: get(entry),
+            cheese_droplet_position: get(entry),
+            cheese_droplet_velocity: get(entry),
+            tag: get(entry),
+            cheese_guyser: has::<CheeseGuyser>(entry),
+            avoids: has::<Avoids>(entry),
+            avoidable: has::<Avoidable>(entry),
+            selectable: has::<Selectable>(entry),
+            selected: has::<Selected>(entry),
+            can_build: has::<CanBuild>(entry),
+            can_attack: has::<CanAttack>(entry),
+            fully_built: has::<FullyBuilt>(entry),
+        }
+    }
+
+    fn add_to_world(self, world: &mut World) -> Entity {
+        let entity = world.push(());
+        let mut entry = world.entry(entity).unwrap();
+
+        macro_rules! add {
+            ($($field:ident),*) => {
+                $(
+                    if let Some(component) = self.$field {
+                        entry.add_component(component);
+                    }
+                )*
+            };
+        }
+
+        macro_rules! add_marker {
+            ($($field:ident => $component:expr),*) => {
+                $(
+                    if self.$field {
+                        entry.add_component($component);
+                    }
+                )*
+            };
+        }
+
+        add!(
+            entity_id,
+            position,
+            previous_position,
+            facing,
+            side,
+            unit,
+            building,
+            health,
+            building_completeness,
+            command_queue,
+            recruitment_queue,
+            abilities,
+            cooldown,
+            energy,
+            spell_cooldowns,
+            move_speed,
+            radius,
+            firing_range,
+            min_firing_range,
+            avoidance,
+            animation_state,
+            cheese_guyser_built_on,
+            damaged_this_tick,
+            agroed,
+            bullet,
+            shell,
+            explosion,
+            cheese_droplet_position,
+            cheese_droplet_velocity,
+            tag
+        );
+
+        add_marker!(
+            cheese_guyser => CheeseGuyser,
+            avoids => Avoids,
+            avoidable => Avoidable,
+            selectable => Selectable,
+            selected => Selected,
+            can_build => CanBuild,
+            can_attack => CanAttack,
+            fully_built => FullyBuilt
+        );
+
+        entity
+    }
+}
+
+thread_local! {
+    // Entities and their indices while saving.
+    static SAVING: RefCell<HashMap<Entity, u32>> = RefCell::new(HashMap::new());
+    // Entity ids reserved for each index while loading.
+    static LOADING: RefCell<(Allocate, Vec<Entity>)> = RefCell::new((Allocate::new(), Vec::new()));
+}
+
+/// Serializes `Entity`s as indices into the entity list of a save. Only usable inside `save` and
+/// `load`.
+pub mod entity {
+    use super::{LOADING, SAVING};
+    use legion::Entity;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(entity: &Entity, serializer: S) -> Result<S::Ok, S::Error> {
+        let index = SAVING.with(|saving| {
+            let mut saving = saving.borrow_mut();
+            let next_index = saving.len() as u32;
+            *saving.entry(*entity).or_insert(next_index)
+        });
+
+        index.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Entity, D::Error> {
+        let index = u32::deserialize(deserializer)? as usize;
+
+        Ok(LOADING.with(|loading| {
+            let (allocate, entities) = &mut *loading.borrow_mut();
+            while entities.len() <= index {
+                entities.push(allocate.next().unwrap());
+            }
+            entities[index]
+        }))
+    }
+}
+
+pub fn save(world: &World, resources: &Resources, path: &Path) -> anyhow::Result<()> {
+    let entities: Vec<Entity> = <Entity>::query().iter(world).copied().collect();
+
+    SAVING.with(|saving| {
+        let mut saving = saving.borrow_mut();
+        saving.clear();
+        saving.extend(
+            entities
+                .iter()
+                .enumerate()
+                .map(|(index, entity)| (*entity, index as u32)),
+        );
+    });
+
+    let save_game = SaveGame {
+        version: SAVE_VERSION,
+        definitions_hash: crate::definitions::hash(),
+        match_settings: resources.get::<MatchSettings>().unwrap().clone(),
+        tick: resources.get::<Tick>().unwrap().0,
+        total_time: resources.get::<TotalTime>().unwrap().0,
+        cheese_coins: resources.get::<CheeseCoins>().unwrap().clone(),
+        upgrades: resources.get::<Upgrades>().unwrap().clone(),
+        next_entity_id: resources.get::<NextEntityId>().unwrap().0,
+        objectives: resources.get::<Objectives>().unwrap().clone(),
+        ai_build_orders: resources.get::<AiBuildOrders>().unwrap().clone(),
+        ai_players: resources.get::<AiPlayers>().unwrap().clone(),
+        alliances: resources.get::<Alliances>().unwrap().clone(),
+        visibility: resources.get::<Visibility>().unwrap().clone(),
+        triggers: resources.get::<Triggers>().unwrap().clone(),
+        scenario_message: resources.get::<ScenarioMessage>().unwrap().clone(),
+        game_stats: resources.get::<GameStats>().unwrap().clone(),
+        control_groups: resources
+            .get::<ControlGroups>()
+            .unwrap()
+            .0
+            .iter()
+            .map(|group| group.iter().map(|entity| EntityRef(*entity)).collect())
+            .collect(),
+        camera: resources.get::<Camera>().unwrap().clone(),
+        gameplay_rng: resources.get::<GameplayRng>().unwrap().clone(),
+        cosmetic_rng: resources.get::<CosmeticRng>().unwrap().clone(),
+        entities: entities
+            .iter()
+            .map(|entity| SavedEntity::new(&world.entry_ref(*entity).unwrap()))
+            .collect(),
+    };
+
+    let string = ron::ser::to_string_pretty(&save_game, ron::ser::PrettyConfig::default());
+    SAVING.with(|saving| saving.borrow_mut().clear());
+    let string = string?;
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    std::fs::write(path, string)?;
+    Ok(())
+}
+
+/// Replace the world and the gameplay resources with a saved match.
+pub fn load(world: &mut World, resources: &mut Resources, path: &Path) -> anyhow::Result<()> {
+    let string = std::fs::read_to_string(path)?;
+
+    LOADING.with(|loading| *loading.borrow_mut() = (Allocate::new(), Vec::new()));
+    let save_game: Result<SaveGame, _> = ron::de::from_str(&string);
+    let mut reserved = LOADING.with(|loading| std::mem::take(&mut loading.borrow_mut().1));
+    let save_game = save_game?;
+
+    if save_game.version != SAVE_VERSION {
+        return Err(anyhow::anyhow!(
+            "{} is version {} but only version {} saves are supported",
+            path.display(),
+            save_game.version,
+            SAVE_VERSION
+        ));
+    }
+
+    if save_game.definitions_hash != crate::definitions::hash() {
+        log::warn!(
+            "{} was saved with different definitions, so the match won't match its replay",
+            path.display()
+        );
+    }
+
+    // Entities that nothing refers to won't have had an id reserved for them.
+    let mut allocate = Allocate::new();
+    while reserved.len() < save_game.entities.len() {
+        reserved.push(allocate.next().unwrap());
+    }
+
+    // The components refer to entities by the reserved ids, so the entities need to have those ids
+    // in the world as well.
+    let mut loaded = World::default();
+    let ids: HashMap<Entity, Entity> = save_game
+        .entities
+        .into_iter()
+        .zip(reserved.iter())
+        .map(|(saved, reserved)| (saved.add_to_world(&mut loaded), *reserved))
+        .collect();
+    *world = copy_world_with_ids(&loaded, ids);
+
+    let mut map = Map::new();
+    let buildings: Vec<(Entity, Vec2, Building)> = <(Entity, &Position, &Building)>::query()
+        .iter(world)
+        .map(|(entity, position, building)| (*entity, position.0, *building))
+        .collect();
+
+    for (entity, position, building) in buildings {
+        match map.insert(position, building.stats().dimensions) {
+            Some(handle) => world.entry(entity).unwrap().add_component(handle),
+            None => log::warn!("{:?} at {:?} overlaps another building", building, position),
+        }
+    }
+
+    let animations = resources.get::<ModelAnimations>().unwrap();
+    let skinned: Vec<(Entity, bool)> = <(Entity, Option<&Building>)>::query()
+        .filter(component::<AnimationState>())
+        .iter(world)
+        .map(|(entity, building)| (*entity, building.is_some()))
+        .collect();
+
+    for (entity, is_building) in skinned {
+        let skin = if is_building {
+            animations.pump.skin.clone()
+        } else {
+            animations.mouse.skin.clone()
+        };
+        world.entry(entity).unwrap().add_component(skin);
+    }
+
+    drop(animations);
+
+    let mut control_groups = ControlGroups::default();
+    for (group, saved) in control_groups.0.iter_mut().zip(save_game.control_groups) {
+        *group = saved.into_iter().map(|entity| entity.0).collect();
+    }
+
+    resources.insert(save_game.match_settings);
+    resources.insert(Tick(save_game.tick));
+    resources.insert(TotalTime(save_game.total_time));
+    resources.insert(save_game.cheese_coins);
+    resources.insert(save_game.upgrades);
+    resources.insert(NextEntityId(save_game.next_entity_id));
+    resources.insert(save_game.objectives);
+    resources.insert(save_game.ai_build_orders);
+    resources.insert(save_game.ai_players);
+    resources.insert(save_game.alliances);
+    resources.insert(save_game.visibility);
+    resources.insert(save_game.triggers);
+    resources.insert(save_game.scenario_message);
+    resources.insert(save_game.game_stats);
+    resources.insert(control_groups);
+    resources.insert(save_game.camera);
+    resources.insert(map);
+    resources.insert(save_game.gameplay_rng);
+    resources.insert(save_game.cosmetic_rng);
+
+    Ok(())
+}
@@ -1,121 +1,582 @@
 use crate::assets::ModelAnimations;
-use crate::ecs;
+use crate::ecs::{self, Building, Side, Unit};
 use crate::pathfinding::Map;
 use crate::resources::{
-    AiBuildOrderItem, AiBuildOrders, Camera, CheeseCoins, LoseCondition, Objectives, WinCondition,
+    AiBuildOrderItem, AiBuildOrders, AiPlayer, AiPlayers, Alliances, Camera, CheeseCoins,
+    CosmeticRng, MatchSettings, Objectives, PlayerSide, ScenarioMessage, Trigger, TriggerAction,
+    TriggerCondition, Triggers,
 };
 use legion::systems::CommandBuffer;
+use legion::world::EntityStore;
 use legion::*;
 use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
 use ultraviolet::Vec2;
 
-// Squad of 10 marines vs 5.
-pub fn one(
-    world: &mut World,
-    animations: &ModelAnimations,
-    map: &mut Map,
-    rng: &mut rand::rngs::SmallRng,
-    objectives: &mut Objectives,
-    camera: &mut Camera,
-    cheese_coins: &mut CheeseCoins,
-    ai_build_orders: &mut AiBuildOrders,
-) {
-    let mut command_buffer = legion::systems::CommandBuffer::new(&world);
-
-    let unit_spawn_point = Vec2::new(-36.0, 0.0);
-
-    spawn_units_in_circle(
-        &mut command_buffer,
-        animations,
-        10,
-        ecs::Unit::MouseMarine,
-        unit_spawn_point,
-        2.0,
-        0.0,
-        ecs::Side::Green,
-    );
-
-    spawn_units_in_circle(
-        &mut command_buffer,
-        animations,
-        5,
-        ecs::Unit::MouseMarine,
-        Vec2::new(40.0, 6.7),
-        1.0,
-        180.0,
-        ecs::Side::Purple,
-    );
-
-    command_buffer.flush(world);
-
-    ecs::Building::Armoury
-        .add_to_world_fully_built(
-            world,
-            Vec2::new(36.0, -10.0),
-            ecs::Side::Purple,
-            animations,
-            map,
-        )
-        .unwrap();
-
-    spawn_pump_over_guyser(
-        Vec2::new(46.0, -5.0),
-        ecs::Side::Purple,
-        world,
-        animations,
-        map,
-        rng,
-    );
-
-    ecs::Building::Armoury
-        .add_to_world_fully_built(
-            world,
-            Vec2::new(54.0, 2.0),
-            ecs::Side::Purple,
-            animations,
-            map,
-        )
-        .unwrap();
+/// Where scenario files are kept. Every `.ron` file in here is listed in the scenarios menu, in
+/// order of file name.
+pub const DIRECTORY: &str = "scenarios";
+
+// How close a position has to be to a guyser to refer to it, e.g. in a `BuildPump` order.
+pub const GUYSER_SNAP_DISTANCE: f32 = 0.5;
+
+/// Everything that a match starts with.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Scenario {
+    /// Shown in the scenarios menu.
+    pub title: String,
+    pub camera: Camera,
+    #[serde(default)]
+    pub cheese_coins: u32,
+    #[serde(default)]
+    pub objectives: Objectives,
+    #[serde(default)]
+    pub units: Vec<UnitPlacement>,
+    #[serde(default)]
+    pub buildings: Vec<BuildingPlacement>,
+    #[serde(default)]
+    pub guysers: Vec<GuyserPlacement>,
+    // Sides that are allied with each other. Any side that isn't in a team plays on its own, so
+    // leaving this empty makes a free-for-all.
+    #[serde(default)]
+    pub teams: Vec<Vec<Side>>,
+    // Whether the sides other than the player's are played by the reactive AI. They each start
+    // with the same number of cheese coins as the player.
+    #[serde(default)]
+    pub reactive_ai: bool,
+    // Followed by the first of the computer's sides, in the order of `Side::ALL`.
+    #[serde(default)]
+    pub ai_build_orders: Vec<(f32, BuildOrderItem)>,
+    #[serde(default)]
+    pub triggers: Vec<Trigger>,
+}
+
+/// `count` units spread out evenly around a circle. With a `radius` of 0, that's a single unit at
+/// `position`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UnitPlacement {
+    pub unit: Unit,
+    pub side: Side,
+    pub position: Vec2,
+    // In degrees.
+    #[serde(default)]
+    pub facing: f32,
+    #[serde(default = "one")]
+    pub count: u32,
+    #[serde(default)]
+    pub radius: f32,
+    #[serde(default)]
+    pub tag: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BuildingPlacement {
+    pub building: Building,
+    pub side: Side,
+    pub position: Vec2,
+    // Buildings that aren't fully built need an engineer to finish them off.
+    #[serde(default = "yes")]
+    pub fully_built: bool,
+    #[serde(default)]
+    pub tag: Option<String>,
+}
 
-    ecs::Building::Armoury
-        .add_to_world_fully_built(
-            world,
-            Vec2::new(44.0, 16.0),
-            ecs::Side::Purple,
+/// Pumps are placed with the guyser that they're built on rather than in `buildings`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GuyserPlacement {
+    pub position: Vec2,
+    // The side that has a pump built on the guyser, if any.
+    #[serde(default)]
+    pub pump: Option<Side>,
+    // Given to the pump, if there is one.
+    #[serde(default)]
+    pub tag: Option<String>,
+}
+
+/// The same as `AiBuildOrderItem`, except that guysers are referred to by their position.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum BuildOrderItem {
+    BuildPump(Vec2),
+    BuildArmoury(Vec2),
+    BuildLarder(Vec2),
+    BuildTurret(Vec2),
+    RecruitMarine(u32),
+    AttackMove(Vec2),
+    SetWaypoint(Vec2),
+}
+
+impl BuildOrderItem {
+    /// Turn this into an `AiBuildOrderItem` by finding the guyser that a `BuildPump` refers to.
+    pub fn resolve(&self, guysers: &[(Entity, Vec2)]) -> Option<AiBuildOrderItem> {
+        Some(match *self {
+            Self::BuildPump(position) => AiBuildOrderItem::BuildPump(
+                guysers
+                    .iter()
+                    .find(|(_, guyser)| (*guyser - position).mag() <= GUYSER_SNAP_DISTANCE)?
+                    .0,
+            ),
+            Self::BuildArmoury(position) => AiBuildOrderItem::BuildArmoury(position),
+            Self::BuildLarder(position) => AiBuildOrderItem::BuildLarder(position),
+            Self::BuildTurret(position) => AiBuildOrderItem::BuildTurret(position),
+            Self::RecruitMarine(count) => AiBuildOrderItem::RecruitMarine(count),
+            Self::AttackMove(position) => AiBuildOrderItem::AttackMove(position),
+            Self::SetWaypoint(position) => AiBuildOrderItem::SetWaypoint(position),
+        })
+    }
+}
+
+impl UnitPlacement {
+    pub fn spawn(&self, buffer: &mut CommandBuffer, animations: &ModelAnimations) {
+        let entities = spawn_units_in_circle(
+            buffer,
             animations,
-            map,
-        )
-        .unwrap();
-
-    spawn_pump_over_guyser(
-        Vec2::new(54.0, 19.0),
-        ecs::Side::Purple,
-        world,
-        animations,
-        map,
-        rng,
-    );
-
-    spawn_pump_over_guyser(
-        Vec2::new(36.0, 22.0),
-        ecs::Side::Purple,
-        world,
-        animations,
-        map,
-        rng,
-    );
-
-    *objectives = Objectives {
-        win_conditions: vec![WinCondition::DestroyAll],
-        lose_conditions: vec![LoseCondition::LetAllUnitsDie],
-    };
+            self.count,
+            self.unit,
+            self.position,
+            self.radius,
+            self.facing,
+            self.side,
+        );
 
-    *camera = Camera {
-        looking_at: unit_spawn_point,
-        distance: 15.0,
+        if let Some(tag) = &self.tag {
+            for entity in entities {
+                buffer.add_component(entity, ecs::Tag(tag.clone()));
+            }
+        }
+    }
+}
+
+fn one() -> u32 {
+    1
+}
+
+fn yes() -> bool {
+    true
+}
+
+/// A scenario file, for showing in the scenarios menu.
+pub struct ScenarioListing {
+    /// The file name without the extension. This is what scenarios are started with.
+    pub name: String,
+    pub title: String,
+}
+
+pub fn path(name: &str) -> PathBuf {
+    Path::new(DIRECTORY).join(name).with_extension("ron")
+}
+
+/// All the valid scenarios in `DIRECTORY`. Invalid ones are logged and left out.
+pub fn list() -> Vec<ScenarioListing> {
+    let entries = match std::fs::read_dir(DIRECTORY) {
+        Ok(entries) => entries,
+        Err(error) => {
+            log::error!("Failed to read {}: {}", DIRECTORY, error);
+            return Vec::new();
+        }
     };
-    *cheese_coins = CheeseCoins(0);
-    *ai_build_orders = AiBuildOrders::default();
+
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension() == Some("ron".as_ref()))
+        .filter_map(|path| Some(path.file_stem()?.to_str()?.to_string()))
+        .collect();
+    names.sort();
+
+    names
+        .into_iter()
+        .filter_map(|name| match Scenario::load(&name) {
+            Ok(scenario) => Some(ScenarioListing {
+                name,
+                title: scenario.title,
+            }),
+            Err(error) => {
+                log::error!("{:?}", error);
+                None
+            }
+        })
+        .collect()
+}
+
+/// A name for a new scenario that isn't used by any of the files in `DIRECTORY`, and a title to go
+/// with it.
+pub fn unused_name() -> (String, String) {
+    (1..)
+        .map(|i| (format!("custom-{}", i), format!("Custom Scenario {}", i)))
+        .find(|(name, _)| !path(name).exists())
+        .unwrap()
+}
+
+impl Scenario {
+    /// An empty map, for starting a new scenario in the editor.
+    pub fn new(title: String) -> Self {
+        Self {
+            title,
+            camera: Camera::default(),
+            cheese_coins: 0,
+            objectives: Objectives::default(),
+            units: Vec::new(),
+            buildings: Vec::new(),
+            guysers: Vec::new(),
+            teams: Vec::new(),
+            reactive_ai: false,
+            ai_build_orders: Vec::new(),
+            triggers: Vec::new(),
+        }
+    }
+
+    /// The reverse of `spawn`: replace the units, buildings and guysers with the ones in the world.
+    /// Groups of units are saved as separate units.
+    pub fn update_from_world(&mut self, world: &impl EntityStore) {
+        let tag = |tag: Option<&ecs::Tag>| tag.map(|tag| tag.0.clone());
+
+        self.units = <(
+            &Unit,
+            &Side,
+            &ecs::Position,
+            &ecs::Facing,
+            Option<&ecs::Tag>,
+        )>::query()
+        .iter(world)
+        .map(|(unit, side, position, facing, unit_tag)| UnitPlacement {
+            unit: *unit,
+            side: *side,
+            position: position.0,
+            facing: facing.0.to_degrees(),
+            count: 1,
+            radius: 0.0,
+            tag: tag(unit_tag),
+        })
+        .collect();
+
+        self.buildings = <(
+            &Building,
+            &Side,
+            &ecs::Position,
+            Option<&ecs::FullyBuilt>,
+            Option<&ecs::Tag>,
+        )>::query()
+        .iter(world)
+        .filter(|(building, ..)| **building != Building::Pump)
+        .map(
+            |(building, side, position, fully_built, building_tag)| BuildingPlacement {
+                building: *building,
+                side: *side,
+                position: position.0,
+                fully_built: fully_built.is_some(),
+                tag: tag(building_tag),
+            },
+        )
+        .collect();
+
+        self.guysers = <(&ecs::Position, Option<&ecs::CheeseGuyserBuiltOn>)>::query()
+            .filter(component::<ecs::CheeseGuyser>())
+            .iter(world)
+            .map(|(position, built_on)| {
+                let pump = built_on.and_then(|built_on| {
+                    <(&Side, Option<&ecs::Tag>)>::query()
+                        .get(world, built_on.pump)
+                        .ok()
+                });
+
+                GuyserPlacement {
+                    position: position.0,
+                    pump: pump.map(|(side, _)| *side),
+                    tag: pump.and_then(|(_, pump_tag)| tag(pump_tag)),
+                }
+            })
+            .collect();
+    }
+
+    /// Save the scenario to `DIRECTORY` as `name`.
+    pub fn save(&self, name: &str) -> anyhow::Result<()> {
+        self.validate()?;
+
+        std::fs::create_dir_all(DIRECTORY)?;
+        let string = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        std::fs::write(path(name), string)?;
+        Ok(())
+    }
+
+    /// Load the scenario called `name` from `DIRECTORY`.
+    pub fn load(name: &str) -> anyhow::Result<Self> {
+        let path = path(name);
+        let context = || format!("Failed to load {}", path.display());
+
+        let string = std::fs::read_to_string(&path)
+            .map_err(|error| anyhow::Error::from(error).context(context()))?;
+
+        Self::parse(&string).map_err(|error| error.context(context()))
+    }
+
+    pub fn parse(string: &str) -> anyhow::Result<Self> {
+        let scenario: Self = ron::de::from_str(string)?;
+        scenario.validate()?;
+        Ok(scenario)
+    }
+
+    // Buildings overlapping each other are only caught when the scenario is spawned, as that needs
+    // a map.
+    fn validate(&self) -> anyhow::Result<()> {
+        let mut errors = Vec::new();
+
+        for placement in &self.buildings {
+            if placement.building == Building::Pump {
+                errors.push(format!(
+                    "Pump at {:?}: pumps are placed with `guysers`, not `buildings`",
+                    placement.position
+                ));
+            }
+        }
+
+        for side in Side::ALL.iter() {
+            let teams = self.teams.iter().filter(|team| team.contains(side)).count();
+
+            if teams > 1 {
+                errors.push(format!("{:?} is in {} teams", side, teams));
+            }
+        }
+
+        let ai_orders = self.ai_build_orders.iter().map(|(_, item)| item);
+        let trigger_ai_orders = self.trigger_actions().filter_map(|action| match action {
+            TriggerAction::AiOrder(item) => Some(item),
+            _ => None,
+        });
+
+        for item in ai_orders.chain(trigger_ai_orders) {
+            if let BuildOrderItem::BuildPump(position) = item {
+                if self.guyser_index(*position).is_none() {
+                    errors.push(format!(
+                        "BuildPump({:?}): there isn't a guyser there",
+                        position
+                    ));
+                }
+            }
+        }
+
+        let spawned = self.trigger_actions().filter_map(|action| match action {
+            TriggerAction::Spawn(placement) => Some(placement),
+            _ => None,
+        });
+
+        let mut tags = Vec::new();
+
+        for placement in self.units.iter().chain(spawned) {
+            if placement.count == 0 {
+                errors.push(format!(
+                    "{:?} at {:?}: `count` must be at least 1",
+                    placement.unit, placement.position
+                ));
+            }
+            tags.extend(placement.tag.as_ref());
+        }
+
+        tags.extend(
+            self.buildings
+                .iter()
+                .filter_map(|placement| placement.tag.as_ref()),
+        );
+        tags.extend(
+            self.guysers
+                .iter()
+                .filter_map(|placement| placement.tag.as_ref()),
+        );
+
+        let trigger_objectives = self.trigger_actions().filter_map(|action| match action {
+            TriggerAction::SetObjectives(objectives) => Some(objectives),
+            _ => None,
+        });
+
+        let mut used_tags: Vec<&String> = self
+            .triggers
+            .iter()
+            .filter_map(|trigger| match &trigger.condition {
+                TriggerCondition::Destroyed(tag) => Some(tag),
+                _ => None,
+            })
+            .collect();
+
+        for objectives in std::iter::once(&self.objectives).chain(trigger_objectives) {
+            used_tags.extend(
+                objectives
+                    .win_conditions
+                    .iter()
+                    .filter_map(|condition| condition.tag()),
+            );
+            used_tags.extend(
+                objectives
+                    .lose_conditions
+                    .iter()
+                    .filter_map(|condition| condition.tag()),
+            );
+        }
+
+        // A typo in a tag would otherwise make whatever uses it happen straight away.
+        for tag in used_tags {
+            if !tags.contains(&tag) {
+                errors.push(format!("{:?}: nothing has that tag", tag));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "Invalid scenario:\n    {}",
+                errors.join("\n    ")
+            ))
+        }
+    }
+
+    fn trigger_actions(&self) -> impl Iterator<Item = &TriggerAction> {
+        self.triggers
+            .iter()
+            .flat_map(|trigger| trigger.actions.iter())
+    }
+
+    // Every side that has something in the scenario, including units spawned by triggers.
+    fn sides(&self) -> Vec<Side> {
+        let spawned = self.trigger_actions().filter_map(|action| match action {
+            TriggerAction::Spawn(placement) => Some(placement.side),
+            _ => None,
+        });
+
+        let sides: Vec<Side> = self
+            .units
+            .iter()
+            .map(|placement| placement.side)
+            .chain(spawned)
+            .chain(self.buildings.iter().map(|placement| placement.side))
+            .chain(self.guysers.iter().filter_map(|placement| placement.pump))
+            .collect();
+
+        Side::ALL
+            .iter()
+            .copied()
+            .filter(|side| sides.contains(side))
+            .collect()
+    }
+
+    fn guyser_index(&self, position: Vec2) -> Option<usize> {
+        self.guysers
+            .iter()
+            .position(|guyser| (guyser.position - position).mag() <= GUYSER_SNAP_DISTANCE)
+    }
+
+    /// Add everything in the scenario to the world and set the scenario's resources. The world and
+    /// map should be empty, and the `MatchSettings` should be set already.
+    pub fn spawn(&self, world: &mut World, resources: &mut Resources) -> anyhow::Result<()> {
+        let difficulty = resources.get::<MatchSettings>().unwrap().difficulty;
+        let player_side = resources.get::<PlayerSide>().unwrap().0;
+        let animations = resources.get::<ModelAnimations>().unwrap();
+        let mut map = resources.get_mut::<Map>().unwrap();
+        let mut rng = resources.get_mut::<CosmeticRng>().unwrap();
+
+        let mut command_buffer = CommandBuffer::new(world);
+
+        for placement in &self.units {
+            placement.spawn(&mut command_buffer, &animations);
+        }
+
+        for placement in &self.buildings {
+            let entity = if placement.fully_built {
+                placement.building.add_to_world_fully_built(
+                    world,
+                    placement.position,
+                    placement.side,
+                    &animations,
+                    &mut map,
+                )
+            } else {
+                placement.building.add_to_world_to_construct(
+                    &mut command_buffer,
+                    placement.position,
+                    placement.side,
+                    &animations,
+                    &mut map,
+                )
+            };
+
+            let entity = entity.ok_or_else(|| {
+                anyhow::anyhow!(
+                    "{:?} at {:?} doesn't fit on the map",
+                    placement.building,
+                    placement.position
+                )
+            })?;
+
+            if let Some(tag) = &placement.tag {
+                command_buffer.add_component(entity, ecs::Tag(tag.clone()));
+            }
+        }
+
+        command_buffer.flush(world);
+
+        let mut guysers = Vec::new();
+
+        for placement in &self.guysers {
+            let guyser = match placement.pump {
+                Some(side) => {
+                    let (guyser, pump) = spawn_pump_over_guyser(
+                        placement.position,
+                        side,
+                        world,
+                        &animations,
+                        &mut map,
+                        &mut rng.0,
+                    )
+                    .ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "The pump at {:?} doesn't fit on the map",
+                            placement.position
+                        )
+                    })?;
+
+                    if let Some(tag) = &placement.tag {
+                        world
+                            .entry(pump)
+                            .unwrap()
+                            .add_component(ecs::Tag(tag.clone()));
+                    }
+
+                    guyser
+                }
+                None => spawn_guyser(world, placement.position),
+            };
+
+            guysers.push((guyser, placement.position));
+        }
+
+        let ai_build_orders = self
+            .ai_build_orders
+            .iter()
+            // `validate` checks that the guysers exist.
+            .filter_map(|(time, item)| {
+                let time = time * difficulty.build_order_time_scale();
+                Some((time, item.resolve(&guysers)?))
+            })
+            .collect();
+
+        drop((animations, map, rng));
+
+        resources.insert(self.objectives.clone());
+        resources.insert(self.camera.clone());
+        resources.insert(CheeseCoins::new(self.cheese_coins));
+        resources.insert(AiBuildOrders(ai_build_orders));
+        resources.insert(Alliances::new(&self.teams));
+        resources.insert(AiPlayers(
+            self.sides()
+                .into_iter()
+                .filter(|side| *side != player_side)
+                .map(|side| AiPlayer::new(side, self.reactive_ai))
+                .collect(),
+        ));
+        resources.insert(Triggers(self.triggers.clone()));
+        resources.insert(ScenarioMessage::default());
+
+        Ok(())
+    }
 }
 
 fn spawn_guyser(world: &mut World, position: Vec2) -> Entity {
@@ -126,378 +587,116 @@ fn spawn_guyser(world: &mut World, position: Vec2) -> Entity {
     ))
 }
 
+// Returns the guyser and the pump.
 fn spawn_pump_over_guyser(
     position: Vec2,
-    side: ecs::Side,
+    side: Side,
     world: &mut World,
     animations: &ModelAnimations,
     map: &mut Map,
-    rng: &mut rand::rngs::SmallRng,
-) {
-    let pump_entity = ecs::Building::Pump
-        .add_to_world_fully_built(world, position, side, animations, map)
-        .unwrap();
+    rng: &mut impl Rng,
+) -> Option<(Entity, Entity)> {
+    let pump_entity =
+        Building::Pump.add_to_world_fully_built(world, position, side, animations, map)?;
 
     let animation_offset = rng.gen_range(0.0, 1.0);
 
-    <&mut ecs::AnimationState>::query()
-        .get_mut(world, pump_entity)
-        .unwrap()
-        .time = animation_offset;
+    // There won't be an animation state when running headless.
+    if let Ok(animation_state) = <&mut ecs::AnimationState>::query().get_mut(world, pump_entity) {
+        animation_state.time = animation_offset;
+    }
 
-    world.push((
+    let guyser = world.push((
         ecs::Position(position),
         ecs::CheeseGuyser,
         ecs::CheeseGuyserBuiltOn { pump: pump_entity },
         ecs::Cooldown(0.0),
     ));
+
+    Some((guyser, pump_entity))
 }
 
 fn spawn_units_in_circle(
     buffer: &mut CommandBuffer,
     animations: &ModelAnimations,
     num: u32,
-    unit: ecs::Unit,
+    unit: Unit,
     center: Vec2,
     radius: f32,
     facing: f32,
-    side: ecs::Side,
-) {
-    for i in 0..num {
-        let rads = (i as f32 / num as f32 * 360.0).to_radians();
-        unit.add_to_world(
-            buffer,
-            Some(animations),
-            center + Vec2::new(rads.sin() * radius, rads.cos() * radius),
-            ecs::Facing(facing.to_radians()),
-            side,
-            None,
-        );
-    }
+    side: Side,
+) -> Vec<Entity> {
+    (0..num)
+        .map(|i| {
+            let rads = (i as f32 / num as f32 * 360.0).to_radians();
+            unit.add_to_world(
+                buffer,
+                Some(animations),
+                center + Vec2::new(rads.sin() * radius, rads.cos() * radius),
+                ecs::Facing(facing.to_radians()),
+                side,
+                None,
+            )
+        })
+        .collect()
 }
 
-// Single engineer has to build a base.
-pub fn two(
-    mut world: &mut World,
-    animations: &ModelAnimations,
-    map: &mut Map,
-    rng: &mut rand::rngs::SmallRng,
-    objectives: &mut Objectives,
-    camera: &mut Camera,
-    cheese_coins: &mut CheeseCoins,
-    ai_build_orders: &mut AiBuildOrders,
-) {
-    let engineer_pos = Vec2::new(-52.69, -53.42);
-
-    let mut command_buffer = legion::systems::CommandBuffer::new(&world);
-
-    ecs::Unit::Engineer.add_to_world(
-        &mut command_buffer,
-        Some(animations),
-        engineer_pos,
-        ecs::Facing(-1.0),
-        ecs::Side::Green,
-        None,
-    );
-
-    let enemy_pos = Vec2::new(15.36, 66.0);
-
-    let direction = engineer_pos - enemy_pos;
-
-    spawn_units_in_circle(
-        &mut command_buffer,
-        animations,
-        5,
-        ecs::Unit::MouseMarine,
-        enemy_pos + Vec2::new(-3.0, 0.0),
-        1.0,
-        direction.y.atan2(direction.x).to_degrees(),
-        ecs::Side::Purple,
-    );
-
-    spawn_units_in_circle(
-        &mut command_buffer,
-        animations,
-        5,
-        ecs::Unit::MouseMarine,
-        enemy_pos + Vec2::new(3.0, 0.0),
-        1.0,
-        direction.y.atan2(direction.x).to_degrees(),
-        ecs::Side::Purple,
-    );
-
-    command_buffer.flush(world);
-
-    // Spawn guysers
-
-    spawn_guyser(&mut world, Vec2::new(-69.58, -78.49));
-    spawn_guyser(&mut world, Vec2::new(-59.77, -68.08));
-    spawn_guyser(&mut world, Vec2::new(-71.5, -39.26));
-    spawn_guyser(&mut world, Vec2::new(-27.52, -59.68));
-    spawn_guyser(&mut world, Vec2::new(-42.69, -77.58));
-
-    *camera = Camera {
-        looking_at: engineer_pos,
-        distance: 30.0,
-    };
-
-    *objectives = Objectives {
-        win_conditions: vec![
-            WinCondition::DestroyAll,
-            WinCondition::BuildN(2, ecs::Building::Pump),
-            WinCondition::BuildN(1, ecs::Building::Armoury),
-        ],
-        lose_conditions: vec![LoseCondition::LetAllUnitsDie],
-    };
-    *cheese_coins = CheeseCoins(100);
-    *ai_build_orders = AiBuildOrders::default();
+#[test]
+fn invalid_scenarios_are_reported() {
+    let string = std::fs::read_to_string(path("3-skirmish")).unwrap();
+
+    let error = Scenario::parse(
+        &string
+            .replace(
+                "reactive_ai: true,",
+                "ai_build_orders: [(5.0, BuildPump((x: 10.0, y: 78.57)))],",
+            )
+            .replace(
+                "side: Purple, position: (x: 57.57",
+                "side: Purple, count: 0, position: (x: 57.57",
+            ),
+    )
+    .err()
+    .unwrap()
+    .to_string();
+
+    assert!(error.contains("BuildPump(Vec2 { x: 10.0, y: 78.57 }): there isn't a guyser there"));
+    assert!(error.contains("`count` must be at least 1"));
 }
 
-pub fn three(
-    mut world: &mut World,
-    animations: &ModelAnimations,
-    map: &mut Map,
-    rng: &mut rand::rngs::SmallRng,
-    objectives: &mut Objectives,
-    camera: &mut Camera,
-    cheese_coins: &mut CheeseCoins,
-    ai_build_orders: &mut AiBuildOrders,
-) {
-    let start = Vec2::new(-57.57, -59.81);
-
-    let mut command_buffer = legion::systems::CommandBuffer::new(&world);
-
-    ecs::Unit::Engineer.add_to_world(
-        &mut command_buffer,
-        Some(animations),
-        start,
-        ecs::Facing(0.0),
-        ecs::Side::Green,
-        None,
-    );
-
-    ecs::Unit::Engineer.add_to_world(
-        &mut command_buffer,
-        Some(animations),
-        -start,
-        ecs::Facing(0.0),
-        ecs::Side::Purple,
-        None,
-    );
-
-    command_buffer.flush(world);
-
-    let base_guysers = [
-        Vec2::new(-72.23, -78.57),
-        Vec2::new(-74.96, -63.91),
-        Vec2::new(-46.65, -78.57),
-        Vec2::new(-56.55, -33.89),
-        Vec2::new(-25.85, -60.5),
-    ];
-
-    let mut enemy_guyser_entities = Vec::new();
-
-    let center_guyser = spawn_guyser(&mut world, Vec2::zero());
-    for guyser in &base_guysers {
-        spawn_guyser(&mut world, *guyser);
-        enemy_guyser_entities.push(spawn_guyser(&mut world, -*guyser));
+#[test]
+fn all_scenarios_start() {
+    let scenarios = list();
+    assert_eq!(scenarios.len(), 5);
+
+    for scenario in scenarios {
+        let mut simulation = crate::Simulation::new();
+        simulation.start_scenario(&scenario.name, 0).unwrap();
     }
+}
 
-    *objectives = Objectives {
-        win_conditions: vec![WinCondition::DestroyAll],
-        lose_conditions: vec![LoseCondition::LetAllUnitsDie],
-    };
+#[test]
+fn scenarios_survive_the_editor() {
+    let original = Scenario::load("1-attacking").unwrap();
 
-    *camera = Camera {
-        looking_at: start,
-        distance: 30.0,
-    };
+    let mut simulation = crate::Simulation::new();
+    simulation.start_editor(Some("1-attacking")).unwrap();
 
-    *cheese_coins = CheeseCoins(100);
-
-    *ai_build_orders = AiBuildOrders(vec![
-        (
-            5.3602057,
-            AiBuildOrderItem::BuildPump(enemy_guyser_entities[0]),
-        ),
-        (
-            12.738736,
-            AiBuildOrderItem::BuildPump(enemy_guyser_entities[1]),
-        ),
-        (
-            23.946945,
-            AiBuildOrderItem::BuildPump(enemy_guyser_entities[2]),
-        ),
-        (
-            31.270610,
-            AiBuildOrderItem::BuildPump(enemy_guyser_entities[4]),
-        ),
-        (
-            39.601414,
-            AiBuildOrderItem::BuildPump(enemy_guyser_entities[3]),
-        ),
-        (
-            49.388996,
-            AiBuildOrderItem::BuildArmoury(Vec2::new(35.87867, 38.60826)),
-        ),
-        (
-            60.0,
-            AiBuildOrderItem::SetWaypoint(Vec2::new(26.272, 30.529)),
-        ),
-        (60.0, AiBuildOrderItem::RecruitMarine(100)),
-        (
-            103.63205,
-            AiBuildOrderItem::AttackMove(Vec2::new(-8.6, -4.9)),
-        ),
-        (111.126175, AiBuildOrderItem::BuildPump(center_guyser)),
-        (
-            114.38,
-            AiBuildOrderItem::AttackMove(Vec2::new(-16.08, -7.71)),
-        ),
-        (
-            125.53555,
-            AiBuildOrderItem::BuildArmoury(Vec2::new(8.027, -2.995)),
-        ),
-        (
-            134.9838,
-            AiBuildOrderItem::AttackMove(Vec2::new(-64.785, -35.520)),
-        ),
-        (
-            142.98,
-            AiBuildOrderItem::SetWaypoint(Vec2::new(-4.4166, -13.563)),
-        ),
-        (
-            145.0,
-            AiBuildOrderItem::AttackMove(Vec2::new(-28.534466, -63.937397)),
-        ),
-        (
-            155.0,
-            AiBuildOrderItem::AttackMove(Vec2::new(-57.537994, -69.26898)),
-        ),
-        (
-            165.0,
-            AiBuildOrderItem::AttackMove(Vec2::new(-78.44591, -80.18292)),
-        ),
-        (
-            175.0,
-            AiBuildOrderItem::AttackMove(Vec2::new(-46.487736, -79.067795)),
-        ),
-    ]);
-}
+    let mut saved = original.clone();
+    saved.update_from_world(&simulation.world);
+    let saved = Scenario::parse(&ron::ser::to_string(&saved).unwrap()).unwrap();
 
-// Here's a list of moves that I made when I played as the enemy side:
-
-/*
-5.3602057: Pump RayCastLocation { pos: Vec2 { x: 72.23, y: 78.57 }, snapped_to_guyser: Some(Entity(63)) }
-12.738736: Pump RayCastLocation { pos: Vec2 { x: 74.96, y: 63.91 }, snapped_to_guyser: Some(Entity(95)) }
-23.44634: MoveTo { target: Vec2 { x: 59.986694, y: 71.15368 }, attack_move: false, path: [] }
-23.946945: Pump RayCastLocation { pos: Vec2 { x: 46.65, y: 78.57 }, snapped_to_guyser: Some(Entity(127)) }
-31.27061: Pump RayCastLocation { pos: Vec2 { x: 25.85, y: 60.5 }, snapped_to_guyser: Some(Entity(191)) }
-39.601414: Pump RayCastLocation { pos: Vec2 { x: 56.55, y: 33.89 }, snapped_to_guyser: Some(Entity(159)) }
-49.388996: Armoury RayCastLocation { pos: Vec2 { x: 35.87867, y: 38.60826 }, snapped_to_guyser: None }
-59.695637: setting waypoint Vec2 { x: 26.272722, y: 30.529737 }
-61.196625: building MouseMarine
-61.38647: building MouseMarine
-62.87598: building MouseMarine
-66.2489: MoveTo { target: Vec2 { x: 44.887505, y: 28.902779 }, attack_move: false, path: [] }
-69.42682: building MouseMarine
-73.06995: building MouseMarine
-79.582565: building MouseMarine
-83.561134: building MouseMarine
-88.373856: building MouseMarine
-93.2919: building MouseMarine
-103.63205: MoveTo { target: Vec2 { x: -8.602108, y: -4.904587 }, attack_move: true, path: [] }
-111.126175: Pump RayCastLocation { pos: Vec2 { x: 0.0, y: 0.0 }, snapped_to_guyser: Some(Entity(31)) }
-114.38201: MoveTo { target: Vec2 { x: -16.081762, y: -7.714676 }, attack_move: true, path: [] }
-125.53555: Armoury RayCastLocation { pos: Vec2 { x: 8.027401, y: -2.9950018 }, snapped_to_guyser: None }
-134.9838: MoveTo { target: Vec2 { x: -64.78539, y: -35.520306 }, attack_move: true, path: [] }
-138.8399: setting waypoint Vec2 { x: -6.710074, y: -15.923779 }
-142.97922: setting waypoint Vec2 { x: -4.4166384, y: -13.563568 }
-143.42065: building MouseMarine
-143.59573: building MouseMarine
-143.77641: building MouseMarine
-143.93388: building MouseMarine
-146.01065: MoveTo { target: Vec2 { x: -28.534466, y: -63.937397 }, attack_move: true, path: [] }
-148.50993: MoveTo { target: Vec2 { x: -57.537994, y: -69.26898 }, attack_move: true, path: [] }
-149.3153: MoveTo { target: Vec2 { x: -78.44591, y: -80.18292 }, attack_move: true, path: [] }
-150.26266: MoveTo { target: Vec2 { x: -46.487736, y: -79.067795 }, attack_move: true, path: [] }
-*/
-
-pub fn sandbox(
-    world: &mut World,
-    animations: &ModelAnimations,
-    map: &mut Map,
-    rng: &mut rand::rngs::SmallRng,
-    objectives: &mut Objectives,
-    camera: &mut Camera,
-    cheese_coins: &mut CheeseCoins,
-    ai_build_orders: &mut AiBuildOrders,
-) {
-    let mut command_buffer = legion::systems::CommandBuffer::new(&world);
-
-    ecs::Unit::Engineer.add_to_world(
-        &mut command_buffer,
-        Some(animations),
-        Vec2::new(0.0, -90.0),
-        ecs::Facing(0.0),
-        ecs::Side::Green,
-        None,
-    );
-
-    spawn_units_in_circle(
-        &mut command_buffer,
-        animations,
-        25,
-        ecs::Unit::MouseMarine,
-        Vec2::new(0.0, 90.0),
-        1.0,
-        0.0,
-        ecs::Side::Purple,
-    );
-
-    spawn_units_in_circle(
-        &mut command_buffer,
-        animations,
-        25,
-        ecs::Unit::MouseMarine,
-        Vec2::new(0.0, 90.0),
-        2.0,
-        0.0,
-        ecs::Side::Purple,
-    );
-
-    spawn_units_in_circle(
-        &mut command_buffer,
-        animations,
-        25,
-        ecs::Unit::MouseMarine,
-        Vec2::new(0.0, 90.0),
-        3.0,
-        0.0,
-        ecs::Side::Purple,
-    );
-
-    spawn_units_in_circle(
-        &mut command_buffer,
-        animations,
-        25,
-        ecs::Unit::MouseMarine,
-        Vec2::new(0.0, 90.0),
-        4.0,
-        0.0,
-        ecs::Side::Purple,
-    );
-
-    command_buffer.flush(world);
-
-    *objectives = Objectives {
-        win_conditions: vec![],
-        lose_conditions: vec![],
+    let units: u32 = original.units.iter().map(|placement| placement.count).sum();
+    let pumps = |scenario: &Scenario| {
+        scenario
+            .guysers
+            .iter()
+            .filter(|guyser| guyser.pump == Some(Side::Purple))
+            .count()
     };
-    *camera = Camera {
-        looking_at: Vec2::new(0.0, -90.0),
-        distance: 50.0,
-    };
-    *cheese_coins = CheeseCoins(10_000_000);
-    *ai_build_orders = AiBuildOrders::default();
+
+    assert_eq!(saved.units.len(), units as usize);
+    assert_eq!(saved.buildings.len(), original.buildings.len());
+    assert_eq!(saved.guysers.len(), original.guysers.len());
+    assert_eq!(pumps(&saved), pumps(&original));
 }
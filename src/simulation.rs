@@ -0,0 +1,1440 @@
+use crate::assets::ModelAnimations;
+use crate::ecs::{self, Side};
+use crate::pathfinding::Map;
+use crate::replay::{RecordedCommand, Replay};
+use crate::resources::{
+    AiBuildOrders, AiDifficulty, AiPlayers, Alliances, Camera, CheeseCoins, ControlGroups,
+    CosmeticRng, DeltaTime, Editor, GameStats, GameplayRng, Gravity, MatchSettings, Mode,
+    NextEntityId, Objectives, PlayerCommands, PlayerSide, ReplayPlayback, ScenarioMessage, Supply,
+    Tick, TickInterpolation, TotalTime, Triggers, Upgrades, Visibility,
+};
+use crate::save;
+use crate::scenarios::{self, Scenario};
+use crate::snapshot::Snapshot;
+use legion::*;
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use std::path::Path;
+#[cfg(test)]
+use ultraviolet::Vec2;
+
+/// The gameplay half of the game: a world, the resources that the gameplay systems need and a
+/// schedule to run them. Nothing in here touches the gpu or a window, so it can be used to run
+/// matches headless (on a server or in tests).
+///
+/// The windowed game builds on top of this by inserting the rendering resources and running the
+/// control and rendering schedules around `advance`.
+///
+/// Gameplay always runs in ticks of `TICK_DURATION`, regardless of the framerate, so that the same
+/// inputs always give the same match.
+pub struct Simulation {
+    pub world: World,
+    pub resources: Resources,
+    schedule: Schedule,
+    // Time that has passed but hasn't been simulated yet.
+    accumulator: f32,
+    // Only set while watching a replay.
+    playback: Option<Playback>,
+    // Matches that were loaded from a save can't be replayed, because the replay would have to
+    // start from the beginning.
+    loaded_from_save: bool,
+}
+
+// The commands of the replay that's being watched, and snapshots to seek back to.
+struct Playback {
+    commands: Vec<RecordedCommand>,
+    next_command: usize,
+    // Taken every `SNAPSHOT_INTERVAL` ticks as the replay plays, in order.
+    snapshots: Vec<Snapshot>,
+}
+
+impl Playback {
+    // Called right before a tick runs.
+    fn prepare_tick(&mut self, world: &World, resources: &Resources) {
+        let tick = resources.get::<Tick>().unwrap().0;
+
+        if tick.is_multiple_of(SNAPSHOT_INTERVAL)
+            && self
+                .snapshots
+                .last()
+                .map(|snapshot| snapshot.tick() < tick)
+                .unwrap_or(true)
+        {
+            self.snapshots.push(Snapshot::take(world, resources));
+        }
+
+        let mut player_commands = resources.get_mut::<PlayerCommands>().unwrap();
+
+        while let Some(recorded) = self.commands.get(self.next_command) {
+            if recorded.tick > tick {
+                break;
+            }
+
+            player_commands.0.push(recorded.command.clone());
+            self.next_command += 1;
+        }
+    }
+}
+
+/// Gameplay runs at 60 ticks per second.
+pub const TICK_DURATION: f32 = 1.0 / 60.0;
+// If a frame takes longer than this (e.g. the window was being dragged), we drop the extra time
+// instead of trying to catch up with hundreds of ticks.
+const MAX_FRAME_TIME: f32 = 0.25;
+// Seeking backwards in a replay re-simulates from the last snapshot before the seek point, so this
+// is a tradeoff between memory and how long seeking takes. 10 seconds seems fine.
+const SNAPSHOT_INTERVAL: u64 = 60 * 10;
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Simulation {
+    pub fn new() -> Self {
+        let mut resources = Resources::default();
+        resources.insert(PlayerSide(Side::Green));
+        resources.insert(Camera::default());
+        resources.insert(ControlGroups::default());
+        resources.insert(Mode::Titlescreen);
+        resources.insert(Gravity(5.0));
+        resources.insert(CheeseCoins::default());
+        resources.insert(Supply::default());
+        resources.insert(Upgrades::default());
+        resources.insert(TotalTime(0.0));
+        resources.insert(DeltaTime(0.0));
+        resources.insert(Tick(0));
+        resources.insert(TickInterpolation(0.0));
+        resources.insert(AiBuildOrders::default());
+        resources.insert(AiPlayers::default());
+        resources.insert(Alliances::default());
+        resources.insert(Visibility::default());
+        resources.insert(GameStats::default());
+        resources.insert(Objectives::default());
+        resources.insert(Triggers::default());
+        resources.insert(ScenarioMessage::default());
+        resources.insert(Map::new());
+        resources.insert(MatchSettings {
+            scenario: String::new(),
+            seed: 0,
+            difficulty: AiDifficulty::default(),
+        });
+        resources.insert(AiDifficulty::default());
+        resources.insert(GameplayRng::new(0));
+        resources.insert(CosmeticRng::new(0));
+        resources.insert(NextEntityId(0));
+        resources.insert(PlayerCommands::default());
+        resources.insert(Replay::new("", 0, AiDifficulty::default()));
+        // Replaced with the real animations when running with a window.
+        resources.insert(ModelAnimations::default());
+
+        let mut builder = Schedule::builder();
+        ecs::add_gameplay_systems(&mut builder);
+
+        Self {
+            world: World::default(),
+            resources,
+            schedule: builder.build(),
+            accumulator: 0.0,
+            playback: None,
+            loaded_from_save: false,
+        }
+    }
+
+    /// Clear the world and set up the scenario called `scenario`. Both random number streams are
+    /// seeded from `seed`, so starting the same scenario with the same seed always plays out the
+    /// same way. The enemy plays at the `AiDifficulty` resource's difficulty.
+    pub fn start_scenario(&mut self, scenario: &str, seed: u64) -> anyhow::Result<()> {
+        let difficulty = *self.resources.get::<AiDifficulty>().unwrap();
+        self.start_scenario_with_difficulty(scenario, seed, difficulty)
+    }
+
+    fn start_scenario_with_difficulty(
+        &mut self,
+        scenario: &str,
+        seed: u64,
+        difficulty: AiDifficulty,
+    ) -> anyhow::Result<()> {
+        let loaded = Scenario::load(scenario)?;
+        self.set_up(&loaded, scenario, seed, difficulty)?;
+        *self.resources.get_mut::<Mode>().unwrap() = Mode::Playing;
+        Ok(())
+    }
+
+    /// Open the scenario called `scenario` in the editor, or a new empty scenario if it's `None`.
+    pub fn start_editor(&mut self, scenario: Option<&str>) -> anyhow::Result<()> {
+        let (name, loaded) = match scenario {
+            Some(name) => (name.to_string(), Scenario::load(name)?),
+            None => {
+                let (name, title) = scenarios::unused_name();
+                (name, Scenario::new(title))
+            }
+        };
+
+        self.set_up(&loaded, &name, 0, AiDifficulty::default())?;
+        // Everything has to be visible to be edited.
+        self.resources.insert(Visibility::new(false));
+        self.resources.insert(Editor::new(name, loaded));
+        *self.resources.get_mut::<Mode>().unwrap() = Mode::Editor;
+        Ok(())
+    }
+
+    fn set_up(
+        &mut self,
+        scenario: &Scenario,
+        name: &str,
+        seed: u64,
+        difficulty: AiDifficulty,
+    ) -> anyhow::Result<()> {
+        let world = &mut self.world;
+        let resources = &mut self.resources;
+
+        resources.insert(MatchSettings {
+            scenario: name.to_string(),
+            seed,
+            difficulty,
+        });
+        resources.insert(GameplayRng::new(seed));
+        resources.insert(CosmeticRng::new(seed));
+        resources.insert(NextEntityId(0));
+        resources.insert(PlayerCommands::default());
+        resources.insert(Replay::new(name, seed, difficulty));
+        resources.insert(TotalTime(0.0));
+        resources.insert(Tick(0));
+        resources.insert(GameStats::default());
+        resources.insert(Upgrades::default());
+        resources.insert(ControlGroups::default());
+        resources.insert(Map::new());
+        resources.insert(Visibility::new(true));
+        self.accumulator = 0.0;
+        self.playback = None;
+        self.loaded_from_save = false;
+
+        world.clear();
+
+        if let Err(error) = scenario.spawn(world, resources) {
+            world.clear();
+            return Err(error.context(format!("Failed to start {}", name)));
+        }
+
+        ecs::assign_entity_ids(world, &mut resources.get_mut::<NextEntityId>().unwrap());
+
+        Ok(())
+    }
+
+    /// Start watching a replay from the beginning.
+    pub fn start_replay(&mut self, replay: Replay) -> anyhow::Result<()> {
+        self.start_scenario_with_difficulty(&replay.scenario, replay.seed, replay.difficulty)?;
+        self.resources.insert(ReplayPlayback::new(replay.ticks));
+        self.playback = Some(Playback {
+            commands: replay.commands,
+            next_command: 0,
+            snapshots: Vec::new(),
+        });
+        *self.resources.get_mut::<Mode>().unwrap() = Mode::Replay;
+        Ok(())
+    }
+
+    /// Run the gameplay systems for a single tick.
+    pub fn tick(&mut self) {
+        if let Some(playback) = &mut self.playback {
+            playback.prepare_tick(&self.world, &self.resources);
+        }
+
+        self.resources.insert(DeltaTime(TICK_DURATION));
+        self.schedule.execute(&mut self.world, &mut self.resources);
+        self.resources.get_mut::<TotalTime>().unwrap().0 += TICK_DURATION;
+        self.resources.get_mut::<Tick>().unwrap().0 += 1;
+    }
+
+    /// Run as many ticks as fit into the time that has passed since the last frame, carrying over
+    /// the remainder. `TickInterpolation` is set to how far into the next tick we are.
+    pub fn advance(&mut self, elapsed: f32) {
+        // The control and rendering systems use the real frame time.
+        let frame_delta_time = self.resources.remove::<DeltaTime>();
+
+        let mut speed = 1.0;
+
+        if self.mode() == Mode::Replay {
+            let (seek_to, playback_speed) = {
+                let mut playback = self.resources.get_mut::<ReplayPlayback>().unwrap();
+                let speed = if playback.paused { 0.0 } else { playback.speed };
+                (playback.seek_to.take(), speed)
+            };
+
+            if let Some(tick) = seek_to {
+                self.seek(tick);
+            }
+
+            speed = playback_speed;
+        }
+
+        self.accumulator += elapsed.min(MAX_FRAME_TIME) * speed;
+
+        while self.accumulator >= TICK_DURATION && self.is_running() {
+            self.tick();
+            self.accumulator -= TICK_DURATION;
+        }
+
+        // Don't let time build up while a replay is sitting at the end.
+        if !self.is_running() {
+            self.accumulator = self.accumulator.min(TICK_DURATION);
+        }
+
+        self.resources
+            .insert(TickInterpolation(self.accumulator / TICK_DURATION));
+
+        if let Some(delta_time) = frame_delta_time {
+            self.resources.insert(delta_time);
+        }
+    }
+
+    /// Jump to a tick of the replay that's being watched. Seeking backwards restores the latest
+    /// snapshot before the tick and simulates forwards from there.
+    pub fn seek(&mut self, tick: u64) {
+        let length = match self.resources.get::<ReplayPlayback>() {
+            Some(playback) if self.playback.is_some() => playback.length,
+            _ => return,
+        };
+
+        let target = tick.min(length);
+
+        if target < self.current_tick() {
+            let playback = self.playback.as_mut().unwrap();
+
+            // There's always a snapshot from the first tick.
+            if let Some(snapshot) = playback
+                .snapshots
+                .iter()
+                .rev()
+                .find(|snapshot| snapshot.tick() <= target)
+            {
+                snapshot.restore(&mut self.world, &mut self.resources);
+                playback.next_command = playback
+                    .commands
+                    .iter()
+                    .position(|recorded| recorded.tick >= snapshot.tick())
+                    .unwrap_or(playback.commands.len());
+                self.resources
+                    .get_mut::<PlayerCommands>()
+                    .unwrap()
+                    .0
+                    .clear();
+            }
+        }
+
+        while self.current_tick() < target {
+            self.tick();
+        }
+
+        self.accumulator = 0.0;
+    }
+
+    /// The replay of the current match so far.
+    pub fn replay(&self) -> Replay {
+        let mut replay = self.resources.get::<Replay>().unwrap().clone();
+        replay.ticks = self.current_tick();
+        replay
+    }
+
+    /// Write the replay of the current match to the `replays` directory.
+    pub fn save_replay(&self) -> anyhow::Result<std::path::PathBuf> {
+        if self.loaded_from_save {
+            return Err(anyhow::anyhow!(
+                "Matches that were loaded from a save can't be replayed"
+            ));
+        }
+
+        let replay = self.replay();
+        let path = replay.default_path();
+        replay.save(&path)?;
+        Ok(path)
+    }
+
+    /// Save the match that's being played.
+    pub fn save_game(&self, path: &Path) -> anyhow::Result<()> {
+        save::save(&self.world, &self.resources, path)
+    }
+
+    /// Replace the current match with a saved one and start playing it.
+    pub fn load_game(&mut self, path: &Path) -> anyhow::Result<()> {
+        save::load(&mut self.world, &mut self.resources, path)?;
+        self.resources.insert(PlayerCommands::default());
+        self.accumulator = 0.0;
+        self.playback = None;
+        self.loaded_from_save = true;
+        *self.resources.get_mut::<Mode>().unwrap() = Mode::Playing;
+        Ok(())
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.resources.get::<Mode>().unwrap().clone()
+    }
+
+    fn current_tick(&self) -> u64 {
+        self.resources.get::<Tick>().unwrap().0
+    }
+
+    // Whether ticks should be run.
+    fn is_running(&self) -> bool {
+        match self.mode() {
+            Mode::Playing => true,
+            Mode::Replay => {
+                let length = self.resources.get::<ReplayPlayback>().unwrap().length;
+                self.current_tick() < length
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Pick a seed for a match when the player hasn't given one.
+pub fn random_seed() -> u64 {
+    SmallRng::from_entropy().gen()
+}
+
+// A skirmish with the scripted enemy turned off, for tests that set up what they need themselves.
+#[cfg(test)]
+fn skirmish_without_ai() -> Simulation {
+    let mut simulation = Simulation::new();
+    simulation.start_scenario("3-skirmish", 0).unwrap();
+    simulation.resources.get_mut::<AiPlayers>().unwrap().0[0].enabled = false;
+    simulation
+}
+
+#[cfg(test)]
+fn spawn(simulation: &mut Simulation, unit: ecs::Unit, position: Vec2, side: Side) -> Entity {
+    let mut buffer = systems::CommandBuffer::new(&simulation.world);
+    let entity = unit.add_to_world(&mut buffer, None, position, ecs::Facing(0.0), side, None);
+    buffer.flush(&mut simulation.world);
+    entity
+}
+
+#[cfg(test)]
+fn spawn_building(
+    simulation: &mut Simulation,
+    building: ecs::Building,
+    position: Vec2,
+    side: Side,
+) -> Entity {
+    let animations = simulation.resources.get::<ModelAnimations>().unwrap();
+    let mut map = simulation.resources.get_mut::<Map>().unwrap();
+    building
+        .add_to_world_fully_built(&mut simulation.world, position, side, &animations, &mut map)
+        .unwrap()
+}
+
+#[cfg(test)]
+fn health(simulation: &Simulation, entity: Entity) -> f32 {
+    get::<ecs::Health>(simulation, entity).0
+}
+
+#[cfg(test)]
+fn position(simulation: &Simulation, entity: Entity) -> Vec2 {
+    get::<ecs::Position>(simulation, entity).0
+}
+
+// Entities are referred to by `EntityId` in player commands.
+#[cfg(test)]
+fn id(simulation: &Simulation, entity: Entity) -> ecs::EntityId {
+    get::<ecs::EntityId>(simulation, entity)
+}
+
+#[cfg(test)]
+fn get<T: Clone + Send + Sync + 'static>(simulation: &Simulation, entity: Entity) -> T {
+    simulation
+        .world
+        .entry_ref(entity)
+        .unwrap()
+        .get_component::<T>()
+        .unwrap()
+        .clone()
+}
+
+#[test]
+fn headless_skirmish() {
+    use crate::ecs::Building;
+
+    let mut simulation = Simulation::new();
+    simulation.start_scenario("3-skirmish", 0).unwrap();
+    assert_eq!(simulation.mode(), Mode::Playing);
+
+    // The ai should have started on its economy by the end of the first minute.
+    for _ in 0..60 * 60 {
+        simulation.tick();
+    }
+
+    let enemy_buildings = |building_type: Building| {
+        <(&Building, &Side)>::query()
+            .iter(&simulation.world)
+            .filter(|(building, side)| **building == building_type && **side == Side::Purple)
+            .count()
+    };
+
+    assert!(enemy_buildings(Building::Pump) >= 2);
+    assert!(enemy_buildings(Building::Armoury) >= 1);
+    assert_eq!(simulation.mode(), Mode::Playing);
+}
+
+#[test]
+fn build_orders_are_paid_for() {
+    use crate::ecs::Building;
+    use crate::resources::{AiBuildOrderItem, AiBuildOrders, CheeseCoins};
+
+    let mut simulation = skirmish_without_ai();
+    *simulation.resources.get_mut::<AiBuildOrders>().unwrap() = AiBuildOrders(vec![
+        (0.0, AiBuildOrderItem::BuildArmoury(Vec2::new(45.0, 50.0))),
+        // This overlaps the armoury, so it can't be followed.
+        (0.0, AiBuildOrderItem::BuildLarder(Vec2::new(46.0, 51.0))),
+        (0.0, AiBuildOrderItem::SetWaypoint(Vec2::new(40.0, 40.0))),
+    ]);
+
+    let armouries = |simulation: &Simulation| {
+        <(&Building, &Side)>::query()
+            .iter(&simulation.world)
+            .filter(|(building, side)| **building == Building::Armoury && **side == Side::Purple)
+            .count()
+    };
+
+    // The AI starts with 100 coins, which isn't enough for an armoury, so it has to wait.
+    simulation.tick();
+    assert_eq!(armouries(&simulation), 0);
+    assert_eq!(
+        simulation.resources.get::<AiBuildOrders>().unwrap().0.len(),
+        3
+    );
+
+    simulation
+        .resources
+        .get_mut::<CheeseCoins>()
+        .unwrap()
+        .add(Side::Purple, 100);
+    simulation.tick();
+    simulation.tick();
+
+    let cheese_coins = simulation.resources.get::<CheeseCoins>().unwrap().clone();
+    assert_eq!(armouries(&simulation), 1);
+    assert_eq!(cheese_coins.get(Side::Purple), 0);
+    assert_eq!(cheese_coins.get(Side::Green), 100);
+
+    // The larder is skipped once it can be afforded, without being paid for.
+    simulation
+        .resources
+        .get_mut::<CheeseCoins>()
+        .unwrap()
+        .add(Side::Purple, 100);
+    simulation.tick();
+    simulation.tick();
+
+    let cheese_coins = simulation.resources.get::<CheeseCoins>().unwrap().clone();
+    assert_eq!(cheese_coins.get(Side::Purple), 100);
+    assert!(simulation
+        .resources
+        .get::<AiBuildOrders>()
+        .unwrap()
+        .0
+        .is_empty());
+}
+
+#[test]
+fn recruitment_is_limited_by_supply() {
+    use crate::ecs::{Building, QueueItem, RecruitmentQueue, Unit};
+    use crate::resources::Supply;
+
+    let mut simulation = skirmish_without_ai();
+
+    let armoury = spawn_building(
+        &mut simulation,
+        Building::Armoury,
+        Vec2::new(-40.0, -50.0),
+        Side::Green,
+    );
+    simulation
+        .world
+        .entry(armoury)
+        .unwrap()
+        .get_component_mut::<RecruitmentQueue>()
+        .unwrap()
+        .queue
+        .extend(std::iter::repeat_n(QueueItem::Unit(Unit::MouseMarine), 10));
+
+    let run_for_a_minute = |simulation: &mut Simulation| {
+        for _ in 0..60 * 60 {
+            simulation.tick();
+        }
+
+        let supply = simulation.resources.get::<Supply>().unwrap().clone();
+        let marines = <(&Unit, &Side)>::query()
+            .iter(&simulation.world)
+            .filter(|(unit, side)| **unit == Unit::MouseMarine && **side == Side::Green)
+            .count();
+        (marines, supply.used(Side::Green), supply.cap(Side::Green))
+    };
+
+    // The engineer and four marines leave no room for a fifth.
+    assert_eq!(run_for_a_minute(&mut simulation), (4, 9, Supply::BASE));
+
+    spawn_building(
+        &mut simulation,
+        Building::Larder,
+        Vec2::new(-40.0, -70.0),
+        Side::Green,
+    );
+    assert_eq!(
+        run_for_a_minute(&mut simulation),
+        (9, 19, Supply::BASE + 10)
+    );
+}
+
+#[test]
+fn upgrades_are_researched_in_order() {
+    use crate::ecs::{Building, EntityId, PlayerCommand, Upgrade};
+    use crate::resources::PlayerCommands;
+
+    let mut simulation = skirmish_without_ai();
+    simulation
+        .resources
+        .get_mut::<CheeseCoins>()
+        .unwrap()
+        .add(Side::Green, 1000);
+
+    spawn_building(
+        &mut simulation,
+        Building::Larder,
+        Vec2::new(-40.0, -50.0),
+        Side::Green,
+    );
+    simulation.tick();
+
+    let larder = <(&EntityId, &Building)>::query()
+        .iter(&simulation.world)
+        .find(|(_, building)| **building == Building::Larder)
+        .map(|(id, _)| *id)
+        .unwrap();
+
+    let research = |simulation: &mut Simulation, upgrade| {
+        simulation
+            .resources
+            .get_mut::<PlayerCommands>()
+            .unwrap()
+            .0
+            .push(PlayerCommand::Research {
+                building: larder,
+                upgrade,
+            });
+        simulation.tick();
+    };
+
+    // Building armour can't be researched until engineer build speed has been.
+    research(&mut simulation, Upgrade::BuildingArmour);
+    research(&mut simulation, Upgrade::EngineerBuildSpeed);
+    // Each upgrade can only be queued once.
+    research(&mut simulation, Upgrade::EngineerBuildSpeed);
+    let spent = Upgrade::EngineerBuildSpeed.stats().cost;
+    let coins = |simulation: &Simulation| {
+        simulation
+            .resources
+            .get::<CheeseCoins>()
+            .unwrap()
+            .get(Side::Green)
+    };
+    assert!(coins(&simulation) <= 1100 - spent);
+    assert!(coins(&simulation) > 1100 - spent - Upgrade::BuildingArmour.stats().cost);
+
+    for _ in 0..60 * 30 {
+        simulation.tick();
+    }
+
+    let upgrades = simulation.resources.get::<Upgrades>().unwrap().clone();
+    assert!(upgrades.has(Side::Green, Upgrade::EngineerBuildSpeed));
+    assert!(!upgrades.has(Side::Green, Upgrade::BuildingArmour));
+    assert!(!upgrades.has(Side::Purple, Upgrade::EngineerBuildSpeed));
+
+    research(&mut simulation, Upgrade::BuildingArmour);
+    for _ in 0..60 * 30 {
+        simulation.tick();
+    }
+
+    let upgrades = simulation.resources.get::<Upgrades>().unwrap();
+    assert!(upgrades.has(Side::Green, Upgrade::BuildingArmour));
+    assert_eq!(upgrades.amount(Side::Green, Upgrade::BuildingArmour), 0.25);
+}
+
+#[test]
+fn larders_cant_recruit() {
+    use crate::ecs::{Building, PlayerCommand, RecruitmentQueue, Unit};
+    use crate::resources::{AiBuildOrderItem, AiBuildOrders, PlayerCommands};
+
+    let mut simulation = skirmish_without_ai();
+    let larders = [
+        spawn_building(
+            &mut simulation,
+            Building::Larder,
+            Vec2::new(-40.0, -50.0),
+            Side::Green,
+        ),
+        spawn_building(
+            &mut simulation,
+            Building::Larder,
+            Vec2::new(40.0, 50.0),
+            Side::Purple,
+        ),
+    ];
+    simulation.tick();
+
+    // Larders have a queue for research, so both the player and the AI could try to recruit there.
+    let command = PlayerCommand::Recruit {
+        building: id(&simulation, larders[0]),
+        unit: Unit::MouseMarine,
+    };
+    simulation
+        .resources
+        .get_mut::<PlayerCommands>()
+        .unwrap()
+        .0
+        .push(command);
+    *simulation.resources.get_mut::<AiBuildOrders>().unwrap() =
+        AiBuildOrders(vec![(0.0, AiBuildOrderItem::RecruitMarine(1))]);
+    simulation.tick();
+
+    for &larder in &larders {
+        assert!(get::<RecruitmentQueue>(&simulation, larder)
+            .queue
+            .is_empty());
+    }
+    let cheese_coins = simulation.resources.get::<CheeseCoins>().unwrap();
+    assert_eq!(cheese_coins.get(Side::Green), 100);
+    assert_eq!(cheese_coins.get(Side::Purple), 100);
+}
+
+#[test]
+fn mind_control_switches_sides() {
+    use crate::ecs::{Energy, PlayerCastTarget, PlayerCommand, Selected, Spell, Unit};
+    use crate::resources::{ControlGroups, PlayerCommands};
+
+    let mut simulation = skirmish_without_ai();
+    let target = spawn(
+        &mut simulation,
+        Unit::Engineer,
+        Vec2::new(-30.0, -30.0),
+        Side::Purple,
+    );
+    let psychic = spawn(
+        &mut simulation,
+        Unit::Psychic,
+        Vec2::new(-30.0, -42.0),
+        Side::Green,
+    );
+    let marine = spawn(
+        &mut simulation,
+        Unit::MouseMarine,
+        Vec2::new(-30.0, -90.0),
+        Side::Green,
+    );
+
+    // The player can select enemy units to look at them.
+    simulation
+        .world
+        .entry(target)
+        .unwrap()
+        .add_component(Selected);
+    simulation.resources.get_mut::<ControlGroups>().unwrap().0[1].push(target);
+    simulation.tick();
+
+    let (target_id, psychic_id, marine_id) = (
+        id(&simulation, target),
+        id(&simulation, psychic),
+        id(&simulation, marine),
+    );
+
+    {
+        let mut player_commands = simulation.resources.get_mut::<PlayerCommands>().unwrap();
+        player_commands.0.push(PlayerCommand::Attack {
+            units: vec![marine_id],
+            target: target_id,
+            queued: false,
+        });
+        player_commands.0.push(PlayerCommand::Cast {
+            units: vec![psychic_id, marine_id],
+            spell: Spell::MindControl,
+            target: PlayerCastTarget::Entity(target_id),
+            queued: false,
+        });
+    }
+
+    for _ in 0..60 * 4 {
+        simulation.tick();
+    }
+
+    let entry = simulation.world.entry_ref(target).unwrap();
+    assert_eq!(*entry.get_component::<Side>().unwrap(), Side::Green);
+    assert!(entry.get_component::<Selected>().is_err());
+    assert!(simulation.resources.get::<ControlGroups>().unwrap().0[1].is_empty());
+
+    // The marine was on its way to attack the engineer, but it's an ally now.
+    let marine_position = position(&simulation, marine);
+    simulation.tick();
+    assert_eq!(position(&simulation, marine), marine_position);
+
+    let energy = get::<Energy>(&simulation, psychic).0;
+    assert!(energy < Spell::MindControl.stats().energy_cost);
+}
+
+#[test]
+fn hits_in_the_same_tick_add_up() {
+    use crate::ecs::Unit;
+
+    let mut simulation = skirmish_without_ai();
+    let target = spawn(
+        &mut simulation,
+        Unit::Engineer,
+        Vec2::new(-30.0, -30.0),
+        Side::Purple,
+    );
+    // Both marines fire at the same time from the same place, so their bullets land together.
+    for _ in 0..2 {
+        spawn(
+            &mut simulation,
+            Unit::MouseMarine,
+            Vec2::new(-30.0, -35.0),
+            Side::Green,
+        );
+    }
+
+    let max_health = Unit::Engineer.stats().max_health;
+
+    for _ in 0..60 {
+        simulation.tick();
+
+        if health(&simulation, target) < max_health {
+            break;
+        }
+    }
+
+    let damage = Unit::MouseMarine.stats().damage - Unit::Engineer.stats().armour;
+    assert_eq!(health(&simulation, target), max_health - damage * 2.0);
+}
+
+#[test]
+fn artillery_shells_hit_everything_nearby() {
+    use crate::ecs::{PlayerCommand, Unit};
+    use crate::resources::PlayerCommands;
+
+    let mut simulation = skirmish_without_ai();
+    let artillery = spawn(
+        &mut simulation,
+        Unit::Artillery,
+        Vec2::new(-30.0, -60.0),
+        Side::Green,
+    );
+    let target = spawn(
+        &mut simulation,
+        Unit::Engineer,
+        Vec2::new(-30.0, -45.0),
+        Side::Purple,
+    );
+    let friendly = spawn(
+        &mut simulation,
+        Unit::Engineer,
+        Vec2::new(-28.0, -45.0),
+        Side::Green,
+    );
+    // This is too close to be fired at, so the artillery has to back away first.
+    let too_close = spawn(
+        &mut simulation,
+        Unit::Engineer,
+        Vec2::new(-30.0, -63.0),
+        Side::Purple,
+    );
+    simulation.tick();
+
+    let (artillery_id, target_id, too_close_id) = (
+        id(&simulation, artillery),
+        id(&simulation, target),
+        id(&simulation, too_close),
+    );
+
+    simulation
+        .resources
+        .get_mut::<PlayerCommands>()
+        .unwrap()
+        .0
+        .push(PlayerCommand::Attack {
+            units: vec![artillery_id],
+            target: too_close_id,
+            queued: false,
+        });
+
+    for _ in 0..60 * 3 {
+        simulation.tick();
+    }
+
+    let distance = (position(&simulation, artillery) - position(&simulation, too_close)).mag();
+    assert!(distance >= Unit::Artillery.stats().min_firing_range);
+
+    simulation
+        .resources
+        .get_mut::<PlayerCommands>()
+        .unwrap()
+        .0
+        .push(PlayerCommand::Attack {
+            units: vec![artillery_id],
+            target: target_id,
+            queued: false,
+        });
+
+    let max_health = Unit::Engineer.stats().max_health;
+
+    for _ in 0..60 * 5 {
+        simulation.tick();
+
+        if health(&simulation, target) < max_health {
+            break;
+        }
+    }
+
+    assert!(health(&simulation, target) < max_health);
+    assert!(health(&simulation, friendly) < max_health);
+}
+
+#[test]
+fn turrets_fire_at_enemies_in_range() {
+    use crate::ecs::{Building, Unit};
+
+    let mut simulation = skirmish_without_ai();
+    spawn_building(
+        &mut simulation,
+        Building::Turret,
+        Vec2::new(-30.0, -60.0),
+        Side::Green,
+    );
+    let in_range = spawn(
+        &mut simulation,
+        Unit::Engineer,
+        Vec2::new(-30.0, -50.0),
+        Side::Purple,
+    );
+    let out_of_range = spawn(
+        &mut simulation,
+        Unit::Engineer,
+        Vec2::new(-30.0, -40.0),
+        Side::Purple,
+    );
+
+    for _ in 0..60 * 2 {
+        simulation.tick();
+    }
+
+    let max_health = Unit::Engineer.stats().max_health;
+    assert!(health(&simulation, in_range) < max_health);
+    assert_eq!(health(&simulation, out_of_range), max_health);
+}
+
+#[test]
+fn difficulty_changes_how_the_ai_plays() {
+    use crate::ecs::{Building, Unit};
+    use crate::resources::{AiPlayers, CheeseCoins};
+
+    struct Outcome {
+        income: [u32; 2],
+        retreated: bool,
+        closest_hit: bool,
+        weakest_hit: bool,
+    }
+
+    // The same skirmish, with a damaged unit away from the AI's base and another unit between a
+    // nearby engineer and a damaged one that's further away.
+    let play = |difficulty| {
+        let mut simulation = Simulation::new();
+        simulation
+            .start_scenario_with_difficulty("3-skirmish", 0, difficulty)
+            .unwrap();
+        simulation.resources.get_mut::<AiPlayers>().unwrap().0[0].enabled = false;
+
+        spawn_building(
+            &mut simulation,
+            Building::Pump,
+            Vec2::new(-56.55, -33.89),
+            Side::Green,
+        );
+        // The AI retreats to the middle of its buildings, which has to be somewhere it can go.
+        for &guyser in &[Vec2::new(56.55, 33.89), Vec2::new(25.85, 60.5)] {
+            spawn_building(&mut simulation, Building::Pump, guyser, Side::Purple);
+        }
+
+        let damaged = spawn(
+            &mut simulation,
+            Unit::MouseMarine,
+            Vec2::new(0.0, -60.0),
+            Side::Purple,
+        );
+        spawn(
+            &mut simulation,
+            Unit::MouseMarine,
+            Vec2::new(-20.0, 20.0),
+            Side::Purple,
+        );
+        let closest = spawn(
+            &mut simulation,
+            Unit::Engineer,
+            Vec2::new(-20.0, 15.0),
+            Side::Green,
+        );
+        let weakest = spawn(
+            &mut simulation,
+            Unit::Engineer,
+            Vec2::new(-20.0, 28.0),
+            Side::Green,
+        );
+        for &(entity, fraction) in &[(damaged, 0.1), (closest, 1.0), (weakest, 0.5)] {
+            let health = get::<Unit>(&simulation, entity).stats().max_health * fraction;
+            let mut entry = simulation.world.entry(entity).unwrap();
+            entry.get_component_mut::<ecs::Health>().unwrap().0 = health;
+        }
+        let (closest_health, weakest_health) =
+            (health(&simulation, closest), health(&simulation, weakest));
+
+        let starting_coins = simulation
+            .resources
+            .get::<CheeseCoins>()
+            .unwrap()
+            .get(Side::Purple);
+
+        for _ in 0..60 {
+            simulation.tick();
+        }
+
+        let retreated = position(&simulation, damaged) != Vec2::new(0.0, -60.0);
+        let closest_hit = health(&simulation, closest) < closest_health;
+        let weakest_hit = health(&simulation, weakest) < weakest_health;
+
+        for _ in 0..60 * 10 {
+            simulation.tick();
+        }
+
+        let cheese_coins = simulation.resources.get::<CheeseCoins>().unwrap();
+        let income = |side| cheese_coins.get(side) - starting_coins;
+
+        Outcome {
+            income: [income(Side::Green), income(Side::Purple)],
+            retreated,
+            closest_hit,
+            weakest_hit,
+        }
+    };
+
+    let easy = play(AiDifficulty::Easy);
+    let insane = play(AiDifficulty::Insane);
+
+    // Only the AI's income changes. It has twice as many pumps as the player.
+    assert_eq!(easy.income[0], insane.income[0]);
+    assert!(easy.income[1] < easy.income[0] * 2);
+    assert!(insane.income[1] > insane.income[0] * 2);
+
+    assert!(!easy.retreated);
+    assert!(insane.retreated);
+
+    // The AI goes for the closest engineer on easy, but finishes off the damaged one on insane.
+    assert!(easy.closest_hit && !easy.weakest_hit);
+    assert!(insane.weakest_hit && !insane.closest_hit);
+}
+
+#[test]
+fn team_skirmish() {
+    use crate::ecs::{Building, DamagedThisTick, Unit};
+    use crate::resources::{Alliances, GameStats};
+
+    let mut simulation = Simulation::new();
+    simulation.start_scenario("4-team-skirmish", 0).unwrap();
+
+    let alliances = simulation.resources.get::<Alliances>().unwrap().clone();
+    assert!(alliances.are_allies(Side::Green, Side::Blue));
+    assert!(alliances.are_allies(Side::Purple, Side::Orange));
+    assert!(alliances.are_enemies(Side::Blue, Side::Purple));
+
+    // Only the enemy's engineer counts as a kill for the player.
+    let ally = spawn(
+        &mut simulation,
+        Unit::Engineer,
+        Vec2::new(0.0, -20.0),
+        Side::Blue,
+    );
+    let enemy = spawn(
+        &mut simulation,
+        Unit::Engineer,
+        Vec2::new(0.0, 20.0),
+        Side::Purple,
+    );
+    for &entity in &[ally, enemy] {
+        simulation
+            .world
+            .entry(entity)
+            .unwrap()
+            .add_component(DamagedThisTick {
+                damage: 1000.0,
+                source: entity,
+                by_enemy: true,
+            });
+    }
+    simulation.tick();
+
+    let stats = simulation.resources.get::<GameStats>().unwrap().clone();
+    assert_eq!((stats.units_lost, stats.enemy_units_killed), (0, 1));
+
+    for _ in 0..60 * 60 {
+        simulation.tick();
+    }
+
+    // Every computer side is played by its own AI, including the player's ally.
+    for side in &[Side::Blue, Side::Purple, Side::Orange] {
+        let pumps = <(&Building, &Side)>::query()
+            .iter(&simulation.world)
+            .filter(|(building, pump_side)| **building == Building::Pump && *pump_side == side)
+            .count();
+
+        assert!(pumps >= 2, "{:?} has {} pumps", side, pumps);
+    }
+}
+
+#[test]
+fn fog_of_war() {
+    use crate::ecs::Position;
+    use crate::resources::{Alliances, Visibility};
+
+    let mut simulation = Simulation::new();
+    simulation.start_scenario("4-team-skirmish", 0).unwrap();
+    simulation.tick();
+
+    let visibility = simulation.resources.get::<Visibility>().unwrap();
+    let alliances = simulation.resources.get::<Alliances>().unwrap();
+
+    for (position, side) in <(&Position, &Side)>::query().iter(&simulation.world) {
+        let seen = visibility.can_see(Side::Green, *side, position.0, &alliances);
+        assert_eq!(seen, alliances.are_allies(Side::Green, *side), "{:?}", side);
+    }
+
+    // The enemy's bases are out of sight at the start.
+    assert_eq!(visibility.ghosts_seen_by(Side::Green).count(), 0);
+    drop((visibility, alliances));
+
+    // Move a marine into sight of an enemy pump and then back out again.
+    let mut simulation = skirmish_without_ai();
+    let pump_position = Vec2::new(56.55, 33.89);
+    let pump = spawn_building(
+        &mut simulation,
+        ecs::Building::Pump,
+        pump_position,
+        Side::Purple,
+    );
+    let marine = spawn(
+        &mut simulation,
+        ecs::Unit::MouseMarine,
+        Vec2::new(0.0, 0.0),
+        Side::Green,
+    );
+
+    let move_marine = |simulation: &mut Simulation, position| {
+        let mut entry = simulation.world.entry(marine).unwrap();
+        entry.get_component_mut::<Position>().unwrap().0 = position;
+        // Forget about anything that it started attacking.
+        entry.add_component(ecs::CommandQueue::default());
+        simulation.tick();
+
+        let ghosts = simulation
+            .resources
+            .get::<Visibility>()
+            .unwrap()
+            .ghosts_seen_by(Side::Green)
+            .count();
+        let target = ecs::best_target(marine, &mut simulation.world, &simulation.resources);
+        let (army, enemy_positions) =
+            ecs::enemies_seen_by_ai(Side::Purple, &mut simulation.world, &simulation.resources);
+        let seen_by_ai = enemy_positions.contains(&position);
+
+        (ghosts, target, army, seen_by_ai)
+    };
+
+    // Everything is hidden from both sides.
+    assert_eq!(
+        move_marine(&mut simulation, Vec2::new(0.0, 0.0)),
+        (0, None, 0, false)
+    );
+    // The marine can see the pump, but the pump can't see as far.
+    assert_eq!(
+        move_marine(&mut simulation, pump_position - Vec2::new(0.0, 16.0)),
+        (1, Some(pump), 0, false)
+    );
+    // Once the pump can see the marine too, the AI counts it.
+    let close = pump_position - Vec2::new(0.0, 8.0);
+    assert_eq!(
+        move_marine(&mut simulation, close),
+        (1, Some(pump), 1, true)
+    );
+    // Back in the fog of war, the pump is only remembered.
+    assert_eq!(
+        move_marine(&mut simulation, Vec2::new(0.0, 0.0)),
+        (1, None, 0, false)
+    );
+}
+
+#[test]
+fn triggers_fire() {
+    use crate::resources::ScenarioMessage;
+
+    let mut simulation = Simulation::new();
+    simulation.start_scenario("2-base-building", 0).unwrap();
+    assert!(simulation
+        .resources
+        .get::<ScenarioMessage>()
+        .unwrap()
+        .0
+        .is_none());
+
+    for _ in 0..60 * 2 {
+        simulation.tick();
+    }
+
+    let message = simulation
+        .resources
+        .get::<ScenarioMessage>()
+        .unwrap()
+        .0
+        .clone();
+    assert_eq!(
+        message.map(|(text, _)| text),
+        Some("Build pumps on the cheese guysers to earn coins".to_string())
+    );
+    assert_eq!(simulation.resources.get::<Triggers>().unwrap().0.len(), 3);
+}
+
+#[test]
+fn objectives_track_progress() {
+    use crate::resources::{LoseCondition, Objectives, WinCondition};
+
+    let mut simulation = Simulation::new();
+    simulation.start_scenario("1-attacking", 0).unwrap();
+    simulation.resources.insert(Objectives {
+        win_conditions: vec![WinCondition::Survive {
+            seconds: 2.0,
+            elapsed: 0.0,
+        }],
+        lose_conditions: vec![LoseCondition::LoseTagged("outpost".into())],
+    });
+
+    for _ in 0..60 {
+        simulation.tick();
+    }
+
+    {
+        let objectives = simulation.resources.get::<Objectives>().unwrap();
+        assert_eq!(
+            objectives.win_conditions[0].to_string(),
+            "Survive for 2 seconds (1/2)"
+        );
+        assert_eq!(
+            objectives.lose_conditions[0].to_string(),
+            "Protect the outpost"
+        );
+    }
+
+    for _ in 0..70 {
+        simulation.tick();
+    }
+
+    assert_eq!(simulation.mode(), Mode::ScenarioWon);
+}
+
+#[test]
+fn framerate_independent() {
+    use crate::ecs::{Position, Unit};
+
+    let units = |simulation: &Simulation| {
+        let mut units: Vec<_> = <(&Position, &Unit, &Side)>::query()
+            .iter(&simulation.world)
+            .map(|(position, unit, side)| (position.0.x, position.0.y, *unit, *side == Side::Green))
+            .collect();
+        units.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        units
+    };
+
+    let mut fixed = Simulation::new();
+    fixed.start_scenario("3-skirmish", 0).unwrap();
+    for _ in 0..600 {
+        fixed.tick();
+    }
+
+    let mut variable = Simulation::new();
+    variable.start_scenario("3-skirmish", 0).unwrap();
+    while variable.resources.get::<Tick>().unwrap().0 < 600 {
+        variable.advance(1.0 / 144.0);
+    }
+
+    assert_eq!(variable.resources.get::<Tick>().unwrap().0, 600);
+    assert_eq!(units(&fixed), units(&variable));
+}
+
+#[test]
+fn player_commands_are_recorded() {
+    use crate::ecs::{EntityId, PlayerCommand, Position, Unit};
+    use crate::resources::PlayerCommands;
+    use ultraviolet::Vec2;
+
+    let mut simulation = Simulation::new();
+    simulation.start_scenario("1-attacking", 0).unwrap();
+    simulation.tick();
+
+    let units: Vec<EntityId> = <(&EntityId, &Side)>::query()
+        .filter(component::<Unit>())
+        .iter(&simulation.world)
+        .filter(|(_, side)| **side == Side::Green)
+        .map(|(id, _)| *id)
+        .collect();
+    assert_eq!(units.len(), 10);
+
+    let command = PlayerCommand::Move {
+        units,
+        target: Vec2::new(10.0, 0.0),
+        queued: false,
+    };
+    simulation
+        .resources
+        .get_mut::<PlayerCommands>()
+        .unwrap()
+        .0
+        .push(command.clone());
+    simulation.tick();
+
+    let replay = simulation.resources.get::<Replay>().unwrap();
+    assert_eq!(replay.commands.len(), 1);
+    assert_eq!(replay.commands[0].tick, 1);
+    assert_eq!(replay.commands[0].command, command);
+
+    drop(replay);
+
+    for _ in 0..120 {
+        simulation.tick();
+    }
+
+    // The units start out in a circle around x = -36.0.
+    let moved = <(&Position, &Side)>::query()
+        .filter(component::<Unit>())
+        .iter(&simulation.world)
+        .filter(|(position, side)| **side == Side::Green && position.0.x > -30.0)
+        .count();
+    assert_eq!(moved, 10);
+}
+
+#[test]
+fn replays_play_back_and_seek() {
+    use crate::ecs::{EntityId, PlayerCommand, Position, Unit};
+    use ultraviolet::Vec2;
+
+    let units = |simulation: &Simulation| {
+        let mut units: Vec<_> = <(&EntityId, &Position, &Unit)>::query()
+            .iter(&simulation.world)
+            .map(|(id, position, unit)| (*id, position.0.x, position.0.y, *unit))
+            .collect();
+        units.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        units
+    };
+
+    let mut played = Simulation::new();
+    played.start_scenario("3-skirmish", 7).unwrap();
+    for tick in 0..1500 {
+        if tick == 100 {
+            let units: Vec<EntityId> = <(&EntityId, &Side)>::query()
+                .filter(component::<Unit>())
+                .iter(&played.world)
+                .filter(|(_, side)| **side == Side::Green)
+                .map(|(id, _)| *id)
+                .collect();
+
+            played
+                .resources
+                .get_mut::<PlayerCommands>()
+                .unwrap()
+                .0
+                .push(PlayerCommand::AttackMove {
+                    units,
+                    target: Vec2::new(20.0, 10.0),
+                    queued: false,
+                });
+        }
+
+        played.tick();
+    }
+
+    let mut watched = Simulation::new();
+    watched.start_replay(played.replay()).unwrap();
+    assert_eq!(watched.mode(), Mode::Replay);
+
+    while watched.is_running() {
+        watched.tick();
+    }
+    assert_eq!(watched.current_tick(), 1500);
+    assert_eq!(units(&played), units(&watched));
+
+    // Seeking backwards goes via the snapshot from tick 600.
+    watched.seek(700);
+    assert_eq!(watched.current_tick(), 700);
+    watched.seek(1500);
+    assert_eq!(units(&played), units(&watched));
+}
+
+#[test]
+fn save_and_load() {
+    use crate::ecs::{Building, CheeseGuyserBuiltOn, EntityId, Position, Unit};
+    use crate::pathfinding::MapHandle;
+
+    let units = |simulation: &Simulation| {
+        let mut units: Vec<_> = <(&EntityId, &Position, &Unit)>::query()
+            .iter(&simulation.world)
+            .map(|(id, position, unit)| (*id, position.0.x, position.0.y, *unit))
+            .collect();
+        units.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        units
+    };
+
+    let mut saved = Simulation::new();
+    saved.start_scenario("3-skirmish", 0).unwrap();
+    for _ in 0..60 * 10 {
+        saved.tick();
+    }
+
+    let path = std::env::temp_dir().join("cheese-save-and-load.ron");
+    saved.save_game(&path).unwrap();
+
+    let mut loaded = Simulation::new();
+    loaded.load_game(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(loaded.mode(), Mode::Playing);
+    assert_eq!(loaded.current_tick(), 600);
+    assert_eq!(units(&saved), units(&loaded));
+
+    // References between entities survive.
+    for built_on in <&CheeseGuyserBuiltOn>::query().iter(&loaded.world) {
+        assert!(<&Building>::query()
+            .get(&loaded.world, built_on.pump)
+            .is_ok());
+    }
+
+    // And the map has been rebuilt.
+    let buildings = <&Building>::query().iter(&loaded.world).count();
+    let handles = <&MapHandle>::query().iter(&loaded.world).count();
+    assert!(buildings > 0);
+    assert_eq!(buildings, handles);
+
+    // The random numbers carry on from where they were.
+    let next_random_numbers = |simulation: &Simulation| {
+        let mut gameplay = simulation.resources.get::<GameplayRng>().unwrap().clone();
+        let mut cosmetic = simulation.resources.get::<CosmeticRng>().unwrap().clone();
+        (gameplay.0.gen::<u64>(), cosmetic.0.gen::<u64>())
+    };
+    assert_eq!(next_random_numbers(&saved), next_random_numbers(&loaded));
+
+    for _ in 0..60 * 10 {
+        loaded.tick();
+    }
+    assert!(loaded.save_replay().is_err());
+}
@@ -0,0 +1,182 @@
+use crate::animation::Skin;
+use crate::ecs::*;
+use crate::pathfinding::{Map, MapHandle};
+use crate::resources::{
+    AiBuildOrders, AiPlayers, Alliances, CheeseCoins, ControlGroups, CosmeticRng, GameStats,
+    GameplayRng, NextEntityId, Objectives, ScenarioMessage, Tick, TotalTime, Triggers, Upgrades,
+    Visibility,
+};
+use legion::storage::{Archetype, ArchetypeWriter, Components, EntityLayout};
+use legion::world::{Allocate, Duplicate, Merger};
+use legion::*;
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// A copy of everything that changes during a match: the world and the gameplay resources.
+///
+/// Restoring a snapshot and running the same commands from there gives exactly the same match as
+/// the one the snapshot was taken from, which is what lets replays seek without re-simulating from
+/// the very start.
+pub struct Snapshot {
+    world: World,
+    map: Map,
+    cheese_coins: CheeseCoins,
+    upgrades: Upgrades,
+    total_time: TotalTime,
+    tick: Tick,
+    game_stats: GameStats,
+    control_groups: ControlGroups,
+    ai_build_orders: AiBuildOrders,
+    ai_players: AiPlayers,
+    alliances: Alliances,
+    visibility: Visibility,
+    objectives: Objectives,
+    triggers: Triggers,
+    scenario_message: ScenarioMessage,
+    gameplay_rng: GameplayRng,
+    cosmetic_rng: CosmeticRng,
+    next_entity_id: NextEntityId,
+}
+
+impl Snapshot {
+    pub fn take(world: &World, resources: &Resources) -> Self {
+        Self {
+            world: copy_world(world),
+            map: resources.get::<Map>().unwrap().clone(),
+            cheese_coins: resources.get::<CheeseCoins>().unwrap().clone(),
+            upgrades: resources.get::<Upgrades>().unwrap().clone(),
+            total_time: resources.get::<TotalTime>().unwrap().clone(),
+            tick: resources.get::<Tick>().unwrap().clone(),
+            game_stats: resources.get::<GameStats>().unwrap().clone(),
+            control_groups: resources.get::<ControlGroups>().unwrap().clone(),
+            ai_build_orders: resources.get::<AiBuildOrders>().unwrap().clone(),
+            ai_players: resources.get::<AiPlayers>().unwrap().clone(),
+            alliances: resources.get::<Alliances>().unwrap().clone(),
+            visibility: resources.get::<Visibility>().unwrap().clone(),
+            objectives: resources.get::<Objectives>().unwrap().clone(),
+            triggers: resources.get::<Triggers>().unwrap().clone(),
+            scenario_message: resources.get::<ScenarioMessage>().unwrap().clone(),
+            gameplay_rng: resources.get::<GameplayRng>().unwrap().clone(),
+            cosmetic_rng: resources.get::<CosmeticRng>().unwrap().clone(),
+            next_entity_id: resources.get::<NextEntityId>().unwrap().clone(),
+        }
+    }
+
+    pub fn restore(&self, world: &mut World, resources: &mut Resources) {
+        *world = copy_world(&self.world);
+        resources.insert(self.map.clone());
+        resources.insert(self.cheese_coins.clone());
+        resources.insert(self.upgrades.clone());
+        resources.insert(self.total_time.clone());
+        resources.insert(self.tick.clone());
+        resources.insert(self.game_stats.clone());
+        resources.insert(self.control_groups.clone());
+        resources.insert(self.ai_build_orders.clone());
+        resources.insert(self.ai_players.clone());
+        resources.insert(self.alliances.clone());
+        resources.insert(self.visibility.clone());
+        resources.insert(self.objectives.clone());
+        resources.insert(self.triggers.clone());
+        resources.insert(self.scenario_message.clone());
+        resources.insert(self.gameplay_rng.clone());
+        resources.insert(self.cosmetic_rng.clone());
+        resources.insert(self.next_entity_id.clone());
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick.0
+    }
+}
+
+// Copies the world into a fresh one. Entities keep their ids (so that components and resources
+// that refer to other entities stay valid) and their order (so that systems iterate over them in
+// the same order, which the simulation being deterministic relies on).
+fn copy_world(world: &World) -> World {
+    copy_world_with_ids(world, HashMap::new())
+}
+
+/// Like `copy_world`, but entities in `ids` get given a different id in the copy. Components that
+/// refer to entities aren't changed.
+pub fn copy_world_with_ids(world: &World, ids: HashMap<Entity, Entity>) -> World {
+    let mut copy = World::default();
+    copy.clone_from(world, &any(), &mut KeepIds::new(ids));
+    copy
+}
+
+struct KeepIds {
+    duplicate: Duplicate,
+    ids: HashMap<Entity, Entity>,
+}
+
+impl KeepIds {
+    fn new(ids: HashMap<Entity, Entity>) -> Self {
+        let mut duplicate = Duplicate::default();
+
+        // Every component that can be on an entity needs to be here, otherwise it's silently
+        // dropped from the copy.
+        duplicate.register_clone::<Abilities>();
+        duplicate.register_clone::<Agroed>();
+        duplicate.register_clone::<AnimationState>();
+        duplicate.register_clone::<Avoidable>();
+        duplicate.register_clone::<Avoidance>();
+        duplicate.register_clone::<Avoids>();
+        duplicate.register_clone::<Building>();
+        duplicate.register_clone::<BuildingCompleteness>();
+        duplicate.register_clone::<Bullet>();
+        duplicate.register_clone::<Shell>();
+        duplicate.register_clone::<CanAttack>();
+        duplicate.register_clone::<CanBuild>();
+        duplicate.register_clone::<CheeseDropletPosition>();
+        duplicate.register_clone::<CheeseDropletVelocity>();
+        duplicate.register_clone::<CheeseGuyser>();
+        duplicate.register_clone::<CheeseGuyserBuiltOn>();
+        duplicate.register_clone::<CommandQueue>();
+        duplicate.register_clone::<Cooldown>();
+        duplicate.register_clone::<Energy>();
+        duplicate.register_clone::<SpellCooldowns>();
+        duplicate.register_clone::<DamagedThisTick>();
+        duplicate.register_clone::<EntityId>();
+        duplicate.register_clone::<Explosion>();
+        duplicate.register_clone::<Facing>();
+        duplicate.register_clone::<FiringRange>();
+        duplicate.register_clone::<MinFiringRange>();
+        duplicate.register_clone::<FullyBuilt>();
+        duplicate.register_clone::<Health>();
+        duplicate.register_clone::<MapHandle>();
+        duplicate.register_clone::<MoveSpeed>();
+        duplicate.register_clone::<MovementDebugging>();
+        duplicate.register_clone::<Position>();
+        duplicate.register_clone::<PreviousPosition>();
+        duplicate.register_clone::<Radius>();
+        duplicate.register_clone::<RecruitmentQueue>();
+        duplicate.register_clone::<Selectable>();
+        duplicate.register_clone::<Selected>();
+        duplicate.register_clone::<Side>();
+        duplicate.register_clone::<Skin>();
+        duplicate.register_clone::<Tag>();
+        duplicate.register_clone::<Unit>();
+
+        Self { duplicate, ids }
+    }
+}
+
+impl Merger for KeepIds {
+    fn assign_id(&mut self, existing: Entity, _allocator: &mut Allocate) -> Entity {
+        self.ids.get(&existing).copied().unwrap_or(existing)
+    }
+
+    fn convert_layout(&mut self, source_layout: EntityLayout) -> EntityLayout {
+        self.duplicate.convert_layout(source_layout)
+    }
+
+    fn merge_archetype(
+        &mut self,
+        src_entity_range: Range<usize>,
+        src_arch: &Archetype,
+        src_components: &Components,
+        dst: &mut ArchetypeWriter,
+    ) {
+        self.duplicate
+            .merge_archetype(src_entity_range, src_arch, src_components, dst)
+    }
+}
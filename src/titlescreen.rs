@@ -1,7 +1,10 @@
 use crate::renderer::{
     Font, LineBuffers, ModelInstance, TextAlignment, TextBuffer, TitlescreenBuffer,
 };
-use crate::resources::{CursorIcon, DeltaTime, DpiScaling, Mode, MouseState, ScreenDimensions};
+use crate::resources::{
+    AiDifficulty, CursorIcon, DeltaTime, DpiScaling, Mode, MouseState, ScreenDimensions,
+};
+use crate::scenarios::{self, ScenarioListing};
 use legion::*;
 use ultraviolet::{Mat4, Rotor3, Vec2, Vec3, Vec4};
 
@@ -15,16 +18,15 @@ const MOON_POSITION: Vec3 = Vec3::new(-1.5, 0.0, 3.0);
 
 const MAIN_MENU: &'static [(&'static str, Vec2)] = &[
     ("Scenarios", Vec2::new(0.3, 3.5 / 6.0)),
-    ("Quit", Vec2::new(0.3, 4.0 / 6.0)),
+    ("Scenario Editor", Vec2::new(0.3, 4.0 / 6.0)),
+    ("Watch Last Replay", Vec2::new(0.3, 4.5 / 6.0)),
+    ("Quit", Vec2::new(0.3, 5.0 / 6.0)),
 ];
 
-const SCENARIOS_MENU: &'static [(&'static str, Vec2)] = &[
-    ("Training 1: Attacking", Vec2::new(0.3, 3.5 / 6.0)),
-    ("Training 2: Base Building", Vec2::new(0.3, 4.0 / 6.0)),
-    ("Skirmish", Vec2::new(0.3, 4.5 / 6.0)),
-    ("Sandbox", Vec2::new(0.3, 5.0 / 6.0)),
-    ("Back", Vec2::new(0.3, 5.5 / 6.0)),
-];
+// Where the first scenario is listed, and the furthest down that the "Back" button can go.
+const SCENARIOS_TOP: f32 = 3.5 / 6.0;
+const SCENARIOS_BOTTOM: f32 = 5.5 / 6.0;
+const SCENARIOS_SPACING: f32 = 0.5 / 6.0;
 
 pub const TEXT_COLOUR: Vec4 = Vec4::new(0.867, 0.675, 0.086, 1.0);
 
@@ -34,14 +36,40 @@ pub fn selected_colour() -> Vec4 {
 
 pub enum Menu {
     Main,
-    Scenarios,
+    // The scenario files that were found when the menu was opened. If `edit` is set, picking one
+    // opens it in the editor instead of playing it. Otherwise, the AI difficulty can be changed.
+    Scenarios {
+        scenarios: Vec<ScenarioListing>,
+        edit: bool,
+    },
 }
 
 impl Menu {
-    fn list(&self) -> &'static [(&'static str, Vec2)] {
+    fn list(&self, difficulty: AiDifficulty) -> Vec<(&str, Vec2)> {
         match self {
-            Self::Main => MAIN_MENU,
-            Self::Scenarios => SCENARIOS_MENU,
+            Self::Main => MAIN_MENU.to_vec(),
+            Self::Scenarios { scenarios, edit } => {
+                let items: Vec<&str> = scenarios
+                    .iter()
+                    .map(|scenario| scenario.title.as_str())
+                    .chain(std::iter::once(if *edit {
+                        "New Scenario"
+                    } else {
+                        difficulty.label()
+                    }))
+                    .chain(std::iter::once("Back"))
+                    .collect();
+
+                // Squash the list together if there are too many scenarios to fit.
+                let spacing = SCENARIOS_SPACING
+                    .min((SCENARIOS_BOTTOM - SCENARIOS_TOP) / (items.len() - 1).max(1) as f32);
+
+                items
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, text)| (text, Vec2::new(0.3, SCENARIOS_TOP + i as f32 * spacing)))
+                    .collect()
+            }
         }
     }
 }
@@ -85,6 +113,7 @@ fn render_text(
     #[resource] mouse_state: &MouseState,
     #[resource] cursor_icon: &mut CursorIcon,
     #[resource] menu: &Menu,
+    #[resource] difficulty: &AiDifficulty,
 ) {
     let screen_dimensions = screen_dimensions.as_vec();
 
@@ -98,7 +127,7 @@ fn render_text(
         TEXT_COLOUR,
     );
 
-    for (text, position) in menu.list().iter() {
+    for (text, position) in menu.list(*difficulty).iter() {
         let center = *position * screen_dimensions;
 
         let (top_left, bottom_right) = text_selection_area(center, text, dpi_scaling.0);
@@ -130,10 +159,11 @@ fn render_click_regions(
     #[resource] screen_dimensions: &ScreenDimensions,
     #[resource] dpi_scaling: &DpiScaling,
     #[resource] menu: &Menu,
+    #[resource] difficulty: &AiDifficulty,
 ) {
     let screen_dimensions = screen_dimensions.as_vec();
 
-    for (text, position) in menu.list().iter() {
+    for (text, position) in menu.list(*difficulty).iter() {
         let center = *position * screen_dimensions;
 
         let (top_left, bottom_right) = text_selection_area(center, text, dpi_scaling.0);
@@ -148,6 +178,7 @@ fn handle_clicks(
     #[resource] mouse_state: &MouseState,
     #[resource] mode: &mut Mode,
     #[resource] menu: &mut Menu,
+    #[resource] difficulty: &mut AiDifficulty,
 ) {
     if !mouse_state.left_state.was_clicked() {
         return;
@@ -155,36 +186,43 @@ fn handle_clicks(
 
     let screen_dimensions = screen_dimensions.as_vec();
 
-    for &(text, position) in menu.list().iter() {
+    let clicked = menu.list(*difficulty).iter().position(|&(text, position)| {
         let center = position * screen_dimensions;
         let (top_left, bottom_right) = text_selection_area(center, text, dpi_scaling.0);
-        if point_in_area(mouse_state.position, top_left, bottom_right) {
-            match text {
-                "Scenarios" => {
-                    *menu = Menu::Scenarios;
-                }
-                "Training 1: Attacking" => {
-                    *mode = Mode::StartScenario(1);
-                }
-                "Training 2: Base Building" => {
-                    *mode = Mode::StartScenario(2);
-                }
-                "Skirmish" => {
-                    *mode = Mode::StartScenario(3);
-                }
-                "Sandbox" => {
-                    *mode = Mode::StartScenario(255);
-                }
-                "Back" => {
-                    *menu = Menu::Main;
+        point_in_area(mouse_state.position, top_left, bottom_right)
+    });
+
+    let index = match clicked {
+        Some(index) => index,
+        None => return,
+    };
+
+    match menu {
+        Menu::Main => match MAIN_MENU[index].0 {
+            "Scenarios" => {
+                *menu = Menu::Scenarios {
+                    scenarios: scenarios::list(),
+                    edit: false,
                 }
-                "Quit" => {
-                    *mode = Mode::Quit;
+            }
+            "Scenario Editor" => {
+                *menu = Menu::Scenarios {
+                    scenarios: scenarios::list(),
+                    edit: true,
                 }
-                _ => {}
             }
-            return;
-        }
+            "Watch Last Replay" => *mode = Mode::StartReplay,
+            "Quit" => *mode = Mode::Quit,
+            _ => {}
+        },
+        // After the scenarios come "New Scenario" (when editing) or the difficulty, and then "Back".
+        Menu::Scenarios { scenarios, edit } => match scenarios.get(index) {
+            Some(scenario) if *edit => *mode = Mode::StartEditor(Some(scenario.name.clone())),
+            Some(scenario) => *mode = Mode::StartScenario(scenario.name.clone(), None),
+            None if *edit && index == scenarios.len() => *mode = Mode::StartEditor(None),
+            None if index == scenarios.len() => *difficulty = difficulty.next(),
+            None => *menu = Menu::Main,
+        },
     }
 }
 